@@ -1,20 +1,123 @@
-use crate::error::{Result, StowError};
-use crate::{fs_ops, logger};
-use std::fs;
-use std::path::Path;
+use crate::diff;
+use crate::error::{Conflict, ConflictKind, Result, StowError};
+use crate::filesystem::Filesystem;
+use crate::logger;
+use std::path::{Path, PathBuf};
+
+/// Suffix of the target copy kept next to the package file it differs from
+pub const ADOPTED_SUFFIX: &str = ".adopted";
+
+/// What adopting a target path into the package does
+#[derive(Debug, Clone, PartialEq)]
+pub enum Adoption {
+    /// The package has nothing at the path, the target path is moved into it
+    Move,
+    /// The package has the same file, the target copy is removed
+    Identical,
+    /// Both are directories, their contents are merged entry by entry
+    Merge,
+    /// The package's version differs, the target copy is kept next to it at the given path
+    KeepBoth(PathBuf),
+}
+
+/// Decide how to adopt a target path into the package
+/// A target copy that differs from the package's version never replaces it: this
+/// fails unless both are kept
+pub fn adoption(
+    fs: &dyn Filesystem,
+    target_path: &Path,
+    package_path: &Path,
+    keep_both: bool,
+) -> Result<Adoption> {
+    if fs.symlink_metadata(package_path).is_err() {
+        return Ok(Adoption::Move);
+    }
+
+    match (fs.is_dir(target_path), fs.is_dir(package_path)) {
+        (true, true) => Ok(Adoption::Merge),
+        (false, false) if fs.read_file(target_path)? == fs.read_file(package_path)? => {
+            Ok(Adoption::Identical)
+        }
+        _ if keep_both => Ok(Adoption::KeepBoth(adopted_path(package_path))),
+        _ => Err(differs_error(vec![(
+            target_path.to_path_buf(),
+            package_path.to_path_buf(),
+        )])),
+    }
+}
+
+/// Where the target copy of a path is kept when it differs from the package's version
+pub fn adopted_path(package_path: &Path) -> PathBuf {
+    let mut name = package_path.file_name().unwrap_or_default().to_os_string();
+    name.push(ADOPTED_SUFFIX);
+    package_path.with_file_name(name)
+}
+
+/// Find the target files that differ from the package's version, with the package file
+/// Directories are compared entry by entry; entries only in the target don't differ
+pub fn differing_files(
+    fs: &dyn Filesystem,
+    target_path: &Path,
+    package_path: &Path,
+) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mut differing = Vec::new();
+    collect_differing_files(fs, target_path, package_path, &mut differing)?;
+    Ok(differing)
+}
+
+fn collect_differing_files(
+    fs: &dyn Filesystem,
+    target_path: &Path,
+    package_path: &Path,
+    differing: &mut Vec<(PathBuf, PathBuf)>,
+) -> Result<()> {
+    match adoption(fs, target_path, package_path, true)? {
+        Adoption::Merge => {
+            for entry in fs.read_dir(target_path)? {
+                if let Some(name) = entry.file_name() {
+                    collect_differing_files(fs, &entry, &package_path.join(name), differing)?;
+                }
+            }
+        }
+        Adoption::KeepBoth(_) => {
+            differing.push((target_path.to_path_buf(), package_path.to_path_buf()));
+        }
+        Adoption::Move | Adoption::Identical => {}
+    }
+    Ok(())
+}
+
+/// Conflicts error for target files that differ from the package's version
+fn differs_error(differing: Vec<(PathBuf, PathBuf)>) -> StowError {
+    StowError::conflicts(
+        differing
+            .into_iter()
+            .map(|(path, source)| Conflict {
+                path,
+                package: None,
+                source: Some(source),
+                kind: ConflictKind::Differs,
+            })
+            .collect(),
+    )
+}
 
 /// Adopt a conflicting file by moving it from target into the package
+/// A file identical to the package's version is just removed from the target; one that
+/// differs is refused, or kept as `<name>.adopted` in the package with `keep_both`
 #[must_use = "adopt operations can fail and should be checked"]
 pub fn adopt_file(
+    fs: &dyn Filesystem,
     target_file: impl AsRef<Path>,
     package_file: impl AsRef<Path>,
+    keep_both: bool,
     dry_run: bool,
 ) -> Result<()> {
     let target_file = target_file.as_ref();
     let package_file = package_file.as_ref();
 
     // Verify target file exists
-    if !target_file.exists() {
+    if !fs.exists(target_file) {
         return Err(StowError::invalid_path(format!(
             "Target file does not exist: {}",
             target_file.display()
@@ -22,39 +125,51 @@ pub fn adopt_file(
     }
 
     // Verify target is not a symlink (we don't adopt symlinks)
-    if fs_ops::is_symlink(target_file) {
+    if fs.is_symlink(target_file) {
         return Err(StowError::invalid_path(format!(
             "Target is already a symlink, cannot adopt: {}",
             target_file.display()
         )));
     }
 
-    logger::action(&format!(
-        "Adopt file: {} -> {}",
-        target_file.display(),
-        package_file.display()
-    ));
+    match adoption(fs, target_file, package_file, keep_both)? {
+        Adoption::Identical => {
+            logger::action(&format!(
+                "Adopt file (identical to {}): {}",
+                package_file.display(),
+                target_file.display()
+            ));
 
-    if !dry_run {
-        // Create parent directories in package if needed
-        fs_ops::ensure_parent_dirs(package_file)?;
-
-        // Move the file from target to package
-        // Try rename first, fall back to copy+remove if it fails (e.g., cross-device)
-        if let Err(rename_err) = fs::rename(target_file, package_file) {
-            // Rename failed, try copy + remove as fallback
-            match fs::copy(target_file, package_file) {
-                Ok(_) => {
-                    fs::remove_file(target_file)
-                        .map_err(|e| StowError::io_error(target_file.to_owned(), e))?;
-                }
-                Err(_) => {
-                    // If copy also fails, return the original rename error
-                    return Err(StowError::io_error(target_file.to_owned(), rename_err));
-                }
+            if !dry_run {
+                fs.remove_file(target_file)?;
             }
         }
+        Adoption::KeepBoth(adopted_file) => {
+            logger::action(&format!(
+                "Adopt file, keeping both: {} -> {}",
+                target_file.display(),
+                adopted_file.display()
+            ));
+            show_differences(fs, package_file, target_file);
+
+            if !dry_run {
+                move_path(fs, target_file, &adopted_file)?;
+            }
+        }
+        Adoption::Move | Adoption::Merge => {
+            logger::action(&format!(
+                "Adopt file: {} -> {}",
+                target_file.display(),
+                package_file.display()
+            ));
+
+            if !dry_run {
+                move_path(fs, target_file, package_file)?;
+            }
+        }
+    }
 
+    if !dry_run {
         logger::verbose(&format!("Adopted: {}", target_file.display()));
     }
 
@@ -62,17 +177,21 @@ pub fn adopt_file(
 }
 
 /// Override a conflicting file by removing it from the target
-pub fn override_file(target_file: impl AsRef<Path>, dry_run: bool) -> Result<()> {
+pub fn override_file(
+    fs: &dyn Filesystem,
+    target_file: impl AsRef<Path>,
+    dry_run: bool,
+) -> Result<()> {
     let target_file = target_file.as_ref();
 
     // Verify target file exists
-    if !target_file.exists() {
+    if !fs.exists(target_file) {
         return Ok(()); // Already gone, nothing to do
     }
 
     // Verify target is not a symlink pointing to our package
     // (we don't want to remove our own symlinks)
-    if fs_ops::is_symlink(target_file) {
+    if fs.is_symlink(target_file) {
         logger::verbose(&format!(
             "Target is a symlink, skipping override: {}",
             target_file.display()
@@ -86,12 +205,10 @@ pub fn override_file(target_file: impl AsRef<Path>, dry_run: bool) -> Result<()>
     ));
 
     if !dry_run {
-        if target_file.is_dir() {
-            fs::remove_dir_all(target_file)
-                .map_err(|e| StowError::io_error(target_file.to_path_buf(), e))?;
+        if fs.is_dir(target_file) {
+            fs.remove_dir_all(target_file)?;
         } else {
-            fs::remove_file(target_file)
-                .map_err(|e| StowError::io_error(target_file.to_path_buf(), e))?;
+            fs.remove_file(target_file)?;
         }
 
         logger::verbose(&format!("Removed: {}", target_file.display()));
@@ -100,18 +217,133 @@ pub fn override_file(target_file: impl AsRef<Path>, dry_run: bool) -> Result<()>
     Ok(())
 }
 
+/// Override a conflicting file by moving it out of the target, so the removal can be undone
+pub fn stash_file(fs: &dyn Filesystem, target_file: &Path, stash_file: &Path) -> Result<()> {
+    logger::action(&format!(
+        "Override (remove) file: {}",
+        target_file.display()
+    ));
+
+    // Creates the stash directory as needed
+    fs.rename(target_file, stash_file)?;
+    logger::verbose(&format!(
+        "Kept for undo: {} -> {}",
+        target_file.display(),
+        stash_file.display()
+    ));
+
+    Ok(())
+}
+
+/// Back up a conflicting file, directory or link by moving it out of the target
+pub fn backup_file(
+    fs: &dyn Filesystem,
+    target_file: impl AsRef<Path>,
+    backup_file: impl AsRef<Path>,
+    dry_run: bool,
+) -> Result<()> {
+    let target_file = target_file.as_ref();
+    let backup_file = backup_file.as_ref();
+
+    logger::action(&format!(
+        "Back up: {} -> {}",
+        target_file.display(),
+        backup_file.display()
+    ));
+
+    if !dry_run {
+        // Creates the backup directory as needed
+        fs.rename(target_file, backup_file)?;
+        logger::verbose(&format!("Backed up: {}", target_file.display()));
+    }
+
+    Ok(())
+}
+
+/// Move a backed up file or directory back to its place in the target
+#[must_use = "restore operations can fail and should be checked"]
+pub fn restore_file(
+    fs: &dyn Filesystem,
+    backup_file: impl AsRef<Path>,
+    target_file: impl AsRef<Path>,
+    dry_run: bool,
+) -> Result<()> {
+    let backup_file = backup_file.as_ref();
+    let target_file = target_file.as_ref();
+
+    logger::action(&format!(
+        "Restore: {} -> {}",
+        backup_file.display(),
+        target_file.display()
+    ));
+
+    if !dry_run {
+        // Recreates missing parent directories in the target
+        fs.rename(backup_file, target_file)?;
+        logger::verbose(&format!("Restored: {}", target_file.display()));
+    }
+
+    Ok(())
+}
+
+/// Replace a link with a real copy of the package file or directory it points to
+#[must_use = "materialize operations can fail and should be checked"]
+pub fn materialize_link(
+    fs: &dyn Filesystem,
+    link_path: impl AsRef<Path>,
+    source_path: impl AsRef<Path>,
+    dry_run: bool,
+) -> Result<()> {
+    let link_path = link_path.as_ref();
+    let source_path = source_path.as_ref();
+
+    logger::action(&format!(
+        "Replace link with a copy: {} (copy of {})",
+        link_path.display(),
+        source_path.display()
+    ));
+
+    if !dry_run {
+        replace_with_copy(fs, link_path, source_path)?;
+        logger::verbose(&format!("Materialized: {}", link_path.display()));
+    }
+
+    Ok(())
+}
+
+/// Remove a link and copy its source to its place, expanding directories into real trees
+/// A hard link is replaced too, so the copy no longer shares the package file
+pub fn replace_with_copy(fs: &dyn Filesystem, link_path: &Path, source_path: &Path) -> Result<()> {
+    // Read before the link is gone, a missing source leaves the link in place
+    let is_dir = fs.metadata(source_path)?.is_dir();
+
+    if fs.is_symlink(link_path) {
+        fs.remove_link(link_path)?;
+    } else {
+        fs.remove_file(link_path)?;
+    }
+    if is_dir {
+        copy_dir_recursive(fs, source_path, link_path)
+    } else {
+        fs.copy_file(source_path, link_path)
+    }
+}
+
 /// Adopt a conflicting directory by moving it into the package
+/// An existing package directory is merged with it file by file, like `adopt_file`
 #[must_use = "adopt operations can fail and should be checked"]
 pub fn adopt_directory(
+    fs: &dyn Filesystem,
     target_dir: impl AsRef<Path>,
     package_dir: impl AsRef<Path>,
+    keep_both: bool,
     dry_run: bool,
 ) -> Result<()> {
     let target_dir = target_dir.as_ref();
     let package_dir = package_dir.as_ref();
 
     // Verify target directory exists
-    if !target_dir.exists() {
+    if !fs.exists(target_dir) {
         return Err(StowError::invalid_path(format!(
             "Target directory does not exist: {}",
             target_dir.display()
@@ -119,45 +351,46 @@ pub fn adopt_directory(
     }
 
     // Verify target is a real directory (not a symlink)
-    if fs_ops::is_symlink(target_dir) {
+    if fs.is_symlink(target_dir) {
         return Err(StowError::invalid_path(format!(
             "Target is already a symlink, cannot adopt: {}",
             target_dir.display()
         )));
     }
 
-    logger::action(&format!(
-        "Adopt directory: {} -> {}",
-        target_dir.display(),
-        package_dir.display()
-    ));
+    let adoption = adoption(fs, target_dir, package_dir, keep_both)?;
+
+    // Nothing is merged if any file would replace a different package file
+    if adoption == Adoption::Merge && !keep_both {
+        let differing = differing_files(fs, target_dir, package_dir)?;
+        if !differing.is_empty() {
+            return Err(differs_error(differing));
+        }
+    }
+
+    match &adoption {
+        Adoption::KeepBoth(adopted_dir) => logger::action(&format!(
+            "Adopt directory, keeping both: {} -> {}",
+            target_dir.display(),
+            adopted_dir.display()
+        )),
+        _ => logger::action(&format!(
+            "Adopt directory: {} -> {}",
+            target_dir.display(),
+            package_dir.display()
+        )),
+    }
 
     if !dry_run {
-        // Create parent directories in package if needed
-        fs_ops::ensure_parent_dirs(package_dir)?;
-
-        // If package dir doesn't exist, just move the whole thing
-        if !package_dir.exists() {
-            // Try rename first, fall back to copy+remove if it fails (e.g., cross-device)
-            if let Err(rename_err) = fs::rename(target_dir, package_dir) {
-                // Rename failed, try recursive copy + remove as fallback
-                match copy_dir_recursive(target_dir, package_dir) {
-                    Ok(_) => {
-                        fs::remove_dir_all(target_dir)
-                            .map_err(|e| StowError::io_error(target_dir.to_owned(), e))?;
-                    }
-                    Err(_) => {
-                        // If copy also fails, return the original rename error
-                        return Err(StowError::io_error(target_dir.to_owned(), rename_err));
-                    }
-                }
+        match adoption {
+            // Rename creates missing parent directories in the package
+            Adoption::Move | Adoption::Identical => move_path(fs, target_dir, package_dir)?,
+            Adoption::KeepBoth(adopted_dir) => move_path(fs, target_dir, &adopted_dir)?,
+            Adoption::Merge => {
+                merge_directories(fs, target_dir, package_dir, keep_both)?;
+                // Remove the target directory with the files left behind as identical
+                fs.remove_dir_all(target_dir)?;
             }
-        } else {
-            // Package dir exists, merge contents
-            merge_directories(target_dir, package_dir)?;
-            // Remove the now-empty target directory
-            fs::remove_dir_all(target_dir)
-                .map_err(|e| StowError::io_error(target_dir.to_path_buf(), e))?;
         }
 
         logger::verbose(&format!("Adopted: {}", target_dir.display()));
@@ -166,24 +399,42 @@ pub fn adopt_directory(
     Ok(())
 }
 
-/// Recursively copy a directory
-fn copy_dir_recursive(src: &Path, dst: &Path) -> Result<()> {
-    fs::create_dir_all(dst).map_err(|e| StowError::io_error(dst.to_path_buf(), e))?;
+/// Move a file or directory
+/// Falls back to copying and removing the source if renaming fails (e.g., cross-device)
+fn move_path(fs: &dyn Filesystem, src: &Path, dst: &Path) -> Result<()> {
+    let Err(rename_err) = fs.rename(src, dst) else {
+        return Ok(());
+    };
 
-    // Collect entries first for better error messages
-    let entries: Vec<_> = fs::read_dir(src)
-        .map_err(|e| StowError::io_error(src.to_path_buf(), e))?
-        .collect::<std::io::Result<_>>()
-        .map_err(|e| StowError::io_error(src.to_path_buf(), e))?;
+    if fs.is_dir(src) {
+        if copy_dir_recursive(fs, src, dst).is_err() {
+            // If copy also fails, return the original rename error
+            return Err(rename_err);
+        }
+        fs.remove_dir_all(src)
+    } else {
+        if fs.copy_file(src, dst).is_err() {
+            // If copy also fails, return the original rename error
+            return Err(rename_err);
+        }
+        fs.remove_file(src)
+    }
+}
+
+/// Recursively copy a directory
+fn copy_dir_recursive(fs: &dyn Filesystem, src: &Path, dst: &Path) -> Result<()> {
+    fs.create_dir_all(dst)?;
 
-    for entry in entries {
-        let src_path = entry.path();
-        let dst_path = dst.join(entry.file_name());
+    for src_path in fs.read_dir(src)? {
+        let Some(name) = src_path.file_name() else {
+            continue;
+        };
+        let dst_path = dst.join(name);
 
-        if src_path.is_dir() {
-            copy_dir_recursive(&src_path, &dst_path)?;
+        if fs.is_dir(&src_path) {
+            copy_dir_recursive(fs, &src_path, &dst_path)?;
         } else {
-            fs::copy(&src_path, &dst_path).map_err(|e| StowError::io_error(src_path, e))?;
+            fs.copy_file(&src_path, &dst_path)?;
         }
     }
 
@@ -191,38 +442,26 @@ fn copy_dir_recursive(src: &Path, dst: &Path) -> Result<()> {
 }
 
 /// Merge contents of source directory into destination directory
-fn merge_directories(src: &Path, dst: &Path) -> Result<()> {
-    // Collect entries first for better error messages
-    let entries: Vec<_> = fs::read_dir(src)
-        .map_err(|e| StowError::io_error(src.to_path_buf(), e))?
-        .collect::<std::io::Result<_>>()
-        .map_err(|e| StowError::io_error(src.to_path_buf(), e))?;
-
-    for entry in entries {
-        let src_path = entry.path();
-        let dst_path = dst.join(entry.file_name());
-
-        if src_path.is_dir() {
-            if dst_path.exists() {
-                merge_directories(&src_path, &dst_path)?;
-            } else {
-                // Try rename, fall back to copy if it fails (e.g., cross-device)
-                if let Err(rename_err) = fs::rename(&src_path, &dst_path) {
-                    // Rename failed, try recursive copy as fallback
-                    if copy_dir_recursive(&src_path, &dst_path).is_err() {
-                        // If copy also fails, return the original rename error
-                        return Err(StowError::io_error(src_path, rename_err));
-                    }
-                }
-            }
-        } else {
-            // Try rename, fall back to copy if it fails (e.g., cross-device)
-            if let Err(rename_err) = fs::rename(&src_path, &dst_path) {
-                // Rename failed, try copy as fallback
-                if fs::copy(&src_path, &dst_path).is_err() {
-                    // If copy also fails, return the original rename error
-                    return Err(StowError::io_error(src_path, rename_err));
-                }
+/// Files identical to the destination's version are left in the source
+fn merge_directories(fs: &dyn Filesystem, src: &Path, dst: &Path, keep_both: bool) -> Result<()> {
+    for src_path in fs.read_dir(src)? {
+        let Some(name) = src_path.file_name() else {
+            continue;
+        };
+        let dst_path = dst.join(name);
+
+        match adoption(fs, &src_path, &dst_path, keep_both)? {
+            Adoption::Merge => merge_directories(fs, &src_path, &dst_path, keep_both)?,
+            Adoption::Move => move_path(fs, &src_path, &dst_path)?,
+            Adoption::Identical => {}
+            Adoption::KeepBoth(adopted_path) => {
+                logger::info(&format!(
+                    "Keeping both: {} -> {}",
+                    src_path.display(),
+                    adopted_path.display()
+                ));
+                show_differences(fs, &dst_path, &src_path);
+                move_path(fs, &src_path, &adopted_path)?;
             }
         }
     }
@@ -230,9 +469,43 @@ fn merge_directories(src: &Path, dst: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Show how a target copy differs from the package's version
+/// Nothing is shown for directories or files that can't be read, and only a
+/// note for binary files or files too large to compare line by line
+fn show_differences(fs: &dyn Filesystem, package_file: &Path, target_file: &Path) {
+    let (Ok(package), Ok(target)) = (fs.read_file(package_file), fs.read_file(target_file)) else {
+        return;
+    };
+
+    let Some(diff) = diff::Diff::new(&package, &target) else {
+        logger::info(&format!(
+            "  {} differs from the package file (binary or too large to compare)",
+            target_file.display()
+        ));
+        return;
+    };
+    let (added, removed) = diff.line_changes();
+    logger::info(&format!(
+        "  {} differs from the package file: {} line(s) added, {} removed",
+        target_file.display(),
+        added,
+        removed
+    ));
+    let unified = diff.unified(
+        &package_file.to_string_lossy(),
+        &target_file.to_string_lossy(),
+    );
+    for line in unified.lines() {
+        logger::info(&format!("    {}", line));
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::filesystem::OsFilesystem;
+    use crate::memfs::MemoryFilesystem;
+    use std::fs;
     use std::fs::File;
     use std::io::Write;
     use tempfile::TempDir;
@@ -248,7 +521,7 @@ mod tests {
         file.write_all(b"test content").unwrap();
 
         // Adopt it
-        let result = adopt_file(&target_file, &package_file, false);
+        let result = adopt_file(&OsFilesystem, &target_file, &package_file, false, false);
         assert!(result.is_ok());
 
         // Verify file was moved
@@ -269,7 +542,7 @@ mod tests {
         File::create(&target_file).unwrap();
 
         // Dry run
-        let result = adopt_file(&target_file, &package_file, true);
+        let result = adopt_file(&OsFilesystem, &target_file, &package_file, false, true);
         assert!(result.is_ok());
 
         // Verify nothing changed
@@ -284,7 +557,7 @@ mod tests {
 
         File::create(&target_file).unwrap();
 
-        let result = override_file(&target_file, false);
+        let result = override_file(&OsFilesystem, &target_file, false);
         assert!(result.is_ok());
         assert!(!target_file.exists());
     }
@@ -296,7 +569,7 @@ mod tests {
 
         File::create(&target_file).unwrap();
 
-        let result = override_file(&target_file, true);
+        let result = override_file(&OsFilesystem, &target_file, true);
         assert!(result.is_ok());
         assert!(target_file.exists());
     }
@@ -308,11 +581,30 @@ mod tests {
         fs::create_dir(&target_dir).unwrap();
         File::create(target_dir.join("file.txt")).unwrap();
 
-        let result = override_file(&target_dir, false);
+        let result = override_file(&OsFilesystem, &target_dir, false);
         assert!(result.is_ok());
         assert!(!target_dir.exists());
     }
 
+    #[test]
+    fn test_backup_file_keeps_layout() {
+        let temp_dir = TempDir::new().unwrap();
+        let target_dir = temp_dir.path().join("target_dir");
+        fs::create_dir(&target_dir).unwrap();
+        fs::write(target_dir.join("file.txt"), "local").unwrap();
+        let backup_dir = temp_dir.path().join("backups/1/target_dir");
+
+        backup_file(&OsFilesystem, &target_dir, &backup_dir, true).unwrap();
+        assert!(target_dir.exists());
+
+        backup_file(&OsFilesystem, &target_dir, &backup_dir, false).unwrap();
+        assert!(!target_dir.exists());
+        assert_eq!(
+            fs::read_to_string(backup_dir.join("file.txt")).unwrap(),
+            "local"
+        );
+    }
+
     #[test]
     fn test_adopt_directory() {
         let temp_dir = TempDir::new().unwrap();
@@ -322,7 +614,7 @@ mod tests {
         fs::create_dir(&target_dir).unwrap();
         File::create(target_dir.join("file.txt")).unwrap();
 
-        let result = adopt_directory(&target_dir, &package_dir, false);
+        let result = adopt_directory(&OsFilesystem, &target_dir, &package_dir, false, false);
         assert!(result.is_ok());
 
         assert!(!target_dir.exists());
@@ -337,7 +629,7 @@ mod tests {
         let package_file = temp_dir.path().join("package").join("file.txt");
 
         // Try to adopt a file that doesn't exist
-        let result = adopt_file(&target_file, &package_file, false);
+        let result = adopt_file(&OsFilesystem, &target_file, &package_file, false, false);
         assert!(result.is_err());
         assert!(matches!(result.unwrap_err(), StowError::InvalidPath(_)));
     }
@@ -367,7 +659,7 @@ mod tests {
         }
 
         // Try to adopt a symlink (should fail)
-        let result = adopt_file(&symlink_file, &package_file, false);
+        let result = adopt_file(&OsFilesystem, &symlink_file, &package_file, false, false);
         assert!(result.is_err());
         assert!(matches!(result.unwrap_err(), StowError::InvalidPath(_)));
     }
@@ -378,7 +670,7 @@ mod tests {
         let target_file = temp_dir.path().join("nonexistent.txt");
 
         // Override a file that doesn't exist (should succeed as no-op)
-        let result = override_file(&target_file, false);
+        let result = override_file(&OsFilesystem, &target_file, false);
         assert!(result.is_ok());
     }
 
@@ -406,7 +698,7 @@ mod tests {
         }
 
         // Override a symlink (should skip it)
-        let result = override_file(&symlink_file, false);
+        let result = override_file(&OsFilesystem, &symlink_file, false);
         assert!(result.is_ok());
         // Symlink should still exist (we don't override symlinks)
         assert!(symlink_file.exists());
@@ -421,7 +713,7 @@ mod tests {
         fs::create_dir(&target_dir).unwrap();
         File::create(target_dir.join("file.txt")).unwrap();
 
-        let result = adopt_directory(&target_dir, &package_dir, true);
+        let result = adopt_directory(&OsFilesystem, &target_dir, &package_dir, false, true);
         assert!(result.is_ok());
 
         // In dry run, nothing should change
@@ -440,7 +732,7 @@ mod tests {
         File::create(target_dir.join("file1.txt")).unwrap();
         File::create(target_dir.join("subdir/file2.txt")).unwrap();
 
-        let result = adopt_directory(&target_dir, &package_dir, false);
+        let result = adopt_directory(&OsFilesystem, &target_dir, &package_dir, false, false);
         assert!(result.is_ok());
 
         assert!(!target_dir.exists());
@@ -448,4 +740,122 @@ mod tests {
         assert!(package_dir.join("file1.txt").exists());
         assert!(package_dir.join("subdir/file2.txt").exists());
     }
+
+    #[test]
+    fn test_adopt_directory_merges_into_existing_package_dir() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        let target_dir = root.join("target/app");
+        let package_dir = root.join("stow/pkg/app");
+        fs.add_file(target_dir.join("local.txt"), "local");
+        fs.add_file(target_dir.join("sub/nested.txt"), "nested");
+        fs.add_file(package_dir.join("packaged.txt"), "packaged");
+
+        adopt_directory(&fs, &target_dir, &package_dir, false, false).unwrap();
+
+        assert!(!fs.exists(&target_dir));
+        assert_eq!(
+            fs.file_content(package_dir.join("local.txt")).unwrap(),
+            b"local"
+        );
+        assert_eq!(
+            fs.file_content(package_dir.join("sub/nested.txt")).unwrap(),
+            b"nested"
+        );
+        assert!(fs.exists(&package_dir.join("packaged.txt")));
+    }
+
+    #[test]
+    fn test_adopt_identical_file_removes_target() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        fs.add_file(root.join("target/file.txt"), "same");
+        fs.add_file(root.join("stow/pkg/file.txt"), "same");
+
+        let target = root.join("target/file.txt");
+        let package = root.join("stow/pkg/file.txt");
+        assert_eq!(
+            adoption(&fs, &target, &package, false).unwrap(),
+            Adoption::Identical
+        );
+        adopt_file(&fs, &target, &package, false, false).unwrap();
+
+        assert!(!fs.exists(&target));
+        assert_eq!(fs.file_content(&package).unwrap(), b"same");
+    }
+
+    #[test]
+    fn test_adopt_differing_file_fails() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        fs.add_file(root.join("target/file.txt"), "local");
+        fs.add_file(root.join("stow/pkg/file.txt"), "package");
+
+        let target = root.join("target/file.txt");
+        let package = root.join("stow/pkg/file.txt");
+        let result = adopt_file(&fs, &target, &package, false, false);
+        assert!(matches!(
+            result,
+            Err(StowError::Conflicts(c)) if c[0].kind == ConflictKind::Differs
+        ));
+        assert_eq!(fs.file_content(&target).unwrap(), b"local");
+        assert_eq!(fs.file_content(&package).unwrap(), b"package");
+    }
+
+    #[test]
+    fn test_adopt_differing_file_keep_both() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        fs.add_file(root.join("target/file.txt"), "local");
+        fs.add_file(root.join("stow/pkg/file.txt"), "package");
+
+        let target = root.join("target/file.txt");
+        let package = root.join("stow/pkg/file.txt");
+        adopt_file(&fs, &target, &package, true, false).unwrap();
+
+        assert!(!fs.exists(&target));
+        assert_eq!(fs.file_content(&package).unwrap(), b"package");
+        assert_eq!(
+            fs.file_content(root.join("stow/pkg/file.txt.adopted"))
+                .unwrap(),
+            b"local"
+        );
+    }
+
+    #[test]
+    fn test_adopt_directory_with_differing_file() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        let target_dir = root.join("target/app");
+        let package_dir = root.join("stow/pkg/app");
+        fs.add_file(target_dir.join("same.txt"), "same");
+        fs.add_file(target_dir.join("sub/config.txt"), "local");
+        fs.add_file(package_dir.join("same.txt"), "same");
+        fs.add_file(package_dir.join("sub/config.txt"), "package");
+
+        assert_eq!(
+            differing_files(&fs, &target_dir, &package_dir).unwrap(),
+            vec![(
+                target_dir.join("sub/config.txt"),
+                package_dir.join("sub/config.txt")
+            )]
+        );
+
+        // Nothing is merged when any file differs
+        assert!(adopt_directory(&fs, &target_dir, &package_dir, false, false).is_err());
+        assert!(fs.exists(&target_dir.join("same.txt")));
+
+        adopt_directory(&fs, &target_dir, &package_dir, true, false).unwrap();
+        assert!(!fs.exists(&target_dir));
+        assert_eq!(
+            fs.file_content(package_dir.join("sub/config.txt")).unwrap(),
+            b"package"
+        );
+        assert_eq!(
+            fs.file_content(package_dir.join("sub/config.txt.adopted"))
+                .unwrap(),
+            b"local"
+        );
+        assert!(!fs.exists(&package_dir.join("same.txt.adopted")));
+    }
 }
@@ -0,0 +1,446 @@
+use crate::config::StowContext;
+use crate::error::{Conflict, ConflictKind, Result, StowError};
+use crate::filesystem::Filesystem;
+use crate::overlay::OverlayFilesystem;
+use crate::planner::{self, Action, Step};
+use crate::{path_utils, state, unstow};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+/// Directory in the target holding backups of conflicting files
+pub const BACKUP_DIR_NAME: &str = ".winstow-backups";
+
+/// Name of the backup index kept in the backup directory
+pub const BACKUP_INDEX_NAME: &str = "index.toml";
+
+/// Version of the backup index format
+const BACKUP_INDEX_VERSION: u32 = 1;
+
+/// Identifier for a new backup
+/// The time comes first so backups sort by age; the process id keeps two runs
+/// within the same second apart
+pub fn new_backup_id() -> String {
+    format!("{}-{}", state::now(), std::process::id())
+}
+
+/// Directory a backup keeps its files in
+pub fn backup_dir(target_dir: &Path, backup_id: &str) -> PathBuf {
+    target_dir.join(BACKUP_DIR_NAME).join(backup_id)
+}
+
+/// Where a target path is kept in a backup, preserving its target-relative layout
+pub fn backup_path(target_dir: &Path, backup_dir: &Path, path: &Path) -> PathBuf {
+    match path_utils::strip_base(path, target_dir) {
+        Some(relative) => backup_dir.join(relative),
+        None => backup_dir.join(path.file_name().unwrap_or(path.as_os_str())),
+    }
+}
+
+/// Path of the backup index of a target directory
+pub fn index_path(target_dir: &Path) -> PathBuf {
+    target_dir.join(BACKUP_DIR_NAME).join(BACKUP_INDEX_NAME)
+}
+
+/// A file, directory or link moved out of the target
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupEntry {
+    /// Where the entry was, relative to the target directory
+    pub path: PathBuf,
+    /// Package whose link took its place
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package: Option<String>,
+}
+
+/// The entries moved out of the target by one operation
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Backup {
+    /// Time of the backup, in seconds since the Unix epoch
+    pub created: u64,
+    #[serde(default)]
+    pub entries: Vec<BackupEntry>,
+}
+
+impl Backup {
+    /// Packages the entries were backed up for, sorted and without duplicates
+    pub fn packages(&self) -> Vec<String> {
+        let packages: BTreeSet<&String> = self
+            .entries
+            .iter()
+            .filter_map(|entry| entry.package.as_ref())
+            .collect();
+        packages.into_iter().cloned().collect()
+    }
+}
+
+/// Record of the backups kept in a target directory, by backup id
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupIndex {
+    version: u32,
+    #[serde(default)]
+    pub backups: BTreeMap<String, Backup>,
+    /// Target directory the recorded paths are relative to
+    #[serde(skip)]
+    base: PathBuf,
+}
+
+impl BackupIndex {
+    /// Create an empty index for the index file at `path`
+    pub fn new(path: &Path) -> Self {
+        Self {
+            version: BACKUP_INDEX_VERSION,
+            backups: BTreeMap::new(),
+            base: index_base(path),
+        }
+    }
+
+    /// Read the index file at `path`, or start with an empty index if there is none
+    pub fn load(fs: &dyn Filesystem, path: &Path) -> Result<Self> {
+        if fs.symlink_metadata(path).is_err() {
+            return Ok(Self::new(path));
+        }
+
+        let content = fs.read_file(path)?;
+        let content = String::from_utf8(content)
+            .map_err(|e| StowError::state_error(format!("{}: {}", path.display(), e)))?;
+        let mut index: BackupIndex = toml::from_str(&content)
+            .map_err(|e| StowError::state_error(format!("{}: {}", path.display(), e)))?;
+
+        if index.version != BACKUP_INDEX_VERSION {
+            return Err(StowError::state_error(format!(
+                "{}: unsupported format version {} (expected {})",
+                path.display(),
+                index.version,
+                BACKUP_INDEX_VERSION
+            )));
+        }
+
+        index.base = index_base(path);
+        Ok(index)
+    }
+
+    /// Write the index file at `path`
+    pub fn save(&self, fs: &dyn Filesystem, path: &Path) -> Result<()> {
+        let content =
+            toml::to_string_pretty(self).map_err(|e| StowError::state_error(e.to_string()))?;
+        fs.write_file(path, content.as_bytes())
+    }
+
+    /// Record the effects of an executed step
+    pub fn record(&mut self, step: &Step, now: u64) {
+        match &step.action {
+            Action::BackupConflict { path, backup_path } => {
+                if let Some(id) = self.backup_id_of(backup_path) {
+                    let backup = self.backups.entry(id).or_insert_with(|| Backup {
+                        created: now,
+                        entries: Vec::new(),
+                    });
+                    backup.entries.push(BackupEntry {
+                        path: relative(path, &self.base),
+                        package: step.package.clone(),
+                    });
+                }
+            }
+            Action::RestoreBackup { backup_path, path } => {
+                if let Some(id) = self.backup_id_of(backup_path)
+                    && let Some(backup) = self.backups.get_mut(&id)
+                {
+                    let restored = relative(path, &self.base);
+                    backup
+                        .entries
+                        .retain(|entry| !path_utils::paths_equal(&entry.path, &restored));
+                    if backup.entries.is_empty() {
+                        self.backups.remove(&id);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Id of the backup a path inside the backup directory belongs to
+    fn backup_id_of(&self, backup_path: &Path) -> Option<String> {
+        let inside = path_utils::strip_base(backup_path, self.base.join(BACKUP_DIR_NAME))?;
+        match inside.components().next()? {
+            Component::Normal(id) => Some(id.to_string_lossy().into_owned()),
+            _ => None,
+        }
+    }
+}
+
+/// Target directory of the index file at `path`
+fn index_base(path: &Path) -> PathBuf {
+    path.parent()
+        .and_then(Path::parent)
+        .unwrap_or(path)
+        .to_path_buf()
+}
+
+/// Path relative to a base directory, or unchanged if outside of it
+fn relative(path: &Path, base: &Path) -> PathBuf {
+    path_utils::strip_base(path, base).unwrap_or_else(|| path.to_path_buf())
+}
+
+/// Plan putting the entries of a backup back into the target
+/// The packages the entries were backed up for are unstowed first, so their links
+/// make room for the restored files
+pub fn plan_restore(
+    context: &StowContext,
+    backup_id: &str,
+    overlay: &Rc<OverlayFilesystem>,
+    plan: &mut planner::Plan,
+) -> Result<()> {
+    let target_dir = context.target_dir();
+    let index = BackupIndex::load(overlay.as_ref(), &index_path(target_dir))?;
+    let backup = index
+        .backups
+        .get(backup_id)
+        .ok_or_else(|| StowError::backup_not_found(backup_id))?;
+
+    let unstower = unstow::Unstower::from_context(context);
+    unstower.plan_packages(&backup.packages(), overlay, plan)?;
+
+    let dir = backup_dir(target_dir, backup_id);
+    let mut restore = planner::Plan::new();
+    let mut restored = Vec::new();
+    for entry in &backup.entries {
+        let path = target_dir.join(&entry.path);
+        let backup_path = dir.join(&entry.path);
+
+        if let Some(conflict) = blocking_conflict(overlay.as_ref(), target_dir, &path) {
+            restore.add_conflict(Conflict {
+                package: entry.package.clone(),
+                source: Some(backup_path),
+                ..conflict
+            });
+            continue;
+        }
+
+        restore.add_for_package(
+            Action::RestoreBackup {
+                backup_path: backup_path.clone(),
+                path,
+            },
+            entry.package.clone(),
+        );
+        restored.push(backup_path);
+    }
+
+    let conflicts = restore.take_conflicts();
+    if !conflicts.is_empty() {
+        return Err(StowError::conflicts(conflicts));
+    }
+
+    // The emptied backup directory is removed, deepest directories first
+    let mut dirs = Vec::new();
+    collect_dirs(overlay.as_ref(), &dir, &restored, &mut dirs)?;
+    for path in dirs.into_iter().rev() {
+        restore.add(Action::RemoveEmptyDir { path });
+    }
+
+    restore.simulate(overlay)?;
+    plan.append(restore);
+    Ok(())
+}
+
+/// What keeps an entry from being restored at `path`: something at the path itself,
+/// or a directory link above it in the target
+fn blocking_conflict(fs: &dyn Filesystem, target_dir: &Path, path: &Path) -> Option<Conflict> {
+    let conflict = |path: &Path, kind| Conflict {
+        path: path.to_path_buf(),
+        package: None,
+        source: None,
+        kind,
+    };
+
+    let mut ancestor = path.parent();
+    while let Some(dir) = ancestor {
+        if path_utils::strip_base(dir, target_dir).is_none_or(|rest| rest.as_os_str().is_empty()) {
+            break;
+        }
+        if fs.is_symlink(dir) {
+            let target = fs.read_link(dir).unwrap_or_default();
+            return Some(conflict(dir, ConflictKind::Link(target)));
+        }
+        ancestor = dir.parent();
+    }
+
+    if fs.is_symlink(path) {
+        let target = fs.read_link(path).unwrap_or_default();
+        Some(conflict(path, ConflictKind::Link(target)))
+    } else if fs.is_dir(path) {
+        Some(conflict(path, ConflictKind::Dir))
+    } else if fs.exists(path) {
+        Some(conflict(path, ConflictKind::File))
+    } else {
+        None
+    }
+}
+
+/// Collect a directory and the real directories below it, outermost first,
+/// without descending into the entries that are moved out
+fn collect_dirs(
+    fs: &dyn Filesystem,
+    dir: &Path,
+    moved: &[PathBuf],
+    dirs: &mut Vec<PathBuf>,
+) -> Result<()> {
+    dirs.push(dir.to_path_buf());
+    for entry in fs.read_dir(dir)? {
+        if fs.is_dir(&entry) && !fs.is_symlink(&entry) && !moved.contains(&entry) {
+            collect_dirs(fs, &entry, moved, dirs)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memfs::MemoryFilesystem;
+    use crate::stow;
+
+    fn context(fs: &Rc<MemoryFilesystem>) -> StowContext {
+        StowContext::new(
+            MemoryFilesystem::root().join("stow"),
+            MemoryFilesystem::root().join("target"),
+            vec![],
+            vec![],
+            false,
+            false,
+            false,
+            false,
+            false,
+            true,
+            false,
+            false,
+            stow::FoldPolicy::default(),
+        )
+        .unwrap()
+        .with_filesystem(Rc::clone(fs) as Rc<dyn Filesystem>)
+    }
+
+    #[test]
+    fn test_backup_path_keeps_layout() {
+        let target = PathBuf::from("/home/user");
+        let dir = backup_dir(&target, "100-1");
+        assert_eq!(dir, target.join(".winstow-backups").join("100-1"));
+        assert_eq!(
+            backup_path(&target, &dir, &target.join(".config/app/settings.json")),
+            dir.join(".config/app/settings.json")
+        );
+    }
+
+    #[test]
+    fn test_index_records_backups_and_restores() {
+        let fs = MemoryFilesystem::new();
+        let target = MemoryFilesystem::root().join("target");
+        let path = index_path(&target);
+        let dir = backup_dir(&target, "100-1");
+        let step = |action| Step {
+            package: Some("pkg".to_string()),
+            action,
+            expect: None,
+        };
+
+        let mut index = BackupIndex::load(&fs, &path).unwrap();
+        index.record(
+            &step(Action::BackupConflict {
+                path: target.join(".config/a.txt"),
+                backup_path: dir.join(".config/a.txt"),
+            }),
+            100,
+        );
+        index.save(&fs, &path).unwrap();
+
+        let mut index = BackupIndex::load(&fs, &path).unwrap();
+        assert_eq!(
+            index.backups["100-1"],
+            Backup {
+                created: 100,
+                entries: vec![BackupEntry {
+                    path: PathBuf::from(".config/a.txt"),
+                    package: Some("pkg".to_string()),
+                }],
+            }
+        );
+
+        index.record(
+            &step(Action::RestoreBackup {
+                backup_path: dir.join(".config/a.txt"),
+                path: target.join(".config/a.txt"),
+            }),
+            200,
+        );
+        assert!(index.backups.is_empty());
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        let fs = Rc::new(MemoryFilesystem::new());
+        let root = MemoryFilesystem::root();
+        let target = root.join("target");
+        fs.add_file(root.join("stow/pkg/.config/app/a.txt"), "package");
+        fs.add_file(root.join("stow/pkg/b.txt"), "package");
+        fs.add_file(target.join(".config/app/a.txt"), "local");
+        let context = context(&fs);
+
+        // Stow with the backup strategy
+        let stower = stow::Stower::from_context(&context, crate::ignore::PatternSet::empty());
+        let overlay = Rc::new(OverlayFilesystem::new(Rc::clone(context.filesystem())));
+        let mut plan = planner::Plan::new();
+        stower
+            .plan_packages(&["pkg".to_string()], &overlay, &mut plan)
+            .unwrap();
+        plan.track_backups(index_path(&target));
+        plan.execute(fs.as_ref(), false).unwrap();
+
+        assert!(fs.is_symlink(&target.join(".config/app/a.txt")));
+        let index = BackupIndex::load(fs.as_ref(), &index_path(&target)).unwrap();
+        let (id, backup) = index.backups.iter().next().unwrap();
+        assert_eq!(backup.packages(), vec!["pkg".to_string()]);
+
+        // Restoring unstows the package and puts the file back
+        let overlay = Rc::new(OverlayFilesystem::new(Rc::clone(context.filesystem())));
+        let mut plan = planner::Plan::new();
+        plan_restore(&context, id, &overlay, &mut plan).unwrap();
+        plan.track_backups(index_path(&target));
+        plan.execute(fs.as_ref(), false).unwrap();
+
+        assert_eq!(
+            fs.file_content(target.join(".config/app/a.txt")).unwrap(),
+            b"local"
+        );
+        assert!(!fs.exists(&target.join("b.txt")));
+        assert!(!fs.exists(&backup_dir(&target, id)));
+        let index = BackupIndex::load(fs.as_ref(), &index_path(&target)).unwrap();
+        assert!(index.backups.is_empty());
+    }
+
+    #[test]
+    fn test_restore_refuses_to_replace_files() {
+        let fs = Rc::new(MemoryFilesystem::new());
+        let root = MemoryFilesystem::root();
+        let target = root.join("target");
+        fs.add_dir(root.join("stow"));
+        fs.add_file(target.join("a.txt"), "new");
+        fs.add_file(backup_dir(&target, "100-1").join("a.txt"), "old");
+        fs.add_file(
+            index_path(&target),
+            "version = 1\n\n[backups.100-1]\ncreated = 100\n\n[[backups.100-1.entries]]\npath = \"a.txt\"\n",
+        );
+        let context = context(&fs);
+
+        let overlay = Rc::new(OverlayFilesystem::new(Rc::clone(context.filesystem())));
+        let mut plan = planner::Plan::new();
+        let result = plan_restore(&context, "100-1", &overlay, &mut plan);
+        assert!(matches!(
+            result,
+            Err(StowError::Conflicts(c)) if c[0].kind == ConflictKind::File
+        ));
+
+        let result = plan_restore(&context, "200-1", &overlay, &mut plan);
+        assert!(matches!(result, Err(StowError::BackupNotFound(_))));
+    }
+}
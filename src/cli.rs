@@ -1,10 +1,15 @@
-use clap::Parser;
+use crate::fs_ops::LinkMode;
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
 
 /// Windows-native symlink farm manager inspired by GNU Stow
+///
+/// Either use a subcommand (`winstow stow vim`) or the GNU Stow style flags
+/// (`winstow -D vim`), which map to the subcommand of the same name.
 #[derive(Parser, Debug)]
 #[command(name = "winstow")]
 #[command(version, about, long_about = None)]
+#[command(subcommand_negates_reqs = true)]
 pub struct Cli {
     /// Stow packages (default action)
     #[arg(short = 'S', long = "stow")]
@@ -18,6 +23,49 @@ pub struct Cli {
     #[arg(short = 'R', long = "restow")]
     pub restow: bool,
 
+    /// Remove broken links into the stow directory (of the given packages, or of all packages)
+    #[arg(long = "prune-broken")]
+    pub prune_broken: bool,
+
+    #[command(flatten)]
+    pub dirs: DirArgs,
+
+    /// Enable verbose output
+    #[arg(short = 'v', long = "verbose", global = true)]
+    pub verbose: bool,
+
+    /// Perform a dry run without making any changes
+    #[arg(short = 'n', long = "dry-run", global = true)]
+    pub dry_run: bool,
+
+    #[command(flatten)]
+    pub conflicts: ConflictArgs,
+
+    #[command(flatten)]
+    pub layout: LayoutArgs,
+
+    #[command(flatten)]
+    pub patterns: PatternArgs,
+
+    /// Put package files into the target as symlinks (default), hard links or copies
+    #[arg(long = "link-mode", value_enum, value_name = "MODE")]
+    pub link_mode: Option<LinkMode>,
+
+    /// Write the computed plan to FILE (JSON, or TOML for .toml files) instead of executing it
+    #[arg(long = "plan-out", value_name = "FILE")]
+    pub plan_out: Option<PathBuf>,
+
+    /// Package names to operate on
+    #[arg(value_name = "PACKAGE", required_unless_present = "prune_broken")]
+    pub packages: Vec<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Stow and target directory
+#[derive(Args, Debug, Clone, Default, PartialEq)]
+pub struct DirArgs {
     /// Stow directory containing packages (default: current directory)
     #[arg(short = 'd', long = "dir")]
     pub stow_dir: Option<PathBuf>,
@@ -25,15 +73,41 @@ pub struct Cli {
     /// Target directory where symlinks will be created (default: user's home directory)
     #[arg(short = 't', long = "target")]
     pub target_dir: Option<PathBuf>,
+}
 
-    /// Enable verbose output
-    #[arg(short = 'v', long = "verbose")]
-    pub verbose: bool,
+/// How package entries map to target paths
+#[derive(Args, Debug, Clone, Default, PartialEq)]
+pub struct LayoutArgs {
+    /// Translate a "dot-" prefix in package entries to "." in the target (dot-bashrc -> .bashrc)
+    #[arg(long = "dotfiles")]
+    pub dotfiles: bool,
 
-    /// Perform a dry run without making any changes
-    #[arg(short = 'n', long = "dry-run")]
-    pub dry_run: bool,
+    /// Never fold directories: create real directories and link only files
+    #[arg(long = "no-folding")]
+    pub no_folding: bool,
+}
 
+/// Ignore and defer patterns
+#[derive(Args, Debug, Clone, Default, PartialEq)]
+pub struct PatternArgs {
+    /// Skip files matching pattern (can be used multiple times)
+    #[arg(long = "ignore", value_name = "PATTERN")]
+    pub ignore: Vec<String>,
+
+    /// Skip files matching pattern if they already exist in target (can be used multiple times)
+    #[arg(long = "defer", value_name = "PATTERN")]
+    pub defer: Vec<String>,
+}
+
+impl PatternArgs {
+    fn is_empty(&self) -> bool {
+        self.ignore.is_empty() && self.defer.is_empty()
+    }
+}
+
+/// Conflict resolution strategy
+#[derive(Args, Debug, Clone, Default, PartialEq)]
+pub struct ConflictArgs {
     /// Move conflicting files into the package (adopt)
     #[arg(long = "adopt")]
     pub adopt: bool,
@@ -42,17 +116,292 @@ pub struct Cli {
     #[arg(long = "override")]
     pub override_conflicts: bool,
 
-    /// Skip files matching pattern (can be used multiple times)
-    #[arg(long = "ignore", value_name = "PATTERN")]
-    pub ignore: Vec<String>,
+    /// Skip conflicting paths and link everything else
+    #[arg(long = "skip-conflicts")]
+    pub skip_conflicts: bool,
 
-    /// Skip files matching pattern if they already exist in target (can be used multiple times)
-    #[arg(long = "defer", value_name = "PATTERN")]
-    pub defer: Vec<String>,
+    /// Move conflicting files into a backup directory in the target (see `restore`)
+    #[arg(long = "backup")]
+    pub backup: bool,
 
-    /// Package names to operate on
+    /// Ask how to resolve each conflict before anything is changed
+    #[arg(long = "interactive")]
+    pub interactive: bool,
+
+    /// When adopting, keep target files that differ from the package's version as <name>.adopted
+    #[arg(long = "keep-both")]
+    pub keep_both: bool,
+}
+
+impl ConflictArgs {
+    /// Check that at most one strategy is selected, and --keep-both only with adopting
+    fn validate(&self) -> Result<(), String> {
+        if self.count() > 1 {
+            return Err(
+                "Use only one of: --adopt, --override, --skip-conflicts, --backup, --interactive"
+                    .to_string(),
+            );
+        }
+        if self.keep_both && !self.adopt && !self.interactive {
+            return Err("--keep-both can only be used with --adopt or --interactive".to_string());
+        }
+        Ok(())
+    }
+
+    /// Check if any conflict option was given
+    fn is_empty(&self) -> bool {
+        self.count() == 0 && !self.keep_both
+    }
+
+    /// Number of strategies selected
+    fn count(&self) -> usize {
+        [
+            self.adopt,
+            self.override_conflicts,
+            self.skip_conflicts,
+            self.backup,
+            self.interactive,
+        ]
+        .iter()
+        .filter(|&&x| x)
+        .count()
+    }
+}
+
+/// Options of `stow` and `restow`
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct StowArgs {
+    /// Packages to stow
     #[arg(value_name = "PACKAGE", required = true)]
     pub packages: Vec<String>,
+
+    #[command(flatten)]
+    pub dirs: DirArgs,
+
+    #[command(flatten)]
+    pub layout: LayoutArgs,
+
+    #[command(flatten)]
+    pub patterns: PatternArgs,
+
+    #[command(flatten)]
+    pub conflicts: ConflictArgs,
+
+    /// Put package files into the target as symlinks (default), hard links or copies
+    #[arg(long = "link-mode", value_enum, value_name = "MODE")]
+    pub link_mode: Option<LinkMode>,
+
+    /// Write the computed plan to FILE (JSON, or TOML for .toml files) instead of executing it
+    #[arg(long = "plan-out", value_name = "FILE")]
+    pub plan_out: Option<PathBuf>,
+}
+
+/// Options of `unstow`
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct UnstowArgs {
+    /// Packages to unstow
+    #[arg(value_name = "PACKAGE", required = true)]
+    pub packages: Vec<String>,
+
+    #[command(flatten)]
+    pub dirs: DirArgs,
+
+    #[command(flatten)]
+    pub layout: LayoutArgs,
+
+    /// Replace the links with real copies of the package files instead of removing them
+    #[arg(long = "materialize")]
+    pub materialize: bool,
+
+    /// Write the computed plan to FILE (JSON, or TOML for .toml files) instead of executing it
+    #[arg(long = "plan-out", value_name = "FILE")]
+    pub plan_out: Option<PathBuf>,
+}
+
+/// Options of `prune`
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct PruneArgs {
+    /// Packages whose broken links are removed (default: all packages)
+    #[arg(value_name = "PACKAGE")]
+    pub packages: Vec<String>,
+
+    #[command(flatten)]
+    pub dirs: DirArgs,
+
+    #[command(flatten)]
+    pub layout: LayoutArgs,
+
+    /// Write the computed plan to FILE (JSON, or TOML for .toml files) instead of executing it
+    #[arg(long = "plan-out", value_name = "FILE")]
+    pub plan_out: Option<PathBuf>,
+}
+
+/// Options of `adopt`
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct AdoptArgs {
+    /// Package to move the paths into (created if it doesn't exist)
+    #[arg(value_name = "PACKAGE")]
+    pub package: String,
+
+    /// Files or directories in the target to move into the package and link back
+    #[arg(value_name = "PATH", required = true)]
+    pub paths: Vec<PathBuf>,
+
+    /// Keep target files that differ from the package's version as <name>.adopted
+    #[arg(long = "keep-both")]
+    pub keep_both: bool,
+
+    #[command(flatten)]
+    pub dirs: DirArgs,
+
+    #[command(flatten)]
+    pub layout: LayoutArgs,
+
+    #[command(flatten)]
+    pub patterns: PatternArgs,
+
+    /// Link the adopted paths back as symlinks (default), hard links or copies
+    #[arg(long = "link-mode", value_enum, value_name = "MODE")]
+    pub link_mode: Option<LinkMode>,
+
+    /// Write the computed plan to FILE (JSON, or TOML for .toml files) instead of executing it
+    #[arg(long = "plan-out", value_name = "FILE")]
+    pub plan_out: Option<PathBuf>,
+}
+
+/// Options of `eject`
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct EjectArgs {
+    /// Links, or directories holding links, to replace with real copies of the package files
+    #[arg(value_name = "PATH", required = true)]
+    pub paths: Vec<PathBuf>,
+
+    #[command(flatten)]
+    pub dirs: DirArgs,
+
+    /// Write the computed plan to FILE (JSON, or TOML for .toml files) instead of executing it
+    #[arg(long = "plan-out", value_name = "FILE")]
+    pub plan_out: Option<PathBuf>,
+}
+
+/// Options of `status`
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct StatusArgs {
+    /// Packages to report on (default: all packages in the stow directory)
+    #[arg(value_name = "PACKAGE")]
+    pub packages: Vec<String>,
+
+    #[command(flatten)]
+    pub dirs: DirArgs,
+
+    #[command(flatten)]
+    pub layout: LayoutArgs,
+
+    #[command(flatten)]
+    pub patterns: PatternArgs,
+}
+
+/// Options of `list`
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct ListArgs {
+    #[command(flatten)]
+    pub dirs: DirArgs,
+
+    #[command(flatten)]
+    pub layout: LayoutArgs,
+
+    #[command(flatten)]
+    pub patterns: PatternArgs,
+
+    /// Also show the target-relative files each package deploys
+    #[arg(long = "tree")]
+    pub tree: bool,
+
+    /// Output format
+    #[arg(long = "format", value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+}
+
+/// Options of `doctor`
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct DoctorArgs {
+    #[command(flatten)]
+    pub dirs: DirArgs,
+}
+
+/// Options of `restore`
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct RestoreArgs {
+    /// Backup to restore (default: list the backups)
+    #[arg(value_name = "BACKUP_ID")]
+    pub backup_id: Option<String>,
+
+    #[command(flatten)]
+    pub dirs: DirArgs,
+
+    #[command(flatten)]
+    pub layout: LayoutArgs,
+}
+
+/// Options of `history`
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct HistoryArgs {
+    #[command(flatten)]
+    pub dirs: DirArgs,
+}
+
+/// Options of `undo`
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct UndoArgs {
+    /// Operation to undo (default: the latest one not undone yet)
+    #[arg(value_name = "ID")]
+    pub id: Option<u64>,
+
+    #[command(flatten)]
+    pub dirs: DirArgs,
+}
+
+/// Subcommands
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Stow packages (create symlinks)
+    Stow(StowArgs),
+    /// Unstow packages (remove symlinks)
+    #[command(alias = "delete")]
+    Unstow(UnstowArgs),
+    /// Restow packages (unstow then stow)
+    Restow(StowArgs),
+    /// Remove broken links into the stow directory
+    Prune(PruneArgs),
+    /// Move files or directories from the target into a package and link them back
+    Adopt(AdoptArgs),
+    /// Replace links into the stow directory with real copies, leaving the files unmanaged
+    Eject(EjectArgs),
+    /// Report which packages are stowed, partially stowed, not stowed or conflicting
+    Status(StatusArgs),
+    /// List the packages in the stow directory with their file count and state
+    List(ListArgs),
+    /// Check the stow directory, the target and symlink support for problems
+    Doctor(DoctorArgs),
+    /// Unstow the packages of a backup and put the backed up files back
+    Restore(RestoreArgs),
+    /// List the recorded stow, unstow, restow and adopt runs
+    History(HistoryArgs),
+    /// Reverse a recorded run after checking the target hasn't changed since
+    Undo(UndoArgs),
+    /// Execute a plan written with --plan-out after checking it still matches the filesystem
+    Apply {
+        /// Plan file to apply
+        #[arg(value_name = "PLAN")]
+        plan: PathBuf,
+    },
+}
+
+/// Output format of report commands
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
 }
 
 /// Action to perform (derived from CLI flags)
@@ -61,6 +410,170 @@ pub enum Action {
     Stow,
     Delete,
     Restow,
+    PruneBroken,
+}
+
+/// Stow options of a command, with the options it doesn't take left at their defaults
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Options {
+    pub dirs: DirArgs,
+    pub layout: LayoutArgs,
+    pub patterns: PatternArgs,
+    pub conflicts: ConflictArgs,
+    pub plan_out: Option<PathBuf>,
+    /// Replace links with real copies instead of removing them
+    pub materialize: bool,
+    /// How package files are put into the target
+    pub link_mode: Option<LinkMode>,
+}
+
+impl Command {
+    /// Name of the subcommand, as typed
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Stow(_) => "stow",
+            Command::Unstow(_) => "unstow",
+            Command::Restow(_) => "restow",
+            Command::Prune(_) => "prune",
+            Command::Adopt(_) => "adopt",
+            Command::Eject(_) => "eject",
+            Command::Status(_) => "status",
+            Command::List(_) => "list",
+            Command::Doctor(_) => "doctor",
+            Command::Restore(_) => "restore",
+            Command::History(_) => "history",
+            Command::Undo(_) => "undo",
+            Command::Apply { .. } => "apply",
+        }
+    }
+
+    /// The link-changing action of the command, if it has one
+    pub fn action(&self) -> Option<Action> {
+        match self {
+            Command::Stow(_) => Some(Action::Stow),
+            Command::Unstow(_) => Some(Action::Delete),
+            Command::Restow(_) => Some(Action::Restow),
+            Command::Prune(_) => Some(Action::PruneBroken),
+            Command::Adopt(_)
+            | Command::Eject(_)
+            | Command::Status(_)
+            | Command::List(_)
+            | Command::Doctor(_)
+            | Command::Restore(_)
+            | Command::History(_)
+            | Command::Undo(_)
+            | Command::Apply { .. } => None,
+        }
+    }
+
+    /// Packages named on the command line
+    pub fn packages(&self) -> &[String] {
+        match self {
+            Command::Stow(args) | Command::Restow(args) => &args.packages,
+            Command::Unstow(args) => &args.packages,
+            Command::Prune(args) => &args.packages,
+            Command::Adopt(args) => std::slice::from_ref(&args.package),
+            Command::Status(args) => &args.packages,
+            Command::Eject(_)
+            | Command::List(_)
+            | Command::Doctor(_)
+            | Command::Restore(_)
+            | Command::History(_)
+            | Command::Undo(_)
+            | Command::Apply { .. } => &[],
+        }
+    }
+
+    /// Stow options of the command
+    pub fn options(&self) -> Options {
+        match self {
+            Command::Stow(args) | Command::Restow(args) => Options {
+                dirs: args.dirs.clone(),
+                layout: args.layout.clone(),
+                patterns: args.patterns.clone(),
+                conflicts: args.conflicts.clone(),
+                plan_out: args.plan_out.clone(),
+                link_mode: args.link_mode,
+                ..Options::default()
+            },
+            Command::Unstow(args) => Options {
+                dirs: args.dirs.clone(),
+                layout: args.layout.clone(),
+                plan_out: args.plan_out.clone(),
+                materialize: args.materialize,
+                ..Options::default()
+            },
+            Command::Prune(args) => Options {
+                dirs: args.dirs.clone(),
+                layout: args.layout.clone(),
+                plan_out: args.plan_out.clone(),
+                ..Options::default()
+            },
+            Command::Adopt(args) => Options {
+                dirs: args.dirs.clone(),
+                layout: args.layout.clone(),
+                patterns: args.patterns.clone(),
+                conflicts: ConflictArgs {
+                    keep_both: args.keep_both,
+                    ..ConflictArgs::default()
+                },
+                plan_out: args.plan_out.clone(),
+                link_mode: args.link_mode,
+                ..Options::default()
+            },
+            Command::Eject(args) => Options {
+                dirs: args.dirs.clone(),
+                plan_out: args.plan_out.clone(),
+                ..Options::default()
+            },
+            Command::Status(args) => Options {
+                dirs: args.dirs.clone(),
+                layout: args.layout.clone(),
+                patterns: args.patterns.clone(),
+                ..Options::default()
+            },
+            Command::List(args) => Options {
+                dirs: args.dirs.clone(),
+                layout: args.layout.clone(),
+                patterns: args.patterns.clone(),
+                ..Options::default()
+            },
+            Command::Doctor(args) => Options {
+                dirs: args.dirs.clone(),
+                ..Options::default()
+            },
+            Command::Restore(args) => Options {
+                dirs: args.dirs.clone(),
+                layout: args.layout.clone(),
+                ..Options::default()
+            },
+            Command::History(args) => Options {
+                dirs: args.dirs.clone(),
+                ..Options::default()
+            },
+            Command::Undo(args) => Options {
+                dirs: args.dirs.clone(),
+                ..Options::default()
+            },
+            Command::Apply { .. } => Options::default(),
+        }
+    }
+
+    /// Check the options of the subcommand
+    fn validate(&self, dry_run: bool) -> Result<(), String> {
+        match self {
+            Command::Stow(args) | Command::Restow(args) => args.conflicts.validate(),
+            Command::Status(_) | Command::List(_) | Command::Doctor(_) | Command::History(_)
+                if dry_run =>
+            {
+                Err(format!(
+                    "{} never changes anything and doesn't accept -n/--dry-run",
+                    self.name()
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 impl Cli {
@@ -68,7 +581,7 @@ impl Cli {
     /// Returns error if multiple conflicting actions are specified
     /// Default action is Stow if no action flag is specified
     pub fn action(&self) -> Result<Action, String> {
-        let action_count = [self.stow, self.delete, self.restow]
+        let action_count = [self.stow, self.delete, self.restow, self.prune_broken]
             .iter()
             .filter(|&&x| x)
             .count();
@@ -80,31 +593,117 @@ impl Cli {
                     Ok(Action::Delete)
                 } else if self.restow {
                     Ok(Action::Restow)
+                } else if self.prune_broken {
+                    Ok(Action::PruneBroken)
                 } else {
                     Ok(Action::Stow)
                 }
             }
             _ => Err(
-                "Multiple actions specified. Use only one of: -S/--stow, -D/--delete, -R/--restow"
+                "Multiple actions specified. Use only one of: -S/--stow, -D/--delete, -R/--restow, --prune-broken"
                     .to_string(),
             ),
         }
     }
 
-    /// Check if adopt or override flags are used with delete action (which is invalid)
+    /// Check that the options fit the subcommand, or the action of the GNU Stow style flags
     pub fn validate_flags(&self) -> Result<(), String> {
+        match &self.command {
+            Some(command) if self.flag_options_used() => Err(format!(
+                "Options of {} go after the subcommand: winstow {} [OPTIONS]",
+                command.name(),
+                command.name()
+            )),
+            Some(command) => command.validate(self.dry_run),
+            None => {
+                self.validate_action_flags()?;
+                self.command()?.validate(self.dry_run)
+            }
+        }
+    }
+
+    /// Check if any option of the GNU Stow style form was given, apart from -v and -n
+    fn flag_options_used(&self) -> bool {
+        self.stow
+            || self.delete
+            || self.restow
+            || self.prune_broken
+            || self.dirs != DirArgs::default()
+            || self.layout != LayoutArgs::default()
+            || !self.patterns.is_empty()
+            || !self.conflicts.is_empty()
+            || self.link_mode.is_some()
+            || self.plan_out.is_some()
+            || !self.packages.is_empty()
+    }
+
+    /// Check the GNU Stow style flags for options the selected action doesn't take
+    fn validate_action_flags(&self) -> Result<(), String> {
         let action = self.action()?;
+        let removes_links = matches!(action, Action::Delete | Action::PruneBroken);
 
-        if action == Action::Delete && (self.adopt || self.override_conflicts) {
-            return Err("--adopt and --override cannot be used with -D/--delete".to_string());
+        if removes_links && !self.conflicts.is_empty() {
+            return Err(
+                "--adopt, --override, --skip-conflicts, --backup, --interactive and --keep-both cannot be used with -D/--delete or --prune-broken"
+                    .to_string(),
+            );
+        }
+
+        if removes_links && !self.patterns.is_empty() {
+            return Err(
+                "--ignore and --defer cannot be used with -D/--delete or --prune-broken"
+                    .to_string(),
+            );
         }
 
-        if action == Action::Delete && (!self.ignore.is_empty() || !self.defer.is_empty()) {
-            return Err("--ignore and --defer cannot be used with -D/--delete".to_string());
+        if removes_links && self.link_mode.is_some() {
+            return Err(
+                "--link-mode cannot be used with -D/--delete or --prune-broken".to_string(),
+            );
         }
 
         Ok(())
     }
+
+    /// The subcommand to run; GNU Stow style flags are mapped to the matching subcommand
+    pub fn command(&self) -> Result<Command, String> {
+        if let Some(command) = &self.command {
+            return Ok(command.clone());
+        }
+
+        let packages = self.packages.clone();
+        let dirs = self.dirs.clone();
+        let layout = self.layout.clone();
+        let plan_out = self.plan_out.clone();
+
+        let stow_args = || StowArgs {
+            packages: packages.clone(),
+            dirs: dirs.clone(),
+            layout: layout.clone(),
+            patterns: self.patterns.clone(),
+            conflicts: self.conflicts.clone(),
+            link_mode: self.link_mode,
+            plan_out: plan_out.clone(),
+        };
+
+        Ok(match self.action()? {
+            Action::Stow => Command::Stow(stow_args()),
+            Action::Restow => Command::Restow(stow_args()),
+            Action::Delete => Command::Unstow(UnstowArgs {
+                packages,
+                dirs,
+                layout,
+                materialize: false,
+                plan_out,
+            }),
+            Action::PruneBroken => Command::Prune(PruneArgs {
+                packages,
+                dirs,
+                layout,
+                plan_out,
+            }),
+        })
+    }
 }
 
 #[cfg(test)]
@@ -139,6 +738,22 @@ mod tests {
         assert!(cli.restow);
     }
 
+    #[test]
+    fn test_prune_broken_without_packages() {
+        let cli = Cli::try_parse_from(["winstow", "--prune-broken"]).unwrap();
+        assert_eq!(cli.action().unwrap(), Action::PruneBroken);
+        assert!(cli.packages.is_empty());
+        assert!(cli.validate_flags().is_ok());
+
+        let cli = Cli::try_parse_from(["winstow", "--prune-broken", "--adopt"]).unwrap();
+        assert!(cli.validate_flags().is_err());
+    }
+
+    #[test]
+    fn test_packages_required_for_stow() {
+        assert!(Cli::try_parse_from(["winstow", "-S"]).is_err());
+    }
+
     #[test]
     fn test_multiple_actions_error() {
         let cli = Cli::try_parse_from(["winstow", "-S", "-D", "mypackage"]).unwrap();
@@ -155,7 +770,7 @@ mod tests {
     #[test]
     fn test_adopt_with_stow() {
         let cli = Cli::try_parse_from(["winstow", "--adopt", "mypackage"]).unwrap();
-        assert!(cli.adopt);
+        assert!(cli.conflicts.adopt);
         assert_eq!(cli.action().unwrap(), Action::Stow);
         assert!(cli.validate_flags().is_ok());
     }
@@ -166,12 +781,309 @@ mod tests {
         assert!(cli.validate_flags().is_err());
     }
 
+    #[test]
+    fn test_skip_conflicts_with_stow() {
+        let cli = Cli::try_parse_from(["winstow", "--skip-conflicts", "mypackage"]).unwrap();
+        assert!(cli.conflicts.skip_conflicts);
+        assert!(cli.validate_flags().is_ok());
+    }
+
+    #[test]
+    fn test_backup_strategy() {
+        let cli = Cli::try_parse_from(["winstow", "stow", "--backup", "vim"]).unwrap();
+        assert!(cli.validate_flags().is_ok());
+        assert!(cli.command().unwrap().options().conflicts.backup);
+
+        let cli =
+            Cli::try_parse_from(["winstow", "stow", "--backup", "--interactive", "vim"]).unwrap();
+        assert!(cli.validate_flags().is_err());
+        let cli = Cli::try_parse_from(["winstow", "-D", "--backup", "vim"]).unwrap();
+        assert!(cli.validate_flags().is_err());
+    }
+
+    #[test]
+    fn test_keep_both_requires_adopting() {
+        let cli = Cli::try_parse_from(["winstow", "--adopt", "--keep-both", "vim"]).unwrap();
+        assert!(cli.validate_flags().is_ok());
+        assert!(cli.command().unwrap().options().conflicts.keep_both);
+
+        let cli =
+            Cli::try_parse_from(["winstow", "adopt", "--keep-both", "vim", ".vimrc"]).unwrap();
+        assert!(cli.command().unwrap().options().conflicts.keep_both);
+
+        let cli = Cli::try_parse_from(["winstow", "stow", "--keep-both", "vim"]).unwrap();
+        assert!(cli.validate_flags().is_err());
+    }
+
+    #[test]
+    fn test_restore_command() {
+        let cli = Cli::try_parse_from(["winstow", "restore", "-t", "home", "100-1"]).unwrap();
+        assert!(cli.validate_flags().is_ok());
+        let command = cli.command().unwrap();
+        assert_eq!(command.action(), None);
+        assert!(matches!(
+            &command,
+            Command::Restore(args) if args.backup_id.as_deref() == Some("100-1")
+        ));
+        assert_eq!(
+            command.options().dirs.target_dir,
+            Some(PathBuf::from("home"))
+        );
+
+        // Without an id the backups are listed
+        let cli = Cli::try_parse_from(["winstow", "restore"]).unwrap();
+        assert!(matches!(
+            cli.command().unwrap(),
+            Command::Restore(RestoreArgs {
+                backup_id: None,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_history_and_undo_commands() {
+        let cli = Cli::try_parse_from(["winstow", "undo", "-t", "home", "3"]).unwrap();
+        assert!(cli.validate_flags().is_ok());
+        let command = cli.command().unwrap();
+        assert_eq!(command.action(), None);
+        assert!(matches!(&command, Command::Undo(args) if args.id == Some(3)));
+        assert_eq!(
+            command.options().dirs.target_dir,
+            Some(PathBuf::from("home"))
+        );
+
+        let cli = Cli::try_parse_from(["winstow", "-n", "undo"]).unwrap();
+        assert!(cli.validate_flags().is_ok());
+
+        // Listing never changes anything
+        let cli = Cli::try_parse_from(["winstow", "-n", "history"]).unwrap();
+        assert!(cli.validate_flags().is_err());
+    }
+
+    #[test]
+    fn test_link_mode_option() {
+        let cli = Cli::try_parse_from(["winstow", "stow", "--link-mode", "hardlink", "mypackage"])
+            .unwrap();
+        assert!(cli.validate_flags().is_ok());
+        assert_eq!(
+            cli.command().unwrap().options().link_mode,
+            Some(LinkMode::Hardlink)
+        );
+
+        let cli = Cli::try_parse_from(["winstow", "--link-mode", "copy", "mypackage"]).unwrap();
+        assert!(cli.validate_flags().is_ok());
+        assert_eq!(
+            cli.command().unwrap().options().link_mode,
+            Some(LinkMode::Copy)
+        );
+
+        // Unstowing recognizes every mode by itself
+        let cli =
+            Cli::try_parse_from(["winstow", "-D", "--link-mode", "copy", "mypackage"]).unwrap();
+        assert!(cli.validate_flags().is_err());
+        assert!(Cli::try_parse_from(["winstow", "--link-mode", "junction", "mypackage"]).is_err());
+    }
+
+    #[test]
+    fn test_multiple_conflict_strategies_are_invalid() {
+        let cli =
+            Cli::try_parse_from(["winstow", "--adopt", "--skip-conflicts", "mypackage"]).unwrap();
+        assert!(cli.validate_flags().is_err());
+    }
+
+    #[test]
+    fn test_dotfiles_flag() {
+        let cli = Cli::try_parse_from(["winstow", "--dotfiles", "-D", "mypackage"]).unwrap();
+        assert!(cli.layout.dotfiles);
+        assert!(cli.validate_flags().is_ok());
+    }
+
+    #[test]
+    fn test_no_folding_flag() {
+        let cli = Cli::try_parse_from(["winstow", "--no-folding", "mypackage"]).unwrap();
+        assert!(cli.layout.no_folding);
+        assert!(cli.validate_flags().is_ok());
+    }
+
     #[test]
     fn test_multiple_packages() {
         let cli = Cli::try_parse_from(["winstow", "pkg1", "pkg2", "pkg3"]).unwrap();
         assert_eq!(cli.packages, vec!["pkg1", "pkg2", "pkg3"]);
     }
 
+    #[test]
+    fn test_plan_out() {
+        let cli = Cli::try_parse_from(["winstow", "--plan-out", "plan.json", "mypackage"]).unwrap();
+        assert_eq!(cli.plan_out, Some(PathBuf::from("plan.json")));
+        assert!(cli.command.is_none());
+    }
+
+    #[test]
+    fn test_apply_command() {
+        let cli = Cli::try_parse_from(["winstow", "apply", "plan.json", "-n"]).unwrap();
+        assert_eq!(
+            cli.command,
+            Some(Command::Apply {
+                plan: PathBuf::from("plan.json")
+            })
+        );
+        assert!(cli.dry_run);
+        assert!(cli.packages.is_empty());
+        assert!(cli.validate_flags().is_ok());
+    }
+
+    #[test]
+    fn test_apply_with_stow_options_is_invalid() {
+        let cli = Cli::try_parse_from(["winstow", "--adopt", "apply", "plan.json"]).unwrap();
+        assert!(cli.validate_flags().is_err());
+    }
+
+    #[test]
+    fn test_flags_map_to_subcommands() {
+        let cli = Cli::try_parse_from(["winstow", "-d", "dots", "--adopt", "vim"]).unwrap();
+        let command = cli.command().unwrap();
+        assert_eq!(command, {
+            let cli =
+                Cli::try_parse_from(["winstow", "stow", "-d", "dots", "--adopt", "vim"]).unwrap();
+            cli.command.unwrap()
+        });
+        assert_eq!(command.action(), Some(Action::Stow));
+        assert_eq!(command.packages(), ["vim"]);
+
+        let cli = Cli::try_parse_from(["winstow", "-D", "--dotfiles", "vim"]).unwrap();
+        let command = cli.command().unwrap();
+        assert_eq!(command.action(), Some(Action::Delete));
+        assert!(command.options().layout.dotfiles);
+
+        let cli = Cli::try_parse_from(["winstow", "--prune-broken"]).unwrap();
+        assert!(matches!(cli.command().unwrap(), Command::Prune(_)));
+    }
+
+    #[test]
+    fn test_stow_subcommands() {
+        let cli = Cli::try_parse_from(["winstow", "restow", "--skip-conflicts", "vim"]).unwrap();
+        assert!(cli.validate_flags().is_ok());
+        let command = cli.command().unwrap();
+        assert_eq!(command.action(), Some(Action::Restow));
+        assert!(command.options().conflicts.skip_conflicts);
+
+        let cli = Cli::try_parse_from(["winstow", "stow", "--adopt", "--override", "vim"]).unwrap();
+        assert!(cli.validate_flags().is_err());
+
+        // Each subcommand only takes its own options
+        assert!(Cli::try_parse_from(["winstow", "unstow", "--adopt", "vim"]).is_err());
+        assert!(Cli::try_parse_from(["winstow", "unstow", "--ignore", "*.bak", "vim"]).is_err());
+        assert!(Cli::try_parse_from(["winstow", "stow"]).is_err());
+
+        let cli = Cli::try_parse_from(["winstow", "delete", "-n", "vim"]).unwrap();
+        assert!(matches!(cli.command().unwrap(), Command::Unstow(_)));
+
+        let cli = Cli::try_parse_from(["winstow", "prune"]).unwrap();
+        assert_eq!(cli.command().unwrap().action(), Some(Action::PruneBroken));
+    }
+
+    #[test]
+    fn test_adopt_subcommand_takes_package_and_paths() {
+        let cli = Cli::try_parse_from(["winstow", "adopt", "vim", ".vimrc", ".vim"]).unwrap();
+        assert!(cli.validate_flags().is_ok());
+        let command = cli.command().unwrap();
+        assert_eq!(command.action(), None);
+        assert_eq!(command.packages(), ["vim"]);
+        assert!(matches!(
+            &command,
+            Command::Adopt(args) if args.paths == [PathBuf::from(".vimrc"), PathBuf::from(".vim")]
+        ));
+        // Only the named paths are adopted, other conflicts still fail
+        assert!(!command.options().conflicts.adopt);
+
+        assert!(Cli::try_parse_from(["winstow", "adopt", "vim"]).is_err());
+    }
+
+    #[test]
+    fn test_materialize_and_eject() {
+        let cli = Cli::try_parse_from(["winstow", "unstow", "--materialize", "vim"]).unwrap();
+        assert!(cli.validate_flags().is_ok());
+        let command = cli.command().unwrap();
+        assert_eq!(command.action(), Some(Action::Delete));
+        assert!(command.options().materialize);
+        assert!(Cli::try_parse_from(["winstow", "stow", "--materialize", "vim"]).is_err());
+
+        let cli = Cli::try_parse_from(["winstow", "eject", "-t", "home", ".vimrc"]).unwrap();
+        assert!(cli.validate_flags().is_ok());
+        let command = cli.command().unwrap();
+        assert_eq!(command.action(), None);
+        assert!(matches!(
+            &command,
+            Command::Eject(args) if args.paths == [PathBuf::from(".vimrc")]
+        ));
+        assert!(Cli::try_parse_from(["winstow", "eject"]).is_err());
+    }
+
+    #[test]
+    fn test_doctor_subcommand() {
+        let cli = Cli::try_parse_from(["winstow", "doctor", "-t", "home"]).unwrap();
+        assert!(cli.validate_flags().is_ok());
+        let command = cli.command().unwrap();
+        assert_eq!(command.action(), None);
+        assert_eq!(
+            command.options().dirs.target_dir,
+            Some(PathBuf::from("home"))
+        );
+
+        let cli = Cli::try_parse_from(["winstow", "doctor", "-n"]).unwrap();
+        assert!(cli.validate_flags().is_err());
+    }
+
+    #[test]
+    fn test_status_command() {
+        let cli = Cli::try_parse_from(["winstow", "status", "-d", "dotfiles", "--dotfiles", "vim"])
+            .unwrap();
+        assert!(cli.validate_flags().is_ok());
+        let Some(Command::Status(args)) = &cli.command else {
+            panic!("expected the status command");
+        };
+        assert_eq!(args.packages, vec!["vim".to_string()]);
+        assert_eq!(args.dirs.stow_dir, Some(PathBuf::from("dotfiles")));
+        assert!(args.layout.dotfiles);
+
+        let cli = Cli::try_parse_from(["winstow", "status"]).unwrap();
+        assert!(cli.validate_flags().is_ok());
+    }
+
+    #[test]
+    fn test_status_with_action_options_is_invalid() {
+        assert!(Cli::try_parse_from(["winstow", "status", "--adopt"]).is_err());
+
+        let cli = Cli::try_parse_from(["winstow", "-D", "status"]).unwrap();
+        assert!(cli.validate_flags().is_err());
+
+        let cli = Cli::try_parse_from(["winstow", "status", "-n"]).unwrap();
+        assert!(cli.validate_flags().is_err());
+    }
+
+    #[test]
+    fn test_list_command() {
+        let cli = Cli::try_parse_from(["winstow", "list"]).unwrap();
+        assert!(cli.validate_flags().is_ok());
+        let Some(Command::List(args)) = &cli.command else {
+            panic!("expected the list command");
+        };
+        assert!(!args.tree);
+        assert_eq!(args.format, OutputFormat::Text);
+
+        let cli = Cli::try_parse_from(["winstow", "list", "--tree", "--format", "json"]).unwrap();
+        let Some(Command::List(args)) = &cli.command else {
+            panic!("expected the list command");
+        };
+        assert!(args.tree);
+        assert_eq!(args.format, OutputFormat::Json);
+
+        assert!(Cli::try_parse_from(["winstow", "list", "--format", "yaml"]).is_err());
+        let cli = Cli::try_parse_from(["winstow", "-R", "list"]).unwrap();
+        assert!(cli.validate_flags().is_err());
+    }
+
     #[test]
     fn test_ignore_patterns() {
         let cli = Cli::try_parse_from([
@@ -183,6 +1095,6 @@ mod tests {
             "mypackage",
         ])
         .unwrap();
-        assert_eq!(cli.ignore, vec!["*.bak", "*.tmp"]);
+        assert_eq!(cli.patterns.ignore, vec!["*.bak", "*.tmp"]);
     }
 }
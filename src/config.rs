@@ -1,6 +1,9 @@
 use crate::error::{Result, StowError};
+use crate::filesystem::{Filesystem, OsFilesystem};
+use crate::fs_ops::LinkMode;
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::{env, fs};
 
 /// Configuration for winstow
@@ -25,6 +28,41 @@ pub struct Config {
     /// Default verbose mode
     #[serde(default)]
     pub verbose: bool,
+
+    /// Translate `dot-` prefixes in package entries to `.` (GNU Stow `--dotfiles`)
+    #[serde(default)]
+    pub dotfiles: bool,
+
+    /// Target-relative directories that are never folded into a directory link
+    #[serde(default, rename = "no-fold")]
+    pub no_fold: Vec<String>,
+
+    /// How package files are put into the target: symlink, hardlink or copy
+    #[serde(default, rename = "link-mode")]
+    pub link_mode: Option<LinkMode>,
+
+    /// Link modes tried in order when the link mode isn't permitted in the target
+    #[serde(default)]
+    pub fallback: Vec<LinkMode>,
+}
+
+/// Options given on the command line that override the configuration file
+#[derive(Debug, Clone, Default)]
+pub struct CliOverrides {
+    pub dir: Option<PathBuf>,
+    pub target: Option<PathBuf>,
+    pub ignore: Vec<String>,
+    pub defer: Vec<String>,
+    pub verbose: bool,
+    pub dry_run: bool,
+    pub adopt: bool,
+    pub override_conflicts: bool,
+    pub skip_conflicts: bool,
+    pub backup: bool,
+    pub keep_both: bool,
+    pub dotfiles: bool,
+    pub no_folding: bool,
+    pub link_mode: Option<LinkMode>,
 }
 
 impl Config {
@@ -32,7 +70,7 @@ impl Config {
     /// Checks in order:
     /// 1. .winstowrc in current directory
     /// 2. .winstowrc in home directory
-    /// 3. config.toml in APPDATA/winstow/
+    /// 3. config.toml in APPDATA/winstow/ (`~/.config/winstow/` on Linux)
     pub fn load() -> Result<Self> {
         // Try current directory
         if let Ok(cwd) = env::current_dir() {
@@ -43,16 +81,16 @@ impl Config {
         }
 
         // Try home directory
-        if let Ok(home) = env::var("USERPROFILE") {
-            let config_path = PathBuf::from(home).join(".winstowrc");
+        if let Some(home) = dirs::home_dir() {
+            let config_path = home.join(".winstowrc");
             if config_path.exists() {
                 return Self::load_from(&config_path);
             }
         }
 
-        // Try APPDATA
-        if let Ok(appdata) = env::var("APPDATA") {
-            let config_path = PathBuf::from(appdata).join("winstow").join("config.toml");
+        // Try APPDATA (or the platform's config directory elsewhere)
+        if let Some(config_dir) = dirs::config_dir() {
+            let config_path = config_dir.join("winstow").join("config.toml");
             if config_path.exists() {
                 return Self::load_from(&config_path);
             }
@@ -76,24 +114,15 @@ impl Config {
 
     /// Merge this config with CLI arguments and create runtime context
     /// CLI arguments take precedence over config file settings
-    #[allow(clippy::too_many_arguments)]
-    pub fn merge_with_cli(
-        &self,
-        cli_dir: Option<PathBuf>,
-        cli_target: Option<PathBuf>,
-        cli_ignore: Vec<String>,
-        cli_defer: Vec<String>,
-        cli_verbose: bool,
-        cli_dry_run: bool,
-        cli_adopt: bool,
-        cli_override_conflicts: bool,
-    ) -> Result<StowContext> {
+    pub fn merge_with_cli(&self, cli: CliOverrides) -> Result<StowContext> {
         // Get effective directories (use CLI, then config, then defaults)
-        let stow_dir = cli_dir
+        let stow_dir = cli
+            .dir
             .or_else(|| self.default_dir.as_ref().map(PathBuf::from))
             .unwrap_or_else(|| env::current_dir().unwrap_or_else(|_| PathBuf::from(".")));
 
-        let target_dir = cli_target
+        let target_dir = cli
+            .target
             .or_else(|| self.default_target.as_ref().map(PathBuf::from))
             .unwrap_or_else(|| {
                 dirs::home_dir().unwrap_or_else(|| {
@@ -104,19 +133,22 @@ impl Config {
                 })
             });
 
-        let ignore = if cli_ignore.is_empty() {
+        let ignore = if cli.ignore.is_empty() {
             self.ignore.clone()
         } else {
-            cli_ignore
+            cli.ignore
         };
 
-        let defer = if cli_defer.is_empty() {
+        let defer = if cli.defer.is_empty() {
             self.defer.clone()
         } else {
-            cli_defer
+            cli.defer
         };
 
-        let verbose = cli_verbose || self.verbose;
+        let verbose = cli.verbose || self.verbose;
+        let dotfiles = cli.dotfiles || self.dotfiles;
+        let fold_policy = crate::stow::FoldPolicy::new(!cli.no_folding, &self.no_fold);
+        let link_mode = cli.link_mode.or(self.link_mode).unwrap_or_default();
 
         StowContext::new(
             stow_dir,
@@ -124,10 +156,20 @@ impl Config {
             ignore,
             defer,
             verbose,
-            cli_dry_run,
-            cli_adopt,
-            cli_override_conflicts,
+            cli.dry_run,
+            cli.adopt,
+            cli.override_conflicts,
+            cli.skip_conflicts,
+            cli.backup,
+            cli.keep_both,
+            dotfiles,
+            fold_policy,
         )
+        .map(|context| {
+            context
+                .with_link_mode(link_mode)
+                .with_fallback(&self.fallback)
+        })
     }
 }
 
@@ -147,6 +189,18 @@ pub struct StowContext {
     dry_run: bool,
     /// Conflict resolution strategy
     conflict_strategy: crate::stow::ConflictStrategy,
+    /// Keep target files that differ from the package's version when adopting
+    adopt_keep_both: bool,
+    /// Translate `dot-` prefixes in package entries to `.`
+    dotfiles: bool,
+    /// Which directories may be folded
+    fold_policy: crate::stow::FoldPolicy,
+    /// How package files are put into the target
+    link_mode: LinkMode,
+    /// Link modes tried in order when `link_mode` isn't permitted
+    fallback: Vec<LinkMode>,
+    /// Filesystem all operations are performed on
+    fs: Rc<dyn Filesystem>,
 }
 
 impl StowContext {
@@ -161,6 +215,11 @@ impl StowContext {
         dry_run: bool,
         adopt: bool,
         override_conflicts: bool,
+        skip_conflicts: bool,
+        backup: bool,
+        adopt_keep_both: bool,
+        dotfiles: bool,
+        fold_policy: crate::stow::FoldPolicy,
     ) -> crate::error::Result<Self> {
         use crate::logger;
 
@@ -175,10 +234,20 @@ impl StowContext {
         } else if override_conflicts {
             logger::warn("--override enabled: will remove conflicting files (destructive)");
             crate::stow::ConflictStrategy::Override
+        } else if skip_conflicts {
+            logger::verbose("  --skip-conflicts enabled: will skip conflicting paths");
+            crate::stow::ConflictStrategy::Skip
+        } else if backup {
+            logger::verbose("  --backup enabled: will move conflicting files into a backup");
+            crate::stow::ConflictStrategy::Backup
         } else {
             crate::stow::ConflictStrategy::Fail
         };
 
+        if dotfiles {
+            logger::verbose("  --dotfiles enabled: dot- prefixes will be translated to .");
+        }
+
         // Log patterns if verbose
         if !ignore.is_empty() {
             logger::verbose(&format!("  Ignore patterns: {:?}", ignore));
@@ -194,9 +263,53 @@ impl StowContext {
             defer,
             dry_run,
             conflict_strategy,
+            adopt_keep_both,
+            dotfiles,
+            fold_policy,
+            link_mode: LinkMode::Symlink,
+            fallback: Vec::new(),
+            fs: Rc::new(OsFilesystem),
         })
     }
 
+    /// Set how package files are put into the target
+    /// Hard links and copies can't stand in for a directory, so nothing is folded with them
+    pub fn with_link_mode(mut self, link_mode: LinkMode) -> Self {
+        if !link_mode.is_symlink() {
+            crate::logger::verbose(&format!(
+                "  --link-mode {}: package files are deployed as {}, directories are never folded",
+                link_mode,
+                link_mode.plural()
+            ));
+            self.fold_policy = crate::stow::FoldPolicy::new(false, &[]);
+        }
+        self.link_mode = link_mode;
+        self
+    }
+
+    /// Set the fallback chain, a list of link modes in order of preference
+    pub fn with_fallback(mut self, chain: &[LinkMode]) -> Self {
+        self.fallback = self.link_mode.fallbacks(chain);
+        if !self.fallback.is_empty() {
+            crate::logger::verbose(&format!(
+                "  Fallback link modes: {}",
+                self.fallback
+                    .iter()
+                    .map(LinkMode::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+        self
+    }
+
+    /// Replace the filesystem operations are performed on
+    #[cfg(test)]
+    pub fn with_filesystem(mut self, fs: Rc<dyn Filesystem>) -> Self {
+        self.fs = fs;
+        self
+    }
+
     /// Get the stow directory
     pub fn stow_dir(&self) -> &Path {
         &self.stow_dir
@@ -217,6 +330,36 @@ impl StowContext {
         self.conflict_strategy
     }
 
+    /// Check if adopting keeps target files that differ from the package's version
+    pub fn adopt_keep_both(&self) -> bool {
+        self.adopt_keep_both
+    }
+
+    /// Check if `dot-` prefixes are translated to `.`
+    pub fn dotfiles(&self) -> bool {
+        self.dotfiles
+    }
+
+    /// Get the fold policy
+    pub fn fold_policy(&self) -> &crate::stow::FoldPolicy {
+        &self.fold_policy
+    }
+
+    /// Get how package files are put into the target
+    pub fn link_mode(&self) -> LinkMode {
+        self.link_mode
+    }
+
+    /// Get the link modes tried when the link mode isn't permitted, in order
+    pub fn fallback(&self) -> &[LinkMode] {
+        &self.fallback
+    }
+
+    /// Get the filesystem operations are performed on
+    pub fn filesystem(&self) -> &Rc<dyn Filesystem> {
+        &self.fs
+    }
+
     /// Build a pattern set from the ignore and defer patterns
     pub fn build_pattern_set(&self) -> crate::error::Result<crate::ignore::PatternSet> {
         crate::ignore::PatternSet::new(&self.ignore, &self.defer)
@@ -251,6 +394,9 @@ default-target = "C:\\target"
 ignore = ["*.bak", ".DS_Store"]
 defer = ["*.lock"]
 verbose = true
+dotfiles = true
+no-fold = ["AppData/Roaming", ".config"]
+link-mode = "copy"
 "#,
         )
         .unwrap();
@@ -261,6 +407,9 @@ verbose = true
         assert_eq!(config.ignore, vec!["*.bak", ".DS_Store"]);
         assert_eq!(config.defer, vec!["*.lock"]);
         assert!(config.verbose);
+        assert!(config.dotfiles);
+        assert_eq!(config.no_fold, vec!["AppData/Roaming", ".config"]);
+        assert_eq!(config.link_mode, Some(LinkMode::Copy));
     }
 
     #[test]
@@ -282,19 +431,21 @@ verbose = true
             ignore: vec!["*.config".to_string()],
             defer: vec!["*.config_defer".to_string()],
             verbose: false,
+            dotfiles: false,
+            no_fold: vec![],
+            link_mode: None,
+            fallback: vec![],
         };
 
         let merged = config
-            .merge_with_cli(
-                Some(PathBuf::from("C:\\cli_stow")),
-                Some(PathBuf::from("C:\\cli_target")),
-                vec!["*.cli".to_string()],
-                vec!["*.cli_defer".to_string()],
-                true,
-                false, // dry_run
-                false, // adopt
-                false, // override_conflicts
-            )
+            .merge_with_cli(CliOverrides {
+                dir: Some(PathBuf::from("C:\\cli_stow")),
+                target: Some(PathBuf::from("C:\\cli_target")),
+                ignore: vec!["*.cli".to_string()],
+                defer: vec!["*.cli_defer".to_string()],
+                verbose: true,
+                ..Default::default()
+            })
             .unwrap();
 
         // CLI should take precedence
@@ -310,24 +461,82 @@ verbose = true
             ignore: vec!["*.config".to_string()],
             defer: vec!["*.config_defer".to_string()],
             verbose: true,
+            dotfiles: true,
+            no_fold: vec![".config".to_string()],
+            link_mode: None,
+            fallback: vec![],
         };
 
-        let merged = config
-            .merge_with_cli(
-                None,
-                None,
-                vec![],
-                vec![],
-                false,
-                false, // dry_run
-                false, // adopt
-                false, // override_conflicts
-            )
-            .unwrap();
+        let merged = config.merge_with_cli(CliOverrides::default()).unwrap();
 
         // Should use config values
         assert_eq!(merged.stow_dir(), Path::new("C:\\config_stow"));
         assert_eq!(merged.target_dir(), Path::new("C:\\config_target"));
+        assert!(merged.dotfiles());
+        assert_eq!(merged.link_mode(), LinkMode::Symlink);
+        assert!(!merged.fold_policy().allows(Path::new(".config")));
+        assert!(merged.fold_policy().allows(Path::new(".local")));
+    }
+
+    #[test]
+    fn test_config_link_mode_disables_folding() {
+        let config = Config {
+            link_mode: Some(LinkMode::Copy),
+            ..Config::default()
+        };
+
+        let merge = |link_mode| {
+            config
+                .merge_with_cli(CliOverrides {
+                    dir: Some(PathBuf::from("C:\\stow")),
+                    target: Some(PathBuf::from("C:\\target")),
+                    link_mode,
+                    ..Default::default()
+                })
+                .unwrap()
+        };
+
+        let merged = merge(None);
+        assert_eq!(merged.link_mode(), LinkMode::Copy);
+        assert!(!merged.fold_policy().allows(Path::new(".local")));
+
+        // The command line takes precedence
+        let merged = merge(Some(LinkMode::Symlink));
+        assert_eq!(merged.link_mode(), LinkMode::Symlink);
+        assert!(merged.fold_policy().allows(Path::new(".local")));
+    }
+
+    #[test]
+    fn test_config_fallback_chain() {
+        let config: Config =
+            toml::from_str(r#"fallback = ["symlink", "hardlink", "copy"]"#).unwrap();
+        assert_eq!(
+            config.fallback,
+            vec![LinkMode::Symlink, LinkMode::Hardlink, LinkMode::Copy]
+        );
+
+        let merge = |link_mode| {
+            config
+                .merge_with_cli(CliOverrides {
+                    dir: Some(PathBuf::from("C:\\stow")),
+                    target: Some(PathBuf::from("C:\\target")),
+                    link_mode,
+                    ..Default::default()
+                })
+                .unwrap()
+        };
+
+        let merged = merge(None);
+        assert_eq!(merged.link_mode(), LinkMode::Symlink);
+        assert_eq!(merged.fallback(), [LinkMode::Hardlink, LinkMode::Copy]);
+
+        // Only the modes listed after the chosen one are fallbacks
+        let merged = merge(Some(LinkMode::Hardlink));
+        assert_eq!(merged.fallback(), [LinkMode::Copy]);
+        assert!(merge(Some(LinkMode::Copy)).fallback().is_empty());
+
+        let merged = merged.with_fallback(&[LinkMode::Copy, LinkMode::Symlink]);
+        assert_eq!(merged.fallback(), [LinkMode::Copy, LinkMode::Symlink]);
     }
 
     #[test]
@@ -336,4 +545,28 @@ verbose = true
         let result = Config::load_from("/nonexistent/path/.winstowrc");
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_context_with_filesystem() {
+        let fs: Rc<dyn Filesystem> = Rc::new(crate::memfs::MemoryFilesystem::new());
+        let context = StowContext::new(
+            PathBuf::from("stow"),
+            PathBuf::from("target"),
+            vec![],
+            vec![],
+            false,
+            false,
+            false,
+            false,
+            false,
+            false,
+            false,
+            false,
+            crate::stow::FoldPolicy::default(),
+        )
+        .unwrap()
+        .with_filesystem(Rc::clone(&fs));
+
+        assert!(Rc::ptr_eq(context.filesystem(), &fs));
+    }
 }
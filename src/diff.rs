@@ -0,0 +1,231 @@
+/// Lines of unchanged context shown around each change
+const CONTEXT_LINES: usize = 3;
+
+/// Largest file compared line by line, in bytes
+const MAX_BYTES: usize = 256 * 1024;
+
+/// Most lines of a file compared line by line
+/// The alignment table grows with the product of both line counts
+const MAX_LINES: usize = 2000;
+
+/// A line of a diff
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Line<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Line differences between two files
+pub struct Diff<'a> {
+    /// Both files aligned line by line, empty if they are equal
+    lines: Vec<Line<'a>>,
+}
+
+impl<'a> Diff<'a> {
+    /// Compare two files line by line
+    /// Returns `None` if they are binary or too large to be compared line by line
+    pub fn new(old: &'a [u8], new: &'a [u8]) -> Option<Self> {
+        if old == new {
+            return Some(Self { lines: Vec::new() });
+        }
+
+        Some(Self {
+            lines: diff_lines(&text_lines(old)?, &text_lines(new)?),
+        })
+    }
+
+    /// Count the lines added and removed going from the old file to the new one
+    pub fn line_changes(&self) -> (usize, usize) {
+        self.lines
+            .iter()
+            .fold((0, 0), |(added, removed), line| match line {
+                Line::Added(_) => (added + 1, removed),
+                Line::Removed(_) => (added, removed + 1),
+                Line::Same(_) => (added, removed),
+            })
+    }
+
+    /// Format the differences as a unified diff
+    /// Returns an empty string if the files are equal
+    pub fn unified(&self, old_name: &str, new_name: &str) -> String {
+        let lines = &self.lines;
+        if lines.iter().all(|line| matches!(line, Line::Same(_))) {
+            return String::new();
+        }
+
+        let mut output = format!("--- {}\n+++ {}\n", old_name, new_name);
+        for (start, end) in hunks(lines) {
+            // Line numbers of the hunk start in both texts, counted from 1
+            let old_start = 1 + lines[..start]
+                .iter()
+                .filter(|line| !matches!(line, Line::Added(_)))
+                .count();
+            let new_start = 1 + lines[..start]
+                .iter()
+                .filter(|line| !matches!(line, Line::Removed(_)))
+                .count();
+            let hunk = &lines[start..end];
+            let old_count = hunk
+                .iter()
+                .filter(|line| !matches!(line, Line::Added(_)))
+                .count();
+            let new_count = hunk
+                .iter()
+                .filter(|line| !matches!(line, Line::Removed(_)))
+                .count();
+
+            output.push_str(&format!(
+                "@@ -{},{} +{},{} @@\n",
+                old_start, old_count, new_start, new_count
+            ));
+            for line in hunk {
+                match line {
+                    Line::Same(text) => output.push_str(&format!(" {}\n", text)),
+                    Line::Removed(text) => output.push_str(&format!("-{}\n", text)),
+                    Line::Added(text) => output.push_str(&format!("+{}\n", text)),
+                }
+            }
+        }
+
+        output
+    }
+}
+
+/// Format the line differences between two files as a unified diff
+/// Returns an empty string if the files are equal, and `None` if they are
+/// binary or too large to be compared line by line
+pub fn unified_diff(old: &[u8], new: &[u8], old_name: &str, new_name: &str) -> Option<String> {
+    Diff::new(old, new).map(|diff| diff.unified(old_name, new_name))
+}
+
+/// Split a file into lines if it is text small enough to be compared line by line
+fn text_lines(content: &[u8]) -> Option<Vec<&str>> {
+    if content.len() > MAX_BYTES || content.contains(&0) {
+        return None;
+    }
+    let lines: Vec<&str> = std::str::from_utf8(content).ok()?.lines().collect();
+    (lines.len() <= MAX_LINES).then_some(lines)
+}
+
+/// Align two texts along their longest common subsequence of lines
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Line<'a>> {
+    // lengths[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut lengths = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i][j] = if old[i] == new[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push(Line::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lengths[i + 1][j] >= lengths[i][j + 1]) {
+            // Removed lines come before the added lines replacing them
+            lines.push(Line::Removed(old[i]));
+            i += 1;
+        } else {
+            lines.push(Line::Added(new[j]));
+            j += 1;
+        }
+    }
+
+    lines
+}
+
+/// Ranges of diff lines to show: the changes with their context, merged where they overlap
+fn hunks(lines: &[Line]) -> Vec<(usize, usize)> {
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+
+    for (index, line) in lines.iter().enumerate() {
+        if matches!(line, Line::Same(_)) {
+            continue;
+        }
+        let start = index.saturating_sub(CONTEXT_LINES);
+        let end = (index + 1 + CONTEXT_LINES).min(lines.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    hunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_equal_texts_have_no_diff() {
+        assert_eq!(
+            unified_diff(b"a\nb\n", b"a\nb\n", "old", "new").as_deref(),
+            Some("")
+        );
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+        let new = "1\n2\n3\n4\nfive\n6\n7\n8\n9\nten\n";
+
+        assert_eq!(
+            unified_diff(old.as_bytes(), new.as_bytes(), "target", "package").unwrap(),
+            "--- target\n+++ package\n\
+             @@ -2,8 +2,9 @@\n 2\n 3\n 4\n-5\n+five\n 6\n 7\n 8\n 9\n+ten\n"
+        );
+    }
+
+    #[test]
+    fn test_distant_changes_get_separate_hunks() {
+        let old: String = (1..=20).map(|n| format!("{}\n", n)).collect();
+        let new: String = (1..=20)
+            .filter(|&n| n != 19)
+            .map(|n| match n {
+                2 => "two\n".to_string(),
+                n => format!("{}\n", n),
+            })
+            .collect();
+
+        let diff = unified_diff(old.as_bytes(), new.as_bytes(), "a", "b").unwrap();
+        assert_eq!(diff.matches("@@ -").count(), 2, "{}", diff);
+        assert!(diff.contains("@@ -1,5 +1,5 @@\n 1\n-2\n+two\n"));
+        assert!(diff.contains("@@ -16,5 +16,4 @@\n 16\n 17\n 18\n-19\n 20\n"));
+        assert_eq!(
+            Diff::new(old.as_bytes(), new.as_bytes())
+                .unwrap()
+                .line_changes(),
+            (1, 2)
+        );
+    }
+
+    #[test]
+    fn test_binary_and_large_files_are_not_compared() {
+        let binary = b"\x89PNG\r\n\x1a\n\0\0";
+        assert_eq!(unified_diff(binary, b"text\n", "a", "b"), None);
+        assert!(Diff::new(b"text\n", &[0xff, 0xfe]).is_none());
+        assert_eq!(unified_diff(binary, binary, "a", "b").as_deref(), Some(""));
+
+        let many_lines = "line\n".repeat(MAX_LINES + 1);
+        assert!(Diff::new(many_lines.as_bytes(), b"line\n").is_none());
+        let long_line = "x".repeat(MAX_BYTES + 1);
+        assert_eq!(unified_diff(b"x", long_line.as_bytes(), "a", "b"), None);
+
+        let most_lines = "line\n".repeat(MAX_LINES);
+        assert_eq!(
+            Diff::new(most_lines.as_bytes(), b"line\n")
+                .unwrap()
+                .line_changes(),
+            (0, MAX_LINES - 1)
+        );
+    }
+}
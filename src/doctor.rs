@@ -0,0 +1,317 @@
+use crate::config::StowContext;
+use crate::error::Result;
+use crate::filesystem::Filesystem;
+use crate::fs_ops::LinkMode;
+use crate::overlay::OverlayFilesystem;
+use crate::state::{self, State};
+use crate::status::{self, StowState};
+use crate::{ignore, planner, stow, unstow};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Name of the temporary link created to check symlink support
+const PROBE_LINK_NAME: &str = ".winstow-probe";
+
+/// Result of a single check
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Passed(String),
+    /// Something that works but needs attention
+    Warning(String),
+    /// Something that keeps winstow from working
+    Failed(String),
+}
+
+/// Check the stow directory, the target directory and symlink support
+/// Nothing is changed, except for a link created and removed again in the target
+pub fn diagnose(context: &StowContext, patterns: &ignore::PatternSet) -> Vec<Outcome> {
+    let fs = context.filesystem().as_ref();
+    let stow_dir = context.stow_dir();
+    let target_dir = context.target_dir();
+    let mut outcomes = Vec::new();
+
+    for (name, dir) in [("Stow", stow_dir), ("Target", target_dir)] {
+        if fs.is_dir(dir) {
+            outcomes.push(Outcome::Passed(format!(
+                "{} directory: {}",
+                name,
+                dir.display()
+            )));
+        } else {
+            outcomes.push(Outcome::Failed(format!(
+                "{} directory {} does not exist",
+                name,
+                dir.display()
+            )));
+        }
+    }
+    if outcomes.iter().any(|o| matches!(o, Outcome::Failed(_))) {
+        return outcomes;
+    }
+
+    outcomes.push(match probe_links(fs, target_dir) {
+        Ok(()) => Outcome::Passed("Symlinks can be created in the target directory".to_string()),
+        Err(e) => match probe_link_mode(fs, stow_dir, target_dir, context.fallback()) {
+            Some(mode) => Outcome::Warning(format!(
+                "Cannot create symlinks in the target, falling back to {}: {}",
+                mode.plural(),
+                e
+            )),
+            None => Outcome::Failed(format!("Cannot create symlinks in the target: {}", e)),
+        },
+    });
+
+    outcomes.push(check_packages(context, patterns));
+    outcomes.push(check_broken_links(context));
+    outcomes.push(check_state(fs, target_dir));
+    outcomes
+}
+
+/// Create and remove a link in a directory
+pub fn probe_links(fs: &dyn Filesystem, dir: &Path) -> Result<()> {
+    let probe = probe_path(dir);
+    fs.create_link(&probe, Path::new(state::STATE_FILE_NAME), false)?;
+    fs.remove_link(&probe)
+}
+
+/// Create a file in the stow directory, hard link it into the target and remove both
+pub fn probe_hard_links(fs: &dyn Filesystem, stow_dir: &Path, target_dir: &Path) -> Result<()> {
+    let source = probe_path(stow_dir);
+    let link = probe_path(target_dir);
+    fs.write_file(&source, b"")?;
+
+    let linked = fs.create_hard_link(&link, &source);
+    let removed = match &linked {
+        Ok(()) => fs.remove_file(&link),
+        Err(_) => Ok(()),
+    };
+    fs.remove_file(&source)?;
+    linked.and(removed)
+}
+
+/// Find the first of `modes` that works from the stow directory into the target
+/// Copies always work; nothing is left behind by the probes
+pub fn probe_link_mode(
+    fs: &dyn Filesystem,
+    stow_dir: &Path,
+    target_dir: &Path,
+    modes: &[LinkMode],
+) -> Option<LinkMode> {
+    modes.iter().copied().find(|mode| match mode {
+        LinkMode::Symlink => probe_links(fs, target_dir).is_ok(),
+        LinkMode::Hardlink => probe_hard_links(fs, stow_dir, target_dir).is_ok(),
+        LinkMode::Copy => true,
+    })
+}
+
+/// Path of the temporary entry created in a directory to probe it
+fn probe_path(dir: &Path) -> PathBuf {
+    dir.join(format!("{}-{}", PROBE_LINK_NAME, std::process::id()))
+}
+
+/// Find the packages and report those in conflict with the target
+fn check_packages(context: &StowContext, patterns: &ignore::PatternSet) -> Outcome {
+    let packages =
+        match status::list_packages(context.filesystem().as_ref(), context.stow_dir(), patterns) {
+            Ok(packages) => packages,
+            Err(e) => return Outcome::Failed(format!("Cannot read the stow directory: {}", e)),
+        };
+    if packages.is_empty() {
+        return Outcome::Warning("No packages found in the stow directory".to_string());
+    }
+
+    let stower = stow::Stower::from_context(context, patterns.clone());
+    let conflicting: Vec<&str> = packages
+        .iter()
+        .filter(|name| {
+            stower
+                .status(name)
+                .is_ok_and(|status| status.state() == StowState::Conflicting)
+        })
+        .map(String::as_str)
+        .collect();
+
+    if conflicting.is_empty() {
+        Outcome::Passed(format!("{} package(s) without conflicts", packages.len()))
+    } else {
+        Outcome::Warning(format!(
+            "Package(s) with conflicts in the target: {} (see `winstow status`)",
+            conflicting.join(", ")
+        ))
+    }
+}
+
+/// Count links into the stow directory whose source no longer exists
+fn check_broken_links(context: &StowContext) -> Outcome {
+    let overlay = Rc::new(OverlayFilesystem::new(Rc::clone(context.filesystem())));
+    let mut plan = planner::Plan::new();
+    let unstower = unstow::Unstower::from_context(context);
+    if let Err(e) = unstower.plan_prune_broken(&[], &overlay, &mut plan) {
+        return Outcome::Failed(format!("Cannot check for broken links: {}", e));
+    }
+
+    let broken = plan
+        .actions()
+        .filter(|action| matches!(action, planner::Action::RemoveLink { .. }))
+        .count();
+    if broken == 0 {
+        Outcome::Passed("No broken links into the stow directory".to_string())
+    } else {
+        Outcome::Warning(format!(
+            "{} broken link(s) into the stow directory (remove them with `winstow prune`)",
+            broken
+        ))
+    }
+}
+
+/// Check that the state file can be read and still matches the target
+fn check_state(fs: &dyn Filesystem, target_dir: &Path) -> Outcome {
+    let path = target_dir.join(state::STATE_FILE_NAME);
+    let mut state = match State::load(fs, &path) {
+        Ok(state) => state,
+        Err(e) => return Outcome::Failed(e.to_string()),
+    };
+
+    let count = |state: &State| -> usize { state.packages.values().map(|p| p.links.len()).sum() };
+    let recorded = count(&state);
+    state.reconcile(fs);
+    let changed = recorded - count(&state);
+
+    if changed == 0 {
+        Outcome::Passed(format!(
+            "State file records {} link(s) of {} package(s)",
+            recorded,
+            state.packages.len()
+        ))
+    } else {
+        Outcome::Warning(format!(
+            "{} of {} recorded link(s) were changed or removed outside of winstow",
+            changed, recorded
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memfs::MemoryFilesystem;
+
+    fn context(fs: &Rc<MemoryFilesystem>) -> StowContext {
+        StowContext::new(
+            MemoryFilesystem::root().join("stow"),
+            MemoryFilesystem::root().join("target"),
+            vec![],
+            vec![],
+            false,
+            false,
+            false,
+            false,
+            false,
+            false,
+            false,
+            false,
+            stow::FoldPolicy::default(),
+        )
+        .unwrap()
+        .with_filesystem(Rc::clone(fs) as Rc<dyn Filesystem>)
+    }
+
+    #[test]
+    fn test_missing_directories_fail() {
+        let fs = Rc::new(MemoryFilesystem::new());
+        let outcomes = diagnose(&context(&fs), &ignore::PatternSet::empty());
+
+        assert_eq!(outcomes.len(), 2);
+        assert!(
+            outcomes
+                .iter()
+                .all(|outcome| matches!(outcome, Outcome::Failed(_)))
+        );
+    }
+
+    #[test]
+    fn test_healthy_setup_passes() {
+        let fs = Rc::new(MemoryFilesystem::new());
+        let root = MemoryFilesystem::root();
+        fs.add_file(root.join("stow/pkg/a.txt"), "a");
+        fs.add_dir(root.join("target"));
+
+        let outcomes = diagnose(&context(&fs), &ignore::PatternSet::empty());
+        assert!(
+            outcomes
+                .iter()
+                .all(|outcome| matches!(outcome, Outcome::Passed(_))),
+            "{:?}",
+            outcomes
+        );
+        // The probe link is removed again
+        assert_eq!(
+            fs.read_dir(&root.join("target")).unwrap(),
+            Vec::<PathBuf>::new()
+        );
+    }
+
+    #[test]
+    fn test_problems_are_warnings() {
+        let fs = Rc::new(MemoryFilesystem::new());
+        let root = MemoryFilesystem::root();
+        fs.add_file(root.join("stow/pkg/a.txt"), "a");
+        fs.add_file(root.join("target/a.txt"), "local");
+        fs.add_dir(root.join("stow/gone"));
+        fs.create_link(
+            &root.join("target/b.txt"),
+            Path::new("../stow/gone/b.txt"),
+            false,
+        )
+        .unwrap();
+
+        let outcomes = diagnose(&context(&fs), &ignore::PatternSet::empty());
+        let warnings: Vec<&Outcome> = outcomes
+            .iter()
+            .filter(|outcome| matches!(outcome, Outcome::Warning(_)))
+            .collect();
+        assert_eq!(warnings.len(), 2, "{:?}", outcomes);
+    }
+
+    #[test]
+    fn test_probe_link_mode_finds_first_permitted_mode() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        let (stow_dir, target_dir) = (root.join("stow"), root.join("target"));
+        fs.add_dir(&stow_dir);
+        fs.add_dir(&target_dir);
+        let chain = [LinkMode::Symlink, LinkMode::Hardlink, LinkMode::Copy];
+
+        let probe = |modes: &[LinkMode]| probe_link_mode(&fs, &stow_dir, &target_dir, modes);
+        assert_eq!(probe(&chain), Some(LinkMode::Symlink));
+        fs.deny_links(LinkMode::Symlink);
+        assert_eq!(probe(&chain), Some(LinkMode::Hardlink));
+        fs.deny_links(LinkMode::Hardlink);
+        assert_eq!(probe(&chain), Some(LinkMode::Copy));
+        assert_eq!(probe(&chain[..2]), None);
+
+        // The probes leave nothing behind
+        assert!(fs.read_dir(&stow_dir).unwrap().is_empty());
+        assert!(fs.read_dir(&target_dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_denied_symlinks_are_a_warning_with_fallback() {
+        let fs = Rc::new(MemoryFilesystem::new());
+        let root = MemoryFilesystem::root();
+        fs.add_file(root.join("stow/pkg/a.txt"), "a");
+        fs.add_dir(root.join("target"));
+        fs.deny_links(LinkMode::Symlink);
+
+        let outcomes = diagnose(&context(&fs), &ignore::PatternSet::empty());
+        assert!(matches!(&outcomes[2], Outcome::Failed(_)), "{:?}", outcomes);
+
+        let context = context(&fs).with_fallback(&[LinkMode::Hardlink, LinkMode::Copy]);
+        let outcomes = diagnose(&context, &ignore::PatternSet::empty());
+        assert!(
+            matches!(&outcomes[2], Outcome::Warning(message) if message.contains("falling back to hard links")),
+            "{:?}",
+            outcomes
+        );
+    }
+}
@@ -1,9 +1,70 @@
+use std::fmt;
 use std::path::PathBuf;
 use thiserror::Error;
 
 /// Custom result type for winstow operations
 pub type Result<T> = std::result::Result<T, StowError>;
 
+/// What is in the way at a conflicting target path
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConflictKind {
+    /// A regular file
+    File,
+    /// A real directory
+    Dir,
+    /// A file differing from the package's version, which adopting would replace
+    Differs,
+    /// A symlink pointing outside the stow directory
+    Link(PathBuf),
+    /// The path is provided by another package
+    Package(String),
+}
+
+impl fmt::Display for ConflictKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConflictKind::File => write!(f, "existing file"),
+            ConflictKind::Dir => write!(f, "existing directory"),
+            ConflictKind::Differs => write!(f, "differs from the package file"),
+            ConflictKind::Link(target) => write!(f, "existing link to {}", target.display()),
+            ConflictKind::Package(package) => {
+                write!(f, "already provided by package '{}'", package)
+            }
+        }
+    }
+}
+
+/// A target path that can't be linked
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    /// Target path that is in the way
+    pub path: PathBuf,
+    /// Package that wanted to link the path
+    pub package: Option<String>,
+    /// Package entry that wanted to be linked at the path
+    pub source: Option<PathBuf>,
+    /// What is in the way
+    pub kind: ConflictKind,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.package {
+            Some(package) => write!(f, "{} ({}): {}", self.path.display(), package, self.kind),
+            None => write!(f, "{}: {}", self.path.display(), self.kind),
+        }
+    }
+}
+
+/// Format a list of conflicts with a summary line
+pub fn format_conflicts(conflicts: &[Conflict]) -> String {
+    let mut message = format!("Found {} conflict(s):", conflicts.len());
+    for conflict in conflicts {
+        message.push_str(&format!("\n  - {}", conflict));
+    }
+    message
+}
+
 /// Errors that can occur during stow operations
 #[derive(Debug, Error)]
 pub enum StowError {
@@ -13,11 +74,26 @@ pub enum StowError {
     )]
     PermissionDenied(String),
 
-    /// Conflict with existing file or directory
+    /// Conflicts with existing files, directories or links of other packages
+    #[error(
+        "{}\n\nTo resolve these conflicts, you can:\n  - Use --adopt to move the existing files into the package (add --keep-both to keep both versions of files that differ)\n  - Use --override to replace the existing files (destructive)\n  - Use --skip-conflicts to link everything else and skip these paths\n  - Use --backup to move the existing files into a backup you can restore later\n  - Use --interactive to decide for each conflict\n  - Manually remove or relocate the conflicting files",
+        format_conflicts(.0)
+    )]
+    Conflicts(Vec<Conflict>),
+
+    /// A plan's recorded state no longer matches the filesystem
     #[error(
-        "Conflict: {path} already exists and is not a symlink pointing to the package.\n\nTo resolve this conflict, you can:\n  - Use --adopt to move the existing file into the package\n  - Use --override to replace the existing file (destructive)\n  - Manually remove or relocate the conflicting file"
+        "Plan precondition failed at {path}: expected {expected}, found {actual}.\n\nThe target changed since the plan was created. Create and review a new plan."
     )]
-    Conflict { path: PathBuf },
+    PreconditionFailed {
+        path: PathBuf,
+        expected: String,
+        actual: String,
+    },
+
+    /// Plan file could not be read or written
+    #[error("Invalid plan file: {0}")]
+    PlanError(String),
 
     /// Package directory not found
     #[error("Package not found: '{package}' does not exist in stow directory '{stow_dir}'")]
@@ -49,6 +125,30 @@ pub enum StowError {
     /// Pattern matching error
     #[error("Invalid pattern: {0}")]
     PatternError(String),
+
+    /// State file could not be read or written
+    #[error("Invalid state file: {0}")]
+    StateError(String),
+
+    /// No backup with the given id in the target
+    #[error("Backup not found: '{0}' (run `winstow restore` to list the backups)")]
+    BackupNotFound(String),
+
+    /// No operation with the given id in the history
+    #[error("Operation not found: {0} (run `winstow history` to list the operations)")]
+    OperationNotFound(u64),
+
+    /// An operation can't be undone
+    #[error("Cannot undo: {0}")]
+    UndoNotPossible(String),
+
+    /// The user stopped an interactive prompt
+    #[error("Aborted, nothing was changed")]
+    Aborted,
+
+    /// `doctor` found problems that keep winstow from working
+    #[error("{0} check(s) failed")]
+    ChecksFailed(usize),
 }
 
 impl StowError {
@@ -57,9 +157,27 @@ impl StowError {
         StowError::PermissionDenied(message.into())
     }
 
-    /// Create a new Conflict error
-    pub fn conflict(path: impl Into<PathBuf>) -> Self {
-        StowError::Conflict { path: path.into() }
+    /// Create a new Conflicts error
+    pub fn conflicts(conflicts: Vec<Conflict>) -> Self {
+        StowError::Conflicts(conflicts)
+    }
+
+    /// Create a new PreconditionFailed error
+    pub fn precondition_failed(
+        path: impl Into<PathBuf>,
+        expected: impl Into<String>,
+        actual: impl Into<String>,
+    ) -> Self {
+        StowError::PreconditionFailed {
+            path: path.into(),
+            expected: expected.into(),
+            actual: actual.into(),
+        }
+    }
+
+    /// Create a new PlanError
+    pub fn plan_error(message: impl Into<String>) -> Self {
+        StowError::PlanError(message.into())
     }
 
     /// Create a new PackageNotFound error
@@ -105,6 +223,46 @@ impl StowError {
     pub fn pattern_error(message: impl Into<String>) -> Self {
         StowError::PatternError(message.into())
     }
+
+    /// Create a new StateError
+    pub fn state_error(message: impl Into<String>) -> Self {
+        StowError::StateError(message.into())
+    }
+
+    /// Create a new BackupNotFound error
+    pub fn backup_not_found(backup_id: impl Into<String>) -> Self {
+        StowError::BackupNotFound(backup_id.into())
+    }
+
+    /// Create a new OperationNotFound error
+    pub fn operation_not_found(id: u64) -> Self {
+        StowError::OperationNotFound(id)
+    }
+
+    /// Create a new UndoNotPossible error
+    pub fn undo_not_possible(message: impl Into<String>) -> Self {
+        StowError::UndoNotPossible(message.into())
+    }
+
+    /// Create a new ChecksFailed error
+    pub fn checks_failed(count: usize) -> Self {
+        StowError::ChecksFailed(count)
+    }
+
+    /// Check whether a link failed because its kind isn't possible at the path,
+    /// like symlinks without the privilege or hard links across volumes
+    pub fn is_link_not_permitted(&self) -> bool {
+        match self {
+            StowError::PermissionDenied(_) => true,
+            StowError::IoError { source, .. } => matches!(
+                source.kind(),
+                std::io::ErrorKind::PermissionDenied
+                    | std::io::ErrorKind::CrossesDevices
+                    | std::io::ErrorKind::Unsupported
+            ),
+            _ => false,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -119,10 +277,25 @@ mod tests {
     }
 
     #[test]
-    fn test_conflict_error() {
-        let err = StowError::conflict(PathBuf::from("test/path"));
-        assert!(err.to_string().contains("Conflict"));
-        assert!(err.to_string().contains("test/path"));
+    fn test_conflicts_error() {
+        let err = StowError::conflicts(vec![
+            Conflict {
+                path: PathBuf::from("test/path"),
+                package: Some("a".to_string()),
+                source: None,
+                kind: ConflictKind::File,
+            },
+            Conflict {
+                path: PathBuf::from("other/path"),
+                package: Some("a".to_string()),
+                source: None,
+                kind: ConflictKind::Package("b".to_string()),
+            },
+        ]);
+        let message = err.to_string();
+        assert!(message.contains("Found 2 conflict(s)"));
+        assert!(message.contains("test/path (a): existing file"));
+        assert!(message.contains("other/path (a): already provided by package 'b'"));
     }
 
     #[test]
@@ -137,4 +310,13 @@ mod tests {
         let err = StowError::invalid_path("invalid path");
         assert!(err.to_string().contains("Invalid path"));
     }
+
+    #[test]
+    fn test_link_not_permitted() {
+        let io = |kind| StowError::io_error("link", std::io::Error::from(kind));
+        assert!(StowError::permission_denied("link").is_link_not_permitted());
+        assert!(io(std::io::ErrorKind::CrossesDevices).is_link_not_permitted());
+        assert!(!io(std::io::ErrorKind::NotFound).is_link_not_permitted());
+        assert!(!StowError::invalid_path("link").is_link_not_permitted());
+    }
 }
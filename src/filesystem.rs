@@ -0,0 +1,347 @@
+use crate::error::{Result, StowError};
+use crate::{fs_ops, path_utils};
+use std::fmt::Debug;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+
+/// Maximum number of symlinks followed while resolving a single path
+const MAX_SYMLINK_DEPTH: usize = 40;
+
+/// Kind of a filesystem entry (symlinks are not followed)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+/// Metadata of a filesystem entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: FileKind,
+}
+
+impl Metadata {
+    /// Create metadata for an entry of the given kind
+    pub fn new(kind: FileKind) -> Self {
+        Self { kind }
+    }
+
+    /// Check if the entry is a symbolic link
+    pub fn is_symlink(&self) -> bool {
+        self.kind == FileKind::Symlink
+    }
+
+    /// Check if the entry is a directory
+    pub fn is_dir(&self) -> bool {
+        self.kind == FileKind::Dir
+    }
+}
+
+/// Filesystem operations used by stow, unstow, adopt and plan execution
+///
+/// All paths passed to a `Filesystem` are absolute. Implementations return
+/// `StowError` so callers can propagate errors without re-wrapping them.
+pub trait Filesystem: Debug {
+    /// Get metadata for a path without following a final symlink
+    fn symlink_metadata(&self, path: &Path) -> Result<Metadata>;
+
+    /// List the entries of a directory as full paths, sorted by name
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>>;
+
+    /// Read the target of a symbolic link
+    fn read_link(&self, path: &Path) -> Result<PathBuf>;
+
+    /// Create a symbolic link, creating missing parent directories
+    fn create_link(&self, link_path: &Path, target_path: &Path, is_directory: bool) -> Result<()>;
+
+    /// Remove a symbolic link (file or directory link)
+    fn remove_link(&self, path: &Path) -> Result<()>;
+
+    /// Create a hard link to a regular file, creating missing parent directories
+    fn create_hard_link(&self, link_path: &Path, source_path: &Path) -> Result<()>;
+
+    /// Check whether two paths are hard links of the same file
+    fn same_file(&self, a: &Path, b: &Path) -> bool;
+
+    /// Create a directory and all missing parents
+    fn create_dir_all(&self, path: &Path) -> Result<()>;
+
+    /// Remove an empty directory
+    fn remove_dir(&self, path: &Path) -> Result<()>;
+
+    /// Remove a regular file
+    fn remove_file(&self, path: &Path) -> Result<()>;
+
+    /// Remove a directory and all of its contents
+    fn remove_dir_all(&self, path: &Path) -> Result<()>;
+
+    /// Move a file or directory, creating missing parent directories of the destination
+    fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+
+    /// Copy a regular file, creating missing parent directories of the destination
+    fn copy_file(&self, from: &Path, to: &Path) -> Result<()>;
+
+    /// Read the contents of a regular file
+    fn read_file(&self, path: &Path) -> Result<Vec<u8>>;
+
+    /// Write a regular file, replacing an existing file and creating missing parent directories
+    fn write_file(&self, path: &Path, contents: &[u8]) -> Result<()>;
+
+    /// Resolve all symlinks and `.`/`..` components of an existing path
+    fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf> {
+        resolve_symlinks(self, path)
+    }
+
+    /// Normalize a path to an absolute path, resolving symlinks where possible
+    fn normalize_path(&self, path: &Path) -> Result<PathBuf> {
+        path_utils::normalize_path_with(path, |p| self.canonicalize(p))
+    }
+
+    /// Get metadata for a path, following symlinks
+    fn metadata(&self, path: &Path) -> Result<Metadata> {
+        let resolved = self
+            .canonicalize(path)
+            .map_err(|e| StowError::io_error(path.to_path_buf(), e))?;
+        self.symlink_metadata(&resolved)
+    }
+
+    /// Check if a path exists (following symlinks)
+    fn exists(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok()
+    }
+
+    /// Check if a path is a symbolic link
+    fn is_symlink(&self, path: &Path) -> bool {
+        self.symlink_metadata(path)
+            .map(|m| m.is_symlink())
+            .unwrap_or(false)
+    }
+
+    /// Check if a path is a directory (following symlinks)
+    fn is_dir(&self, path: &Path) -> bool {
+        self.metadata(path).map(|m| m.is_dir()).unwrap_or(false)
+    }
+
+    /// Check if a path is an empty directory
+    fn is_empty_dir(&self, path: &Path) -> Result<bool> {
+        if !self.is_dir(path) {
+            return Ok(false);
+        }
+
+        Ok(self.read_dir(path)?.is_empty())
+    }
+
+    /// Read a symlink and return its normalized absolute destination
+    fn resolve_link(&self, link_path: &Path) -> Result<PathBuf> {
+        let link_target = self.read_link(link_path)?;
+        let link_target_abs = if link_target.is_relative() {
+            link_path.parent().unwrap_or(link_path).join(&link_target)
+        } else {
+            link_target
+        };
+
+        self.normalize_path(&link_target_abs)
+    }
+}
+
+/// Resolve symlinks component by component using only `symlink_metadata` and `read_link`
+/// Used by filesystems that can't delegate canonicalization to the operating system
+pub fn resolve_symlinks<F: Filesystem + ?Sized>(fs: &F, path: &Path) -> std::io::Result<PathBuf> {
+    resolve_symlinks_with(path, |p| fs.symlink_metadata(p), |p| fs.read_link(p))
+}
+
+/// Resolve symlinks component by component using the given lookup functions
+/// Both functions are only called with paths whose parent is already resolved
+pub fn resolve_symlinks_with(
+    path: &Path,
+    symlink_metadata: impl Fn(&Path) -> Result<Metadata>,
+    read_link: impl Fn(&Path) -> Result<PathBuf>,
+) -> std::io::Result<PathBuf> {
+    let mut resolved = PathBuf::new();
+    // Components still to be resolved, in reverse order
+    let mut pending: Vec<PathBuf> = path
+        .components()
+        .rev()
+        .map(|c| PathBuf::from(c.as_os_str()))
+        .collect();
+    let mut followed = 0;
+
+    while let Some(component) = pending.pop() {
+        match component.components().next() {
+            None | Some(Component::CurDir) => {}
+            Some(Component::Prefix(_)) | Some(Component::RootDir) => resolved.push(&component),
+            Some(Component::ParentDir) => {
+                resolved.pop();
+            }
+            Some(Component::Normal(name)) => {
+                let candidate = resolved.join(name);
+                let metadata = symlink_metadata(&candidate)
+                    .map_err(|_| std::io::Error::from(ErrorKind::NotFound))?;
+
+                if !metadata.is_symlink() {
+                    resolved = candidate;
+                    continue;
+                }
+
+                followed += 1;
+                if followed > MAX_SYMLINK_DEPTH {
+                    return Err(std::io::Error::other("too many levels of symbolic links"));
+                }
+
+                // Continue resolution with the link target's components
+                let target =
+                    read_link(&candidate).map_err(|_| std::io::Error::from(ErrorKind::NotFound))?;
+                if target.is_absolute() {
+                    resolved = PathBuf::new();
+                }
+                pending.extend(
+                    target
+                        .components()
+                        .rev()
+                        .map(|c| PathBuf::from(c.as_os_str())),
+                );
+            }
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// The real filesystem of the host operating system
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OsFilesystem;
+
+impl Filesystem for OsFilesystem {
+    fn symlink_metadata(&self, path: &Path) -> Result<Metadata> {
+        let metadata = path
+            .symlink_metadata()
+            .map_err(|e| StowError::io_error(path.to_path_buf(), e))?;
+
+        Ok(Metadata::new(kind_of(&metadata.file_type())))
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let mut entries = fs::read_dir(path)
+            .map_err(|e| StowError::io_error(path.to_path_buf(), e))?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<std::io::Result<Vec<_>>>()
+            .map_err(|e| StowError::io_error(path.to_path_buf(), e))?;
+
+        // Sort for deterministic plans and output
+        entries.sort();
+        Ok(entries)
+    }
+
+    fn read_link(&self, path: &Path) -> Result<PathBuf> {
+        fs_ops::read_symlink(path)
+    }
+
+    fn create_link(&self, link_path: &Path, target_path: &Path, is_directory: bool) -> Result<()> {
+        fs_ops::create_symlink(link_path, target_path, is_directory)
+    }
+
+    fn remove_link(&self, path: &Path) -> Result<()> {
+        fs_ops::remove_symlink(path)
+    }
+
+    fn create_hard_link(&self, link_path: &Path, source_path: &Path) -> Result<()> {
+        fs_ops::create_hard_link(link_path, source_path)
+    }
+
+    fn same_file(&self, a: &Path, b: &Path) -> bool {
+        fs_ops::same_file(a, b)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        fs::create_dir_all(path).map_err(|e| StowError::io_error(path.to_path_buf(), e))
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<()> {
+        fs_ops::remove_empty_directory(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        fs::remove_file(path).map_err(|e| StowError::io_error(path.to_path_buf(), e))
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<()> {
+        fs::remove_dir_all(path).map_err(|e| StowError::io_error(path.to_path_buf(), e))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        fs_ops::ensure_parent_dirs(to)?;
+        fs::rename(from, to).map_err(|e| StowError::io_error(from.to_path_buf(), e))
+    }
+
+    fn copy_file(&self, from: &Path, to: &Path) -> Result<()> {
+        fs_ops::ensure_parent_dirs(to)?;
+        fs::copy(from, to)
+            .map(|_| ())
+            .map_err(|e| StowError::io_error(from.to_path_buf(), e))
+    }
+
+    fn read_file(&self, path: &Path) -> Result<Vec<u8>> {
+        fs::read(path).map_err(|e| StowError::io_error(path.to_path_buf(), e))
+    }
+
+    fn write_file(&self, path: &Path, contents: &[u8]) -> Result<()> {
+        fs_ops::ensure_parent_dirs(path)?;
+
+        // Write next to the file and rename, so readers never see a partial file
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let temp = path.with_file_name(format!(".{}.tmp-{}", name, std::process::id()));
+        fs::write(&temp, contents).map_err(|e| StowError::io_error(temp.clone(), e))?;
+        fs::rename(&temp, path).map_err(|e| {
+            let _ = fs::remove_file(&temp);
+            StowError::io_error(path.to_path_buf(), e)
+        })
+    }
+
+    fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf> {
+        path.canonicalize()
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Metadata> {
+        let metadata = path
+            .metadata()
+            .map_err(|e| StowError::io_error(path.to_path_buf(), e))?;
+
+        Ok(Metadata::new(kind_of(&metadata.file_type())))
+    }
+
+    fn normalize_path(&self, path: &Path) -> Result<PathBuf> {
+        path_utils::normalize_path(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn is_symlink(&self, path: &Path) -> bool {
+        fs_ops::is_symlink(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        fs_ops::is_directory(path).unwrap_or(false)
+    }
+
+    fn is_empty_dir(&self, path: &Path) -> Result<bool> {
+        fs_ops::is_empty_directory(path)
+    }
+}
+
+/// Map a std file type to a `FileKind`
+fn kind_of(file_type: &fs::FileType) -> FileKind {
+    if file_type.is_symlink() {
+        FileKind::Symlink
+    } else if file_type.is_dir() {
+        FileKind::Dir
+    } else {
+        FileKind::File
+    }
+}
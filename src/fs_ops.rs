@@ -1,14 +1,91 @@
+use std::fmt;
 use std::fs;
 use std::path::{Path, PathBuf};
-use windows::Win32::Foundation::{ERROR_PRIVILEGE_NOT_HELD, WIN32_ERROR};
-use windows::Win32::Storage::FileSystem::{
-    CreateSymbolicLinkW, SYMBOLIC_LINK_FLAG_ALLOW_UNPRIVILEGED_CREATE, SYMBOLIC_LINK_FLAG_DIRECTORY,
-};
-use windows::core::PCWSTR;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
 
 use crate::error::{Result, StowError};
 
-/// Create a symbolic link on Windows
+/// How a package file is put into the target
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkMode {
+    /// A symbolic link to the package file or directory
+    #[default]
+    Symlink,
+    /// A hard link to the package file; directories are always real directories
+    Hardlink,
+    /// A copy of the package file; directories are always real directories
+    Copy,
+}
+
+impl LinkMode {
+    /// Check if the mode creates symbolic links
+    pub fn is_symlink(&self) -> bool {
+        *self == LinkMode::Symlink
+    }
+
+    /// What the mode puts into the target, for messages
+    pub fn plural(self) -> &'static str {
+        match self {
+            LinkMode::Symlink => "symlinks",
+            LinkMode::Hardlink => "hard links",
+            LinkMode::Copy => "copies",
+        }
+    }
+
+    /// The modes of a fallback chain to try when this one isn't permitted
+    /// These are the modes listed after this one, or all others if it isn't listed
+    pub fn fallbacks(self, chain: &[LinkMode]) -> Vec<LinkMode> {
+        let after = chain
+            .iter()
+            .position(|mode| *mode == self)
+            .map_or(0, |index| index + 1);
+
+        let mut fallbacks = Vec::new();
+        for mode in &chain[after..] {
+            if *mode != self && !fallbacks.contains(mode) {
+                fallbacks.push(*mode);
+            }
+        }
+        fallbacks
+    }
+}
+
+impl fmt::Display for LinkMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkMode::Symlink => write!(f, "symlink"),
+            LinkMode::Hardlink => write!(f, "hardlink"),
+            LinkMode::Copy => write!(f, "copy"),
+        }
+    }
+}
+
+/// Platform-specific primitives for creating and removing links
+///
+/// The rest of winstow only talks to the free functions in this module; the
+/// backend for the current target is selected at compile time.
+trait LinkBackend {
+    /// Create a symbolic link at `link_path` pointing to `target_path`
+    fn create_symlink(link_path: &Path, target_path: &Path, is_directory: bool) -> Result<()>;
+
+    /// Remove a symbolic link (file or directory link) without touching its target
+    fn remove_symlink(link_path: &Path) -> Result<()>;
+
+    /// Identity of the file at `path` (volume and file index), shared by all its hard links
+    /// Links are followed; `None` if the file can't be opened
+    fn file_id(path: &Path) -> Option<(u64, u64)>;
+}
+
+#[cfg(windows)]
+type Backend = windows_backend::WindowsBackend;
+
+#[cfg(unix)]
+type Backend = unix_backend::UnixBackend;
+
+/// Create a symbolic link
 /// - `link_path`: The path where the symlink will be created
 /// - `target_path`: The path the symlink should point to (should be relative)
 /// - `is_directory`: Whether the target is a directory
@@ -27,43 +104,168 @@ pub fn create_symlink(
         fs::create_dir_all(parent).map_err(|e| StowError::io_error(parent.to_path_buf(), e))?;
     }
 
-    // Convert paths to wide strings for Windows API
-    let link_wide = to_wide_string(link_path);
-    let target_wide = to_wide_string(target_path);
+    Backend::create_symlink(link_path, target_path, is_directory)
+}
+
+/// Remove a symbolic link (file or directory link)
+pub fn remove_symlink(link_path: impl AsRef<Path>) -> Result<()> {
+    Backend::remove_symlink(link_path.as_ref())
+}
 
-    // Set up flags
-    let mut flags = SYMBOLIC_LINK_FLAG_ALLOW_UNPRIVILEGED_CREATE;
-    if is_directory {
-        flags |= SYMBOLIC_LINK_FLAG_DIRECTORY;
+/// Create a hard link at `link_path` to the file at `source_path`
+/// Both must be on the same volume
+pub fn create_hard_link(link_path: impl AsRef<Path>, source_path: impl AsRef<Path>) -> Result<()> {
+    let link_path = link_path.as_ref();
+
+    ensure_parent_dirs(link_path)?;
+    fs::hard_link(source_path, link_path)
+        .map_err(|e| StowError::io_error(link_path.to_path_buf(), e))
+}
+
+/// Check whether two paths are hard links of the same file
+pub fn same_file(a: impl AsRef<Path>, b: impl AsRef<Path>) -> bool {
+    match (Backend::file_id(a.as_ref()), Backend::file_id(b.as_ref())) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
     }
+}
 
-    // Create the symbolic link
-    unsafe {
-        let result = CreateSymbolicLinkW(
-            PCWSTR(link_wide.as_ptr()),
-            PCWSTR(target_wide.as_ptr()),
-            flags,
-        );
+#[cfg(windows)]
+mod windows_backend {
+    use super::LinkBackend;
+    use crate::error::{Result, StowError};
+    use std::fs;
+    use std::os::windows::fs::FileTypeExt;
+    use std::os::windows::io::AsRawHandle;
+    use std::path::Path;
+    use windows::Win32::Foundation::{ERROR_PRIVILEGE_NOT_HELD, HANDLE, WIN32_ERROR};
+    use windows::Win32::Storage::FileSystem::{
+        BY_HANDLE_FILE_INFORMATION, CreateSymbolicLinkW, GetFileInformationByHandle,
+        SYMBOLIC_LINK_FLAG_ALLOW_UNPRIVILEGED_CREATE, SYMBOLIC_LINK_FLAG_DIRECTORY,
+    };
+    use windows::core::PCWSTR;
+
+    /// Link backend using `CreateSymbolicLinkW`
+    pub struct WindowsBackend;
+
+    impl LinkBackend for WindowsBackend {
+        fn create_symlink(link_path: &Path, target_path: &Path, is_directory: bool) -> Result<()> {
+            // Convert paths to wide strings for Windows API
+            let link_wide = to_wide_string(link_path);
+            let target_wide = to_wide_string(target_path);
+
+            // Set up flags
+            let mut flags = SYMBOLIC_LINK_FLAG_ALLOW_UNPRIVILEGED_CREATE;
+            if is_directory {
+                flags |= SYMBOLIC_LINK_FLAG_DIRECTORY;
+            }
 
-        if !result {
-            // Get the last error
-            let error = WIN32_ERROR(windows::Win32::Foundation::GetLastError().0);
+            // Create the symbolic link
+            unsafe {
+                let result = CreateSymbolicLinkW(
+                    PCWSTR(link_wide.as_ptr()),
+                    PCWSTR(target_wide.as_ptr()),
+                    flags,
+                );
+
+                if !result {
+                    // Get the last error
+                    let error = WIN32_ERROR(windows::Win32::Foundation::GetLastError().0);
+
+                    if error == ERROR_PRIVILEGE_NOT_HELD {
+                        return Err(StowError::permission_denied(format!(
+                            "Cannot create symlink at {}",
+                            link_path.display()
+                        )));
+                    } else {
+                        return Err(StowError::symlink_error(
+                            link_path.to_path_buf(),
+                            format!("Failed to create symlink: {:?}", error),
+                        ));
+                    }
+                }
+            }
 
-            if error == ERROR_PRIVILEGE_NOT_HELD {
-                return Err(StowError::permission_denied(format!(
-                    "Cannot create symlink at {}",
-                    link_path.display()
-                )));
+            Ok(())
+        }
+
+        fn remove_symlink(link_path: &Path) -> Result<()> {
+            let metadata = link_path
+                .symlink_metadata()
+                .map_err(|e| StowError::io_error(link_path.to_path_buf(), e))?;
+
+            // Directory symlinks must be removed with RemoveDirectory on Windows
+            let result = if metadata.file_type().is_symlink_dir() {
+                fs::remove_dir(link_path)
             } else {
-                return Err(StowError::symlink_error(
-                    link_path.to_path_buf(),
-                    format!("Failed to create symlink: {:?}", error),
-                ));
-            }
+                fs::remove_file(link_path)
+            };
+
+            result.map_err(|e| StowError::io_error(link_path.to_path_buf(), e))
+        }
+
+        fn file_id(path: &Path) -> Option<(u64, u64)> {
+            let file = fs::File::open(path).ok()?;
+            let mut info = BY_HANDLE_FILE_INFORMATION::default();
+
+            unsafe { GetFileInformationByHandle(HANDLE(file.as_raw_handle()), &mut info) }.ok()?;
+
+            let index = (u64::from(info.nFileIndexHigh) << 32) | u64::from(info.nFileIndexLow);
+            Some((u64::from(info.dwVolumeSerialNumber), index))
         }
     }
 
-    Ok(())
+    /// Convert a path to a null-terminated wide string for Windows API calls
+    fn to_wide_string(path: &Path) -> Vec<u16> {
+        use std::os::windows::ffi::OsStrExt;
+
+        let path_str = path.as_os_str();
+        let mut wide: Vec<u16> = path_str.encode_wide().collect();
+        wide.push(0); // Null terminator
+        wide
+    }
+}
+
+#[cfg(unix)]
+mod unix_backend {
+    use super::LinkBackend;
+    use crate::error::{Result, StowError};
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::path::Path;
+
+    /// Link backend using `std::os::unix::fs::symlink`
+    pub struct UnixBackend;
+
+    impl LinkBackend for UnixBackend {
+        fn create_symlink(link_path: &Path, target_path: &Path, _is_directory: bool) -> Result<()> {
+            // Unix symlinks don't distinguish between file and directory links
+            std::os::unix::fs::symlink(target_path, link_path).map_err(|e| {
+                if e.kind() == ErrorKind::PermissionDenied {
+                    StowError::permission_denied(format!(
+                        "Cannot create symlink at {}",
+                        link_path.display()
+                    ))
+                } else {
+                    StowError::symlink_error(
+                        link_path.to_path_buf(),
+                        format!("Failed to create symlink: {}", e),
+                    )
+                }
+            })
+        }
+
+        fn remove_symlink(link_path: &Path) -> Result<()> {
+            fs::remove_file(link_path).map_err(|e| StowError::io_error(link_path.to_path_buf(), e))
+        }
+
+        fn file_id(path: &Path) -> Option<(u64, u64)> {
+            use std::os::unix::fs::MetadataExt;
+
+            let metadata = fs::metadata(path).ok()?;
+            Some((metadata.dev(), metadata.ino()))
+        }
+    }
 }
 
 /// Check if a path is a symbolic link
@@ -132,16 +334,6 @@ pub fn ensure_parent_dirs(path: impl AsRef<Path>) -> Result<()> {
     Ok(())
 }
 
-/// Convert a path to a null-terminated wide string for Windows API calls
-fn to_wide_string(path: &Path) -> Vec<u16> {
-    use std::os::windows::ffi::OsStrExt;
-
-    let path_str = path.as_os_str();
-    let mut wide: Vec<u16> = path_str.encode_wide().collect();
-    wide.push(0); // Null terminator
-    wide
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -216,10 +408,10 @@ mod tests {
         assert!(!is_directory(&file).unwrap());
     }
 
-    // Note: Actual symlink creation tests require either Developer Mode or admin privileges
-    // These tests are marked as ignored and can be run manually with proper permissions
+    // Note: Actual symlink creation tests on Windows require either Developer Mode or admin
+    // privileges. They are marked as ignored there and can be run manually with proper permissions
     #[test]
-    #[ignore]
+    #[cfg_attr(target_os = "windows", ignore)]
     fn test_create_file_symlink() {
         let temp_dir = TempDir::new().unwrap();
         let target = temp_dir.path().join("target.txt");
@@ -241,7 +433,7 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
+    #[cfg_attr(target_os = "windows", ignore)]
     fn test_create_directory_symlink() {
         let temp_dir = TempDir::new().unwrap();
         let target = temp_dir.path().join("target_dir");
@@ -261,6 +453,23 @@ mod tests {
         }
     }
 
+    #[test]
+    #[cfg_attr(target_os = "windows", ignore)]
+    fn test_remove_directory_symlink() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().join("target_dir");
+        fs::create_dir(&target).unwrap();
+        File::create(target.join("file.txt")).unwrap();
+
+        let link = temp_dir.path().join("link_dir");
+        create_symlink(&link, &target, true).unwrap();
+
+        assert!(remove_symlink(&link).is_ok());
+        assert!(!is_symlink(&link));
+        // The link target must be left untouched
+        assert!(target.join("file.txt").exists());
+    }
+
     #[test]
     fn test_read_symlink_nonexistent() {
         let temp_dir = TempDir::new().unwrap();
@@ -334,4 +543,36 @@ mod tests {
         let result = is_directory(&nonexistent);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_link_mode_fallbacks() {
+        let chain = [LinkMode::Symlink, LinkMode::Hardlink, LinkMode::Copy];
+        assert_eq!(
+            LinkMode::Symlink.fallbacks(&chain),
+            [LinkMode::Hardlink, LinkMode::Copy]
+        );
+        assert_eq!(LinkMode::Hardlink.fallbacks(&chain), [LinkMode::Copy]);
+        assert!(LinkMode::Copy.fallbacks(&chain).is_empty());
+        assert_eq!(
+            LinkMode::Symlink.fallbacks(&[LinkMode::Copy, LinkMode::Hardlink, LinkMode::Copy]),
+            [LinkMode::Copy, LinkMode::Hardlink]
+        );
+    }
+
+    #[test]
+    fn test_create_hard_link_shares_file_id() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let copy = temp_dir.path().join("copy.txt");
+        fs::write(&source, "content").unwrap();
+        fs::write(&copy, "content").unwrap();
+
+        let link = temp_dir.path().join("sub").join("link.txt");
+        create_hard_link(&link, &source).unwrap();
+
+        assert!(!is_symlink(&link));
+        assert!(same_file(&link, &source));
+        assert!(!same_file(&copy, &source));
+        assert!(!same_file(temp_dir.path().join("missing"), &source));
+    }
 }
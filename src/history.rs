@@ -0,0 +1,696 @@
+use crate::adopt::{self, Adoption};
+use crate::error::{Result, StowError};
+use crate::filesystem::Filesystem;
+use crate::fs_ops::LinkMode;
+use crate::planner::{Action, PathState, Plan, Step};
+use crate::{backup, path_utils, state};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Directory in the target holding the operation history and the files removed by overrides
+pub const HISTORY_DIR_NAME: &str = ".winstow-history";
+
+/// Name of the history file kept in the history directory
+pub const HISTORY_INDEX_NAME: &str = "index.toml";
+
+/// Version of the history format
+const HISTORY_VERSION: u32 = 1;
+
+/// Number of operations kept; older ones can't be undone anymore
+const MAX_OPERATIONS: usize = 50;
+
+/// Path of the history file of a target directory
+pub fn index_path(target_dir: &Path) -> PathBuf {
+    target_dir.join(HISTORY_DIR_NAME).join(HISTORY_INDEX_NAME)
+}
+
+/// Directory an operation keeps the files it removed in
+pub fn operation_dir(target_dir: &Path, id: u64) -> PathBuf {
+    target_dir.join(HISTORY_DIR_NAME).join(id.to_string())
+}
+
+/// State a path was left in by an operation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PathRecord {
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub state: PathState,
+}
+
+/// A stow, unstow, restow or adopt run with what it takes to reverse it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Operation {
+    /// Sequential number of the operation
+    pub id: u64,
+    /// Time of the operation, in seconds since the Unix epoch
+    pub time: u64,
+    /// Command line the operation was run with
+    pub command: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub undone: bool,
+    /// The executed steps, in execution order
+    #[serde(default)]
+    pub actions: Vec<Step>,
+    /// The steps reversing the operation, in execution order
+    #[serde(default)]
+    pub undo: Vec<Step>,
+    /// State of every touched path after the operation, checked before undoing it
+    #[serde(default)]
+    pub after: Vec<PathRecord>,
+    /// Target directory the operation ran in
+    #[serde(skip)]
+    base: PathBuf,
+    /// Paths touched so far, in order
+    #[serde(skip)]
+    touched: Vec<PathBuf>,
+}
+
+impl Operation {
+    /// Capture what reverses a step, right before it is executed
+    /// Returns where a path the step would delete is moved instead, so it can be put back
+    pub fn prepare(
+        &mut self,
+        fs: &dyn Filesystem,
+        step: &Step,
+        created_dirs: &[PathBuf],
+    ) -> Result<Option<PathBuf>> {
+        let mut inverse = Vec::new();
+        // Directories created outside the touched path, removed again once moved back
+        let mut moved_parents = Vec::new();
+        let mut stash = None;
+
+        match &step.action {
+            Action::CreateFileLink {
+                link_path,
+                target_path,
+                mode,
+            } if !mode.is_symlink() => {
+                inverse.push(Action::RemoveCopy {
+                    path: link_path.clone(),
+                    source_path: path_utils::link_destination(link_path, target_path),
+                });
+            }
+            Action::CreateFileLink { link_path, .. } | Action::CreateDirLink { link_path, .. } => {
+                inverse.push(Action::RemoveLink {
+                    path: link_path.clone(),
+                });
+            }
+            Action::UnfoldDirLink { link_path, .. } => {
+                inverse.push(Action::RefoldDir {
+                    link_path: link_path.clone(),
+                    target_path: fs.read_link(link_path)?,
+                });
+            }
+            Action::RefoldDir {
+                link_path,
+                target_path,
+            } => {
+                let parent = link_path.parent().unwrap_or(link_path);
+                inverse.push(Action::UnfoldDirLink {
+                    link_path: link_path.clone(),
+                    original_target: fs.normalize_path(&parent.join(target_path))?,
+                });
+                for entry in fs.read_dir(link_path)? {
+                    inverse.push(create_link(fs, &entry)?);
+                }
+            }
+            Action::AdoptFile {
+                target_path,
+                package_path,
+                keep_both,
+            }
+            | Action::AdoptDir {
+                target_path,
+                package_path,
+                keep_both,
+            } => {
+                moved_parents = state::missing_parents(fs, package_path);
+                adoption_inverse(fs, target_path, package_path, *keep_both, &mut inverse)?;
+            }
+            Action::RemoveConflict { path } => {
+                // Links are left alone by overrides
+                if fs.exists(path) && !fs.is_symlink(path) {
+                    let dir = operation_dir(&self.base, self.id);
+                    let stash_path = backup::backup_path(&self.base, &dir, path);
+                    moved_parents = state::missing_parents(fs, &stash_path);
+                    inverse.push(Action::MovePath {
+                        from: stash_path.clone(),
+                        to: path.clone(),
+                    });
+                    stash = Some(stash_path);
+                }
+            }
+            Action::BackupConflict { path, backup_path } => {
+                moved_parents = state::missing_parents(fs, backup_path);
+                inverse.push(Action::RestoreBackup {
+                    backup_path: backup_path.clone(),
+                    path: path.clone(),
+                });
+            }
+            Action::RestoreBackup { backup_path, path } => {
+                inverse.push(Action::BackupConflict {
+                    path: path.clone(),
+                    backup_path: backup_path.clone(),
+                });
+            }
+            Action::RemoveLink { path } => inverse.push(create_link(fs, path)?),
+            Action::RemoveCopy { path, source_path } => {
+                inverse.push(create_copy(fs, path, source_path)?);
+            }
+            Action::MaterializeLink { path, source_path } => {
+                inverse.push(Action::RemoveConflict { path: path.clone() });
+                if fs.is_symlink(path) {
+                    inverse.push(create_link(fs, path)?);
+                } else {
+                    inverse.push(create_copy(fs, path, source_path)?);
+                }
+            }
+            Action::RemoveEmptyDir { path } => {
+                if !fs.is_symlink(path) && fs.is_empty_dir(path)? {
+                    inverse.push(Action::CreateDir { path: path.clone() });
+                }
+            }
+            Action::MovePath { from, to } => inverse.push(Action::MovePath {
+                from: to.clone(),
+                to: from.clone(),
+            }),
+            Action::CopyFile { to, .. } => {
+                inverse.push(Action::RemoveConflict { path: to.clone() });
+            }
+            Action::CreateDir { path } => {
+                inverse.push(Action::RemoveEmptyDir { path: path.clone() });
+            }
+        }
+
+        // Created directories are removed again innermost first, once emptied
+        for path in moved_parents
+            .into_iter()
+            .chain(created_dirs.iter().cloned())
+            .rev()
+        {
+            inverse.push(Action::RemoveEmptyDir { path });
+        }
+
+        // Steps are reversed in the opposite order they were executed in
+        let steps = inverse.into_iter().map(|action| Step {
+            package: step.package.clone(),
+            action,
+            expect: None,
+        });
+        self.undo.splice(0..0, steps);
+
+        self.actions.push(Step {
+            expect: None,
+            ..step.clone()
+        });
+        self.touched.extend(created_dirs.iter().cloned());
+        self.touched.push(step.action.path().to_path_buf());
+
+        Ok(stash)
+    }
+
+    /// Record the state the executed steps left every touched path in
+    pub fn finish(&mut self, fs: &dyn Filesystem) {
+        let mut after: Vec<PathRecord> = Vec::new();
+        for path in std::mem::take(&mut self.touched) {
+            if after.iter().all(|record| record.path != path) {
+                let state = PathState::of(fs, &path);
+                after.push(PathRecord { path, state });
+            }
+        }
+        self.after = after;
+    }
+}
+
+/// Action recreating the link at `path` as it is now
+fn create_link(fs: &dyn Filesystem, path: &Path) -> Result<Action> {
+    let link_path = path.to_path_buf();
+    let target_path = fs.read_link(path)?;
+
+    if fs.is_dir(path) {
+        Ok(Action::CreateDirLink {
+            link_path,
+            target_path,
+        })
+    } else {
+        Ok(Action::CreateFileLink {
+            link_path,
+            target_path,
+            mode: LinkMode::Symlink,
+        })
+    }
+}
+
+/// Action recreating the hard link or copy of `source_path` at `path` as it is now
+fn create_copy(fs: &dyn Filesystem, path: &Path, source_path: &Path) -> Result<Action> {
+    let mode = if fs.same_file(path, source_path) {
+        LinkMode::Hardlink
+    } else {
+        LinkMode::Copy
+    };
+
+    Ok(Action::CreateFileLink {
+        link_path: path.to_path_buf(),
+        target_path: path_utils::compute_relative_path(path.parent().unwrap_or(path), source_path)?,
+        mode,
+    })
+}
+
+/// Actions putting back a target path adopted into the package, decided the way the
+/// adoption itself is
+fn adoption_inverse(
+    fs: &dyn Filesystem,
+    target_path: &Path,
+    package_path: &Path,
+    keep_both: bool,
+    inverse: &mut Vec<Action>,
+) -> Result<()> {
+    match adopt::adoption(fs, target_path, package_path, keep_both)? {
+        Adoption::Move => inverse.push(Action::MovePath {
+            from: package_path.to_path_buf(),
+            to: target_path.to_path_buf(),
+        }),
+        // The target copy is removed, the package file is copied back in its place
+        Adoption::Identical => inverse.push(Action::CopyFile {
+            from: package_path.to_path_buf(),
+            to: target_path.to_path_buf(),
+        }),
+        Adoption::KeepBoth(adopted_path) => inverse.push(Action::MovePath {
+            from: adopted_path,
+            to: target_path.to_path_buf(),
+        }),
+        Adoption::Merge => {
+            inverse.push(Action::CreateDir {
+                path: target_path.to_path_buf(),
+            });
+            for entry in fs.read_dir(target_path)? {
+                let Some(name) = entry.file_name() else {
+                    continue;
+                };
+                adoption_inverse(fs, &entry, &package_path.join(name), keep_both, inverse)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Record of the operations run in a target directory, oldest first
+#[derive(Debug, Serialize, Deserialize)]
+pub struct History {
+    version: u32,
+    #[serde(default)]
+    pub operations: Vec<Operation>,
+    /// Target directory the history belongs to
+    #[serde(skip)]
+    base: PathBuf,
+}
+
+impl History {
+    /// Create an empty history for the history file at `path`
+    pub fn new(path: &Path) -> Self {
+        Self {
+            version: HISTORY_VERSION,
+            operations: Vec::new(),
+            base: index_base(path),
+        }
+    }
+
+    /// Read the history file at `path`, or start with an empty history if there is none
+    pub fn load(fs: &dyn Filesystem, path: &Path) -> Result<Self> {
+        if fs.symlink_metadata(path).is_err() {
+            return Ok(Self::new(path));
+        }
+
+        let content = fs.read_file(path)?;
+        let content = String::from_utf8(content)
+            .map_err(|e| StowError::state_error(format!("{}: {}", path.display(), e)))?;
+        let mut history: History = toml::from_str(&content)
+            .map_err(|e| StowError::state_error(format!("{}: {}", path.display(), e)))?;
+
+        if history.version != HISTORY_VERSION {
+            return Err(StowError::state_error(format!(
+                "{}: unsupported format version {} (expected {})",
+                path.display(),
+                history.version,
+                HISTORY_VERSION
+            )));
+        }
+
+        history.base = index_base(path);
+        Ok(history)
+    }
+
+    /// Write the history file at `path`
+    pub fn save(&self, fs: &dyn Filesystem, path: &Path) -> Result<()> {
+        let content =
+            toml::to_string_pretty(self).map_err(|e| StowError::state_error(e.to_string()))?;
+        fs.write_file(path, content.as_bytes())
+    }
+
+    /// Start recording an operation, numbered after the last one
+    pub fn begin(&self, command: &str, now: u64) -> Operation {
+        Operation {
+            id: self.operations.last().map_or(1, |last| last.id + 1),
+            time: now,
+            command: command.to_string(),
+            undone: false,
+            actions: Vec::new(),
+            undo: Vec::new(),
+            after: Vec::new(),
+            base: self.base.clone(),
+            touched: Vec::new(),
+        }
+    }
+
+    /// Add a finished operation, dropping the oldest ones with the files they kept
+    pub fn push(&mut self, fs: &dyn Filesystem, operation: Operation) -> Result<()> {
+        self.operations.push(operation);
+
+        while self.operations.len() > MAX_OPERATIONS {
+            let dropped = self.operations.remove(0);
+            let dir = operation_dir(&self.base, dropped.id);
+            if fs.symlink_metadata(&dir).is_ok() {
+                fs.remove_dir_all(&dir)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get an operation by id
+    pub fn get(&self, id: u64) -> Option<&Operation> {
+        self.operations.iter().find(|operation| operation.id == id)
+    }
+
+    /// Mark an operation as undone
+    pub fn mark_undone(&mut self, id: u64) {
+        if let Some(operation) = self.operations.iter_mut().find(|op| op.id == id) {
+            operation.undone = true;
+        }
+    }
+}
+
+/// Target directory of the history file at `path`
+fn index_base(path: &Path) -> PathBuf {
+    path.parent()
+        .and_then(Path::parent)
+        .unwrap_or(path)
+        .to_path_buf()
+}
+
+/// Plan reversing a recorded operation, the latest one not undone yet if no id is given
+/// Fails if any path the operation touched has changed since; returns the operation's id
+pub fn plan_undo(
+    fs: &dyn Filesystem,
+    index_path: &Path,
+    id: Option<u64>,
+    plan: &mut Plan,
+) -> Result<u64> {
+    let history = History::load(fs, index_path)?;
+    let operation = match id {
+        Some(id) => history
+            .get(id)
+            .ok_or_else(|| StowError::operation_not_found(id))?,
+        None => history
+            .operations
+            .iter()
+            .rev()
+            .find(|operation| !operation.undone)
+            .ok_or_else(|| StowError::undo_not_possible("no operation to undo"))?,
+    };
+
+    if operation.undone {
+        return Err(StowError::undo_not_possible(format!(
+            "operation {} was already undone",
+            operation.id
+        )));
+    }
+
+    for record in &operation.after {
+        let current = PathState::of(fs, &record.path);
+        if current != record.state {
+            return Err(StowError::undo_not_possible(format!(
+                "{} has changed since operation {} (expected {}, found {})",
+                record.path.display(),
+                operation.id,
+                record.state,
+                current
+            )));
+        }
+    }
+
+    for step in &operation.undo {
+        plan.add_for_package(step.action.clone(), step.package.clone());
+    }
+    plan.mark_undoes(operation.id);
+
+    Ok(operation.id)
+}
+
+/// How long ago an operation ran, for listing
+pub fn describe_age(time: u64, now: u64) -> String {
+    let seconds = now.saturating_sub(time);
+    match seconds {
+        0..60 => format!("{} second(s) ago", seconds),
+        60..3600 => format!("{} minute(s) ago", seconds / 60),
+        3600..86400 => format!("{} hour(s) ago", seconds / 3600),
+        _ => format!("{} day(s) ago", seconds / 86400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memfs::MemoryFilesystem;
+
+    /// Execute a plan recording it in the history of the `target` directory
+    fn execute(fs: &MemoryFilesystem, mut plan: Plan) {
+        let target = MemoryFilesystem::root().join("target");
+        plan.track_history(index_path(&target), "winstow test".to_string());
+        plan.execute(fs, false).unwrap();
+    }
+
+    /// Plan and execute undoing an operation
+    fn undo(fs: &MemoryFilesystem, id: Option<u64>) -> Result<u64> {
+        let target = MemoryFilesystem::root().join("target");
+        let mut plan = Plan::new();
+        let id = plan_undo(fs, &index_path(&target), id, &mut plan)?;
+        execute(fs, plan);
+        Ok(id)
+    }
+
+    fn link_plan(name: &str) -> Plan {
+        let root = MemoryFilesystem::root();
+        let mut plan = Plan::new();
+        plan.add(Action::CreateFileLink {
+            link_path: root.join("target").join(name),
+            target_path: PathBuf::from("../stow/pkg").join(name),
+            mode: LinkMode::Symlink,
+        });
+        plan
+    }
+
+    #[test]
+    fn test_undo_reverses_operation() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        fs.add_file(root.join("stow/pkg1/app/a.txt"), "a");
+        fs.add_file(root.join("stow/pkg2/app/b.txt"), "b");
+        fs.add_file(root.join("stow/pkg2/file.txt"), "package");
+        fs.add_file(root.join("target/file.txt"), "adopted");
+        fs.add_file(root.join("target/old/settings.txt"), "old");
+        fs.create_link(
+            &root.join("target/app"),
+            Path::new("../stow/pkg1/app"),
+            true,
+        )
+        .unwrap();
+
+        let mut plan = Plan::new();
+        plan.add(Action::UnfoldDirLink {
+            link_path: root.join("target/app"),
+            original_target: root.join("stow/pkg1/app"),
+        });
+        plan.add(Action::CreateFileLink {
+            link_path: root.join("target/app/a.txt"),
+            target_path: PathBuf::from("../../stow/pkg1/app/a.txt"),
+            mode: LinkMode::Symlink,
+        });
+        plan.add(Action::CreateFileLink {
+            link_path: root.join("target/app/b.txt"),
+            target_path: PathBuf::from("../../stow/pkg2/app/b.txt"),
+            mode: LinkMode::Symlink,
+        });
+        plan.add(Action::AdoptFile {
+            target_path: root.join("target/file.txt"),
+            package_path: root.join("stow/pkg2/file.txt"),
+            keep_both: true,
+        });
+        plan.add(Action::RemoveConflict {
+            path: root.join("target/old"),
+        });
+        plan.add(Action::CreateFileLink {
+            link_path: root.join("target/new/nested/c.txt"),
+            target_path: PathBuf::from("../../../stow/pkg2/new/nested/c.txt"),
+            mode: LinkMode::Symlink,
+        });
+        execute(&fs, plan);
+
+        // The overridden directory is kept for the undo
+        assert_eq!(
+            fs.file_content(root.join("target/.winstow-history/1/old/settings.txt")),
+            Some(b"old".to_vec())
+        );
+        assert!(fs.is_dir(&root.join("target/new/nested")));
+
+        assert_eq!(undo(&fs, None).unwrap(), 1);
+
+        assert_eq!(
+            fs.read_link(&root.join("target/app")).unwrap(),
+            PathBuf::from("../stow/pkg1/app")
+        );
+        assert_eq!(
+            fs.file_content(root.join("target/file.txt")),
+            Some(b"adopted".to_vec())
+        );
+        assert!(!fs.exists(&root.join("stow/pkg2/file.txt.adopted")));
+        assert_eq!(
+            fs.file_content(root.join("target/old/settings.txt")),
+            Some(b"old".to_vec())
+        );
+        assert!(!fs.exists(&root.join("target/new")));
+        assert!(!fs.exists(&root.join("target/.winstow-history/1")));
+
+        let history = History::load(&fs, &index_path(&root.join("target"))).unwrap();
+        assert!(history.get(1).unwrap().undone);
+    }
+
+    #[test]
+    fn test_undo_puts_back_adopted_identical_file() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        fs.add_file(root.join("stow/pkg/file.txt"), "same");
+        fs.add_file(root.join("target/file.txt"), "same");
+
+        let mut plan = Plan::new();
+        plan.add(Action::AdoptFile {
+            target_path: root.join("target/file.txt"),
+            package_path: root.join("stow/pkg/file.txt"),
+            keep_both: false,
+        });
+        execute(&fs, plan);
+        assert!(!fs.exists(&root.join("target/file.txt")));
+
+        undo(&fs, None).unwrap();
+        assert_eq!(
+            fs.file_content(root.join("target/file.txt")),
+            Some(b"same".to_vec())
+        );
+        assert_eq!(
+            fs.file_content(root.join("stow/pkg/file.txt")),
+            Some(b"same".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_undo_brings_back_removed_hard_link() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        let source = root.join("stow/pkg/app/a.txt");
+        let link = root.join("target/app/a.txt");
+        fs.add_file(&source, "a");
+
+        let mut plan = Plan::new();
+        plan.add(Action::CreateFileLink {
+            link_path: link.clone(),
+            target_path: PathBuf::from("../../stow/pkg/app/a.txt"),
+            mode: LinkMode::Hardlink,
+        });
+        execute(&fs, plan);
+        let mut plan = Plan::new();
+        plan.add(Action::RemoveCopy {
+            path: link.clone(),
+            source_path: source.clone(),
+        });
+        execute(&fs, plan);
+        assert!(!fs.exists(&link));
+
+        undo(&fs, Some(2)).unwrap();
+        assert!(fs.same_file(&link, &source));
+
+        undo(&fs, Some(1)).unwrap();
+        assert!(!fs.exists(&root.join("target/app")));
+        assert!(fs.exists(&source));
+    }
+
+    #[test]
+    fn test_undo_refuses_changed_target() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        fs.add_file(root.join("stow/pkg/a.txt"), "a");
+        execute(&fs, link_plan("a.txt"));
+
+        fs.remove_link(&root.join("target/a.txt")).unwrap();
+        fs.add_file(root.join("target/a.txt"), "mine");
+
+        let err = undo(&fs, Some(1)).unwrap_err();
+        assert!(matches!(err, StowError::UndoNotPossible(_)));
+        assert_eq!(
+            fs.file_content(root.join("target/a.txt")),
+            Some(b"mine".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_undo_picks_latest_operation_not_undone() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        fs.add_file(root.join("stow/pkg/a.txt"), "a");
+        fs.add_file(root.join("stow/pkg/b.txt"), "b");
+        execute(&fs, link_plan("a.txt"));
+        execute(&fs, link_plan("b.txt"));
+
+        assert_eq!(undo(&fs, None).unwrap(), 2);
+        assert!(fs.is_symlink(&root.join("target/a.txt")));
+        assert!(!fs.exists(&root.join("target/b.txt")));
+
+        assert!(matches!(
+            undo(&fs, Some(2)),
+            Err(StowError::UndoNotPossible(_))
+        ));
+        assert!(matches!(
+            undo(&fs, Some(9)),
+            Err(StowError::OperationNotFound(9))
+        ));
+
+        assert_eq!(undo(&fs, None).unwrap(), 1);
+        assert!(!fs.exists(&root.join("target/a.txt")));
+        assert!(matches!(
+            undo(&fs, None),
+            Err(StowError::UndoNotPossible(_))
+        ));
+    }
+
+    #[test]
+    fn test_history_keeps_latest_operations() {
+        let fs = MemoryFilesystem::new();
+        let target = MemoryFilesystem::root().join("target");
+        fs.add_file(operation_dir(&target, 1).join("old.txt"), "old");
+
+        let mut history = History::new(&index_path(&target));
+        for _ in 0..MAX_OPERATIONS + 2 {
+            let operation = history.begin("winstow test", 0);
+            history.push(&fs, operation).unwrap();
+        }
+
+        assert_eq!(history.operations.len(), MAX_OPERATIONS);
+        assert_eq!(history.operations[0].id, 3);
+        assert!(!fs.exists(&operation_dir(&target, 1)));
+    }
+
+    #[test]
+    fn test_describe_age() {
+        assert_eq!(describe_age(100, 130), "30 second(s) ago");
+        assert_eq!(describe_age(0, 7200), "2 hour(s) ago");
+        assert_eq!(describe_age(200, 100), "0 second(s) ago");
+    }
+}
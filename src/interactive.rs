@@ -0,0 +1,299 @@
+use crate::diff;
+use crate::error::{Conflict, ConflictKind, Result, StowError};
+use crate::filesystem::Filesystem;
+use crate::stow::ConflictStrategy;
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+/// An answer to the conflict prompt
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Choice {
+    /// Resolve this conflict
+    Resolve(ConflictStrategy),
+    /// Resolve this and all remaining conflicts the same way
+    ResolveAll(ConflictStrategy),
+    Diff,
+    Quit,
+}
+
+impl Choice {
+    /// Parse an answer; uppercase letters apply to all remaining conflicts
+    fn parse(answer: &str) -> Option<Self> {
+        let strategy = match answer.to_ascii_lowercase().as_str() {
+            "a" => ConflictStrategy::Adopt,
+            "o" => ConflictStrategy::Override,
+            "b" => ConflictStrategy::Backup,
+            "s" => ConflictStrategy::Skip,
+            "d" => return Some(Choice::Diff),
+            "q" => return Some(Choice::Quit),
+            _ => return None,
+        };
+
+        if answer.chars().all(|c| c.is_ascii_uppercase()) {
+            Some(Choice::ResolveAll(strategy))
+        } else {
+            Some(Choice::Resolve(strategy))
+        }
+    }
+}
+
+/// Strategies that can resolve a conflict of the given kind
+fn allowed_strategies(kind: &ConflictKind) -> &'static [ConflictStrategy] {
+    use ConflictStrategy::*;
+    match kind {
+        ConflictKind::File | ConflictKind::Dir => &[Adopt, Override, Backup, Skip],
+        // Adopting a file that differs from the package's version needs --keep-both
+        ConflictKind::Differs => &[Override, Backup, Skip],
+        // Symlinks are never adopted
+        ConflictKind::Link(_) => &[Override, Backup, Skip],
+        // Paths of other packages are never touched
+        ConflictKind::Package(_) => &[Skip],
+    }
+}
+
+/// Prompt text listing the answers for a conflict
+fn prompt(conflict: &Conflict) -> String {
+    let mut options: Vec<&str> = allowed_strategies(&conflict.kind)
+        .iter()
+        .map(|strategy| match strategy {
+            ConflictStrategy::Adopt => "[a]dopt",
+            ConflictStrategy::Override => "[o]verride",
+            ConflictStrategy::Backup => "[b]ack up",
+            ConflictStrategy::Skip | ConflictStrategy::Fail => "[s]kip",
+        })
+        .collect();
+    if matches!(conflict.kind, ConflictKind::File | ConflictKind::Differs) {
+        options.push("[d]iff");
+    }
+    options.push("[q]uit");
+    format!("{} (uppercase: all remaining)? ", options.join(" "))
+}
+
+/// Asks how to resolve each conflict before anything is executed
+/// Reading from any input makes the prompts scriptable
+pub struct Wizard<'a, R: BufRead, W: Write> {
+    fs: &'a dyn Filesystem,
+    input: R,
+    output: W,
+}
+
+impl<'a, R: BufRead, W: Write> Wizard<'a, R, W> {
+    /// Create a wizard reading the package and target files for diffs from `fs`
+    pub fn new(fs: &'a dyn Filesystem, input: R, output: W) -> Self {
+        Self { fs, input, output }
+    }
+
+    /// Ask for a strategy for each conflict
+    /// Quitting or the end of the input aborts without a resolution
+    pub fn resolve(
+        &mut self,
+        conflicts: &[Conflict],
+    ) -> Result<BTreeMap<PathBuf, ConflictStrategy>> {
+        let mut resolutions = BTreeMap::new();
+        let mut all: Option<ConflictStrategy> = None;
+
+        for (index, conflict) in conflicts.iter().enumerate() {
+            let allowed = allowed_strategies(&conflict.kind);
+            self.say(&format!(
+                "Conflict {}/{}: {}",
+                index + 1,
+                conflicts.len(),
+                conflict
+            ))?;
+
+            if let Some(strategy) = all.filter(|strategy| allowed.contains(strategy)) {
+                self.say(&format!("  -> {}", describe(strategy)))?;
+                resolutions.insert(conflict.path.clone(), strategy);
+                continue;
+            }
+
+            let strategy = loop {
+                let answer = self.ask(&prompt(conflict))?;
+                match Choice::parse(&answer) {
+                    Some(Choice::Quit) => return Err(StowError::Aborted),
+                    Some(Choice::Diff)
+                        if matches!(conflict.kind, ConflictKind::File | ConflictKind::Differs) =>
+                    {
+                        self.show_diff(conflict)?;
+                    }
+                    Some(Choice::Resolve(strategy)) if allowed.contains(&strategy) => {
+                        break strategy;
+                    }
+                    Some(Choice::ResolveAll(strategy)) if allowed.contains(&strategy) => {
+                        all = Some(strategy);
+                        break strategy;
+                    }
+                    _ => self.say(&format!("'{}' is not one of the listed answers", answer))?,
+                }
+            };
+            resolutions.insert(conflict.path.clone(), strategy);
+        }
+
+        Ok(resolutions)
+    }
+
+    /// Print the differences between the file in the target and the package file
+    fn show_diff(&mut self, conflict: &Conflict) -> Result<()> {
+        let Some(source) = &conflict.source else {
+            return self.say("No package file to compare with");
+        };
+
+        let target = self.fs.read_file(&conflict.path)?;
+        let package = self.fs.read_file(source)?;
+        let Some(diff) = diff::unified_diff(
+            &target,
+            &package,
+            &conflict.path.to_string_lossy(),
+            &source.to_string_lossy(),
+        ) else {
+            return self.say("The files differ (binary or too large to show)");
+        };
+
+        if diff.is_empty() {
+            self.say("The files are identical")
+        } else {
+            self.write(&diff)
+        }
+    }
+
+    /// Print a prompt and read the trimmed answer
+    fn ask(&mut self, prompt: &str) -> Result<String> {
+        self.write(prompt)?;
+        self.output.flush().map_err(output_error)?;
+
+        let mut answer = String::new();
+        let read = self
+            .input
+            .read_line(&mut answer)
+            .map_err(|e| StowError::io_error(Path::new("<stdin>"), e))?;
+        if read == 0 {
+            return Err(StowError::Aborted);
+        }
+        Ok(answer.trim().to_string())
+    }
+
+    fn say(&mut self, line: &str) -> Result<()> {
+        self.write(&format!("{}\n", line))
+    }
+
+    fn write(&mut self, text: &str) -> Result<()> {
+        self.output.write_all(text.as_bytes()).map_err(output_error)
+    }
+}
+
+/// What a strategy does to a conflicting path
+fn describe(strategy: ConflictStrategy) -> &'static str {
+    match strategy {
+        ConflictStrategy::Adopt => "adopt",
+        ConflictStrategy::Override => "override",
+        ConflictStrategy::Backup => "back up",
+        ConflictStrategy::Skip | ConflictStrategy::Fail => "skip",
+    }
+}
+
+fn output_error(e: std::io::Error) -> StowError {
+    StowError::io_error(Path::new("<stdout>"), e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memfs::MemoryFilesystem;
+
+    fn conflict(name: &str, kind: ConflictKind) -> Conflict {
+        let root = MemoryFilesystem::root();
+        Conflict {
+            path: root.join("target").join(name),
+            package: Some("pkg".to_string()),
+            source: Some(root.join("stow/pkg").join(name)),
+            kind,
+        }
+    }
+
+    fn run(
+        fs: &MemoryFilesystem,
+        conflicts: &[Conflict],
+        input: &str,
+    ) -> (Result<BTreeMap<PathBuf, ConflictStrategy>>, String) {
+        let mut output = Vec::new();
+        let result = Wizard::new(fs, input.as_bytes(), &mut output).resolve(conflicts);
+        (result, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_answers_become_resolutions() {
+        let fs = MemoryFilesystem::new();
+        let conflicts = vec![
+            conflict("a", ConflictKind::File),
+            conflict("b", ConflictKind::Dir),
+            conflict("c", ConflictKind::Link(PathBuf::from("/elsewhere"))),
+            conflict("d", ConflictKind::Package("other".to_string())),
+        ];
+
+        // Adopting a link and overriding another package's path are refused
+        let (result, output) = run(&fs, &conflicts, "a\nb\na\no\no\ns\n");
+        let resolutions = result.unwrap();
+        assert_eq!(
+            resolutions.values().copied().collect::<Vec<_>>(),
+            vec![
+                ConflictStrategy::Adopt,
+                ConflictStrategy::Backup,
+                ConflictStrategy::Override,
+                ConflictStrategy::Skip,
+            ]
+        );
+        assert_eq!(output.matches("not one of the listed answers").count(), 2);
+    }
+
+    #[test]
+    fn test_uppercase_applies_to_remaining_conflicts() {
+        let fs = MemoryFilesystem::new();
+        let conflicts = vec![
+            conflict("a", ConflictKind::File),
+            conflict("b", ConflictKind::File),
+            conflict("c", ConflictKind::Package("other".to_string())),
+        ];
+
+        // Backing up doesn't apply to the other package's path, which is asked for
+        let (result, _) = run(&fs, &conflicts, "B\ns\n");
+        let resolutions = result.unwrap();
+        assert_eq!(resolutions[&conflicts[1].path], ConflictStrategy::Backup);
+        assert_eq!(resolutions[&conflicts[2].path], ConflictStrategy::Skip);
+    }
+
+    #[test]
+    fn test_diff_shows_file_differences() {
+        let fs = MemoryFilesystem::new();
+        let file = conflict("a", ConflictKind::File);
+        fs.add_file(&file.path, "local\n");
+        fs.add_file(file.source.as_ref().unwrap(), "packaged\n");
+
+        let (result, output) = run(&fs, std::slice::from_ref(&file), "d\ns\n");
+        assert_eq!(result.unwrap()[&file.path], ConflictStrategy::Skip);
+        assert!(output.contains("-local\n+packaged\n"), "{}", output);
+    }
+
+    #[test]
+    fn test_diff_of_binary_files_is_not_printed() {
+        let fs = MemoryFilesystem::new();
+        let file = conflict("a", ConflictKind::File);
+        fs.add_file(&file.path, b"\0\x01\xff".to_vec());
+        fs.add_file(file.source.as_ref().unwrap(), b"\0\x02\xff".to_vec());
+
+        let (_, output) = run(&fs, std::slice::from_ref(&file), "d\ns\n");
+        assert!(output.contains("The files differ (binary"), "{}", output);
+        assert!(!output.contains("\u{fffd}"), "{}", output);
+    }
+
+    #[test]
+    fn test_quit_and_end_of_input_abort() {
+        let fs = MemoryFilesystem::new();
+        let conflicts = vec![conflict("a", ConflictKind::File)];
+
+        let (result, _) = run(&fs, &conflicts, "q\n");
+        assert!(matches!(result, Err(StowError::Aborted)));
+        let (result, _) = run(&fs, &conflicts, "");
+        assert!(matches!(result, Err(StowError::Aborted)));
+    }
+}
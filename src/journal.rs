@@ -0,0 +1,460 @@
+use crate::error::Result;
+use crate::filesystem::{Filesystem, Metadata};
+use crate::logger;
+use std::cell::{Cell, RefCell};
+use std::path::{Path, PathBuf};
+
+/// A single step needed to revert a change made through a `Journal`
+#[derive(Debug, Clone, PartialEq)]
+enum Undo {
+    /// Remove a link that was created
+    RemoveLink(PathBuf),
+    /// Re-create a link that was removed
+    RestoreLink {
+        path: PathBuf,
+        target: PathBuf,
+        is_directory: bool,
+    },
+    /// Remove a directory that was created
+    RemoveDir(PathBuf),
+    /// Re-create a directory that was removed
+    CreateDir(PathBuf),
+    /// Remove a file that was created by a copy, hard link or write
+    RemoveFile(PathBuf),
+    /// Move a file or directory back to where it came from
+    Move { from: PathBuf, to: PathBuf },
+}
+
+/// Filesystem wrapper that records an undo entry for every change it makes
+///
+/// Removed files and directories are moved into a staging area next to
+/// their original location instead of being deleted, so a failed plan can
+/// be rolled back completely. `commit` deletes the staging area, `rollback`
+/// reverts all recorded changes in reverse order.
+#[derive(Debug)]
+pub struct Journal<'a> {
+    inner: &'a dyn Filesystem,
+    entries: RefCell<Vec<Undo>>,
+    staged: RefCell<Vec<PathBuf>>,
+    counter: Cell<usize>,
+}
+
+impl<'a> Journal<'a> {
+    /// Create an empty journal on top of a filesystem
+    pub fn new(inner: &'a dyn Filesystem) -> Self {
+        Self {
+            inner,
+            entries: RefCell::new(Vec::new()),
+            staged: RefCell::new(Vec::new()),
+            counter: Cell::new(0),
+        }
+    }
+
+    /// Get the number of recorded changes
+    pub fn len(&self) -> usize {
+        self.entries.borrow().len()
+    }
+
+    /// Keep all changes and delete the staging area
+    pub fn commit(self) {
+        for path in self.staged.take() {
+            let result = if self.inner.is_dir(&path) && !self.inner.is_symlink(&path) {
+                self.inner.remove_dir_all(&path)
+            } else {
+                self.inner.remove_file(&path)
+            };
+
+            if let Err(e) = result {
+                logger::warn(&format!(
+                    "Failed to remove staged file {}: {}",
+                    path.display(),
+                    e
+                ));
+            }
+        }
+    }
+
+    /// Revert all recorded changes in reverse order
+    ///
+    /// Rollback continues past failing steps so as much as possible is restored.
+    /// Returns the number of steps that could not be reverted.
+    pub fn rollback(self) -> usize {
+        let mut failures = 0;
+
+        for undo in self.entries.take().into_iter().rev() {
+            logger::verbose(&format!("Rollback: {:?}", undo));
+
+            if let Err(e) = self.revert(&undo) {
+                logger::warn(&format!("Rollback step failed ({:?}): {}", undo, e));
+                failures += 1;
+            }
+        }
+
+        failures
+    }
+
+    /// Revert a single recorded change
+    fn revert(&self, undo: &Undo) -> Result<()> {
+        match undo {
+            Undo::RemoveLink(path) => self.inner.remove_link(path),
+            Undo::RestoreLink {
+                path,
+                target,
+                is_directory,
+            } => self.inner.create_link(path, target, *is_directory),
+            Undo::RemoveDir(path) => self.inner.remove_dir(path),
+            Undo::CreateDir(path) => self.inner.create_dir_all(path),
+            Undo::RemoveFile(path) => self.inner.remove_file(path),
+            Undo::Move { from, to } => self.inner.rename(from, to),
+        }
+    }
+
+    /// Record a change
+    fn record(&self, undo: Undo) {
+        self.entries.borrow_mut().push(undo);
+    }
+
+    /// Create missing parent directories of a path, recording each one
+    fn create_parents(&self, path: &Path) -> Result<()> {
+        let mut missing = Vec::new();
+        let mut current = path.parent();
+        while let Some(dir) = current {
+            if self.inner.symlink_metadata(dir).is_ok() {
+                break;
+            }
+            missing.push(dir.to_path_buf());
+            current = dir.parent();
+        }
+
+        // Create from the outermost directory inwards so rollback removes the innermost first
+        for dir in missing.into_iter().rev() {
+            self.inner.create_dir_all(&dir)?;
+            self.record(Undo::RemoveDir(dir));
+        }
+
+        Ok(())
+    }
+
+    /// Move an existing entry into the staging area, recording how to restore it
+    fn stage(&self, path: &Path) -> Result<()> {
+        let staging = self.staging_path(path);
+        self.inner.rename(path, &staging)?;
+        self.record(Undo::Move {
+            from: staging.clone(),
+            to: path.to_path_buf(),
+        });
+        self.staged.borrow_mut().push(staging);
+        Ok(())
+    }
+
+    /// Find an unused staging path next to the given path
+    fn staging_path(&self, path: &Path) -> PathBuf {
+        let parent = path.parent().unwrap_or(path);
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        loop {
+            let n = self.counter.get();
+            self.counter.set(n + 1);
+
+            let candidate = parent.join(format!(".{}.winstow-{}-{}", name, std::process::id(), n));
+            if self.inner.symlink_metadata(&candidate).is_err() {
+                return candidate;
+            }
+        }
+    }
+}
+
+impl Filesystem for Journal<'_> {
+    fn symlink_metadata(&self, path: &Path) -> Result<Metadata> {
+        self.inner.symlink_metadata(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        self.inner.read_dir(path)
+    }
+
+    fn read_link(&self, path: &Path) -> Result<PathBuf> {
+        self.inner.read_link(path)
+    }
+
+    fn create_link(&self, link_path: &Path, target_path: &Path, is_directory: bool) -> Result<()> {
+        self.create_parents(link_path)?;
+        self.inner
+            .create_link(link_path, target_path, is_directory)?;
+        self.record(Undo::RemoveLink(link_path.to_path_buf()));
+        Ok(())
+    }
+
+    fn remove_link(&self, path: &Path) -> Result<()> {
+        let target = self.inner.read_link(path)?;
+        let is_directory = self.inner.is_dir(path);
+        self.inner.remove_link(path)?;
+        self.record(Undo::RestoreLink {
+            path: path.to_path_buf(),
+            target,
+            is_directory,
+        });
+        Ok(())
+    }
+
+    fn create_hard_link(&self, link_path: &Path, source_path: &Path) -> Result<()> {
+        self.create_parents(link_path)?;
+        self.inner.create_hard_link(link_path, source_path)?;
+        self.record(Undo::RemoveFile(link_path.to_path_buf()));
+        Ok(())
+    }
+
+    fn same_file(&self, a: &Path, b: &Path) -> bool {
+        self.inner.same_file(a, b)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        self.create_parents(path)?;
+        if self.inner.symlink_metadata(path).is_err() {
+            self.inner.create_dir_all(path)?;
+            self.record(Undo::RemoveDir(path.to_path_buf()));
+        }
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<()> {
+        if !self.inner.is_empty_dir(path)? && self.is_empty_dir(path)? {
+            // Only staged entries are left, they go to the staging area with the directory
+            self.staged.borrow_mut().retain(|p| !p.starts_with(path));
+            return self.stage(path);
+        }
+
+        self.inner.remove_dir(path)?;
+        self.record(Undo::CreateDir(path.to_path_buf()));
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        // Only the staging area is deleted, and only on commit
+        self.inner.symlink_metadata(path)?;
+        self.stage(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<()> {
+        self.inner.symlink_metadata(path)?;
+        self.stage(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        if self.inner.symlink_metadata(to).is_ok_and(|m| !m.is_dir()) {
+            // The destination file would be replaced
+            self.stage(to)?;
+        }
+        self.create_parents(to)?;
+        self.inner.rename(from, to)?;
+        self.record(Undo::Move {
+            from: to.to_path_buf(),
+            to: from.to_path_buf(),
+        });
+        Ok(())
+    }
+
+    fn copy_file(&self, from: &Path, to: &Path) -> Result<()> {
+        if self.inner.symlink_metadata(to).is_ok() {
+            // The destination file would be overwritten
+            self.stage(to)?;
+        }
+        self.create_parents(to)?;
+        self.inner.copy_file(from, to)?;
+        self.record(Undo::RemoveFile(to.to_path_buf()));
+        Ok(())
+    }
+
+    fn read_file(&self, path: &Path) -> Result<Vec<u8>> {
+        self.inner.read_file(path)
+    }
+
+    fn write_file(&self, path: &Path, contents: &[u8]) -> Result<()> {
+        if self.inner.symlink_metadata(path).is_ok() {
+            // The existing file would be replaced
+            self.stage(path)?;
+        }
+        self.create_parents(path)?;
+        self.inner.write_file(path, contents)?;
+        self.record(Undo::RemoveFile(path.to_path_buf()));
+        Ok(())
+    }
+
+    fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf> {
+        self.inner.canonicalize(path)
+    }
+
+    fn normalize_path(&self, path: &Path) -> Result<PathBuf> {
+        self.inner.normalize_path(path)
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Metadata> {
+        self.inner.metadata(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn is_symlink(&self, path: &Path) -> bool {
+        self.inner.is_symlink(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.inner.is_dir(path)
+    }
+
+    fn is_empty_dir(&self, path: &Path) -> Result<bool> {
+        if !self.inner.is_dir(path) {
+            return Ok(false);
+        }
+
+        // Entries removed through the journal are only staged until commit
+        let staged = self.staged.borrow();
+        Ok(self
+            .inner
+            .read_dir(path)?
+            .iter()
+            .all(|entry| staged.contains(entry)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memfs::MemoryFilesystem;
+
+    #[test]
+    fn test_rollback_removes_created_link_and_parents() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        fs.add_dir(root.join("target"));
+
+        let journal = Journal::new(&fs);
+        journal
+            .create_link(&root.join("target/a/b/link"), Path::new("../x"), false)
+            .unwrap();
+        assert!(fs.is_symlink(&root.join("target/a/b/link")));
+        assert_eq!(journal.len(), 3);
+
+        assert_eq!(journal.rollback(), 0);
+        assert!(!fs.exists(&root.join("target/a")));
+        assert!(fs.exists(&root.join("target")));
+    }
+
+    #[test]
+    fn test_rollback_restores_removed_entries() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        fs.add_file(root.join("target/file.txt"), "file");
+        fs.add_file(root.join("target/dir/nested.txt"), "nested");
+        fs.add_file(root.join("stow/pkg/real.txt"), "real");
+        fs.create_link(
+            &root.join("target/real.txt"),
+            Path::new("../stow/pkg/real.txt"),
+            false,
+        )
+        .unwrap();
+
+        let journal = Journal::new(&fs);
+        journal.remove_file(&root.join("target/file.txt")).unwrap();
+        journal.remove_dir_all(&root.join("target/dir")).unwrap();
+        journal.remove_link(&root.join("target/real.txt")).unwrap();
+        assert!(!fs.exists(&root.join("target/file.txt")));
+        assert!(!fs.exists(&root.join("target/dir")));
+
+        journal.rollback();
+        assert_eq!(
+            fs.file_content(root.join("target/file.txt")).unwrap(),
+            b"file"
+        );
+        assert_eq!(
+            fs.file_content(root.join("target/dir/nested.txt")).unwrap(),
+            b"nested"
+        );
+        assert_eq!(
+            fs.read_link(&root.join("target/real.txt")).unwrap(),
+            PathBuf::from("../stow/pkg/real.txt")
+        );
+        assert_eq!(fs.read_dir(&root.join("target")).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_rollback_restores_replaced_file() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        fs.add_file(root.join("target/file.txt"), "target");
+        fs.add_file(root.join("stow/pkg/file.txt"), "package");
+
+        let journal = Journal::new(&fs);
+        journal
+            .rename(
+                &root.join("target/file.txt"),
+                &root.join("stow/pkg/file.txt"),
+            )
+            .unwrap();
+        assert_eq!(
+            fs.file_content(root.join("stow/pkg/file.txt")).unwrap(),
+            b"target"
+        );
+
+        journal.rollback();
+        assert_eq!(
+            fs.file_content(root.join("target/file.txt")).unwrap(),
+            b"target"
+        );
+        assert_eq!(
+            fs.file_content(root.join("stow/pkg/file.txt")).unwrap(),
+            b"package"
+        );
+        assert_eq!(fs.read_dir(&root.join("stow/pkg")).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_commit_deletes_staging_area() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        fs.add_file(root.join("target/file.txt"), "file");
+        fs.add_file(root.join("target/dir/nested.txt"), "nested");
+
+        let journal = Journal::new(&fs);
+        journal.remove_file(&root.join("target/file.txt")).unwrap();
+        journal.remove_dir_all(&root.join("target/dir")).unwrap();
+        journal.commit();
+
+        assert!(fs.read_dir(&root.join("target")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_directory_holding_only_removed_files_can_be_removed() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        fs.add_file(root.join("target/dir/file.txt"), "file");
+
+        let journal = Journal::new(&fs);
+        journal
+            .remove_file(&root.join("target/dir/file.txt"))
+            .unwrap();
+        assert!(journal.is_empty_dir(&root.join("target/dir")).unwrap());
+        journal.remove_dir(&root.join("target/dir")).unwrap();
+        assert!(!fs.exists(&root.join("target/dir")));
+
+        journal.rollback();
+        assert_eq!(
+            fs.file_content(root.join("target/dir/file.txt")).unwrap(),
+            b"file"
+        );
+        assert_eq!(fs.read_dir(&root.join("target/dir")).unwrap().len(), 1);
+
+        let journal = Journal::new(&fs);
+        journal
+            .remove_file(&root.join("target/dir/file.txt"))
+            .unwrap();
+        journal.remove_dir(&root.join("target/dir")).unwrap();
+        journal.commit();
+        assert!(fs.read_dir(&root.join("target")).unwrap().is_empty());
+    }
+}
@@ -1,18 +1,32 @@
 mod adopt;
+mod backup;
 mod cli;
 mod config;
+mod diff;
+mod doctor;
 mod error;
+mod filesystem;
 mod fs_ops;
+mod history;
 mod ignore;
+mod interactive;
+mod journal;
 mod logger;
+#[cfg(test)]
+mod memfs;
+mod overlay;
 mod path_utils;
 mod planner;
+mod state;
+mod status;
 mod stow;
 mod unstow;
 
 use clap::Parser;
 use cli::{Action, Cli};
 use error::Result;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
 fn main() {
     // Parse command-line arguments
@@ -41,10 +55,56 @@ fn main() {
 }
 
 fn run(cli: Cli) -> Result<()> {
-    // Determine which action to perform (before moving cli fields)
-    let action = cli.action().map_err(error::StowError::invalid_path)?;
+    // GNU Stow style flags are mapped to the matching subcommand
+    let command = cli.command().map_err(error::StowError::invalid_path)?;
+
+    // Applying a plan doesn't use packages or the configuration file
+    if let cli::Command::Apply { plan } = &command {
+        return apply(plan, cli.dry_run);
+    }
 
-    // Load configuration file
+    let options = command.options();
+    let mut context = load_context(&options, cli.verbose, cli.dry_run)?;
+
+    logger::verbose(&format!("Stow directory: {}", context.stow_dir().display()));
+    logger::verbose(&format!(
+        "Target directory: {}",
+        context.target_dir().display()
+    ));
+
+    // Build pattern set from context
+    let patterns = context.build_pattern_set()?;
+
+    // Commands that link packages pick the link mode before planning
+    if matches!(
+        command,
+        cli::Command::Stow(_) | cli::Command::Restow(_) | cli::Command::Adopt(_)
+    ) {
+        context = choose_link_mode(context);
+    }
+
+    match &command {
+        cli::Command::Status(args) => status(&context, patterns, &args.packages),
+        cli::Command::List(args) => list(&context, patterns, args.tree, args.format),
+        cli::Command::Doctor(_) => doctor(&context, patterns),
+        cli::Command::Restore(args) => restore(&context, args.backup_id.as_deref()),
+        cli::Command::History(_) => history(&context),
+        cli::Command::Undo(args) => undo(&context, args.id),
+        cli::Command::Adopt(args) => adopt(&context, patterns, args, options.plan_out.as_deref()),
+        cli::Command::Eject(args) => eject(&context, &args.paths, options.plan_out.as_deref()),
+        _ => match command.action() {
+            Some(action) => run_action(&context, patterns, action, command.packages(), &options),
+            None => unreachable!("apply runs before the configuration is loaded"),
+        },
+    }
+}
+
+/// Load the configuration file and merge it with the command's options
+fn load_context(
+    options: &cli::Options,
+    verbose: bool,
+    dry_run: bool,
+) -> Result<config::StowContext> {
     let file_config = match config::Config::load() {
         Ok(config) => {
             logger::verbose("Loaded configuration file");
@@ -57,88 +117,513 @@ fn run(cli: Cli) -> Result<()> {
         }
     };
 
-    // Merge config file with CLI arguments to create complete runtime context
-    let context = file_config.merge_with_cli(
-        cli.stow_dir,
-        cli.target_dir,
-        cli.ignore,
-        cli.defer,
-        cli.verbose,
-        cli.dry_run,
-        cli.adopt,
-        cli.override_conflicts,
-    )?;
+    // CLI arguments take precedence over the configuration file
+    let options = options.clone();
+    file_config.merge_with_cli(config::CliOverrides {
+        dir: options.dirs.stow_dir,
+        target: options.dirs.target_dir,
+        ignore: options.patterns.ignore,
+        defer: options.patterns.defer,
+        verbose,
+        dry_run,
+        adopt: options.conflicts.adopt,
+        override_conflicts: options.conflicts.override_conflicts,
+        skip_conflicts: options.conflicts.skip_conflicts,
+        backup: options.conflicts.backup,
+        keep_both: options.conflicts.keep_both,
+        dotfiles: options.layout.dotfiles,
+        no_folding: options.layout.no_folding,
+        link_mode: options.link_mode,
+    })
+}
 
-    logger::verbose(&format!("Stow directory: {}", context.stow_dir().display()));
-    logger::verbose(&format!(
-        "Target directory: {}",
-        context.target_dir().display()
-    ));
+/// With a fallback chain, probe the target once and use the first link mode that works there
+/// Without one, or if no mode works, the link mode is used as is and fails when linking
+fn choose_link_mode(context: config::StowContext) -> config::StowContext {
+    if context.fallback().is_empty() {
+        return context;
+    }
 
-    if context.is_dry_run() {
+    let mut modes = vec![context.link_mode()];
+    modes.extend_from_slice(context.fallback());
+    let probed = doctor::probe_link_mode(
+        context.filesystem().as_ref(),
+        context.stow_dir(),
+        context.target_dir(),
+        &modes,
+    );
+
+    match probed {
+        Some(mode) if mode != context.link_mode() => {
+            logger::warn(&format!(
+                "Cannot create {} in the target, using {} instead",
+                context.link_mode().plural(),
+                mode.plural()
+            ));
+            context.with_link_mode(mode).with_fallback(&modes)
+        }
+        _ => context,
+    }
+}
+
+/// Plan and execute (or write out) a stow, unstow, restow or prune
+fn run_action(
+    context: &config::StowContext,
+    patterns: ignore::PatternSet,
+    action: Action,
+    packages: &[String],
+    options: &cli::Options,
+) -> Result<()> {
+    let plan_out = options.plan_out.as_deref();
+    if context.is_dry_run() && plan_out.is_none() {
         logger::info("=== DRY RUN MODE - No changes will be made ===");
     }
 
-    // Build pattern set from context
-    let patterns = context.build_pattern_set()?;
+    // All packages are planned into one combined plan before anything is executed
+    let fs = context.filesystem();
+    let overlay = Rc::new(overlay::OverlayFilesystem::new(Rc::clone(fs)));
+    let mut plan = planner::Plan::new();
+
+    let (operation, done, would) = match action {
+        Action::Stow => ("Stow", "Stowed", "Would stow"),
+        Action::Delete if options.materialize => ("Eject", "Ejected", "Would eject"),
+        Action::Delete => ("Unstow", "Unstowed", "Would unstow"),
+        Action::Restow => ("Restow", "Restowed", "Would restow"),
+        Action::PruneBroken => ("Prune", "Pruned", "Would prune"),
+    };
+    if packages.is_empty() {
+        logger::verbose(&format!("{}: all packages", operation));
+    } else {
+        logger::verbose(&format!("{}: {} package(s)", operation, packages.len()));
+    }
+    for package in packages {
+        logger::operation(operation, package);
+    }
 
     match action {
         Action::Stow => {
-            logger::verbose(&format!("Stowing {} package(s)", cli.packages.len()));
+            let stower = stow::Stower::from_context(context, patterns);
+            let stower = resolve_conflicts(stower, options, packages, &overlay)?;
+            stower.plan_packages(packages, &overlay, &mut plan)?;
+        }
 
-            let stower = stow::Stower::from_context(&context, patterns);
+        Action::Delete => {
+            let unstower =
+                unstow::Unstower::from_context(context).with_materialize(options.materialize);
+            unstower.plan_packages(packages, &overlay, &mut plan)?;
+        }
 
-            for package in &cli.packages {
-                logger::operation("Stow", package);
-                stower.stow_package(package)?;
-            }
+        Action::Restow => {
+            // Unstow first, then stow
+            let unstower = unstow::Unstower::from_context(context);
+            let stower = stow::Stower::from_context(context, patterns);
+            unstower.plan_packages(packages, &overlay, &mut plan)?;
+            let stower = resolve_conflicts(stower, options, packages, &overlay)?;
+            stower.plan_packages(packages, &overlay, &mut plan)?;
+        }
 
-            if !context.is_dry_run() {
-                logger::success(&format!("Stowed {} package(s)", cli.packages.len()));
-            } else {
-                logger::info(&format!("Would stow {} package(s)", cli.packages.len()));
-            }
+        Action::PruneBroken => {
+            let unstower = unstow::Unstower::from_context(context);
+            unstower.plan_prune_broken(packages, &overlay, &mut plan)?;
         }
+    }
 
-        Action::Delete => {
-            logger::verbose(&format!("Unstowing {} package(s)", cli.packages.len()));
+    if !finish_plan(context, &mut plan, plan_out)? {
+        return Ok(());
+    }
 
-            let unstower = unstow::Unstower::from_context(&context);
+    let summary = if action == Action::PruneBroken {
+        let removed = plan
+            .actions()
+            .filter(|action| matches!(action, planner::Action::RemoveLink { .. }))
+            .count();
+        format!("{} broken link(s)", removed)
+    } else {
+        format!("{} package(s)", packages.len())
+    };
 
-            for package in &cli.packages {
-                logger::operation("Unstow", package);
-                unstower.unstow_package(package)?;
-            }
+    if !context.is_dry_run() {
+        logger::success(&format!("{} {}", done, summary));
+    } else {
+        logger::info(&format!("{} {}", would, summary));
+    }
+
+    Ok(())
+}
+
+/// Write the plan to `plan_out`, or execute it; returns whether it was executed
+/// The state file, backup index and history are updated in the same transaction
+fn finish_plan(
+    context: &config::StowContext,
+    plan: &mut planner::Plan,
+    plan_out: Option<&Path>,
+) -> Result<bool> {
+    logger::verbose(&format!("Plan has {} actions", plan.len()));
+    plan.track_state(context.target_dir().join(state::STATE_FILE_NAME));
+    plan.track_backups(backup::index_path(context.target_dir()));
+    plan.track_history(history::index_path(context.target_dir()), command_line());
+    plan.set_fallback(context.fallback().to_vec());
+
+    if let Some(plan_out) = plan_out {
+        plan.save(plan_out)?;
+        logger::success(&format!(
+            "Wrote plan with {} action(s) to {}",
+            plan.len(),
+            plan_out.display()
+        ));
+        return Ok(false);
+    }
+
+    plan.execute(context.filesystem().as_ref(), context.is_dry_run())?;
+    Ok(true)
+}
+
+/// The command line winstow was run with, as recorded in the history
+fn command_line() -> String {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    format!("winstow {}", args.join(" "))
+}
+
+/// Move target paths into a package, creating it if needed, and link them back
+fn adopt(
+    context: &config::StowContext,
+    patterns: ignore::PatternSet,
+    args: &cli::AdoptArgs,
+    plan_out: Option<&Path>,
+) -> Result<()> {
+    if context.is_dry_run() && plan_out.is_none() {
+        logger::info("=== DRY RUN MODE - No changes will be made ===");
+    }
+    logger::operation("Adopt", &args.package);
+
+    let paths = absolute_paths(&args.paths)?;
+
+    let overlay = Rc::new(overlay::OverlayFilesystem::new(Rc::clone(
+        context.filesystem(),
+    )));
+    let mut plan = planner::Plan::new();
+    let stower = stow::Stower::from_context(context, patterns);
+    stower.plan_adopt_paths(&args.package, &paths, &overlay, &mut plan)?;
+
+    if !finish_plan(context, &mut plan, plan_out)? {
+        return Ok(());
+    }
+
+    if !context.is_dry_run() {
+        logger::success(&format!(
+            "Adopted {} path(s) into {}",
+            paths.len(),
+            args.package
+        ));
+    } else {
+        logger::info(&format!(
+            "Would adopt {} path(s) into {}",
+            paths.len(),
+            args.package
+        ));
+    }
+    Ok(())
+}
+
+/// Replace links into the stow directory with real copies of the package files
+fn eject(context: &config::StowContext, paths: &[PathBuf], plan_out: Option<&Path>) -> Result<()> {
+    if context.is_dry_run() && plan_out.is_none() {
+        logger::info("=== DRY RUN MODE - No changes will be made ===");
+    }
+
+    let paths = absolute_paths(paths)?;
+    let overlay = Rc::new(overlay::OverlayFilesystem::new(Rc::clone(
+        context.filesystem(),
+    )));
+    let mut plan = planner::Plan::new();
+    let unstower = unstow::Unstower::from_context(context);
+    unstower.plan_eject(&paths, &overlay, &mut plan)?;
+
+    if !finish_plan(context, &mut plan, plan_out)? {
+        return Ok(());
+    }
+
+    let ejected = plan
+        .actions()
+        .filter(|action| matches!(action, planner::Action::MaterializeLink { .. }))
+        .count();
+    if !context.is_dry_run() {
+        logger::success(&format!("Ejected {} link(s)", ejected));
+    } else {
+        logger::info(&format!("Would eject {} link(s)", ejected));
+    }
+    Ok(())
+}
+
+/// Make paths given on the command line absolute
+/// Relative paths are taken from the current directory, links are not resolved
+fn absolute_paths(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    paths
+        .iter()
+        .map(|path| {
+            std::path::absolute(path).map_err(|e| error::StowError::io_error(path.clone(), e))
+        })
+        .collect()
+}
+
+/// With --interactive, ask how to resolve the conflicts stowing the packages would run into
+fn resolve_conflicts(
+    stower: stow::Stower,
+    options: &cli::Options,
+    packages: &[String],
+    overlay: &Rc<overlay::OverlayFilesystem>,
+) -> Result<stow::Stower> {
+    if !options.conflicts.interactive {
+        return Ok(stower);
+    }
 
-            if !context.is_dry_run() {
-                logger::success(&format!("Unstowed {} package(s)", cli.packages.len()));
-            } else {
-                logger::info(&format!("Would unstow {} package(s)", cli.packages.len()));
+    let conflicts = stower.find_conflicts(packages, overlay)?;
+    if conflicts.is_empty() {
+        return Ok(stower);
+    }
+
+    let mut wizard =
+        interactive::Wizard::new(overlay.as_ref(), std::io::stdin().lock(), std::io::stdout());
+    let resolutions = wizard.resolve(&conflicts)?;
+    Ok(stower.with_resolutions(resolutions))
+}
+
+/// Report the installation state of packages without changing anything
+fn status(
+    context: &config::StowContext,
+    patterns: ignore::PatternSet,
+    packages: &[String],
+) -> Result<()> {
+    let packages = if packages.is_empty() {
+        status::list_packages(context.filesystem().as_ref(), context.stow_dir(), &patterns)?
+    } else {
+        packages.to_vec()
+    };
+
+    let stower = stow::Stower::from_context(context, patterns);
+    let recorded = state::load_target(context.filesystem().as_ref(), context.target_dir())?;
+    let relative = |path: &Path| {
+        path_utils::strip_base(path, context.target_dir()).unwrap_or_else(|| path.to_path_buf())
+    };
+
+    for package in &packages {
+        let status = stower.status(package)?;
+        logger::info(&format!(
+            "{}: {} ({} linked, {} missing)",
+            status.name,
+            status.state(),
+            status.linked.len(),
+            status.missing.len()
+        ));
+
+        for path in &status.missing {
+            logger::info(&format!("  missing link: {}", relative(path).display()));
+        }
+        for conflict in &status.foreign_links {
+            logger::info(&format!(
+                "  link elsewhere: {} ({})",
+                relative(&conflict.path).display(),
+                conflict.kind
+            ));
+        }
+        for conflict in &status.shadowed {
+            logger::info(&format!(
+                "  shadowed by {}: {}",
+                conflict.kind,
+                relative(&conflict.path).display()
+            ));
+        }
+        for path in &status.deferred {
+            logger::info(&format!("  deferred: {}", relative(path).display()));
+        }
+        for path in &status.linked {
+            match recorded.link_mode(path) {
+                Some(mode) if !mode.is_symlink() => logger::verbose(&format!(
+                    "  linked: {} ({})",
+                    relative(path).display(),
+                    mode
+                )),
+                _ => logger::verbose(&format!("  linked: {}", relative(path).display())),
             }
         }
+    }
 
-        Action::Restow => {
-            logger::verbose(&format!("Restowing {} package(s)", cli.packages.len()));
+    Ok(())
+}
 
-            let unstower = unstow::Unstower::from_context(&context);
-            let stower = stow::Stower::from_context(&context, patterns);
+/// List the packages in the stow directory with their footprint and state
+fn list(
+    context: &config::StowContext,
+    patterns: ignore::PatternSet,
+    tree: bool,
+    format: cli::OutputFormat,
+) -> Result<()> {
+    let names =
+        status::list_packages(context.filesystem().as_ref(), context.stow_dir(), &patterns)?;
+    let stower = stow::Stower::from_context(context, patterns);
+
+    let mut packages = Vec::new();
+    for name in &names {
+        let files = stower.package_files(name)?;
+        let status = stower.status(name)?;
+        packages.push(status::PackageInfo::new(&status, &files, tree));
+    }
 
-            for package in &cli.packages {
-                logger::operation("Restow", package);
-                // Unstow first
-                unstower.unstow_package(package)?;
-                // Then stow
-                stower.stow_package(package)?;
-            }
+    if format == cli::OutputFormat::Json {
+        logger::info(&status::to_json(&packages));
+        return Ok(());
+    }
 
-            if !context.is_dry_run() {
-                logger::success(&format!("Restowed {} package(s)", cli.packages.len()));
-            } else {
-                logger::info(&format!("Would restow {} package(s)", cli.packages.len()));
+    for package in &packages {
+        logger::info(&format!(
+            "{}: {} file(s), {}",
+            package.name, package.files, package.state
+        ));
+        for path in package.tree.iter().flatten() {
+            logger::info(&format!("  {}", path));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check the setup for problems and report every finding
+fn doctor(context: &config::StowContext, patterns: ignore::PatternSet) -> Result<()> {
+    let outcomes = doctor::diagnose(context, &patterns);
+
+    let mut failed = 0;
+    for outcome in &outcomes {
+        match outcome {
+            doctor::Outcome::Passed(message) => logger::success(message),
+            doctor::Outcome::Warning(message) => logger::warn(message),
+            doctor::Outcome::Failed(message) => {
+                logger::error(message);
+                failed += 1;
             }
         }
     }
 
+    if failed > 0 {
+        return Err(error::StowError::checks_failed(failed));
+    }
+    Ok(())
+}
+
+/// Put the files of a backup back, or list the backups if no id is given
+fn restore(context: &config::StowContext, backup_id: Option<&str>) -> Result<()> {
+    let fs = context.filesystem();
+    let index_path = backup::index_path(context.target_dir());
+
+    let Some(backup_id) = backup_id else {
+        let index = backup::BackupIndex::load(fs.as_ref(), &index_path)?;
+        if index.backups.is_empty() {
+            logger::info("No backups");
+        }
+        for (id, backup) in &index.backups {
+            logger::info(&format!(
+                "{}: {} path(s) backed up for {}",
+                id,
+                backup.entries.len(),
+                backup.packages().join(", ")
+            ));
+        }
+        return Ok(());
+    };
+
+    if context.is_dry_run() {
+        logger::info("=== DRY RUN MODE - No changes will be made ===");
+    }
+    logger::operation("Restore", backup_id);
+
+    let overlay = Rc::new(overlay::OverlayFilesystem::new(Rc::clone(fs)));
+    let mut plan = planner::Plan::new();
+    backup::plan_restore(context, backup_id, &overlay, &mut plan)?;
+    plan.track_state(context.target_dir().join(state::STATE_FILE_NAME));
+    plan.track_backups(index_path);
+    plan.track_history(history::index_path(context.target_dir()), command_line());
+    plan.set_fallback(context.fallback().to_vec());
+    plan.execute(fs.as_ref(), context.is_dry_run())?;
+
+    if !context.is_dry_run() {
+        logger::success(&format!("Restored backup {}", backup_id));
+    } else {
+        logger::info(&format!("Would restore backup {}", backup_id));
+    }
+    Ok(())
+}
+
+/// List the recorded operations, oldest first
+fn history(context: &config::StowContext) -> Result<()> {
+    let index_path = history::index_path(context.target_dir());
+    let history = history::History::load(context.filesystem().as_ref(), &index_path)?;
+    if history.operations.is_empty() {
+        logger::info("No operations recorded");
+    }
+
+    let now = state::now();
+    for operation in &history.operations {
+        logger::info(&format!(
+            "{}: {} ({}, {} action(s)){}",
+            operation.id,
+            operation.command,
+            history::describe_age(operation.time, now),
+            operation.actions.len(),
+            if operation.undone { " [undone]" } else { "" }
+        ));
+    }
+    Ok(())
+}
+
+/// Reverse a recorded operation, the latest one not undone yet if no id is given
+fn undo(context: &config::StowContext, id: Option<u64>) -> Result<()> {
+    if context.is_dry_run() {
+        logger::info("=== DRY RUN MODE - No changes will be made ===");
+    }
+
+    let fs = context.filesystem();
+    let index_path = history::index_path(context.target_dir());
+    let mut plan = planner::Plan::new();
+    let id = history::plan_undo(fs.as_ref(), &index_path, id, &mut plan)?;
+    logger::operation("Undo", &id.to_string());
+
+    plan.track_state(context.target_dir().join(state::STATE_FILE_NAME));
+    plan.track_backups(backup::index_path(context.target_dir()));
+    plan.track_history(index_path, command_line());
+    plan.set_fallback(context.fallback().to_vec());
+    plan.execute(fs.as_ref(), context.is_dry_run())?;
+
+    if !context.is_dry_run() {
+        logger::success(&format!("Undid operation {}", id));
+    } else {
+        logger::info(&format!("Would undo operation {}", id));
+    }
+    Ok(())
+}
+
+/// Execute a previously written plan after checking its preconditions
+fn apply(plan_file: &Path, dry_run: bool) -> Result<()> {
+    let plan = planner::Plan::load(plan_file)?;
+    logger::verbose(&format!(
+        "Loaded plan with {} actions from {}",
+        plan.len(),
+        plan_file.display()
+    ));
+
+    if dry_run {
+        logger::info("=== DRY RUN MODE - No changes will be made ===");
+    }
+
+    // Nothing is executed unless every action still finds the state it was planned for
+    let fs: Rc<dyn filesystem::Filesystem> = Rc::new(filesystem::OsFilesystem);
+    plan.verify(Rc::clone(&fs))?;
+    plan.execute(fs.as_ref(), dry_run)?;
+
+    if !dry_run {
+        logger::success(&format!("Applied {} action(s)", plan.len()));
+    } else {
+        logger::info(&format!("Would apply {} action(s)", plan.len()));
+    }
+
     Ok(())
 }
 
@@ -161,20 +646,15 @@ mod tests {
         fs::create_dir(&package_dir).unwrap();
         fs::File::create(package_dir.join("file.txt")).unwrap();
 
-        let cli = Cli {
-            stow: false,
-            delete: false,
-            restow: false,
-            stow_dir: Some(stow_dir),
-            target_dir: Some(target_dir),
-            verbose: false,
-            dry_run: true, // Dry run
-            adopt: false,
-            override_conflicts: false,
-            ignore: vec![],
-            defer: vec![],
-            packages: vec!["test".to_string()],
-        };
+        let cli = Cli::parse_from([
+            "winstow",
+            "-d",
+            stow_dir.to_str().unwrap(),
+            "-t",
+            target_dir.to_str().unwrap(),
+            "-n", // Dry run
+            "test",
+        ]);
 
         // Default action should be stow
         let result = run(cli);
@@ -194,20 +674,16 @@ mod tests {
         fs::create_dir(&package_dir).unwrap();
         fs::File::create(package_dir.join("file.txt")).unwrap();
 
-        let cli = Cli {
-            stow: false,
-            delete: true,
-            restow: false,
-            stow_dir: Some(stow_dir),
-            target_dir: Some(target_dir),
-            verbose: false,
-            dry_run: true, // Dry run
-            adopt: false,
-            override_conflicts: false,
-            ignore: vec![],
-            defer: vec![],
-            packages: vec!["test".to_string()],
-        };
+        let cli = Cli::parse_from([
+            "winstow",
+            "-D",
+            "-d",
+            stow_dir.to_str().unwrap(),
+            "-t",
+            target_dir.to_str().unwrap(),
+            "-n", // Dry run
+            "test",
+        ]);
 
         let result = run(cli);
         assert!(result.is_ok());
@@ -226,22 +702,57 @@ mod tests {
         fs::create_dir(&package_dir).unwrap();
         fs::File::create(package_dir.join("file.txt")).unwrap();
 
-        let cli = Cli {
-            stow: false,
-            delete: false,
-            restow: true,
-            stow_dir: Some(stow_dir),
-            target_dir: Some(target_dir),
-            verbose: false,
-            dry_run: true, // Dry run
-            adopt: false,
-            override_conflicts: false,
-            ignore: vec![],
-            defer: vec![],
-            packages: vec!["test".to_string()],
-        };
+        let cli = Cli::parse_from([
+            "winstow",
+            "-R",
+            "-d",
+            stow_dir.to_str().unwrap(),
+            "-t",
+            target_dir.to_str().unwrap(),
+            "-n", // Dry run
+            "test",
+        ]);
 
         let result = run(cli);
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_run_plan_out_then_apply() {
+        let temp_dir = TempDir::new().unwrap();
+        let stow_dir = temp_dir.path().join("stow");
+        let target_dir = temp_dir.path().join("target");
+        let plan_file = temp_dir.path().join("plan.json");
+        fs::create_dir(&stow_dir).unwrap();
+        fs::create_dir(&target_dir).unwrap();
+
+        // Create a test package
+        let package_dir = stow_dir.join("test");
+        fs::create_dir(&package_dir).unwrap();
+        fs::File::create(package_dir.join("file.txt")).unwrap();
+
+        let cli = Cli::parse_from([
+            "winstow",
+            "-d",
+            stow_dir.to_str().unwrap(),
+            "-t",
+            target_dir.to_str().unwrap(),
+            "--plan-out",
+            plan_file.to_str().unwrap(),
+            "test",
+        ]);
+        run(cli).unwrap();
+
+        // Writing the plan leaves the target untouched
+        assert!(plan_file.exists());
+        assert!(!target_dir.join("file.txt").exists());
+
+        let cli = Cli::parse_from(["winstow", "apply", plan_file.to_str().unwrap()]);
+        run(cli).unwrap();
+        assert!(fs_ops::is_symlink(target_dir.join("file.txt")));
+        assert_eq!(
+            fs::canonicalize(target_dir.join("file.txt")).unwrap(),
+            fs::canonicalize(package_dir.join("file.txt")).unwrap()
+        );
+    }
 }
@@ -0,0 +1,637 @@
+use crate::error::{Result, StowError};
+use crate::filesystem::{self, FileKind, Filesystem, Metadata};
+use crate::fs_ops::LinkMode;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+/// A node stored in the in-memory filesystem
+/// Hard links of a file share its content
+#[derive(Debug, Clone)]
+enum Node {
+    File(Rc<Vec<u8>>),
+    Dir,
+    Symlink { target: PathBuf },
+}
+
+impl Node {
+    fn kind(&self) -> FileKind {
+        match self {
+            Node::File(_) => FileKind::File,
+            Node::Dir => FileKind::Dir,
+            Node::Symlink { .. } => FileKind::Symlink,
+        }
+    }
+}
+
+/// An entry of the in-memory filesystem, keyed by its lookup key
+#[derive(Debug, Clone)]
+struct Entry {
+    /// Full path with the case it was created with
+    path: PathBuf,
+    node: Node,
+}
+
+/// In-memory filesystem for simulating stow, unstow and adopt flows
+///
+/// Models files, directories and symlinks (including directory links and
+/// relative link targets). Lookups are case-insensitive by default, like NTFS.
+/// All absolute paths share a single root, regardless of drive prefix.
+#[derive(Debug)]
+pub struct MemoryFilesystem {
+    entries: RefCell<BTreeMap<String, Entry>>,
+    case_insensitive: bool,
+    /// Link modes that fail as if they weren't permitted
+    denied: RefCell<Vec<LinkMode>>,
+    /// Directories listed with `read_dir`, in order
+    dirs_read: RefCell<Vec<PathBuf>>,
+}
+
+impl Default for MemoryFilesystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryFilesystem {
+    /// Create an empty, case-insensitive filesystem
+    pub fn new() -> Self {
+        Self {
+            entries: RefCell::new(BTreeMap::new()),
+            case_insensitive: true,
+            denied: RefCell::new(Vec::new()),
+            dirs_read: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Create an empty, case-sensitive filesystem
+    pub fn case_sensitive() -> Self {
+        Self {
+            case_insensitive: false,
+            ..Self::new()
+        }
+    }
+
+    /// The root directory used for paths in this filesystem
+    pub fn root() -> PathBuf {
+        if cfg!(windows) {
+            PathBuf::from("C:\\")
+        } else {
+            PathBuf::from("/")
+        }
+    }
+
+    /// Make creating symlinks or hard links fail as if they weren't permitted
+    pub fn deny_links(&self, mode: LinkMode) {
+        self.denied.borrow_mut().push(mode);
+    }
+
+    /// Directories listed with `read_dir` so far, in order
+    pub fn dirs_read(&self) -> Vec<PathBuf> {
+        self.dirs_read.borrow().clone()
+    }
+
+    /// Create a file with the given content, creating missing parents
+    pub fn add_file(&self, path: impl AsRef<Path>, content: impl Into<Vec<u8>>) {
+        let path = path.as_ref();
+        self.insert_with_parents(path, Node::File(Rc::new(content.into())))
+            .expect("failed to add file");
+    }
+
+    /// Create a directory, creating missing parents
+    pub fn add_dir(&self, path: impl AsRef<Path>) {
+        self.create_dir_all(path.as_ref())
+            .expect("failed to add directory");
+    }
+
+    /// Get the content of a file (following symlinks)
+    pub fn file_content(&self, path: impl AsRef<Path>) -> Option<Vec<u8>> {
+        let resolved = self.canonicalize(path.as_ref()).ok()?;
+        match self.get(&resolved)?.node {
+            Node::File(content) => Some(content.to_vec()),
+            _ => None,
+        }
+    }
+
+    /// Compute the lookup key of a path whose parent is already resolved
+    fn key(&self, path: &Path) -> String {
+        let names: Vec<String> = path
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => {
+                    let name = name.to_string_lossy();
+                    Some(if self.case_insensitive {
+                        name.to_lowercase()
+                    } else {
+                        name.into_owned()
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+        names.join("/")
+    }
+
+    /// Resolve the parent directory of a path, leaving the final component unresolved
+    fn locate(&self, path: &Path) -> Result<PathBuf> {
+        if !path.is_absolute() {
+            return Err(StowError::invalid_path(format!(
+                "In-memory filesystem requires absolute paths: {}",
+                path.display()
+            )));
+        }
+
+        let located = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => self.canonicalize(parent).map(|p| p.join(name)),
+            _ => self.canonicalize(path),
+        };
+
+        located.map_err(|e| StowError::io_error(path.to_path_buf(), e))
+    }
+
+    /// Look up an entry by a path whose parent is already resolved
+    fn get(&self, located: &Path) -> Option<Entry> {
+        let key = self.key(located);
+        if key.is_empty() {
+            return Some(Entry {
+                path: located.to_path_buf(),
+                node: Node::Dir,
+            });
+        }
+
+        self.entries.borrow().get(&key).cloned()
+    }
+
+    /// Keys of all entries inside the directory with the given key
+    fn descendant_keys(&self, key: &str) -> Vec<String> {
+        let prefix = if key.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", key)
+        };
+
+        self.entries
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    /// Insert a new entry, failing if the path already exists
+    fn insert(&self, path: &Path, node: Node) -> Result<()> {
+        let located = self.locate(path)?;
+        let parent = located.parent().unwrap_or(&located);
+        if !matches!(self.get(parent).map(|e| e.node), Some(Node::Dir)) {
+            return Err(not_found(parent));
+        }
+
+        if self.get(&located).is_some() {
+            return Err(StowError::io_error(
+                path.to_path_buf(),
+                std::io::Error::from(ErrorKind::AlreadyExists),
+            ));
+        }
+
+        let key = self.key(&located);
+        self.entries.borrow_mut().insert(
+            key,
+            Entry {
+                path: located,
+                node,
+            },
+        );
+        Ok(())
+    }
+
+    /// Insert a new entry, creating missing parent directories first
+    fn insert_with_parents(&self, path: &Path, node: Node) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+        self.insert(path, node)
+    }
+
+    /// Look up an entry that must exist
+    fn existing(&self, path: &Path) -> Result<(PathBuf, Entry)> {
+        let located = self.locate(path)?;
+        let entry = self.get(&located).ok_or_else(|| not_found(path))?;
+        Ok((located, entry))
+    }
+}
+
+impl Filesystem for MemoryFilesystem {
+    fn symlink_metadata(&self, path: &Path) -> Result<Metadata> {
+        let (_, entry) = self.existing(path)?;
+        Ok(Metadata::new(entry.node.kind()))
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let resolved = self
+            .canonicalize(path)
+            .map_err(|e| StowError::io_error(path.to_path_buf(), e))?;
+        match self.get(&resolved).map(|e| e.node) {
+            Some(Node::Dir) => {}
+            _ => return Err(not_a_directory(path)),
+        }
+        self.dirs_read.borrow_mut().push(path.to_path_buf());
+
+        let key = self.key(&resolved);
+        let entries = self.entries.borrow();
+        let mut children: Vec<PathBuf> = self
+            .descendant_keys(&key)
+            .iter()
+            .filter(|k| !k[key.len()..].trim_start_matches('/').contains('/'))
+            .filter_map(|k| entries.get(k))
+            .filter_map(|e| e.path.file_name().map(|name| path.join(name)))
+            .collect();
+
+        children.sort();
+        Ok(children)
+    }
+
+    fn read_link(&self, path: &Path) -> Result<PathBuf> {
+        match self.existing(path)?.1.node {
+            Node::Symlink { target } => Ok(target),
+            _ => Err(StowError::io_error(
+                path.to_path_buf(),
+                std::io::Error::from(ErrorKind::InvalidInput),
+            )),
+        }
+    }
+
+    fn create_link(&self, link_path: &Path, target_path: &Path, _is_directory: bool) -> Result<()> {
+        if self.denied.borrow().contains(&LinkMode::Symlink) {
+            return Err(StowError::permission_denied(format!(
+                "Cannot create symlink at {}",
+                link_path.display()
+            )));
+        }
+        self.insert_with_parents(
+            link_path,
+            Node::Symlink {
+                target: target_path.to_path_buf(),
+            },
+        )
+    }
+
+    fn remove_link(&self, path: &Path) -> Result<()> {
+        let (located, entry) = self.existing(path)?;
+        if !matches!(entry.node, Node::Symlink { .. }) {
+            return Err(StowError::symlink_error(
+                path.to_path_buf(),
+                "Not a symbolic link",
+            ));
+        }
+
+        self.entries.borrow_mut().remove(&self.key(&located));
+        Ok(())
+    }
+
+    fn create_hard_link(&self, link_path: &Path, source_path: &Path) -> Result<()> {
+        if self.denied.borrow().contains(&LinkMode::Hardlink) {
+            return Err(StowError::io_error(
+                link_path.to_path_buf(),
+                std::io::Error::from(ErrorKind::CrossesDevices),
+            ));
+        }
+        let content = match self.existing(source_path)?.1.node {
+            Node::File(content) => content,
+            _ => {
+                return Err(StowError::io_error(
+                    source_path.to_path_buf(),
+                    std::io::Error::from(ErrorKind::InvalidInput),
+                ));
+            }
+        };
+
+        self.insert_with_parents(link_path, Node::File(content))
+    }
+
+    fn same_file(&self, a: &Path, b: &Path) -> bool {
+        let node = |path: &Path| {
+            let resolved = self.canonicalize(path).ok()?;
+            self.get(&resolved).map(|e| e.node)
+        };
+
+        match (node(a), node(b)) {
+            (Some(Node::File(a)), Some(Node::File(b))) => Rc::ptr_eq(&a, &b),
+            _ => false,
+        }
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        if self.is_dir(path) {
+            return Ok(());
+        }
+
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+
+        self.insert(path, Node::Dir)
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<()> {
+        let (located, entry) = self.existing(path)?;
+        if !matches!(entry.node, Node::Dir) {
+            return Err(not_a_directory(path));
+        }
+
+        let key = self.key(&located);
+        if !self.descendant_keys(&key).is_empty() {
+            return Err(StowError::directory_not_empty(path.to_path_buf()));
+        }
+
+        self.entries.borrow_mut().remove(&key);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        let (located, entry) = self.existing(path)?;
+        if matches!(entry.node, Node::Dir) {
+            return Err(StowError::io_error(
+                path.to_path_buf(),
+                std::io::Error::from(ErrorKind::IsADirectory),
+            ));
+        }
+
+        self.entries.borrow_mut().remove(&self.key(&located));
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<()> {
+        let (located, entry) = self.existing(path)?;
+        if !matches!(entry.node, Node::Dir) {
+            return Err(not_a_directory(path));
+        }
+
+        let key = self.key(&located);
+        let descendants = self.descendant_keys(&key);
+        let mut entries = self.entries.borrow_mut();
+        for descendant in descendants {
+            entries.remove(&descendant);
+        }
+        entries.remove(&key);
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let (from_located, _) = self.existing(from)?;
+        if let Some(parent) = to.parent() {
+            self.create_dir_all(parent)?;
+        }
+        let to_located = self.locate(to)?;
+
+        if let Some(existing) = self.get(&to_located) {
+            // Like the OS, only replace files, never directories
+            if matches!(existing.node, Node::Dir) {
+                return Err(StowError::io_error(
+                    to.to_path_buf(),
+                    std::io::Error::from(ErrorKind::AlreadyExists),
+                ));
+            }
+            self.entries.borrow_mut().remove(&self.key(&to_located));
+        }
+
+        let from_key = self.key(&from_located);
+        let mut keys = self.descendant_keys(&from_key);
+        keys.push(from_key);
+
+        let mut entries = self.entries.borrow_mut();
+        for key in keys {
+            let entry = entries
+                .remove(&key)
+                .expect("entry disappeared during rename");
+            let relative = entry
+                .path
+                .strip_prefix(&from_located)
+                .map(Path::to_path_buf)
+                .unwrap_or_default();
+            let new_path = if relative.as_os_str().is_empty() {
+                to_located.clone()
+            } else {
+                to_located.join(relative)
+            };
+            entries.insert(
+                self.key(&new_path),
+                Entry {
+                    path: new_path,
+                    node: entry.node,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    fn copy_file(&self, from: &Path, to: &Path) -> Result<()> {
+        let content = self.file_content(from).ok_or_else(|| not_found(from))?;
+        if let Some(parent) = to.parent() {
+            self.create_dir_all(parent)?;
+        }
+
+        let to_located = self.locate(to)?;
+        if let Some(existing) = self.get(&to_located) {
+            if matches!(existing.node, Node::Dir) {
+                return Err(not_a_directory(to));
+            }
+            self.entries.borrow_mut().remove(&self.key(&to_located));
+        }
+
+        self.insert(to, Node::File(Rc::new(content)))
+    }
+
+    fn read_file(&self, path: &Path) -> Result<Vec<u8>> {
+        self.file_content(path).ok_or_else(|| not_found(path))
+    }
+
+    fn write_file(&self, path: &Path, contents: &[u8]) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+
+        let located = self.locate(path)?;
+        if let Some(existing) = self.get(&located) {
+            if matches!(existing.node, Node::Dir) {
+                return Err(not_a_directory(path));
+            }
+            self.entries.borrow_mut().remove(&self.key(&located));
+        }
+
+        self.insert(path, Node::File(Rc::new(contents.to_vec())))
+    }
+
+    fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf> {
+        let resolved = filesystem::resolve_symlinks(self, path)?;
+
+        // Report the stored case of every component, like the OS does
+        Ok(match self.get(&resolved) {
+            Some(entry) if !self.key(&resolved).is_empty() => entry.path,
+            _ => resolved,
+        })
+    }
+}
+
+fn not_found(path: &Path) -> StowError {
+    StowError::io_error(
+        path.to_path_buf(),
+        std::io::Error::from(ErrorKind::NotFound),
+    )
+}
+
+fn not_a_directory(path: &Path) -> StowError {
+    StowError::io_error(
+        path.to_path_buf(),
+        std::io::Error::from(ErrorKind::NotADirectory),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn root() -> PathBuf {
+        MemoryFilesystem::root()
+    }
+
+    #[test]
+    fn test_add_and_read_file() {
+        let fs = MemoryFilesystem::new();
+        let file = root().join("dir").join("file.txt");
+        fs.add_file(&file, "content");
+
+        assert!(fs.exists(&file));
+        assert!(fs.is_dir(&root().join("dir")));
+        assert_eq!(fs.file_content(&file).unwrap(), b"content");
+    }
+
+    #[test]
+    fn test_read_dir_sorted() {
+        let fs = MemoryFilesystem::new();
+        let dir = root().join("dir");
+        fs.add_file(dir.join("b.txt"), "");
+        fs.add_file(dir.join("a.txt"), "");
+        fs.add_file(dir.join("sub").join("c.txt"), "");
+
+        let entries = fs.read_dir(&dir).unwrap();
+        assert_eq!(
+            entries,
+            vec![dir.join("a.txt"), dir.join("b.txt"), dir.join("sub")]
+        );
+    }
+
+    #[test]
+    fn test_case_insensitive_lookup() {
+        let fs = MemoryFilesystem::new();
+        fs.add_file(root().join("Dir").join("File.txt"), "x");
+
+        let lower = root().join("dir").join("file.txt");
+        assert!(fs.exists(&lower));
+        // Canonical paths keep the stored case
+        assert_eq!(
+            fs.canonicalize(&lower).unwrap(),
+            root().join("Dir").join("File.txt")
+        );
+    }
+
+    #[test]
+    fn test_case_sensitive_lookup() {
+        let fs = MemoryFilesystem::case_sensitive();
+        fs.add_file(root().join("Dir").join("File.txt"), "x");
+
+        assert!(!fs.exists(&root().join("dir").join("file.txt")));
+    }
+
+    #[test]
+    fn test_relative_directory_link() {
+        let fs = MemoryFilesystem::new();
+        fs.add_file(root().join("stow").join("pkg").join("app").join("cfg"), "x");
+        let link = root().join("target").join("app");
+        fs.create_link(&link, Path::new("../stow/pkg/app"), true)
+            .unwrap();
+
+        assert!(fs.is_symlink(&link));
+        assert!(fs.is_dir(&link));
+        assert!(fs.exists(&link.join("cfg")));
+        assert_eq!(
+            fs.resolve_link(&link).unwrap(),
+            root().join("stow").join("pkg").join("app")
+        );
+        assert_eq!(fs.read_dir(&link).unwrap(), vec![link.join("cfg")]);
+    }
+
+    #[test]
+    fn test_dangling_link() {
+        let fs = MemoryFilesystem::new();
+        let link = root().join("link");
+        fs.create_link(&link, Path::new("missing"), false).unwrap();
+
+        assert!(fs.is_symlink(&link));
+        assert!(!fs.exists(&link));
+    }
+
+    #[test]
+    fn test_remove_dir_requires_empty() {
+        let fs = MemoryFilesystem::new();
+        let dir = root().join("dir");
+        fs.add_file(dir.join("file.txt"), "");
+
+        assert!(matches!(
+            fs.remove_dir(&dir),
+            Err(StowError::DirectoryNotEmpty(_))
+        ));
+        fs.remove_file(&dir.join("file.txt")).unwrap();
+        assert!(fs.remove_dir(&dir).is_ok());
+        assert!(!fs.exists(&dir));
+    }
+
+    #[test]
+    fn test_rename_directory() {
+        let fs = MemoryFilesystem::new();
+        let from = root().join("from");
+        fs.add_file(from.join("sub").join("file.txt"), "x");
+
+        let to = root().join("new").join("to");
+        fs.rename(&from, &to).unwrap();
+
+        assert!(!fs.exists(&from));
+        assert_eq!(
+            fs.file_content(to.join("sub").join("file.txt")).unwrap(),
+            b"x"
+        );
+    }
+
+    #[test]
+    fn test_remove_link_rejects_files() {
+        let fs = MemoryFilesystem::new();
+        let file = root().join("file.txt");
+        fs.add_file(&file, "");
+
+        assert!(fs.remove_link(&file).is_err());
+        assert!(fs.exists(&file));
+    }
+
+    #[test]
+    fn test_hard_link_shares_file() {
+        let fs = MemoryFilesystem::new();
+        let source = root().join("source.txt");
+        let copy = root().join("copy.txt");
+        fs.add_file(&source, "x");
+        fs.copy_file(&source, &copy).unwrap();
+
+        let link = root().join("dir").join("link.txt");
+        fs.create_hard_link(&link, &source).unwrap();
+
+        assert!(!fs.is_symlink(&link));
+        assert_eq!(fs.file_content(&link).unwrap(), b"x");
+        assert!(fs.same_file(&link, &source));
+        assert!(!fs.same_file(&copy, &source));
+
+        // Removing the source keeps the linked file
+        fs.remove_file(&source).unwrap();
+        assert_eq!(fs.file_content(&link).unwrap(), b"x");
+    }
+}
@@ -0,0 +1,597 @@
+use crate::adopt::{self, Adoption};
+use crate::error::{Result, StowError};
+use crate::filesystem::{self, FileKind, Filesystem, Metadata};
+use crate::planner::{self, Action};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// An entry changed in the overlay
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    /// A regular file
+    File,
+    /// A directory created in the overlay, hiding anything below it in the base
+    Dir,
+    /// A symbolic link
+    Link(PathBuf),
+    /// A directory moved here from a path in the base
+    Moved(PathBuf),
+    /// An entry that was removed, hiding anything below it in the base
+    Removed,
+}
+
+/// Where the entry for a path comes from
+enum Location {
+    Node(Node),
+    Base(PathBuf),
+    Missing,
+}
+
+/// Copy-on-write view of a filesystem used to plan several packages at once
+///
+/// Changes are kept in memory and never reach the base filesystem, so each
+/// package can be planned against the state left behind by the packages
+/// planned before it.
+#[derive(Debug)]
+pub struct OverlayFilesystem {
+    base: Rc<dyn Filesystem>,
+    changes: RefCell<BTreeMap<String, (PathBuf, Node)>>,
+}
+
+impl OverlayFilesystem {
+    /// Create an overlay without changes on top of a filesystem
+    pub fn new(base: Rc<dyn Filesystem>) -> Self {
+        Self {
+            base,
+            changes: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    /// Simulate the effects of a single action
+    pub fn apply_action(&self, action: &Action) -> Result<()> {
+        match action {
+            Action::CreateFileLink {
+                link_path,
+                target_path,
+                mode,
+            } => planner::create_file_link(self, link_path, target_path, *mode)?,
+            Action::CreateDirLink {
+                link_path,
+                target_path,
+            } => self.create_link(link_path, target_path, true)?,
+            Action::UnfoldDirLink { link_path, .. } => {
+                self.remove_link(link_path)?;
+                self.create_dir_all(link_path)?;
+            }
+            Action::RefoldDir {
+                link_path,
+                target_path,
+            } => planner::refold_dir(self, link_path, target_path)?,
+            Action::AdoptFile {
+                target_path,
+                package_path,
+                keep_both,
+            } => match adopt::adoption(self, target_path, package_path, *keep_both)? {
+                Adoption::Identical => self.remove_file(target_path)?,
+                Adoption::KeepBoth(adopted_path) => self.rename(target_path, &adopted_path)?,
+                Adoption::Move | Adoption::Merge => self.rename(target_path, package_path)?,
+            },
+            Action::AdoptDir {
+                target_path,
+                package_path,
+                keep_both,
+            } => match adopt::adoption(self, target_path, package_path, *keep_both)? {
+                // Merged contents only become visible once the plan is executed
+                Adoption::Merge => self.remove_dir_all(target_path)?,
+                Adoption::KeepBoth(adopted_path) => self.rename(target_path, &adopted_path)?,
+                Adoption::Move | Adoption::Identical => self.rename(target_path, package_path)?,
+            },
+            Action::RemoveConflict { path } => {
+                if self.exists(path) && !self.is_symlink(path) {
+                    self.remove_dir_all(path)?;
+                }
+            }
+            Action::BackupConflict { path, backup_path } => self.rename(path, backup_path)?,
+            Action::RestoreBackup { backup_path, path } => self.rename(backup_path, path)?,
+            Action::RemoveLink { path } => self.remove_link(path)?,
+            Action::RemoveCopy { path, .. } => self.remove_file(path)?,
+            Action::MaterializeLink { path, source_path } => {
+                adopt::replace_with_copy(self, path, source_path)?
+            }
+            Action::RemoveEmptyDir { path } => {
+                if self.is_empty_dir(path)? {
+                    self.remove_dir(path)?;
+                }
+            }
+            Action::MovePath { from, to } => self.rename(from, to)?,
+            Action::CopyFile { from, to } => self.copy_file(from, to)?,
+            Action::CreateDir { path } => self.create_dir_all(path)?,
+        }
+
+        Ok(())
+    }
+
+    /// Key of a path in the change map (case-insensitive on Windows)
+    fn key(path: &Path) -> String {
+        let key = path
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        if cfg!(windows) {
+            key.to_lowercase()
+        } else {
+            key
+        }
+    }
+
+    /// Find the entry for a path whose parent is already resolved
+    fn locate(&self, path: &Path) -> Location {
+        let changes = self.changes.borrow();
+        let mut suffix = Vec::new();
+        let mut current = Some(path);
+
+        while let Some(p) = current {
+            if let Some((_, node)) = changes.get(&Self::key(p)) {
+                if suffix.is_empty() {
+                    return Location::Node(node.clone());
+                }
+
+                return match node {
+                    Node::Moved(from) => {
+                        Location::Base(suffix.iter().rev().fold(from.clone(), |p, n| p.join(n)))
+                    }
+                    _ => Location::Missing,
+                };
+            }
+
+            if let Some(name) = p.file_name() {
+                suffix.push(name);
+            }
+            current = p.parent();
+        }
+
+        Location::Base(path.to_path_buf())
+    }
+
+    /// Get metadata for a path whose parent is already resolved
+    fn lookup(&self, path: &Path) -> Result<Metadata> {
+        match self.locate(path) {
+            Location::Node(Node::File) => Ok(Metadata::new(FileKind::File)),
+            Location::Node(Node::Dir) => Ok(Metadata::new(FileKind::Dir)),
+            Location::Node(Node::Link(_)) => Ok(Metadata::new(FileKind::Symlink)),
+            Location::Node(Node::Moved(from)) => self.base.symlink_metadata(&from),
+            Location::Base(base_path) => self.base.symlink_metadata(&base_path),
+            Location::Node(Node::Removed) | Location::Missing => Err(not_found(path)),
+        }
+    }
+
+    /// Read a link whose parent is already resolved
+    fn lookup_link(&self, path: &Path) -> Result<PathBuf> {
+        match self.locate(path) {
+            Location::Node(Node::Link(target)) => Ok(target),
+            Location::Node(Node::Moved(base_path)) | Location::Base(base_path) => {
+                self.base.read_link(&base_path)
+            }
+            _ => Err(StowError::symlink_error(path, "Not a symbolic link")),
+        }
+    }
+
+    /// Resolve all symlinks in the parent of a path
+    fn resolve_parent(&self, path: &Path) -> Result<PathBuf> {
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => Ok(self
+                .canonicalize(parent)
+                .map_err(|e| StowError::io_error(path.to_path_buf(), e))?
+                .join(name)),
+            _ => Ok(path.to_path_buf()),
+        }
+    }
+
+    /// Record a change, replacing any changes below the path
+    fn set(&self, path: &Path, node: Node) {
+        self.take_descendants(path);
+        self.changes
+            .borrow_mut()
+            .insert(Self::key(path), (path.to_path_buf(), node));
+    }
+
+    /// Remove and return the changes below a path, relative to it
+    fn take_descendants(&self, path: &Path) -> Vec<(PathBuf, Node)> {
+        let prefix = format!("{}/", Self::key(path));
+        let mut changes = self.changes.borrow_mut();
+        let keys: Vec<String> = changes
+            .keys()
+            .filter(|k| k.starts_with(&prefix))
+            .cloned()
+            .collect();
+
+        keys.into_iter()
+            .filter_map(|k| changes.remove(&k))
+            .filter_map(|(p, node)| {
+                let relative = p.strip_prefix(path).ok()?.to_path_buf();
+                Some((relative, node))
+            })
+            .collect()
+    }
+}
+
+impl Filesystem for OverlayFilesystem {
+    fn symlink_metadata(&self, path: &Path) -> Result<Metadata> {
+        self.lookup(&self.resolve_parent(path)?)
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let dir = self
+            .canonicalize(path)
+            .map_err(|e| StowError::io_error(path.to_path_buf(), e))?;
+
+        let mut entries = BTreeMap::new();
+        match self.locate(&dir) {
+            Location::Node(Node::Dir) => {}
+            Location::Node(Node::Moved(base_path)) | Location::Base(base_path) => {
+                for entry in self.base.read_dir(&base_path)? {
+                    if let Some(name) = entry.file_name() {
+                        let entry_path = path.join(name);
+                        entries.insert(Self::key(&dir.join(name)), entry_path);
+                    }
+                }
+            }
+            Location::Node(_) => return Err(not_a_directory(path)),
+            Location::Missing => return Err(not_found(path)),
+        }
+
+        // Apply the changes made directly inside this directory
+        let dir_key = Self::key(&dir);
+        for (key, (changed_path, node)) in self.changes.borrow().iter() {
+            let Some(name) = changed_path.file_name() else {
+                continue;
+            };
+            if changed_path.parent().map(Self::key).as_ref() != Some(&dir_key) {
+                continue;
+            }
+
+            if *node == Node::Removed {
+                entries.remove(key);
+            } else {
+                entries.insert(key.clone(), path.join(name));
+            }
+        }
+
+        let mut entries: Vec<PathBuf> = entries.into_values().collect();
+        entries.sort();
+        Ok(entries)
+    }
+
+    fn read_link(&self, path: &Path) -> Result<PathBuf> {
+        self.lookup_link(&self.resolve_parent(path)?)
+    }
+
+    fn create_link(&self, link_path: &Path, target_path: &Path, _is_directory: bool) -> Result<()> {
+        if let Some(parent) = link_path.parent() {
+            self.create_dir_all(parent)?;
+        }
+
+        let resolved = self.resolve_parent(link_path)?;
+        if self.lookup(&resolved).is_ok() {
+            return Err(StowError::io_error(
+                link_path.to_path_buf(),
+                std::io::Error::from(ErrorKind::AlreadyExists),
+            ));
+        }
+
+        self.set(&resolved, Node::Link(target_path.to_path_buf()));
+        Ok(())
+    }
+
+    fn remove_link(&self, path: &Path) -> Result<()> {
+        let resolved = self.resolve_parent(path)?;
+        if !self.lookup(&resolved)?.is_symlink() {
+            return Err(StowError::symlink_error(path, "Not a symbolic link"));
+        }
+
+        self.set(&resolved, Node::Removed);
+        Ok(())
+    }
+
+    fn create_hard_link(&self, link_path: &Path, source_path: &Path) -> Result<()> {
+        if self.metadata(source_path)?.is_dir() {
+            return Err(StowError::io_error(
+                source_path.to_path_buf(),
+                std::io::Error::from(ErrorKind::IsADirectory),
+            ));
+        }
+
+        if let Some(parent) = link_path.parent() {
+            self.create_dir_all(parent)?;
+        }
+
+        let resolved = self.resolve_parent(link_path)?;
+        if self.lookup(&resolved).is_ok() {
+            return Err(StowError::io_error(
+                link_path.to_path_buf(),
+                std::io::Error::from(ErrorKind::AlreadyExists),
+            ));
+        }
+
+        self.set(&resolved, Node::File);
+        Ok(())
+    }
+
+    fn same_file(&self, a: &Path, b: &Path) -> bool {
+        let locate = |path: &Path| {
+            let resolved = self.canonicalize(path).ok()?;
+            match self.locate(&resolved) {
+                Location::Base(base_path) => Some(base_path),
+                _ => None,
+            }
+        };
+
+        // Files created in the overlay are only tracked by existence
+        match (locate(a), locate(b)) {
+            (Some(a), Some(b)) => self.base.same_file(&a, &b),
+            _ => false,
+        }
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        if self.is_dir(path) {
+            return Ok(());
+        }
+
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+
+        let resolved = self.resolve_parent(path)?;
+        if self.lookup(&resolved).is_ok() {
+            return Err(not_a_directory(path));
+        }
+
+        self.set(&resolved, Node::Dir);
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<()> {
+        let resolved = self.resolve_parent(path)?;
+        if !self.lookup(&resolved)?.is_dir() {
+            return Err(not_a_directory(path));
+        }
+        if !self.read_dir(path)?.is_empty() {
+            return Err(StowError::directory_not_empty(path));
+        }
+
+        self.set(&resolved, Node::Removed);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        let resolved = self.resolve_parent(path)?;
+        if self.lookup(&resolved)?.is_dir() {
+            return Err(StowError::io_error(
+                path.to_path_buf(),
+                std::io::Error::from(ErrorKind::IsADirectory),
+            ));
+        }
+
+        self.set(&resolved, Node::Removed);
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<()> {
+        let resolved = self.resolve_parent(path)?;
+        self.lookup(&resolved)?;
+
+        self.set(&resolved, Node::Removed);
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let from_resolved = self.resolve_parent(from)?;
+        let metadata = self.lookup(&from_resolved)?;
+
+        let node = match self.locate(&from_resolved) {
+            Location::Node(node) => node,
+            Location::Base(base_path) if metadata.is_dir() => Node::Moved(base_path),
+            Location::Base(base_path) if metadata.is_symlink() => {
+                Node::Link(self.base.read_link(&base_path)?)
+            }
+            Location::Base(_) => Node::File,
+            Location::Missing => return Err(not_found(from)),
+        };
+
+        if let Some(parent) = to.parent() {
+            self.create_dir_all(parent)?;
+        }
+        let to_resolved = self.resolve_parent(to)?;
+        if self.lookup(&to_resolved).is_ok_and(|m| m.is_dir()) {
+            return Err(StowError::io_error(
+                to.to_path_buf(),
+                std::io::Error::from(ErrorKind::AlreadyExists),
+            ));
+        }
+
+        let descendants = self.take_descendants(&from_resolved);
+        self.set(&from_resolved, Node::Removed);
+        self.set(&to_resolved, node);
+        for (relative, node) in descendants {
+            let path = to_resolved.join(relative);
+            self.changes
+                .borrow_mut()
+                .insert(Self::key(&path), (path, node));
+        }
+
+        Ok(())
+    }
+
+    fn copy_file(&self, from: &Path, to: &Path) -> Result<()> {
+        if self.metadata(from)?.is_dir() {
+            return Err(StowError::io_error(
+                from.to_path_buf(),
+                std::io::Error::from(ErrorKind::IsADirectory),
+            ));
+        }
+
+        if let Some(parent) = to.parent() {
+            self.create_dir_all(parent)?;
+        }
+        self.set(&self.resolve_parent(to)?, Node::File);
+        Ok(())
+    }
+
+    fn read_file(&self, path: &Path) -> Result<Vec<u8>> {
+        let resolved = self
+            .canonicalize(path)
+            .map_err(|e| StowError::io_error(path.to_path_buf(), e))?;
+
+        match self.locate(&resolved) {
+            Location::Base(base_path) => self.base.read_file(&base_path),
+            // Only the existence of files is tracked in the overlay
+            Location::Node(Node::File) => Err(StowError::io_error(
+                path.to_path_buf(),
+                std::io::Error::from(ErrorKind::Unsupported),
+            )),
+            Location::Node(_) => Err(StowError::io_error(
+                path.to_path_buf(),
+                std::io::Error::from(ErrorKind::IsADirectory),
+            )),
+            Location::Missing => Err(not_found(path)),
+        }
+    }
+
+    fn write_file(&self, path: &Path, _contents: &[u8]) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+
+        let resolved = self.resolve_parent(path)?;
+        if self.lookup(&resolved).is_ok_and(|m| m.is_dir()) {
+            return Err(StowError::io_error(
+                path.to_path_buf(),
+                std::io::Error::from(ErrorKind::IsADirectory),
+            ));
+        }
+
+        self.set(&resolved, Node::File);
+        Ok(())
+    }
+
+    fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf> {
+        filesystem::resolve_symlinks_with(
+            &std::path::absolute(path)?,
+            |p| self.lookup(p),
+            |p| self.lookup_link(p),
+        )
+    }
+}
+
+/// Error for a path that does not exist in the overlay
+fn not_found(path: &Path) -> StowError {
+    StowError::io_error(
+        path.to_path_buf(),
+        std::io::Error::from(ErrorKind::NotFound),
+    )
+}
+
+/// Error for a path that is not a directory in the overlay
+fn not_a_directory(path: &Path) -> StowError {
+    StowError::io_error(
+        path.to_path_buf(),
+        std::io::Error::from(ErrorKind::NotADirectory),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memfs::MemoryFilesystem;
+
+    fn setup() -> (Rc<MemoryFilesystem>, OverlayFilesystem, PathBuf) {
+        let base = Rc::new(MemoryFilesystem::new());
+        let root = MemoryFilesystem::root();
+        base.add_file(root.join("stow/pkg/app/a.txt"), "a");
+        base.add_file(root.join("target/existing.txt"), "existing");
+        let overlay = OverlayFilesystem::new(base.clone());
+        (base, overlay, root)
+    }
+
+    #[test]
+    fn test_changes_do_not_reach_base() {
+        let (base, overlay, root) = setup();
+        overlay
+            .create_link(&root.join("target/app"), Path::new("../stow/pkg/app"), true)
+            .unwrap();
+        overlay
+            .remove_file(&root.join("target/existing.txt"))
+            .unwrap();
+
+        assert!(overlay.is_symlink(&root.join("target/app")));
+        assert!(!overlay.exists(&root.join("target/existing.txt")));
+        assert!(!base.exists(&root.join("target/app")));
+        assert!(base.exists(&root.join("target/existing.txt")));
+    }
+
+    #[test]
+    fn test_follows_links_into_base() {
+        let (_, overlay, root) = setup();
+        overlay
+            .create_link(&root.join("target/app"), Path::new("../stow/pkg/app"), true)
+            .unwrap();
+
+        assert!(overlay.exists(&root.join("target/app/a.txt")));
+        assert_eq!(
+            overlay.read_dir(&root.join("target/app")).unwrap(),
+            vec![root.join("target/app/a.txt")]
+        );
+        assert_eq!(
+            overlay.resolve_link(&root.join("target/app")).unwrap(),
+            root.join("stow/pkg/app")
+        );
+    }
+
+    #[test]
+    fn test_unfolded_directory_hides_base_contents() {
+        let (base, overlay, root) = setup();
+        base.create_link(&root.join("target/app"), Path::new("../stow/pkg/app"), true)
+            .unwrap();
+
+        overlay.remove_link(&root.join("target/app")).unwrap();
+        overlay.create_dir_all(&root.join("target/app")).unwrap();
+
+        assert!(!overlay.is_symlink(&root.join("target/app")));
+        assert!(overlay.is_empty_dir(&root.join("target/app")).unwrap());
+    }
+
+    #[test]
+    fn test_read_dir_merges_changes() {
+        let (_, overlay, root) = setup();
+        overlay
+            .create_link(&root.join("target/new.txt"), Path::new("x"), false)
+            .unwrap();
+        overlay
+            .remove_file(&root.join("target/existing.txt"))
+            .unwrap();
+
+        assert_eq!(
+            overlay.read_dir(&root.join("target")).unwrap(),
+            vec![root.join("target/new.txt")]
+        );
+    }
+
+    #[test]
+    fn test_rename_directory_keeps_contents() {
+        let (base, overlay, root) = setup();
+        base.add_file(root.join("target/conf/x.txt"), "x");
+
+        overlay
+            .rename(&root.join("target/conf"), &root.join("stow/pkg/conf"))
+            .unwrap();
+
+        assert!(!overlay.exists(&root.join("target/conf")));
+        assert!(overlay.exists(&root.join("stow/pkg/conf/x.txt")));
+    }
+}
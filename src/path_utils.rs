@@ -1,29 +1,44 @@
 use crate::error::{Result, StowError};
+use std::ffi::{OsStr, OsString};
 use std::path::{Component, Path, PathBuf};
 
 /// Normalize a path to an absolute path with consistent separators
 /// Resolves `.` and `..` components and canonicalizes the path
 pub fn normalize_path(path: impl AsRef<Path>) -> Result<PathBuf> {
-    let path = path.as_ref();
-
-    // Try to canonicalize (resolves symlinks, removes . and .., makes absolute)
-    match path.canonicalize() {
-        Ok(canonical) => Ok(canonical),
-        Err(_e) => {
-            // If canonicalize fails (e.g., path doesn't exist), try to make it absolute
-            if path.is_absolute() {
-                Ok(path.to_owned())
-            } else {
-                std::env::current_dir()
-                    .map(|cwd| cwd.join(path))
-                    .map_err(|io_err| StowError::io_error(path.to_owned(), io_err))
-            }
-        }
+    normalize_path_with(path.as_ref(), |p| p.canonicalize())
+}
+
+/// Normalize a path using the given canonicalization function
+/// If the path itself cannot be canonicalized (e.g., it doesn't exist), its parent is
+/// normalized instead and the file name is appended, so dangling paths still compare
+/// equal to their canonical counterparts
+pub fn normalize_path_with(
+    path: &Path,
+    canonicalize: impl Fn(&Path) -> std::io::Result<PathBuf> + Copy,
+) -> Result<PathBuf> {
+    if let Ok(canonical) = canonicalize(path) {
+        return Ok(canonical);
+    }
+
+    if let (Some(parent), Some(name)) = (path.parent(), path.file_name())
+        && !parent.as_os_str().is_empty()
+        && let Ok(parent_norm) = normalize_path_with(parent, canonicalize)
+    {
+        return Ok(parent_norm.join(name));
+    }
+
+    // Nothing along the path exists, just make it absolute
+    if path.is_absolute() {
+        Ok(path.to_owned())
+    } else {
+        std::env::current_dir()
+            .map(|cwd| cwd.join(path))
+            .map_err(|io_err| StowError::io_error(path.to_owned(), io_err))
     }
 }
 
 /// Compute a relative path from one location to another
-/// Both paths should be absolute
+/// Both paths should be absolute and already normalized
 pub fn compute_relative_path(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<PathBuf> {
     let from = from.as_ref();
     let to = to.as_ref();
@@ -35,10 +50,6 @@ pub fn compute_relative_path(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Re
         ));
     }
 
-    // Normalize both paths
-    let from = normalize_path(from)?;
-    let to = normalize_path(to)?;
-
     // Split paths into components
     let from_components: Vec<_> = from.components().collect();
     let to_components: Vec<_> = to.components().collect();
@@ -67,6 +78,71 @@ pub fn compute_relative_path(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Re
     Ok(relative)
 }
 
+/// Path a link's target points to, resolving `.` and `..` lexically against the
+/// directory holding the link
+/// Works for links that don't exist yet, unlike normalizing through the filesystem
+pub fn link_destination(link_path: impl AsRef<Path>, target: impl AsRef<Path>) -> PathBuf {
+    let link_path = link_path.as_ref();
+    let mut destination = link_path.parent().unwrap_or(link_path).to_path_buf();
+
+    for component in target.as_ref().components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                destination.pop();
+            }
+            other => destination.push(other),
+        }
+    }
+
+    destination
+}
+
+/// Strip a base directory from a path, using case-insensitive comparison on Windows
+/// Returns `None` if the path is not inside the base directory
+/// Both paths should already be normalized
+pub fn strip_base(path: impl AsRef<Path>, base: impl AsRef<Path>) -> Option<PathBuf> {
+    let mut path_components = path.as_ref().components();
+
+    for base_component in base.as_ref().components() {
+        let path_component = path_components.next()?;
+        if !components_equal(&path_component, &base_component) {
+            return None;
+        }
+    }
+
+    Some(path_components.as_path().to_path_buf())
+}
+
+/// Translate a package entry name the way GNU Stow's `--dotfiles` does
+/// A leading `dot-` becomes `.` (`dot-bashrc` -> `.bashrc`). Names where nothing
+/// would follow the dot, or a second dot would, are left unchanged.
+pub fn dotfile_name(name: &OsStr) -> OsString {
+    let Some(rest) = name.to_str().and_then(|n| n.strip_prefix("dot-")) else {
+        return name.to_owned();
+    };
+
+    if rest.is_empty() || rest.starts_with('.') {
+        return name.to_owned();
+    }
+
+    OsString::from(format!(".{}", rest))
+}
+
+/// Name of the package entry that `dotfile_name` translates to a target name
+/// A leading `.` becomes `dot-` (`.bashrc` -> `dot-bashrc`); other names are unchanged.
+pub fn dotfile_source_name(name: &OsStr) -> OsString {
+    let Some(rest) = name.to_str().and_then(|n| n.strip_prefix('.')) else {
+        return name.to_owned();
+    };
+
+    if rest.is_empty() || rest.starts_with('.') {
+        return name.to_owned();
+    }
+
+    OsString::from(format!("dot-{}", rest))
+}
+
 /// Compare two path components with case-insensitivity on Windows
 #[inline]
 fn components_equal(a: &Component, b: &Component) -> bool {
@@ -86,20 +162,17 @@ fn components_equal(a: &Component, b: &Component) -> bool {
 }
 
 /// Check if two paths are equal, using case-insensitive comparison on Windows
+/// Both paths should already be normalized
 #[inline]
 pub fn paths_equal(a: impl AsRef<Path>, b: impl AsRef<Path>) -> bool {
     let a = a.as_ref();
     let b = b.as_ref();
 
-    // Try to normalize both paths first
-    let a_norm = normalize_path(a).unwrap_or_else(|_| a.to_owned());
-    let b_norm = normalize_path(b).unwrap_or_else(|_| b.to_owned());
-
     #[cfg(target_os = "windows")]
     {
         // Case-insensitive component-wise comparison to avoid string allocation
-        let a_components: Vec<_> = a_norm.components().collect();
-        let b_components: Vec<_> = b_norm.components().collect();
+        let a_components: Vec<_> = a.components().collect();
+        let b_components: Vec<_> = b.components().collect();
 
         if a_components.len() != b_components.len() {
             return false;
@@ -115,7 +188,7 @@ pub fn paths_equal(a: impl AsRef<Path>, b: impl AsRef<Path>) -> bool {
 
     #[cfg(not(target_os = "windows"))]
     {
-        a_norm == b_norm
+        a == b
     }
 }
 
@@ -138,6 +211,72 @@ mod tests {
         assert!(result.unwrap().is_absolute());
     }
 
+    #[test]
+    fn test_normalize_path_nonexistent_child() {
+        let cwd = env::current_dir().unwrap();
+        let canonical_cwd = cwd.canonicalize().unwrap();
+
+        // The missing file name is appended to the canonical parent
+        let normalized = normalize_path(cwd.join("does-not-exist.txt")).unwrap();
+        assert_eq!(normalized, canonical_cwd.join("does-not-exist.txt"));
+    }
+
+    #[test]
+    fn test_paths_equal() {
+        assert!(paths_equal("/a/b/c", "/a/b/c"));
+        assert!(!paths_equal("/a/b/c", "/a/b"));
+    }
+
+    #[test]
+    fn test_strip_base() {
+        assert_eq!(
+            strip_base("/stow/pkg/file.txt", "/stow"),
+            Some(PathBuf::from("pkg/file.txt"))
+        );
+        assert_eq!(strip_base("/stow", "/stow"), Some(PathBuf::new()));
+        assert_eq!(strip_base("/other/pkg", "/stow"), None);
+        assert_eq!(strip_base("/stow", "/stow/pkg"), None);
+    }
+
+    #[test]
+    fn test_link_destination() {
+        assert_eq!(
+            link_destination("/target/app/a.txt", "../../stow/pkg/app/a.txt"),
+            PathBuf::from("/stow/pkg/app/a.txt")
+        );
+        assert_eq!(
+            link_destination("/target/a.txt", "./b.txt"),
+            PathBuf::from("/target/b.txt")
+        );
+        assert_eq!(
+            link_destination("/target/a.txt", "/stow/pkg/a.txt"),
+            PathBuf::from("/stow/pkg/a.txt")
+        );
+    }
+
+    #[test]
+    fn test_dotfile_name() {
+        let translate = |name: &str| dotfile_name(OsStr::new(name));
+        assert_eq!(translate("dot-bashrc"), ".bashrc");
+        assert_eq!(translate("dot-config"), ".config");
+        assert_eq!(translate("bashrc"), "bashrc");
+        assert_eq!(translate("dot-"), "dot-");
+        assert_eq!(translate("dot-.hidden"), "dot-.hidden");
+        assert_eq!(translate("my-dot-file"), "my-dot-file");
+    }
+
+    #[test]
+    fn test_dotfile_source_name() {
+        let translate = |name: &str| dotfile_source_name(OsStr::new(name));
+        assert_eq!(translate(".bashrc"), "dot-bashrc");
+        assert_eq!(translate("bashrc"), "bashrc");
+        assert_eq!(translate(".."), "..");
+        assert_eq!(translate("..hidden"), "..hidden");
+        for name in [".bashrc", ".config", "bashrc"] {
+            assert_eq!(dotfile_name(&translate(name)), name);
+        }
+    }
+
     #[test]
     fn test_compute_relative_path_same_dir() {
         // Test relative path within same directory structure
@@ -1,14 +1,30 @@
-use crate::error::{Result, StowError};
-use crate::{fs_ops, logger};
-use std::path::PathBuf;
+use crate::backup::BackupIndex;
+use crate::error::{Conflict, Result, StowError};
+use crate::filesystem::Filesystem;
+use crate::fs_ops::LinkMode;
+use crate::history::History;
+use crate::journal::Journal;
+use crate::overlay::OverlayFilesystem;
+use crate::state::{self, State};
+use crate::{adopt, logger, path_utils};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Version of the plan file format written by `Plan::save`
+const PLAN_FORMAT_VERSION: u32 = 1;
 
 /// Represents an action to be performed during stow/unstow operations
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
 pub enum Action {
-    /// Create a file symlink
+    /// Create a file symlink, or a hard link or copy of the file it would point to
     CreateFileLink {
         link_path: PathBuf,
         target_path: PathBuf,
+        #[serde(default, skip_serializing_if = "LinkMode::is_symlink")]
+        mode: LinkMode,
     },
     /// Create a directory symlink (folding)
     CreateDirLink {
@@ -21,16 +37,171 @@ pub enum Action {
         link_path: PathBuf,
         original_target: PathBuf,
     },
+    /// Replace a directory holding only links into one package directory
+    /// with a single directory symlink to it (refolding)
+    RefoldDir {
+        link_path: PathBuf,
+        target_path: PathBuf,
+    },
+    /// Move a conflicting file from the target into the package
+    /// With `keep_both`, a file differing from the package's version is kept next to it
+    AdoptFile {
+        target_path: PathBuf,
+        package_path: PathBuf,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        keep_both: bool,
+    },
+    /// Move a conflicting directory from the target into the package
+    AdoptDir {
+        target_path: PathBuf,
+        package_path: PathBuf,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        keep_both: bool,
+    },
+    /// Remove a conflicting file or directory from the target (override)
+    RemoveConflict { path: PathBuf },
+    /// Move a conflicting file or directory from the target into a backup directory
+    BackupConflict { path: PathBuf, backup_path: PathBuf },
+    /// Move a backed up file or directory back to its place in the target
+    RestoreBackup { backup_path: PathBuf, path: PathBuf },
     /// Remove a symlink (file or directory)
     RemoveLink { path: PathBuf },
+    /// Remove a hard link or copy of a package file
+    RemoveCopy { path: PathBuf, source_path: PathBuf },
+    /// Replace a symlink with a real copy of the package file or directory it points to
+    MaterializeLink { path: PathBuf, source_path: PathBuf },
     /// Remove an empty directory
     RemoveEmptyDir { path: PathBuf },
+    /// Move a file or directory back to where it was (undo)
+    MovePath { from: PathBuf, to: PathBuf },
+    /// Copy a package file back into the target (undo of adopting an identical file)
+    CopyFile { from: PathBuf, to: PathBuf },
+    /// Create a directory that was removed (undo)
+    CreateDir { path: PathBuf },
+}
+
+impl Action {
+    /// The target path changed by this action
+    pub fn path(&self) -> &Path {
+        match self {
+            Action::CreateFileLink { link_path, .. }
+            | Action::CreateDirLink { link_path, .. }
+            | Action::UnfoldDirLink { link_path, .. }
+            | Action::RefoldDir { link_path, .. } => link_path,
+            Action::AdoptFile { target_path, .. } | Action::AdoptDir { target_path, .. } => {
+                target_path
+            }
+            Action::RemoveConflict { path }
+            | Action::BackupConflict { path, .. }
+            | Action::RestoreBackup { path, .. }
+            | Action::RemoveLink { path }
+            | Action::RemoveCopy { path, .. }
+            | Action::MaterializeLink { path, .. }
+            | Action::RemoveEmptyDir { path }
+            | Action::CreateDir { path } => path,
+            Action::MovePath { to, .. } | Action::CopyFile { to, .. } => to,
+        }
+    }
+}
+
+/// State of a path, recorded when planning and checked again before applying a plan
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PathState {
+    /// Nothing exists at the path
+    Missing,
+    /// A regular file
+    File,
+    /// A real directory
+    Dir,
+    /// A symbolic link with the given (unresolved) target
+    Link { target: PathBuf },
+}
+
+impl PathState {
+    /// Observe the current state of a path
+    pub fn of(fs: &dyn Filesystem, path: &Path) -> Self {
+        match fs.symlink_metadata(path) {
+            Err(_) => PathState::Missing,
+            Ok(metadata) if metadata.is_symlink() => match fs.read_link(path) {
+                Ok(target) => PathState::Link { target },
+                Err(_) => PathState::Missing,
+            },
+            Ok(metadata) if metadata.is_dir() => PathState::Dir,
+            Ok(_) => PathState::File,
+        }
+    }
+}
+
+impl fmt::Display for PathState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathState::Missing => write!(f, "nothing"),
+            PathState::File => write!(f, "a file"),
+            PathState::Dir => write!(f, "a directory"),
+            PathState::Link { target } => write!(f, "a link to {}", target.display()),
+        }
+    }
+}
+
+/// A planned action with the package it belongs to and the state it expects to find
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Step {
+    /// Package the action was planned for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package: Option<String>,
+    /// The action to perform
+    #[serde(flatten)]
+    pub action: Action,
+    /// State of the action's path right before the action, as seen when planning
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expect: Option<PathState>,
+}
+
+/// On-disk representation of a plan
+#[derive(Serialize, Deserialize)]
+struct PlanFile {
+    version: u32,
+    /// State file updated when the plan is executed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    state_file: Option<PathBuf>,
+    /// Backup index updated when the plan is executed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    backup_index: Option<PathBuf>,
+    /// Operation history the plan is recorded in when executed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    history_file: Option<PathBuf>,
+    /// Command line the plan was made with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    command: Option<String>,
+    /// Link modes tried when a file link's mode isn't permitted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    fallback: Vec<LinkMode>,
+    steps: Vec<Step>,
 }
 
 /// A plan containing a sequence of actions to execute
 #[derive(Debug, Default)]
 pub struct Plan {
-    actions: Vec<Action>,
+    steps: Vec<Step>,
+    /// Paths that could not be planned, collected so they can be reported together
+    conflicts: Vec<Conflict>,
+    /// State file updated when the plan is executed
+    state_file: Option<PathBuf>,
+    /// Backup index updated when the plan is executed
+    backup_index: Option<PathBuf>,
+    /// Operation history the plan is recorded in when executed
+    history_file: Option<PathBuf>,
+    /// Command line the plan was made with
+    command: Option<String>,
+    /// Operation in the history the plan reverses
+    undoes: Option<u64>,
+    /// Link modes tried when a file link's mode isn't permitted
+    fallback: Vec<LinkMode>,
+    /// Target paths found already linked while planning
+    linked: Vec<PathBuf>,
+    /// Target paths left alone because of a defer pattern
+    deferred: Vec<PathBuf>,
 }
 
 impl Plan {
@@ -41,39 +212,398 @@ impl Plan {
 
     /// Add an action to the plan
     pub fn add(&mut self, action: Action) {
-        self.actions.push(action);
+        self.steps.push(Step {
+            package: None,
+            action,
+            expect: None,
+        });
+    }
+
+    /// Add an action owned by a package other than the one being planned
+    pub fn add_for_package(&mut self, action: Action, package: Option<String>) {
+        self.steps.push(Step {
+            package,
+            action,
+            expect: None,
+        });
+    }
+
+    /// Record the executed actions in the state file at `path`
+    pub fn track_state(&mut self, path: PathBuf) {
+        self.state_file = Some(path);
+    }
+
+    /// Record backed up and restored files in the backup index at `path`
+    pub fn track_backups(&mut self, path: PathBuf) {
+        self.backup_index = Some(path);
+    }
+
+    /// Record the executed actions and how to reverse them in the history file at `path`
+    pub fn track_history(&mut self, path: PathBuf, command: String) {
+        self.history_file = Some(path);
+        self.command = Some(command);
+    }
+
+    /// Try the link modes in `fallback` in turn when creating a file link isn't permitted
+    pub fn set_fallback(&mut self, fallback: Vec<LinkMode>) {
+        self.fallback = fallback;
+    }
+
+    /// Mark the plan as reversing an operation, which the history records instead
+    pub fn mark_undoes(&mut self, id: u64) {
+        self.undoes = Some(id);
+    }
+
+    /// Record a conflict found while planning
+    pub fn add_conflict(&mut self, conflict: Conflict) {
+        self.conflicts.push(conflict);
+    }
+
+    /// Record a target path that is already linked correctly
+    pub fn add_linked(&mut self, path: PathBuf) {
+        self.linked.push(path);
+    }
+
+    /// Record a target path that was deferred
+    pub fn add_deferred(&mut self, path: PathBuf) {
+        self.deferred.push(path);
+    }
+
+    /// Get the target paths found already linked
+    pub fn linked(&self) -> &[PathBuf] {
+        &self.linked
+    }
+
+    /// Get the deferred target paths
+    pub fn deferred(&self) -> &[PathBuf] {
+        &self.deferred
+    }
+
+    /// Remove and return the recorded conflicts
+    pub fn take_conflicts(&mut self) -> Vec<Conflict> {
+        std::mem::take(&mut self.conflicts)
+    }
+
+    /// Append the actions and conflicts of another plan
+    ///
+    /// A directory link created earlier in this plan and unfolded by the
+    /// appended plan is never created in the first place; the links placed
+    /// inside the unfolded directory create it instead.
+    pub fn append(&mut self, other: Plan) {
+        self.conflicts.extend(other.conflicts);
+        self.linked.extend(other.linked);
+        self.deferred.extend(other.deferred);
+
+        for step in other.steps {
+            if let Action::UnfoldDirLink { link_path, .. } = &step.action {
+                let created = self.steps.iter().position(|s| {
+                    matches!(&s.action, Action::CreateDirLink { link_path: created, .. }
+                        if path_utils::paths_equal(created, link_path))
+                });
+
+                if let Some(index) = created {
+                    self.steps.remove(index);
+                    continue;
+                }
+            }
+
+            self.steps.push(step);
+        }
+    }
+
+    /// Mark all actions without an owner as belonging to a package
+    pub fn set_package(&mut self, package_name: &str) {
+        for step in self.steps.iter_mut().filter(|s| s.package.is_none()) {
+            step.package = Some(package_name.to_string());
+        }
+    }
+
+    /// Simulate the plan on an overlay, recording the state each action expects to find
+    pub fn simulate(&mut self, overlay: &OverlayFilesystem) -> Result<()> {
+        for step in &mut self.steps {
+            step.expect = Some(PathState::of(overlay, step.action.path()));
+            overlay.apply_action(&step.action)?;
+        }
+        Ok(())
+    }
+
+    /// Check that every action still finds the state recorded when the plan was made
+    /// Nothing is changed on the filesystem; later actions are checked against the
+    /// simulated effects of earlier ones
+    pub fn verify(&self, fs: Rc<dyn Filesystem>) -> Result<()> {
+        let overlay = OverlayFilesystem::new(fs);
+
+        for step in &self.steps {
+            let path = step.action.path();
+            if let Some(expected) = &step.expect {
+                let actual = PathState::of(&overlay, path);
+                if actual != *expected {
+                    return Err(StowError::precondition_failed(
+                        path,
+                        expected.to_string(),
+                        actual.to_string(),
+                    ));
+                }
+            }
+
+            overlay.apply_action(&step.action)?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the actions of the plan in execution order
+    pub fn actions(&self) -> impl Iterator<Item = &Action> {
+        self.steps.iter().map(|step| &step.action)
+    }
+
+    /// Get the steps of the plan in execution order
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
     }
 
     /// Get the number of actions in the plan
     pub fn len(&self) -> usize {
-        self.actions.len()
+        self.steps.len()
+    }
+
+    /// Write the plan to a file
+    /// The format is TOML for `.toml` files and JSON otherwise
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = PlanFile {
+            version: PLAN_FORMAT_VERSION,
+            state_file: self.state_file.clone(),
+            backup_index: self.backup_index.clone(),
+            history_file: self.history_file.clone(),
+            command: self.command.clone(),
+            fallback: self.fallback.clone(),
+            steps: self.steps.clone(),
+        };
+
+        let content = if is_toml(path) {
+            toml::to_string_pretty(&file).map_err(|e| StowError::plan_error(e.to_string()))?
+        } else {
+            serde_json::to_string_pretty(&file).map_err(|e| StowError::plan_error(e.to_string()))?
+        };
+
+        std::fs::write(path, content).map_err(|e| StowError::io_error(path.to_path_buf(), e))
+    }
+
+    /// Read a plan written by `save`
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| StowError::io_error(path.to_path_buf(), e))?;
+
+        let file: PlanFile = if is_toml(path) {
+            toml::from_str(&content).map_err(|e| StowError::plan_error(e.to_string()))?
+        } else {
+            serde_json::from_str(&content).map_err(|e| StowError::plan_error(e.to_string()))?
+        };
+
+        if file.version != PLAN_FORMAT_VERSION {
+            return Err(StowError::plan_error(format!(
+                "Unsupported plan format version {} (expected {})",
+                file.version, PLAN_FORMAT_VERSION
+            )));
+        }
+
+        Ok(Self {
+            steps: file.steps,
+            state_file: file.state_file,
+            backup_index: file.backup_index,
+            history_file: file.history_file,
+            command: file.command,
+            fallback: file.fallback,
+            ..Self::default()
+        })
     }
 
     /// Execute all actions in the plan
+    ///
+    /// Execution is transactional: if any action fails, all changes made by
+    /// earlier actions are rolled back and the target is left unchanged.
+    /// A tracked state file, backup index and history are updated as part of the
+    /// same transaction.
     #[must_use = "plan execution can fail and should be checked"]
-    pub fn execute(&self, dry_run: bool) -> Result<()> {
-        for action in &self.actions {
-            execute_action(action, dry_run)?;
+    pub fn execute(&self, fs: &dyn Filesystem, dry_run: bool) -> Result<()> {
+        if dry_run {
+            for action in self.actions() {
+                execute_action(fs, action, true)?;
+            }
+            return Ok(());
+        }
+
+        let journal = Journal::new(fs);
+        if let Err(e) = self.execute_journaled(&journal) {
+            logger::warn(&format!(
+                "Action failed, rolling back {} change(s)",
+                journal.len()
+            ));
+
+            let failures = journal.rollback();
+            if failures > 0 {
+                logger::error(&format!(
+                    "Rollback incomplete: {} change(s) could not be reverted",
+                    failures
+                ));
+            }
+            return Err(e);
+        }
+
+        journal.commit();
+        Ok(())
+    }
+
+    /// Execute the actions and update the state file, backup index and history through a journal
+    fn execute_journaled(&self, journal: &Journal) -> Result<()> {
+        // A plan without actions leaves the state file untouched
+        let mut state = match &self.state_file {
+            Some(path) if !self.steps.is_empty() => Some(State::load(journal, path)?),
+            _ => None,
+        };
+        // The backup index is only touched by plans that back up or restore files
+        let touches_backups = self.actions().any(|action| {
+            matches!(
+                action,
+                Action::BackupConflict { .. } | Action::RestoreBackup { .. }
+            )
+        });
+        let mut backups = match &self.backup_index {
+            Some(path) if touches_backups => Some(BackupIndex::load(journal, path)?),
+            _ => None,
+        };
+        let mut history = match &self.history_file {
+            Some(path) if !self.steps.is_empty() || self.undoes.is_some() => {
+                Some(History::load(journal, path)?)
+            }
+            _ => None,
+        };
+        let now = state::now();
+        // An undo is recorded by marking the operation it reverses, not as an operation
+        let mut operation = match &history {
+            Some(history) if self.undoes.is_none() => {
+                Some(history.begin(self.command.as_deref().unwrap_or_default(), now))
+            }
+            _ => None,
+        };
+
+        for step in &self.steps {
+            let created_dirs = if state.is_some() || operation.is_some() {
+                state::missing_parents(journal, step.action.path())
+            } else {
+                Vec::new()
+            };
+
+            // File links are created first with a fallback, so the mode that worked is recorded
+            let linked = match &step.action {
+                Action::CreateFileLink { mode, .. } if !self.fallback.is_empty() => {
+                    Some(self.link_with_fallback(journal, step, *mode)?)
+                }
+                _ => None,
+            };
+            let step = linked.as_ref().unwrap_or(step);
+
+            let stash = match &mut operation {
+                Some(operation) => operation.prepare(journal, step, &created_dirs)?,
+                None => None,
+            };
+            match stash {
+                // Overridden files are kept until the operation drops out of the history
+                Some(stash) => adopt::stash_file(journal, step.action.path(), &stash)?,
+                None if linked.is_none() => execute_action(journal, &step.action, false)?,
+                None => {}
+            }
+
+            if let Some(state) = &mut state {
+                state.record(journal, step, &created_dirs, now);
+            }
+            if let Some(backups) = &mut backups {
+                backups.record(step, now);
+            }
         }
+
+        if let (Some(backups), Some(path)) = (&backups, &self.backup_index) {
+            logger::verbose(&format!("Updating backup index: {}", path.display()));
+            backups.save(journal, path)?;
+        }
+
+        if let (Some(history), Some(path)) = (&mut history, &self.history_file) {
+            if let Some(mut operation) = operation {
+                operation.finish(journal);
+                history.push(journal, operation)?;
+            } else if let Some(id) = self.undoes {
+                history.mark_undone(id);
+            }
+            logger::verbose(&format!("Updating operation history: {}", path.display()));
+            history.save(journal, path)?;
+        }
+
+        if let (Some(state), Some(path)) = (&mut state, &self.state_file) {
+            state.reconcile(journal);
+            logger::verbose(&format!("Updating state file: {}", path.display()));
+            state.save(journal, path)?;
+        }
+
         Ok(())
     }
+
+    /// Execute a file link step, falling back to the next link mode while its mode isn't permitted
+    /// Returns the step with the link mode that was used
+    fn link_with_fallback(&self, fs: &dyn Filesystem, step: &Step, mode: LinkMode) -> Result<Step> {
+        let mut step = step.clone();
+        let mut current = mode;
+        let mut result = execute_action(fs, &step.action, false);
+
+        for next in mode.fallbacks(&self.fallback) {
+            match &result {
+                Err(e) if e.is_link_not_permitted() => {}
+                _ => break,
+            }
+            logger::warn(&format!(
+                "Cannot create {} at {}, falling back to {}",
+                current.plural(),
+                step.action.path().display(),
+                next.plural()
+            ));
+
+            if let Action::CreateFileLink { mode, .. } = &mut step.action {
+                *mode = next;
+            }
+            current = next;
+            result = execute_action(fs, &step.action, false);
+        }
+        result.map(|()| step)
+    }
+}
+
+/// Check if a plan file should be read and written as TOML
+fn is_toml(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("toml"))
 }
 
 /// Execute a single action
-fn execute_action(action: &Action, dry_run: bool) -> Result<()> {
+fn execute_action(fs: &dyn Filesystem, action: &Action, dry_run: bool) -> Result<()> {
     match action {
         Action::CreateFileLink {
             link_path,
             target_path,
+            mode,
         } => {
+            let kind = match mode {
+                LinkMode::Symlink => "file link",
+                LinkMode::Hardlink => "hard link",
+                LinkMode::Copy => "file copy",
+            };
             logger::action(&format!(
-                "Create file link: {} -> {}",
+                "Create {}: {} -> {}",
+                kind,
                 link_path.display(),
                 target_path.display()
             ));
 
             if !dry_run {
-                fs_ops::create_symlink(link_path, target_path, false)?;
+                create_file_link(fs, link_path, target_path, *mode)?;
             }
         }
 
@@ -88,7 +618,7 @@ fn execute_action(action: &Action, dry_run: bool) -> Result<()> {
             ));
 
             if !dry_run {
-                fs_ops::create_symlink(link_path, target_path, true)?;
+                fs.create_link(link_path, target_path, true)?;
             }
         }
 
@@ -104,31 +634,127 @@ fn execute_action(action: &Action, dry_run: bool) -> Result<()> {
 
             if !dry_run {
                 // Remove the symlink
-                std::fs::remove_file(link_path)
-                    .map_err(|e| StowError::io_error(link_path.clone(), e))?;
+                fs.remove_link(link_path)?;
 
                 // Create a real directory
-                std::fs::create_dir(link_path)
-                    .map_err(|e| StowError::io_error(link_path.clone(), e))?;
+                fs.create_dir_all(link_path)?;
 
                 // Now we need to populate it with links to the original target's contents
                 // This is handled by the stow logic after unfolding
             }
         }
 
+        Action::RefoldDir {
+            link_path,
+            target_path,
+        } => {
+            logger::action(&format!(
+                "Refold directory: {} -> {}",
+                link_path.display(),
+                target_path.display()
+            ));
+
+            if !dry_run {
+                refold_dir(fs, link_path, target_path)?;
+            }
+        }
+
+        Action::AdoptFile {
+            target_path,
+            package_path,
+            keep_both,
+        } => {
+            // Logs the action itself and honors dry-run
+            adopt::adopt_file(fs, target_path, package_path, *keep_both, dry_run)?;
+        }
+
+        Action::AdoptDir {
+            target_path,
+            package_path,
+            keep_both,
+        } => {
+            // Logs the action itself and honors dry-run
+            adopt::adopt_directory(fs, target_path, package_path, *keep_both, dry_run)?;
+        }
+
+        Action::RemoveConflict { path } => {
+            // Logs the action itself and honors dry-run
+            adopt::override_file(fs, path, dry_run)?;
+        }
+
+        Action::BackupConflict { path, backup_path } => {
+            // Logs the action itself and honors dry-run
+            adopt::backup_file(fs, path, backup_path, dry_run)?;
+        }
+
+        Action::RestoreBackup { backup_path, path } => {
+            // Logs the action itself and honors dry-run
+            adopt::restore_file(fs, backup_path, path, dry_run)?;
+        }
+
         Action::RemoveLink { path } => {
             logger::action(&format!("Remove link: {}", path.display()));
 
             if !dry_run {
-                std::fs::remove_file(path).map_err(|e| StowError::io_error(path.clone(), e))?;
+                fs.remove_link(path)?;
+            }
+        }
+
+        Action::RemoveCopy { path, .. } => {
+            logger::action(&format!("Remove linked copy: {}", path.display()));
+
+            if !dry_run {
+                fs.remove_file(path)?;
             }
         }
 
+        Action::MaterializeLink { path, source_path } => {
+            // Logs the action itself and honors dry-run
+            adopt::materialize_link(fs, path, source_path, dry_run)?;
+        }
+
         Action::RemoveEmptyDir { path } => {
             logger::action(&format!("Remove empty directory: {}", path.display()));
 
             if !dry_run {
-                fs_ops::remove_empty_directory(path)?;
+                // Directories still holding other files are kept
+                if fs.is_empty_dir(path)? {
+                    fs.remove_dir(path)?;
+                } else {
+                    logger::verbose(&format!("Directory not empty, keeping: {}", path.display()));
+                }
+            }
+        }
+
+        Action::MovePath { from, to } => {
+            logger::action(&format!(
+                "Move back: {} -> {}",
+                from.display(),
+                to.display()
+            ));
+
+            if !dry_run {
+                fs.rename(from, to)?;
+            }
+        }
+
+        Action::CopyFile { from, to } => {
+            logger::action(&format!(
+                "Copy back: {} -> {}",
+                from.display(),
+                to.display()
+            ));
+
+            if !dry_run {
+                fs.copy_file(from, to)?;
+            }
+        }
+
+        Action::CreateDir { path } => {
+            logger::action(&format!("Create directory: {}", path.display()));
+
+            if !dry_run {
+                fs.create_dir_all(path)?;
             }
         }
     }
@@ -136,9 +762,38 @@ fn execute_action(action: &Action, dry_run: bool) -> Result<()> {
     Ok(())
 }
 
+/// Put a package file into the target the way the link mode asks for
+/// `target_path` is the symlink target, relative to the link's directory
+pub fn create_file_link(
+    fs: &dyn Filesystem,
+    link_path: &Path,
+    target_path: &Path,
+    mode: LinkMode,
+) -> Result<()> {
+    let source_path = || path_utils::link_destination(link_path, target_path);
+
+    match mode {
+        LinkMode::Symlink => fs.create_link(link_path, target_path, false),
+        LinkMode::Hardlink => fs.create_hard_link(link_path, &source_path()),
+        LinkMode::Copy => fs.copy_file(&source_path(), link_path),
+    }
+}
+
+/// Replace a directory of links with a single directory link
+/// Only the links inside the directory are removed; anything else makes this fail
+pub fn refold_dir(fs: &dyn Filesystem, link_path: &Path, target_path: &Path) -> Result<()> {
+    for entry in fs.read_dir(link_path)? {
+        fs.remove_link(&entry)?;
+    }
+    fs.remove_dir(link_path)?;
+    fs.create_link(link_path, target_path, true)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::filesystem::OsFilesystem;
+    use crate::memfs::MemoryFilesystem;
 
     #[test]
     fn test_plan_creation() {
@@ -148,21 +803,136 @@ mod tests {
         plan.add(Action::CreateFileLink {
             link_path: PathBuf::from("link"),
             target_path: PathBuf::from("target"),
+            mode: LinkMode::Symlink,
         });
 
         assert_eq!(plan.len(), 1);
     }
 
+    #[test]
+    fn test_append_skips_unfolding_links_created_in_same_plan() {
+        let mut plan = Plan::new();
+        plan.add(Action::CreateDirLink {
+            link_path: PathBuf::from("/target/app"),
+            target_path: PathBuf::from("../stow/pkg1/app"),
+        });
+
+        let mut other = Plan::new();
+        other.add(Action::UnfoldDirLink {
+            link_path: PathBuf::from("/target/app"),
+            original_target: PathBuf::from("/stow/pkg1/app"),
+        });
+        other.add(Action::CreateFileLink {
+            link_path: PathBuf::from("/target/app/a.txt"),
+            target_path: PathBuf::from("../../stow/pkg1/app/a.txt"),
+            mode: LinkMode::Symlink,
+        });
+        plan.append(other);
+
+        assert_eq!(
+            plan.actions().collect::<Vec<_>>(),
+            vec![&Action::CreateFileLink {
+                link_path: PathBuf::from("/target/app/a.txt"),
+                target_path: PathBuf::from("../../stow/pkg1/app/a.txt"),
+                mode: LinkMode::Symlink,
+            }]
+        );
+    }
+
+    /// A stow plan recorded against an in-memory filesystem
+    fn simulated_plan(fs: &Rc<MemoryFilesystem>) -> Plan {
+        let root = MemoryFilesystem::root();
+        let mut plan = Plan::new();
+        plan.add(Action::RemoveConflict {
+            path: root.join("target/file.txt"),
+        });
+        plan.add(Action::CreateFileLink {
+            link_path: root.join("target/file.txt"),
+            target_path: PathBuf::from("../stow/pkg/file.txt"),
+            mode: LinkMode::Symlink,
+        });
+        plan.set_package("pkg");
+
+        let overlay = OverlayFilesystem::new(fs.clone());
+        plan.simulate(&overlay).unwrap();
+        plan
+    }
+
+    #[test]
+    fn test_simulate_records_preconditions() {
+        let fs = Rc::new(MemoryFilesystem::new());
+        let root = MemoryFilesystem::root();
+        fs.add_file(root.join("target/file.txt"), "existing");
+
+        let plan = simulated_plan(&fs);
+
+        let expected: Vec<_> = plan.steps().iter().map(|s| s.expect.clone()).collect();
+        assert_eq!(
+            expected,
+            vec![Some(PathState::File), Some(PathState::Missing)]
+        );
+        assert!(
+            plan.steps
+                .iter()
+                .all(|s| s.package.as_deref() == Some("pkg"))
+        );
+        // Simulating doesn't touch the filesystem
+        assert!(!fs.is_symlink(&root.join("target/file.txt")));
+    }
+
+    #[test]
+    fn test_verify_detects_changed_target() {
+        let fs = Rc::new(MemoryFilesystem::new());
+        let root = MemoryFilesystem::root();
+        fs.add_file(root.join("target/file.txt"), "existing");
+        let plan = simulated_plan(&fs);
+        assert!(plan.verify(fs.clone()).is_ok());
+
+        fs.remove_file(&root.join("target/file.txt")).unwrap();
+        let result = plan.verify(fs.clone());
+        assert!(matches!(result, Err(StowError::PreconditionFailed { .. })));
+    }
+
+    #[test]
+    fn test_save_and_load_round_trip() {
+        let fs = Rc::new(MemoryFilesystem::new());
+        fs.add_file(MemoryFilesystem::root().join("target/file.txt"), "existing");
+        let plan = simulated_plan(&fs);
+        let temp_dir = tempfile::TempDir::new().unwrap();
+
+        for name in ["plan.json", "plan.toml"] {
+            let path = temp_dir.path().join(name);
+            plan.save(&path).unwrap();
+
+            let loaded = Plan::load(&path).unwrap();
+            assert_eq!(loaded.steps, plan.steps);
+        }
+
+        let json = std::fs::read_to_string(temp_dir.path().join("plan.json")).unwrap();
+        assert!(json.contains("\"action\": \"create_file_link\""));
+        assert!(json.contains("\"package\": \"pkg\""));
+    }
+
+    #[test]
+    fn test_load_rejects_unknown_version() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("plan.json");
+        std::fs::write(&path, r#"{"version": 99, "steps": []}"#).unwrap();
+
+        assert!(matches!(Plan::load(&path), Err(StowError::PlanError(_))));
+    }
+
     #[test]
     fn test_execute_dry_run() {
         let mut plan = Plan::new();
         plan.add(Action::CreateFileLink {
             link_path: PathBuf::from("/nonexistent/link"),
             target_path: PathBuf::from("/nonexistent/target"),
+            mode: LinkMode::Symlink,
         });
 
         // Dry run should not fail even with invalid paths
-        let result = plan.execute(true);
+        let result = plan.execute(&OsFilesystem, true);
         assert!(result.is_ok());
     }
 
@@ -175,7 +945,7 @@ mod tests {
         });
 
         // Dry run should not fail
-        let result = plan.execute(true);
+        let result = plan.execute(&OsFilesystem, true);
         assert!(result.is_ok());
     }
 
@@ -188,7 +958,7 @@ mod tests {
         });
 
         // Dry run should not fail
-        let result = plan.execute(true);
+        let result = plan.execute(&OsFilesystem, true);
         assert!(result.is_ok());
     }
 
@@ -200,7 +970,7 @@ mod tests {
         });
 
         // Dry run should not fail
-        let result = plan.execute(true);
+        let result = plan.execute(&OsFilesystem, true);
         assert!(result.is_ok());
     }
 
@@ -212,7 +982,7 @@ mod tests {
         });
 
         // Dry run should not fail
-        let result = plan.execute(true);
+        let result = plan.execute(&OsFilesystem, true);
         assert!(result.is_ok());
     }
 
@@ -223,6 +993,7 @@ mod tests {
         plan.add(Action::CreateFileLink {
             link_path: PathBuf::from("link1"),
             target_path: PathBuf::from("target1"),
+            mode: LinkMode::Symlink,
         });
 
         plan.add(Action::CreateDirLink {
@@ -237,7 +1008,7 @@ mod tests {
         assert_eq!(plan.len(), 3);
 
         // Dry run should succeed
-        let result = plan.execute(true);
+        let result = plan.execute(&OsFilesystem, true);
         assert!(result.is_ok());
     }
 
@@ -249,6 +1020,7 @@ mod tests {
         plan.add(Action::CreateFileLink {
             link_path: PathBuf::from("link"),
             target_path: PathBuf::from("target"),
+            mode: LinkMode::Symlink,
         });
 
         assert_eq!(plan.len(), 1);
@@ -259,4 +1031,384 @@ mod tests {
 
         assert_eq!(plan.len(), 2);
     }
+
+    #[test]
+    fn test_execute_unfold_and_link() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        fs.add_file(root.join("stow/pkg1/shared/a.txt"), "a");
+        fs.add_file(root.join("stow/pkg2/shared/b.txt"), "b");
+        fs.create_link(
+            &root.join("target/shared"),
+            Path::new("../stow/pkg1/shared"),
+            true,
+        )
+        .unwrap();
+
+        let mut plan = Plan::new();
+        plan.add(Action::UnfoldDirLink {
+            link_path: root.join("target/shared"),
+            original_target: root.join("stow/pkg1/shared"),
+        });
+        plan.add(Action::CreateFileLink {
+            link_path: root.join("target/shared/a.txt"),
+            target_path: PathBuf::from("../../stow/pkg1/shared/a.txt"),
+            mode: LinkMode::Symlink,
+        });
+        plan.add(Action::CreateFileLink {
+            link_path: root.join("target/shared/b.txt"),
+            target_path: PathBuf::from("../../stow/pkg2/shared/b.txt"),
+            mode: LinkMode::Symlink,
+        });
+
+        plan.execute(&fs, false).unwrap();
+
+        assert!(!fs.is_symlink(&root.join("target/shared")));
+        assert_eq!(
+            fs.file_content(root.join("target/shared/a.txt")).unwrap(),
+            b"a"
+        );
+        assert_eq!(
+            fs.file_content(root.join("target/shared/b.txt")).unwrap(),
+            b"b"
+        );
+    }
+
+    #[test]
+    fn test_execute_adopt_dry_run() {
+        let mut plan = Plan::new();
+        plan.add(Action::AdoptFile {
+            target_path: PathBuf::from("/nonexistent/target"),
+            package_path: PathBuf::from("/nonexistent/package"),
+            keep_both: false,
+        });
+        plan.add(Action::RemoveConflict {
+            path: PathBuf::from("/nonexistent/other"),
+        });
+
+        // Dry run still validates that there is something to adopt
+        assert!(plan.execute(&OsFilesystem, true).is_err());
+    }
+
+    #[test]
+    fn test_execute_adopt_then_link() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        fs.add_file(root.join("target/app/local.txt"), "local");
+        fs.add_file(root.join("target/file.txt"), "existing");
+        fs.add_file(root.join("stow/pkg/file.txt"), "existing");
+        fs.add_file(root.join("target/old.txt"), "old");
+
+        let mut plan = Plan::new();
+        plan.add(Action::AdoptFile {
+            target_path: root.join("target/file.txt"),
+            package_path: root.join("stow/pkg/file.txt"),
+            keep_both: false,
+        });
+        plan.add(Action::AdoptDir {
+            target_path: root.join("target/app"),
+            package_path: root.join("stow/pkg/app"),
+            keep_both: false,
+        });
+        plan.add(Action::RemoveConflict {
+            path: root.join("target/old.txt"),
+        });
+        plan.add(Action::CreateFileLink {
+            link_path: root.join("target/file.txt"),
+            target_path: PathBuf::from("../stow/pkg/file.txt"),
+            mode: LinkMode::Symlink,
+        });
+
+        plan.execute(&fs, false).unwrap();
+
+        assert_eq!(
+            fs.file_content(root.join("target/file.txt")).unwrap(),
+            b"existing"
+        );
+        assert!(fs.is_symlink(&root.join("target/file.txt")));
+        assert!(fs.exists(&root.join("stow/pkg/app/local.txt")));
+        assert!(!fs.exists(&root.join("target/old.txt")));
+    }
+
+    #[test]
+    fn test_execute_rolls_back_on_failure() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        fs.add_file(root.join("stow/pkg1/app/a.txt"), "a");
+        fs.add_file(root.join("stow/pkg2/app/b.txt"), "b");
+        fs.add_file(root.join("stow/pkg2/file.txt"), "package");
+        fs.add_file(root.join("target/file.txt"), "adopted");
+        fs.add_file(root.join("target/old.txt"), "old");
+        fs.add_file(root.join("target/blocker.txt"), "blocker");
+        fs.create_link(
+            &root.join("target/app"),
+            Path::new("../stow/pkg1/app"),
+            true,
+        )
+        .unwrap();
+
+        let mut plan = Plan::new();
+        plan.add(Action::UnfoldDirLink {
+            link_path: root.join("target/app"),
+            original_target: root.join("stow/pkg1/app"),
+        });
+        plan.add(Action::CreateFileLink {
+            link_path: root.join("target/app/a.txt"),
+            target_path: PathBuf::from("../../stow/pkg1/app/a.txt"),
+            mode: LinkMode::Symlink,
+        });
+        plan.add(Action::CreateFileLink {
+            link_path: root.join("target/app/b.txt"),
+            target_path: PathBuf::from("../../stow/pkg2/app/b.txt"),
+            mode: LinkMode::Symlink,
+        });
+        plan.add(Action::AdoptFile {
+            target_path: root.join("target/file.txt"),
+            package_path: root.join("stow/pkg2/file.txt"),
+            keep_both: true,
+        });
+        plan.add(Action::RemoveConflict {
+            path: root.join("target/old.txt"),
+        });
+        plan.add(Action::CreateFileLink {
+            link_path: root.join("target/new/nested/c.txt"),
+            target_path: PathBuf::from("../../../stow/pkg2/new/nested/c.txt"),
+            mode: LinkMode::Symlink,
+        });
+        // Fails: a regular file is in the way
+        plan.add(Action::CreateFileLink {
+            link_path: root.join("target/blocker.txt"),
+            target_path: PathBuf::from("../stow/pkg2/blocker.txt"),
+            mode: LinkMode::Symlink,
+        });
+
+        assert!(plan.execute(&fs, false).is_err());
+
+        // The target is exactly as before
+        assert_eq!(
+            fs.read_link(&root.join("target/app")).unwrap(),
+            PathBuf::from("../stow/pkg1/app")
+        );
+        assert_eq!(
+            fs.file_content(root.join("target/file.txt")).unwrap(),
+            b"adopted"
+        );
+        assert_eq!(
+            fs.file_content(root.join("target/old.txt")).unwrap(),
+            b"old"
+        );
+        assert!(!fs.exists(&root.join("target/new")));
+        assert_eq!(fs.read_dir(&root.join("target")).unwrap().len(), 4);
+
+        // The package is exactly as before
+        assert_eq!(
+            fs.file_content(root.join("stow/pkg2/file.txt")).unwrap(),
+            b"package"
+        );
+        assert!(!fs.exists(&root.join("stow/pkg2/file.txt.adopted")));
+        assert_eq!(fs.read_dir(&root.join("stow/pkg2")).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_execute_commit_removes_staged_files() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        fs.add_file(root.join("target/old.txt"), "old");
+        fs.add_file(root.join("stow/pkg/old.txt"), "package");
+
+        let mut plan = Plan::new();
+        plan.add(Action::RemoveConflict {
+            path: root.join("target/old.txt"),
+        });
+        plan.add(Action::CreateFileLink {
+            link_path: root.join("target/old.txt"),
+            target_path: PathBuf::from("../stow/pkg/old.txt"),
+            mode: LinkMode::Symlink,
+        });
+
+        plan.execute(&fs, false).unwrap();
+
+        // Only the link is left, no staging files
+        assert_eq!(
+            fs.read_dir(&root.join("target")).unwrap(),
+            vec![root.join("target/old.txt")]
+        );
+        assert!(fs.is_symlink(&root.join("target/old.txt")));
+    }
+
+    #[test]
+    fn test_execute_remove_empty_dir_keeps_non_empty() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        fs.add_file(root.join("target/dir/other.txt"), "");
+
+        let mut plan = Plan::new();
+        plan.add(Action::RemoveEmptyDir {
+            path: root.join("target/dir"),
+        });
+
+        assert!(plan.execute(&fs, false).is_ok());
+        assert!(fs.exists(&root.join("target/dir/other.txt")));
+    }
+
+    #[test]
+    fn test_execute_refold_dir_rolls_back_on_failure() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        fs.add_file(root.join("stow/pkg/app/a.txt"), "a");
+        fs.add_dir(root.join("target/app"));
+        fs.create_link(
+            &root.join("target/app/a.txt"),
+            Path::new("../../stow/pkg/app/a.txt"),
+            false,
+        )
+        .unwrap();
+
+        let mut plan = Plan::new();
+        plan.add(Action::RefoldDir {
+            link_path: root.join("target/app"),
+            target_path: PathBuf::from("../stow/pkg/app"),
+        });
+        plan.execute(&fs, false).unwrap();
+        assert_eq!(
+            fs.read_link(&root.join("target/app")).unwrap(),
+            PathBuf::from("../stow/pkg/app")
+        );
+
+        // A real file in the directory makes the refold fail and leaves it as it was
+        fs.remove_link(&root.join("target/app")).unwrap();
+        fs.add_dir(root.join("target/app"));
+        fs.create_link(
+            &root.join("target/app/a.txt"),
+            Path::new("../../stow/pkg/app/a.txt"),
+            false,
+        )
+        .unwrap();
+        fs.add_file(root.join("target/app/local.txt"), "local");
+
+        assert!(plan.execute(&fs, false).is_err());
+        assert!(fs.is_symlink(&root.join("target/app/a.txt")));
+        assert_eq!(
+            fs.file_content(root.join("target/app/local.txt")).unwrap(),
+            b"local"
+        );
+    }
+
+    #[test]
+    fn test_execute_updates_state_file_transactionally() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        let state_path = root.join("target").join(state::STATE_FILE_NAME);
+        fs.add_file(root.join("stow/pkg/a.txt"), "a");
+        fs.add_dir(root.join("target"));
+
+        let mut plan = Plan::new();
+        plan.add(Action::CreateFileLink {
+            link_path: root.join("target/app/a.txt"),
+            target_path: PathBuf::from("../../stow/pkg/a.txt"),
+            mode: LinkMode::Symlink,
+        });
+        plan.set_package("pkg");
+        plan.track_state(state_path.clone());
+        plan.execute(&fs, false).unwrap();
+
+        let state = State::load(&fs, &state_path).unwrap();
+        let package = &state.packages["pkg"];
+        assert_eq!(package.links[0].path, PathBuf::from("app/a.txt"));
+        assert_eq!(package.dirs, vec![PathBuf::from("app")]);
+
+        // A failing action also reverts the state file
+        let mut plan = Plan::new();
+        plan.add(Action::CreateFileLink {
+            link_path: root.join("target/b.txt"),
+            target_path: PathBuf::from("../stow/pkg/b.txt"),
+            mode: LinkMode::Symlink,
+        });
+        plan.add(Action::RemoveLink {
+            path: root.join("target/missing"),
+        });
+        plan.set_package("pkg");
+        plan.track_state(state_path.clone());
+        let before = fs.file_content(&state_path).unwrap();
+
+        assert!(plan.execute(&fs, false).is_err());
+        assert!(fs.symlink_metadata(&root.join("target/b.txt")).is_err());
+        assert_eq!(fs.file_content(&state_path).unwrap(), before);
+    }
+
+    #[test]
+    fn test_execute_falls_back_when_links_are_not_permitted() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        let state_path = root.join("target").join(state::STATE_FILE_NAME);
+        let history_path = crate::history::index_path(&root.join("target"));
+        fs.add_file(root.join("stow/pkg/a.txt"), "a");
+        fs.add_file(root.join("stow/pkg/b.txt"), "b");
+        fs.add_dir(root.join("target"));
+        fs.deny_links(LinkMode::Symlink);
+
+        let link_plan = |name: &str, fallback: Vec<LinkMode>| {
+            let mut plan = Plan::new();
+            plan.add(Action::CreateFileLink {
+                link_path: root.join("target").join(name),
+                target_path: PathBuf::from("../stow/pkg").join(name),
+                mode: LinkMode::Symlink,
+            });
+            plan.set_package("pkg");
+            plan.track_state(state_path.clone());
+            plan.track_history(history_path.clone(), "winstow pkg".to_string());
+            plan.set_fallback(fallback);
+            plan
+        };
+
+        // Without a fallback the link fails
+        let err = link_plan("a.txt", vec![]).execute(&fs, false).unwrap_err();
+        assert!(err.is_link_not_permitted());
+
+        link_plan("a.txt", vec![LinkMode::Hardlink, LinkMode::Copy])
+            .execute(&fs, false)
+            .unwrap();
+        assert!(fs.same_file(&root.join("target/a.txt"), &root.join("stow/pkg/a.txt")));
+
+        fs.deny_links(LinkMode::Hardlink);
+        link_plan("b.txt", vec![LinkMode::Hardlink, LinkMode::Copy])
+            .execute(&fs, false)
+            .unwrap();
+        let copy = root.join("target/b.txt");
+        assert!(!fs.is_symlink(&copy));
+        assert!(!fs.same_file(&copy, &root.join("stow/pkg/b.txt")));
+        assert_eq!(fs.file_content(&copy).unwrap(), b"b");
+
+        // The mode that was used is recorded for each link
+        let state = State::load(&fs, &state_path).unwrap();
+        let modes: Vec<LinkMode> = state.packages["pkg"].links.iter().map(|l| l.mode).collect();
+        assert_eq!(modes, [LinkMode::Hardlink, LinkMode::Copy]);
+
+        let history = History::load(&fs, &history_path).unwrap();
+        let operation = history.operations.last().unwrap();
+        assert!(matches!(
+            operation.actions[0].action,
+            Action::CreateFileLink {
+                mode: LinkMode::Copy,
+                ..
+            }
+        ));
+        assert!(matches!(
+            operation.undo[0].action,
+            Action::RemoveCopy { .. }
+        ));
+    }
+
+    #[test]
+    fn test_save_and_load_keeps_fallback() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let mut plan = Plan::new();
+        plan.set_fallback(vec![LinkMode::Hardlink, LinkMode::Copy]);
+
+        for name in ["plan.json", "plan.toml"] {
+            let path = temp_dir.path().join(name);
+            plan.save(&path).unwrap();
+            assert_eq!(Plan::load(&path).unwrap().fallback, plan.fallback);
+        }
+    }
 }
@@ -0,0 +1,608 @@
+use crate::error::{Result, StowError};
+use crate::filesystem::Filesystem;
+use crate::fs_ops::LinkMode;
+use crate::path_utils;
+use crate::planner::{Action, Step};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the state file kept in the target directory
+pub const STATE_FILE_NAME: &str = ".winstow-state.toml";
+
+/// Version of the state file format
+const STATE_FORMAT_VERSION: u32 = 1;
+
+/// Kind of a recorded link
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkKind {
+    File,
+    Dir,
+}
+
+/// A link created by winstow
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LinkRecord {
+    /// Path of the link, relative to the target directory
+    pub path: PathBuf,
+    /// Relative target of the link, as written
+    pub target: PathBuf,
+    pub kind: LinkKind,
+    /// How the file was put into the target
+    #[serde(default, skip_serializing_if = "LinkMode::is_symlink")]
+    pub mode: LinkMode,
+    /// Content hash of a hard link or copy when it was created
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+impl LinkRecord {
+    /// Check if the link recorded for the absolute `path` is still in place
+    /// A hard link or copy is as long as it shares the package file or its content is unchanged
+    fn is_in_place(&self, fs: &dyn Filesystem, path: &Path) -> bool {
+        if self.mode.is_symlink() {
+            return fs.read_link(path).ok().as_ref() == Some(&self.target);
+        }
+
+        if !fs
+            .symlink_metadata(path)
+            .is_ok_and(|m| !m.is_dir() && !m.is_symlink())
+        {
+            return false;
+        }
+
+        fs.same_file(path, &path_utils::link_destination(path, &self.target))
+            || self.hash.as_ref().is_some_and(|hash| {
+                fs.read_file(path)
+                    .is_ok_and(|content| content_hash(&content) == *hash)
+            })
+    }
+}
+
+/// A directory link of another package that was unfolded into a real directory
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UnfoldRecord {
+    /// Path of the unfolded directory, relative to the target directory
+    pub path: PathBuf,
+    /// Where the directory link pointed before
+    pub original_target: PathBuf,
+}
+
+/// Everything winstow did in the target for one package
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PackageState {
+    /// Time of the last operation, in seconds since the Unix epoch
+    pub updated: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub links: Vec<LinkRecord>,
+    /// Real directories created while stowing the package
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dirs: Vec<PathBuf>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unfolds: Vec<UnfoldRecord>,
+}
+
+impl PackageState {
+    /// Check if nothing is recorded for the package
+    fn is_empty(&self) -> bool {
+        self.links.is_empty() && self.dirs.is_empty() && self.unfolds.is_empty()
+    }
+}
+
+/// Persistent record of what winstow installed into a target directory
+///
+/// Paths are stored relative to the directory containing the state file, so a
+/// target can be moved without invalidating its state.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct State {
+    version: u32,
+    #[serde(default)]
+    pub packages: BTreeMap<String, PackageState>,
+    /// Directory the recorded paths are relative to
+    #[serde(skip)]
+    base: PathBuf,
+}
+
+impl State {
+    /// Create an empty state for the state file at `path`
+    pub fn new(path: &Path) -> Self {
+        Self {
+            version: STATE_FORMAT_VERSION,
+            packages: BTreeMap::new(),
+            base: path.parent().unwrap_or(path).to_path_buf(),
+        }
+    }
+
+    /// Read the state file at `path`, or start with an empty state if there is none
+    pub fn load(fs: &dyn Filesystem, path: &Path) -> Result<Self> {
+        if fs.symlink_metadata(path).is_err() {
+            return Ok(Self::new(path));
+        }
+
+        let content = fs.read_file(path)?;
+        let content = String::from_utf8(content)
+            .map_err(|e| StowError::state_error(format!("{}: {}", path.display(), e)))?;
+        let mut state: State = toml::from_str(&content)
+            .map_err(|e| StowError::state_error(format!("{}: {}", path.display(), e)))?;
+
+        if state.version != STATE_FORMAT_VERSION {
+            return Err(StowError::state_error(format!(
+                "{}: unsupported format version {} (expected {})",
+                path.display(),
+                state.version,
+                STATE_FORMAT_VERSION
+            )));
+        }
+
+        state.base = path.parent().unwrap_or(path).to_path_buf();
+        Ok(state)
+    }
+
+    /// Write the state file at `path`
+    pub fn save(&self, fs: &dyn Filesystem, path: &Path) -> Result<()> {
+        let content =
+            toml::to_string_pretty(self).map_err(|e| StowError::state_error(e.to_string()))?;
+        fs.write_file(path, content.as_bytes())
+    }
+
+    /// Record the effects of a step executed on `fs`
+    /// `created_dirs` are the directories that were missing before the step
+    pub fn record(&mut self, fs: &dyn Filesystem, step: &Step, created_dirs: &[PathBuf], now: u64) {
+        match &step.action {
+            Action::CreateFileLink {
+                link_path,
+                target_path,
+                ..
+            }
+            | Action::CreateDirLink {
+                link_path,
+                target_path,
+            } => {
+                let path = self.relative(link_path);
+                self.forget_links(|p| p == path);
+
+                let (kind, mode) = match step.action {
+                    Action::CreateFileLink { mode, .. } => (LinkKind::File, mode),
+                    _ => (LinkKind::Dir, LinkMode::Symlink),
+                };
+                // Hard links and copies are recognized by content once they stop sharing the file
+                let hash = match mode {
+                    LinkMode::Symlink => None,
+                    _ => fs.read_file(link_path).ok().map(|c| content_hash(&c)),
+                };
+                let dirs: Vec<PathBuf> = created_dirs.iter().map(|d| self.relative(d)).collect();
+                if let Some(package) = self.package_mut(step, now) {
+                    package.links.push(LinkRecord {
+                        path,
+                        target: target_path.clone(),
+                        kind,
+                        mode,
+                        hash,
+                    });
+                    for dir in dirs {
+                        if !package.dirs.contains(&dir) {
+                            package.dirs.push(dir);
+                        }
+                    }
+                }
+            }
+
+            Action::UnfoldDirLink {
+                link_path,
+                original_target,
+            } => {
+                let path = self.relative(link_path);
+                self.forget_links(|p| p == path);
+
+                if let Some(package) = self.package_mut(step, now) {
+                    package.dirs.push(path.clone());
+                    package.unfolds.push(UnfoldRecord {
+                        path,
+                        original_target: original_target.clone(),
+                    });
+                }
+            }
+
+            Action::RefoldDir {
+                link_path,
+                target_path,
+            } => {
+                let path = self.relative(link_path);
+                self.forget_links(|p| p.starts_with(&path));
+                for package in self.packages.values_mut() {
+                    package.dirs.retain(|d| *d != path);
+                    package.unfolds.retain(|u| u.path != path);
+                }
+
+                if let Some(package) = self.package_mut(step, now) {
+                    package.links.push(LinkRecord {
+                        path,
+                        target: target_path.clone(),
+                        kind: LinkKind::Dir,
+                        mode: LinkMode::Symlink,
+                        hash: None,
+                    });
+                }
+            }
+
+            // A materialized link leaves a real copy behind that isn't managed anymore
+            Action::RemoveLink { path }
+            | Action::RemoveCopy { path, .. }
+            | Action::MaterializeLink { path, .. } => {
+                let path = self.relative(path);
+                self.forget_links(|p| p == path);
+                self.package_mut(step, now);
+            }
+
+            // Directories are only removed when empty, which `reconcile` picks up
+            Action::RemoveEmptyDir { .. }
+            | Action::AdoptFile { .. }
+            | Action::AdoptDir { .. }
+            | Action::RemoveConflict { .. }
+            | Action::BackupConflict { .. }
+            | Action::RestoreBackup { .. }
+            | Action::MovePath { .. }
+            | Action::CopyFile { .. }
+            | Action::CreateDir { .. } => {}
+        }
+    }
+
+    /// Drop records that no longer match the filesystem, and packages without records
+    pub fn reconcile(&mut self, fs: &dyn Filesystem) {
+        let base = self.base.clone();
+        let is_real_dir = |path: &Path| {
+            fs.symlink_metadata(&base.join(path))
+                .is_ok_and(|m| m.is_dir())
+        };
+
+        for package in self.packages.values_mut() {
+            package
+                .links
+                .retain(|link| link.is_in_place(fs, &base.join(&link.path)));
+            package.dirs.retain(|dir| is_real_dir(dir));
+            package.unfolds.retain(|unfold| is_real_dir(&unfold.path));
+        }
+
+        self.packages.retain(|_, package| !package.is_empty());
+    }
+
+    /// Record of the link at the absolute `path`, if it is still in place
+    pub fn link_in_place(&self, fs: &dyn Filesystem, path: &Path) -> Option<&LinkRecord> {
+        let relative = self.relative(path);
+
+        self.packages
+            .values()
+            .flat_map(|package| &package.links)
+            .find(|link| link.path == relative)
+            .filter(|link| link.is_in_place(fs, path))
+    }
+
+    /// How the file at the absolute `path` was put into the target, if it is recorded
+    pub fn link_mode(&self, path: &Path) -> Option<LinkMode> {
+        let relative = self.relative(path);
+
+        self.packages
+            .values()
+            .flat_map(|package| &package.links)
+            .find(|link| link.path == relative)
+            .map(|link| link.mode)
+    }
+
+    /// Absolute paths of the links recorded for a package
+    pub fn link_paths(&self, package_name: &str) -> Vec<PathBuf> {
+        self.packages
+            .iter()
+            .filter(|(name, _)| path_utils::paths_equal(Path::new(name), Path::new(package_name)))
+            .flat_map(|(_, package)| &package.links)
+            .map(|link| self.base.join(&link.path))
+            .collect()
+    }
+
+    /// Get the state of a package touched by a step, marking it as updated
+    fn package_mut(&mut self, step: &Step, now: u64) -> Option<&mut PackageState> {
+        let package = self.packages.entry(step.package.clone()?).or_default();
+        package.updated = now;
+        Some(package)
+    }
+
+    /// Remove the link records matching a relative path from every package
+    fn forget_links(&mut self, matches: impl Fn(&Path) -> bool) {
+        for package in self.packages.values_mut() {
+            package.links.retain(|link| !matches(&link.path));
+        }
+    }
+
+    /// Path relative to the state's base directory, or unchanged if outside of it
+    fn relative(&self, path: &Path) -> PathBuf {
+        path_utils::strip_base(path, &self.base).unwrap_or_else(|| path.to_path_buf())
+    }
+}
+
+/// Current time in seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Read the state file of a target directory
+/// Used while planning to tell which links, hard links and copies belong to a package
+pub fn load_target(fs: &dyn Filesystem, target_dir: &Path) -> Result<Rc<State>> {
+    State::load(fs, &target_dir.join(STATE_FILE_NAME)).map(Rc::new)
+}
+
+/// Hash of file contents used to recognize copies (64-bit FNV-1a, as hex)
+pub fn content_hash(content: &[u8]) -> String {
+    let hash = content
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    format!("{:016x}", hash)
+}
+
+/// Check if `path` is a link, hard link or copy of `source_norm` put there by winstow
+/// A recorded link still in place is owned by whatever its record points to; links the
+/// state file doesn't know about (e.g. stowed before it existed) are recognized on disk.
+/// The source path must be normalized
+pub fn is_deployed(
+    fs: &dyn Filesystem,
+    state: Option<&State>,
+    path: &Path,
+    source_norm: &Path,
+) -> bool {
+    if let Some(link) = state.and_then(|s| s.link_in_place(fs, path)) {
+        let destination = path_utils::link_destination(path, &link.target);
+        let destination = fs.normalize_path(&destination).unwrap_or(destination);
+        return path_utils::paths_equal(destination, source_norm);
+    }
+
+    match fs.symlink_metadata(path) {
+        Ok(m) if m.is_symlink() => fs
+            .resolve_link(path)
+            .is_ok_and(|target| path_utils::paths_equal(target, source_norm)),
+        Ok(m) if !m.is_dir() => fs.same_file(path, source_norm),
+        _ => false,
+    }
+}
+
+/// Directories missing above a path, outermost first
+pub fn missing_parents(fs: &dyn Filesystem, path: &Path) -> Vec<PathBuf> {
+    let mut missing = Vec::new();
+    let mut current = path.parent();
+    while let Some(dir) = current {
+        if fs.symlink_metadata(dir).is_ok() {
+            break;
+        }
+        missing.push(dir.to_path_buf());
+        current = dir.parent();
+    }
+
+    missing.reverse();
+    missing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memfs::MemoryFilesystem;
+    use crate::planner;
+
+    fn step(package: &str, action: Action) -> Step {
+        Step {
+            package: Some(package.to_string()),
+            action,
+            expect: None,
+        }
+    }
+
+    #[test]
+    fn test_record_and_round_trip() {
+        let fs = MemoryFilesystem::new();
+        let target = MemoryFilesystem::root().join("target");
+        let state_path = target.join(STATE_FILE_NAME);
+        fs.add_dir(&target);
+
+        let mut state = State::load(&fs, &state_path).unwrap();
+        assert!(state.packages.is_empty());
+
+        state.record(
+            &fs,
+            &step(
+                "pkg",
+                Action::CreateFileLink {
+                    link_path: target.join("app/a.txt"),
+                    target_path: PathBuf::from("../../stow/pkg/app/a.txt"),
+                    mode: LinkMode::Symlink,
+                },
+            ),
+            &[target.join("app")],
+            42,
+        );
+        state.record(
+            &fs,
+            &step(
+                "pkg",
+                Action::CreateDirLink {
+                    link_path: target.join("dir"),
+                    target_path: PathBuf::from("../stow/pkg/dir"),
+                },
+            ),
+            &[],
+            42,
+        );
+        state.save(&fs, &state_path).unwrap();
+
+        let loaded = State::load(&fs, &state_path).unwrap();
+        let package = &loaded.packages["pkg"];
+        assert_eq!(package.updated, 42);
+        assert_eq!(package.dirs, vec![PathBuf::from("app")]);
+        assert_eq!(
+            package.links,
+            vec![
+                LinkRecord {
+                    path: PathBuf::from("app/a.txt"),
+                    target: PathBuf::from("../../stow/pkg/app/a.txt"),
+                    kind: LinkKind::File,
+                    mode: LinkMode::Symlink,
+                    hash: None,
+                },
+                LinkRecord {
+                    path: PathBuf::from("dir"),
+                    target: PathBuf::from("../stow/pkg/dir"),
+                    kind: LinkKind::Dir,
+                    mode: LinkMode::Symlink,
+                    hash: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unfold_moves_ownership_and_reconcile_drops_removed_entries() {
+        let fs = MemoryFilesystem::new();
+        let target = MemoryFilesystem::root().join("target");
+        let mut state = State::new(&target.join(STATE_FILE_NAME));
+
+        state.record(
+            &fs,
+            &step(
+                "pkg1",
+                Action::CreateDirLink {
+                    link_path: target.join("shared"),
+                    target_path: PathBuf::from("../stow/pkg1/shared"),
+                },
+            ),
+            &[],
+            1,
+        );
+        state.record(
+            &fs,
+            &step(
+                "pkg2",
+                Action::UnfoldDirLink {
+                    link_path: target.join("shared"),
+                    original_target: PathBuf::from("/stow/pkg1/shared"),
+                },
+            ),
+            &[],
+            2,
+        );
+
+        assert!(state.packages["pkg1"].links.is_empty());
+        assert_eq!(state.packages["pkg2"].unfolds.len(), 1);
+
+        // Nothing exists on disk, so every record is dropped
+        state.reconcile(&fs);
+        assert!(state.packages.is_empty());
+    }
+
+    #[test]
+    fn test_copies_are_recognized_by_file_or_content() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        let target = root.join("target");
+        let source = root.join("stow/pkg/a.txt");
+        fs.add_file(&source, "a");
+        fs.add_file(root.join("stow/pkg/b.txt"), "b");
+        let mut state = State::new(&target.join(STATE_FILE_NAME));
+
+        let mut deploy = |name: &str, mode: LinkMode| {
+            let link_path = target.join(name);
+            let target_path = PathBuf::from("../stow/pkg").join(name);
+            planner::create_file_link(&fs, &link_path, &target_path, mode).unwrap();
+            let action = Action::CreateFileLink {
+                link_path,
+                target_path,
+                mode,
+            };
+            state.record(&fs, &step("pkg", action), &[], 1);
+        };
+        deploy("a.txt", LinkMode::Hardlink);
+        deploy("b.txt", LinkMode::Copy);
+
+        let link = &state.packages["pkg"].links[0];
+        assert_eq!(link.mode, LinkMode::Hardlink);
+        assert_eq!(link.hash.as_deref(), Some(content_hash(b"a").as_str()));
+        assert_eq!(state.link_mode(&target.join("b.txt")), Some(LinkMode::Copy));
+        assert_eq!(state.link_mode(&target.join("c.txt")), None);
+
+        assert!(is_deployed(&fs, None, &target.join("a.txt"), &source));
+        let copy = target.join("b.txt");
+        let copy_source = root.join("stow/pkg/b.txt");
+        assert!(!is_deployed(&fs, None, &copy, &copy_source));
+        assert!(is_deployed(&fs, Some(&state), &copy, &copy_source));
+        assert!(!is_deployed(&fs, Some(&state), &copy, &source));
+
+        // A copy edited in the target is no longer the package's
+        fs.write_file(&copy, b"edited").unwrap();
+        assert!(!is_deployed(&fs, Some(&state), &copy, &copy_source));
+        state.reconcile(&fs);
+        assert_eq!(state.packages["pkg"].links.len(), 1);
+    }
+
+    #[test]
+    fn test_recorded_symlinks_are_owned_by_their_record() {
+        let fs = MemoryFilesystem::new();
+        let root = MemoryFilesystem::root();
+        let target = root.join("target");
+        let source = root.join("stow/pkg/a.txt");
+        fs.add_file(&source, "a");
+        let link_path = target.join("a.txt");
+        fs.create_link(&link_path, Path::new("../stow/pkg/a.txt"), false)
+            .unwrap();
+        let mut state = State::new(&target.join(STATE_FILE_NAME));
+        let action = Action::CreateFileLink {
+            link_path: link_path.clone(),
+            target_path: PathBuf::from("../stow/pkg/a.txt"),
+            mode: LinkMode::Symlink,
+        };
+        state.record(&fs, &step("pkg", action), &[], 1);
+
+        assert!(state.link_in_place(&fs, &link_path).is_some());
+        assert!(is_deployed(&fs, Some(&state), &link_path, &source));
+        assert!(!is_deployed(
+            &fs,
+            Some(&state),
+            &link_path,
+            &root.join("stow/other/a.txt")
+        ));
+
+        // A link changed since it was recorded is only judged by where it points now
+        fs.remove_link(&link_path).unwrap();
+        fs.create_link(&link_path, &root.join("elsewhere/a.txt"), false)
+            .unwrap();
+        assert!(state.link_in_place(&fs, &link_path).is_none());
+        assert!(!is_deployed(&fs, Some(&state), &link_path, &source));
+    }
+
+    #[test]
+    fn test_load_target_reports_corrupt_state_file() {
+        let fs = MemoryFilesystem::new();
+        let target = MemoryFilesystem::root().join("target");
+        fs.add_file(target.join(STATE_FILE_NAME), "version = ");
+
+        assert!(matches!(
+            load_target(&fs, &target),
+            Err(StowError::StateError(_))
+        ));
+    }
+
+    #[test]
+    fn test_load_rejects_unknown_version() {
+        let fs = MemoryFilesystem::new();
+        let path = MemoryFilesystem::root()
+            .join("target")
+            .join(STATE_FILE_NAME);
+        fs.add_file(&path, "version = 99\n");
+
+        assert!(matches!(
+            State::load(&fs, &path),
+            Err(StowError::StateError(_))
+        ));
+    }
+}
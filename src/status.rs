@@ -0,0 +1,222 @@
+use crate::error::{Conflict, ConflictKind, Result};
+use crate::filesystem::Filesystem;
+use crate::ignore;
+use crate::stow::PROTECTION_MARKERS;
+use serde::Serialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// How much of a package is installed in the target
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StowState {
+    /// Every entry of the package is linked
+    Stowed,
+    /// Some entries are linked, others are missing
+    Partial,
+    /// No entry of the package is linked
+    NotStowed,
+    /// Something else is in the way of at least one entry
+    Conflicting,
+}
+
+impl fmt::Display for StowState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StowState::Stowed => write!(f, "stowed"),
+            StowState::Partial => write!(f, "partially stowed"),
+            StowState::NotStowed => write!(f, "not stowed"),
+            StowState::Conflicting => write!(f, "conflicting"),
+        }
+    }
+}
+
+/// Installation state of a package, found by planning it without executing anything
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackageStatus {
+    pub name: String,
+    /// Target paths already linked to the package
+    pub linked: Vec<PathBuf>,
+    /// Target paths a stow would link
+    pub missing: Vec<PathBuf>,
+    /// Links to somewhere else, including other packages, in the way of package entries
+    pub foreign_links: Vec<Conflict>,
+    /// Real files or directories in the way of package entries
+    pub shadowed: Vec<Conflict>,
+    /// Target paths skipped because of a defer pattern
+    pub deferred: Vec<PathBuf>,
+}
+
+impl PackageStatus {
+    /// Create a status from what planning the package found
+    pub fn new(
+        name: &str,
+        linked: Vec<PathBuf>,
+        missing: Vec<PathBuf>,
+        conflicts: Vec<Conflict>,
+        deferred: Vec<PathBuf>,
+    ) -> Self {
+        let (shadowed, foreign_links) = conflicts.into_iter().partition(|c| {
+            matches!(
+                c.kind,
+                ConflictKind::File | ConflictKind::Dir | ConflictKind::Differs
+            )
+        });
+
+        Self {
+            name: name.to_string(),
+            linked,
+            missing,
+            foreign_links,
+            shadowed,
+            deferred,
+        }
+    }
+
+    /// Summarize the status
+    pub fn state(&self) -> StowState {
+        if !self.foreign_links.is_empty() || !self.shadowed.is_empty() {
+            StowState::Conflicting
+        } else if self.missing.is_empty() {
+            StowState::Stowed
+        } else if self.linked.is_empty() {
+            StowState::NotStowed
+        } else {
+            StowState::Partial
+        }
+    }
+}
+
+/// Summary of a package in the stow directory, as shown by `list`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PackageInfo {
+    pub name: String,
+    /// Number of files the package deploys
+    pub files: usize,
+    pub state: StowState,
+    /// Target-relative paths of the deployed files, when requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tree: Option<Vec<String>>,
+}
+
+impl PackageInfo {
+    /// Create the summary of a package from its files and status
+    pub fn new(status: &PackageStatus, files: &[PathBuf], with_tree: bool) -> Self {
+        Self {
+            name: status.name.clone(),
+            files: files.len(),
+            state: status.state(),
+            tree: with_tree.then(|| {
+                files
+                    .iter()
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .collect()
+            }),
+        }
+    }
+}
+
+/// Format package summaries as a JSON array
+pub fn to_json(packages: &[PackageInfo]) -> String {
+    // Only strings, numbers and unit variants are serialized, which can't fail
+    serde_json::to_string_pretty(packages).expect("package summaries serialize to JSON")
+}
+
+/// Names of the packages in a stow directory, sorted
+/// Hidden directories, directories matching an ignore pattern and directories
+/// containing a protection marker are not packages
+pub fn list_packages(
+    fs: &dyn Filesystem,
+    stow_dir: &Path,
+    patterns: &ignore::PatternSet,
+) -> Result<Vec<String>> {
+    let mut names: Vec<String> = fs
+        .read_dir(stow_dir)?
+        .into_iter()
+        .filter(|path| fs.is_dir(path) && !patterns.should_ignore(path))
+        .filter(|path| {
+            !PROTECTION_MARKERS
+                .iter()
+                .any(|marker| fs.exists(&path.join(marker)))
+        })
+        .filter_map(|path| Some(path.file_name()?.to_string_lossy().into_owned()))
+        .filter(|name| !name.starts_with('.'))
+        .collect();
+
+    names.sort();
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memfs::MemoryFilesystem;
+
+    fn conflict(path: &str, kind: ConflictKind) -> Conflict {
+        Conflict {
+            path: PathBuf::from(path),
+            package: Some("pkg".to_string()),
+            source: None,
+            kind,
+        }
+    }
+
+    #[test]
+    fn test_state() {
+        let linked = vec![PathBuf::from("a")];
+        let missing = vec![PathBuf::from("b")];
+
+        let status = PackageStatus::new("pkg", linked.clone(), vec![], vec![], vec![]);
+        assert_eq!(status.state(), StowState::Stowed);
+
+        let status = PackageStatus::new("pkg", linked.clone(), missing.clone(), vec![], vec![]);
+        assert_eq!(status.state(), StowState::Partial);
+
+        let status = PackageStatus::new("pkg", vec![], missing.clone(), vec![], vec![]);
+        assert_eq!(status.state(), StowState::NotStowed);
+
+        let conflicts = vec![
+            conflict("c", ConflictKind::File),
+            conflict("d", ConflictKind::Package("other".to_string())),
+        ];
+        let status = PackageStatus::new("pkg", linked, missing, conflicts, vec![]);
+        assert_eq!(status.state(), StowState::Conflicting);
+        assert_eq!(status.shadowed.len(), 1);
+        assert_eq!(status.foreign_links.len(), 1);
+    }
+
+    #[test]
+    fn test_package_info_json() {
+        let status = PackageStatus::new("vim", vec![PathBuf::from("a")], vec![], vec![], vec![]);
+        let files = vec![PathBuf::from(".vimrc")];
+
+        let json = to_json(&[PackageInfo::new(&status, &files, false)]);
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!([{ "name": "vim", "files": 1, "state": "stowed" }])
+        );
+
+        let json = to_json(&[PackageInfo::new(&status, &files, true)]);
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value[0]["tree"], serde_json::json!([".vimrc"]));
+    }
+
+    #[test]
+    fn test_list_packages() {
+        let fs = MemoryFilesystem::new();
+        let stow = MemoryFilesystem::root().join("stow");
+        fs.add_file(stow.join("vim/.vimrc"), "");
+        fs.add_file(stow.join("bash/.bashrc"), "");
+        fs.add_file(stow.join(".git/config"), "");
+        fs.add_file(stow.join("old/.nonstow"), "");
+        fs.add_file(stow.join("tmp.bak/file"), "");
+        fs.add_file(stow.join("README.md"), "");
+
+        let patterns = ignore::PatternSet::new(&["*.bak".to_string()], &[]).unwrap();
+        assert_eq!(
+            list_packages(&fs, &stow, &patterns).unwrap(),
+            vec!["bash".to_string(), "vim".to_string()]
+        );
+    }
+}
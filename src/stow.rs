@@ -1,7 +1,13 @@
-use crate::error::{Result, StowError};
-use crate::{adopt, fs_ops, ignore, logger, path_utils, planner};
-use std::fs;
+use crate::error::{Conflict, ConflictKind, Result, StowError};
+use crate::filesystem::Filesystem;
+use crate::fs_ops::LinkMode;
+use crate::overlay::OverlayFilesystem;
+use crate::state::{self, State};
+use crate::{adopt, backup, history, ignore, logger, path_utils, planner, status};
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
 /// Conflict resolution strategy
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -12,15 +18,122 @@ pub enum ConflictStrategy {
     Adopt,
     /// Override (remove) conflicting files
     Override,
+    /// Skip conflicting paths and link everything else
+    Skip,
+    /// Move conflicting files into a backup directory
+    Backup,
+}
+
+/// Marker files that protect a directory from being stowed into (GNU Stow compatible)
+pub const PROTECTION_MARKERS: [&str; 2] = [".stow", ".nonstow"];
+
+/// Check if a target directory must never be stowed into or unstowed from
+/// This is the case for the stow directory itself, the backup and history directories
+/// of the target and for any real directory containing a `.stow` or `.nonstow` marker file
+pub fn is_protected_dir(
+    fs: &dyn Filesystem,
+    path: &Path,
+    stow_dir: &Path,
+    target_dir: &Path,
+) -> bool {
+    if let Ok(path_norm) = fs.normalize_path(path) {
+        let is_dir = |dir: &Path| {
+            fs.normalize_path(dir)
+                .is_ok_and(|dir_norm| path_utils::paths_equal(&path_norm, &dir_norm))
+        };
+        if is_dir(stow_dir)
+            || is_dir(&target_dir.join(backup::BACKUP_DIR_NAME))
+            || is_dir(&target_dir.join(history::HISTORY_DIR_NAME))
+        {
+            return true;
+        }
+    }
+
+    fs.is_dir(path)
+        && !fs.is_symlink(path)
+        && PROTECTION_MARKERS
+            .iter()
+            .any(|marker| fs.exists(&path.join(marker)))
+}
+
+/// Which target directories may be replaced by a single directory link
+#[derive(Debug, Clone, PartialEq)]
+pub struct FoldPolicy {
+    /// Fold directories at all (`--no-folding` turns this off)
+    folding: bool,
+    /// Target-relative directories that are never folded, split into components
+    never_fold: Vec<PathBuf>,
+}
+
+impl Default for FoldPolicy {
+    fn default() -> Self {
+        Self {
+            folding: true,
+            never_fold: Vec::new(),
+        }
+    }
+}
+
+impl FoldPolicy {
+    /// Create a fold policy
+    /// `never_fold` paths are relative to the target directory and may use `/` or `\`
+    pub fn new(folding: bool, never_fold: &[String]) -> Self {
+        let never_fold = never_fold
+            .iter()
+            .map(|path| {
+                path.split(['/', '\\'])
+                    .filter(|part| !part.is_empty() && *part != ".")
+                    .collect()
+            })
+            .collect();
+
+        Self {
+            folding,
+            never_fold,
+        }
+    }
+
+    /// Check whether the directory at a target-relative path may be folded
+    pub fn allows(&self, relative: &Path) -> bool {
+        self.folding
+            && !self
+                .never_fold
+                .iter()
+                .any(|path| path_utils::paths_equal(path, relative))
+    }
+
+    /// Check whether the directory at a target path may be folded
+    pub fn allows_target(&self, target_dir: &Path, target_path: &Path) -> bool {
+        match path_utils::strip_base(target_path, target_dir) {
+            Some(relative) => self.allows(&relative),
+            None => self.folding,
+        }
+    }
 }
 
 /// Stow operation manager
+#[derive(Clone)]
 pub struct Stower {
     stow_dir: PathBuf,
     target_dir: PathBuf,
+    /// Used by the test helpers that plan and execute in one step
+    #[cfg(test)]
     dry_run: bool,
     conflict_strategy: ConflictStrategy,
+    /// Keep target files that differ from the package's version next to it when adopting
+    keep_both: bool,
+    /// Strategies chosen for single target paths, taking precedence over `conflict_strategy`
+    resolutions: BTreeMap<PathBuf, ConflictStrategy>,
+    /// Directory conflicting files are moved to by the backup strategy
+    backup_dir: PathBuf,
     patterns: ignore::PatternSet,
+    dotfiles: bool,
+    fold_policy: FoldPolicy,
+    /// How the package's files are put into the target
+    link_mode: LinkMode,
+    /// State of the target, used to recognize hard links and copies deployed earlier
+    state: Option<Rc<State>>,
+    fs: Rc<dyn Filesystem>,
 }
 
 /// Decision for how to handle a directory
@@ -28,6 +141,10 @@ pub struct Stower {
 enum FoldDecision {
     /// Create a single directory symlink (fold)
     Fold,
+    /// Target is already a symlink to this directory, nothing to do
+    AlreadyLinked,
+    /// Target doesn't exist but must not be folded, create it while linking children
+    Create,
     /// Remove existing symlink and traverse into directory (unfold)
     Unfold(PathBuf), // Contains the original target
     /// Traverse into directory and link children (already a real directory)
@@ -45,9 +162,18 @@ impl Stower {
         Self {
             stow_dir: context.stow_dir().to_owned(),
             target_dir: context.target_dir().to_owned(),
+            #[cfg(test)]
             dry_run: context.is_dry_run(),
             conflict_strategy: context.conflict_strategy(),
+            keep_both: context.adopt_keep_both(),
+            resolutions: BTreeMap::new(),
+            backup_dir: backup::backup_dir(context.target_dir(), &backup::new_backup_id()),
             patterns,
+            dotfiles: context.dotfiles(),
+            fold_policy: context.fold_policy().clone(),
+            link_mode: context.link_mode(),
+            state: None,
+            fs: Rc::clone(context.filesystem()),
         }
     }
 
@@ -59,12 +185,21 @@ impl Stower {
         _verbose: bool,
         dry_run: bool,
     ) -> Self {
+        let target_dir = target_dir.into();
         Self {
             stow_dir: stow_dir.into(),
-            target_dir: target_dir.into(),
+            backup_dir: backup::backup_dir(&target_dir, "test"),
+            target_dir,
             dry_run,
             conflict_strategy: ConflictStrategy::Fail,
+            keep_both: false,
+            resolutions: BTreeMap::new(),
             patterns: ignore::PatternSet::empty(),
+            dotfiles: false,
+            fold_policy: FoldPolicy::default(),
+            link_mode: LinkMode::Symlink,
+            state: None,
+            fs: Rc::new(crate::filesystem::OsFilesystem),
         }
     }
 
@@ -75,6 +210,19 @@ impl Stower {
         self
     }
 
+    /// Keep target files that differ from the package's version when adopting
+    #[cfg(test)]
+    pub fn with_keep_both(mut self, keep_both: bool) -> Self {
+        self.keep_both = keep_both;
+        self
+    }
+
+    /// Use a conflict strategy for single target paths, e.g. as answered interactively
+    pub fn with_resolutions(mut self, resolutions: BTreeMap<PathBuf, ConflictStrategy>) -> Self {
+        self.resolutions = resolutions;
+        self
+    }
+
     /// Set the ignore and defer patterns
     #[cfg(test)]
     pub fn with_patterns(mut self, patterns: ignore::PatternSet) -> Self {
@@ -82,17 +230,303 @@ impl Stower {
         self
     }
 
+    /// Enable or disable `dot-` prefix translation
+    #[cfg(test)]
+    pub fn with_dotfiles(mut self, dotfiles: bool) -> Self {
+        self.dotfiles = dotfiles;
+        self
+    }
+
+    /// Set the fold policy
+    #[cfg(test)]
+    pub fn with_fold_policy(mut self, fold_policy: FoldPolicy) -> Self {
+        self.fold_policy = fold_policy;
+        self
+    }
+
+    /// Set how the package's files are put into the target, never folding without symlinks
+    #[cfg(test)]
+    pub fn with_link_mode(mut self, link_mode: LinkMode) -> Self {
+        if !link_mode.is_symlink() {
+            self.fold_policy = FoldPolicy::new(false, &[]);
+        }
+        self.link_mode = link_mode;
+        self
+    }
+
+    /// Set the filesystem to operate on
+    #[cfg(test)]
+    pub fn with_filesystem(mut self, fs: Rc<dyn Filesystem>) -> Self {
+        self.fs = fs;
+        self
+    }
+
     /// Stow a package
+    #[cfg(test)]
     #[must_use = "stow operations can fail and should be checked"]
     pub fn stow_package(&self, package_name: &str) -> Result<()> {
+        self.stow_packages(&[package_name.to_string()])
+    }
+
+    /// Stow several packages with a single combined plan
+    /// Nothing is executed unless every package could be planned without conflicts
+    #[cfg(test)]
+    #[must_use = "stow operations can fail and should be checked"]
+    pub fn stow_packages(&self, package_names: &[String]) -> Result<()> {
+        let overlay = Rc::new(OverlayFilesystem::new(Rc::clone(&self.fs)));
+        let mut plan = planner::Plan::new();
+        self.plan_packages(package_names, &overlay, &mut plan)?;
+
+        logger::verbose(&format!("Plan has {} actions", plan.len()));
+
+        // Execute the combined plan
+        plan.execute(self.fs.as_ref(), self.dry_run)?;
+
+        Ok(())
+    }
+
+    /// Plan stowing packages one after another on top of an overlay
+    /// Each package's plan is applied to the overlay, so later packages see the links
+    /// and unfolded directories of earlier ones. The plans are appended to `plan`.
+    pub fn plan_packages(
+        &self,
+        package_names: &[String],
+        overlay: &Rc<OverlayFilesystem>,
+        plan: &mut planner::Plan,
+    ) -> Result<()> {
+        self.plan_on_overlay(package_names, overlay, plan)?;
+
+        // Conflicts of all packages are reported together
+        let (skipped, conflicts): (Vec<Conflict>, Vec<Conflict>) = plan
+            .take_conflicts()
+            .into_iter()
+            .partition(|conflict| self.strategy_for(&conflict.path) == ConflictStrategy::Skip);
+
+        if !conflicts.is_empty() {
+            return Err(StowError::conflicts(conflicts));
+        }
+
+        if !skipped.is_empty() {
+            logger::warn(&format!("Skipping {} conflicting path(s):", skipped.len()));
+            for conflict in &skipped {
+                logger::info(&format!("  - {}", conflict));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Find the conflicts stowing packages on top of an overlay would run into
+    /// Nothing is resolved and the overlay is left unchanged
+    pub fn find_conflicts(
+        &self,
+        package_names: &[String],
+        overlay: &Rc<OverlayFilesystem>,
+    ) -> Result<Vec<Conflict>> {
+        let scratch = Rc::new(OverlayFilesystem::new(
+            Rc::clone(overlay) as Rc<dyn Filesystem>
+        ));
+        let finder = Stower {
+            conflict_strategy: ConflictStrategy::Fail,
+            resolutions: BTreeMap::new(),
+            ..self.clone()
+        };
+
+        let mut plan = planner::Plan::new();
+        finder.plan_on_overlay(package_names, &scratch, &mut plan)?;
+        Ok(plan.take_conflicts())
+    }
+
+    /// Plan moving target paths into a package and stowing the package
+    /// Each path keeps its target-relative location in the package, which is created
+    /// if needed. The package is then stowed as usual, so the paths are linked back
+    /// with the normal fold decisions.
+    pub fn plan_adopt_paths(
+        &self,
+        package_name: &str,
+        target_paths: &[PathBuf],
+        overlay: &Rc<OverlayFilesystem>,
+        plan: &mut planner::Plan,
+    ) -> Result<()> {
+        let mut components = Path::new(package_name).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(std::path::Component::Normal(_)), None)
+        ) {
+            return Err(StowError::invalid_path(format!(
+                "Invalid package name: '{}'",
+                package_name
+            )));
+        }
+
+        let view = Stower {
+            fs: Rc::clone(overlay) as Rc<dyn Filesystem>,
+            ..self.clone()
+        };
+
+        let package_dir = self.stow_dir.join(package_name);
+        for target_path in target_paths {
+            let mut adopt_plan = planner::Plan::new();
+            let package_path = view.adopted_path(target_path, &package_dir)?;
+            if !view.plan_adopt(&mut adopt_plan, target_path, &package_path)? {
+                return Err(StowError::conflicts(adopt_plan.take_conflicts()));
+            }
+
+            adopt_plan.set_package(package_name);
+            adopt_plan.simulate(overlay)?;
+            plan.append(adopt_plan);
+        }
+
+        self.plan_packages(&[package_name.to_string()], overlay, plan)
+    }
+
+    /// Where a target path is moved to when it is adopted into a package directory
+    /// Only existing files and directories inside the target, outside of the stow
+    /// directory and not already linked can be adopted.
+    fn adopted_path(&self, target_path: &Path, package_dir: &Path) -> Result<PathBuf> {
+        let invalid = |reason: &str| {
+            StowError::invalid_path(format!(
+                "Cannot adopt {}: {}",
+                target_path.display(),
+                reason
+            ))
+        };
+
+        if !self.fs.exists(target_path) {
+            return Err(invalid("it does not exist"));
+        }
+        if self.fs.is_symlink(target_path) {
+            return Err(invalid("it is a link"));
+        }
+
+        // Resolve links in the parent, but not the path itself
+        let target_norm = match (target_path.parent(), target_path.file_name()) {
+            (Some(parent), Some(name)) => self.fs.normalize_path(parent)?.join(name),
+            _ => return Err(invalid("it is not inside the target directory")),
+        };
+        let stow_dir_norm = self.fs.normalize_path(&self.stow_dir)?;
+        if path_utils::strip_base(&target_norm, &stow_dir_norm).is_some() {
+            return Err(invalid("it is inside the stow directory"));
+        }
+        let relative = match path_utils::strip_base(&target_norm, &self.target_dir) {
+            Some(relative) if !relative.as_os_str().is_empty() => relative,
+            _ => return Err(invalid("it is not inside the target directory")),
+        };
+
+        let mut package_path = package_dir.to_path_buf();
+        for name in relative.iter() {
+            if self.dotfiles {
+                package_path.push(path_utils::dotfile_source_name(name));
+            } else {
+                package_path.push(name);
+            }
+        }
+
+        // An ignored entry would never be linked back
+        if self.patterns.should_ignore(&package_path) {
+            return Err(invalid("it matches an ignore pattern"));
+        }
+        Ok(package_path)
+    }
+
+    /// Plan packages one after another, simulating each plan on the overlay
+    fn plan_on_overlay(
+        &self,
+        package_names: &[String],
+        overlay: &Rc<OverlayFilesystem>,
+        plan: &mut planner::Plan,
+    ) -> Result<()> {
+        let view = Stower {
+            fs: Rc::clone(overlay) as Rc<dyn Filesystem>,
+            state: Some(state::load_target(overlay.as_ref(), &self.target_dir)?),
+            ..self.clone()
+        };
+
+        for package_name in package_names {
+            let mut package_plan = view.plan_package(package_name)?;
+            package_plan.set_package(package_name);
+            package_plan.simulate(overlay)?;
+            plan.append(package_plan);
+        }
+
+        Ok(())
+    }
+
+    /// Report how much of a package is installed, without changing anything
+    /// The package is planned as for a stow that fails on conflicts; the links
+    /// it would create are missing, everything in its way is reported.
+    pub fn status(&self, package_name: &str) -> Result<status::PackageStatus> {
+        let view = Stower {
+            conflict_strategy: ConflictStrategy::Fail,
+            state: Some(state::load_target(self.fs.as_ref(), &self.target_dir)?),
+            ..self.clone()
+        };
+
+        let mut plan = view.plan_package(package_name)?;
+        plan.set_package(package_name);
+
+        let missing = plan
+            .steps()
+            .iter()
+            .filter(|step| step.package.as_deref() == Some(package_name))
+            .filter_map(|step| match &step.action {
+                planner::Action::CreateFileLink { link_path, .. }
+                | planner::Action::CreateDirLink { link_path, .. } => Some(link_path.clone()),
+                _ => None,
+            })
+            .collect();
+
+        Ok(status::PackageStatus::new(
+            package_name,
+            plan.linked().to_vec(),
+            missing,
+            plan.take_conflicts(),
+            plan.deferred().to_vec(),
+        ))
+    }
+
+    /// Target-relative paths of the files a package deploys, sorted
+    /// Ignored entries are left out and `dot-` prefixes are translated when enabled
+    pub fn package_files(&self, package_name: &str) -> Result<Vec<PathBuf>> {
+        let package_path = self.stow_dir.join(package_name);
+        if !self.fs.is_dir(&package_path) {
+            return Err(StowError::package_not_found(package_name, &self.stow_dir));
+        }
+
+        let mut files = Vec::new();
+        let mut pending = vec![(package_path, PathBuf::new())];
+        while let Some((source_dir, relative_dir)) = pending.pop() {
+            for source_path in self.fs.read_dir(&source_dir)? {
+                let Some(name) = source_path.file_name() else {
+                    continue;
+                };
+                if self.patterns.should_ignore(&source_path) {
+                    continue;
+                }
+
+                let relative = relative_dir.join(self.target_name(name));
+                if self.fs.is_dir(&source_path) {
+                    pending.push((source_path, relative));
+                } else {
+                    files.push(relative);
+                }
+            }
+        }
+
+        files.sort();
+        Ok(files)
+    }
+
+    /// Plan stowing a single package against the current filesystem
+    fn plan_package(&self, package_name: &str) -> Result<planner::Plan> {
         let package_path = self.stow_dir.join(package_name);
 
         // Verify package exists
-        if !package_path.exists() {
+        if !self.fs.exists(&package_path) {
             return Err(StowError::package_not_found(package_name, &self.stow_dir));
         }
 
-        if !package_path.is_dir() {
+        if !self.fs.is_dir(&package_path) {
             return Err(StowError::invalid_path(format!(
                 "Package '{}' is not a directory",
                 package_name
@@ -104,15 +538,23 @@ impl Stower {
         // Create a plan
         let mut plan = planner::Plan::new();
 
+        if is_protected_dir(
+            self.fs.as_ref(),
+            &self.target_dir,
+            &self.stow_dir,
+            &self.target_dir,
+        ) {
+            logger::warn(&format!(
+                "Target directory {} is a stow directory, not stowing into it",
+                self.target_dir.display()
+            ));
+            return Ok(plan);
+        }
+
         // Traverse the package and build the plan
         self.plan_stow_directory(&package_path, &self.target_dir, &mut plan)?;
 
-        logger::verbose(&format!("Plan has {} actions", plan.len()));
-
-        // Execute the plan
-        plan.execute(self.dry_run)?;
-
-        Ok(())
+        Ok(plan)
     }
 
     /// Recursively plan stowing a directory
@@ -123,13 +565,10 @@ impl Stower {
         plan: &mut planner::Plan,
     ) -> Result<()> {
         // Read the source directory contents
-        let entries = fs::read_dir(source_dir)
-            .map_err(|e| StowError::io_error(source_dir.to_path_buf(), e))?;
-
-        for entry in entries {
-            let entry = entry.map_err(|e| StowError::io_error(source_dir.to_owned(), e))?;
-            let source_path = entry.path();
-            let name = entry.file_name();
+        for source_path in self.fs.read_dir(source_dir)? {
+            let Some(name) = source_path.file_name() else {
+                continue;
+            };
 
             // Check if this path should be ignored
             if self.patterns.should_ignore(&source_path) {
@@ -137,13 +576,9 @@ impl Stower {
                 continue;
             }
 
-            let target_path = target_parent.join(&name);
+            let target_path = target_parent.join(self.target_name(name));
 
-            let metadata = entry
-                .metadata()
-                .map_err(|e| StowError::io_error(source_path.clone(), e))?;
-
-            if metadata.is_dir() {
+            if self.fs.is_dir(&source_path) {
                 // Handle directory
                 self.plan_stow_dir_item(&source_path, &target_path, plan)?;
             } else {
@@ -163,10 +598,10 @@ impl Stower {
         plan: &mut planner::Plan,
     ) -> Result<()> {
         // Normalize source path once at the start to avoid repeated syscalls
-        let source_norm = path_utils::normalize_path(source_path)?;
+        let source_norm = self.fs.normalize_path(source_path)?;
 
         // Check if target exists
-        if target_path.exists() {
+        if self.fs.exists(target_path) {
             // Check if this path should be deferred (only when target exists)
             // This matches GNU Stow's behavior: defer only if already stowed by another package
             if self.patterns.should_defer(source_path) {
@@ -174,58 +609,65 @@ impl Stower {
                     "Deferring: {} (already exists)",
                     source_path.display()
                 ));
+                plan.add_deferred(target_path.to_path_buf());
                 return Ok(());
             }
 
-            // Check if it's already a symlink to the same source
-            if fs_ops::is_symlink(target_path) {
-                let link_target = fs_ops::read_symlink(target_path)?;
-                let link_target_abs = if link_target.is_relative() {
-                    target_path
-                        .parent()
-                        .unwrap_or(target_path)
-                        .join(&link_target)
-                } else {
-                    link_target.clone()
-                };
-
-                let link_target_norm = path_utils::normalize_path(&link_target_abs)?;
-
-                if path_utils::paths_equal(&link_target_norm, &source_norm) {
-                    // Already linked correctly, skip
-                    logger::verbose(&format!("Already linked: {}", target_path.display()));
-                    return Ok(());
-                }
+            // A link, hard link or copy of the source put there earlier
+            if state::is_deployed(
+                self.fs.as_ref(),
+                self.state.as_deref(),
+                target_path,
+                &source_norm,
+            ) {
+                logger::verbose(&format!("Already linked: {}", target_path.display()));
+                plan.add_linked(target_path.to_path_buf());
+                return Ok(());
             }
 
             // Conflict: target exists and is not the right symlink
+            // Links of other packages are never adopted or overridden
+            let kind = self.conflict_kind(target_path, &source_norm);
+            if matches!(kind, ConflictKind::Package(_)) {
+                self.record_conflict(plan, target_path, &source_norm, kind);
+                return Ok(());
+            }
+
             // Handle based on conflict strategy
-            match self.conflict_strategy {
-                ConflictStrategy::Fail => {
-                    return Err(StowError::conflict(target_path));
+            match self.strategy_for(target_path) {
+                ConflictStrategy::Fail | ConflictStrategy::Skip => {
+                    self.record_conflict(plan, target_path, &source_norm, kind);
+                    return Ok(());
                 }
                 ConflictStrategy::Adopt => {
-                    // Adopt the file first
-                    adopt::adopt_file(target_path, source_path, self.dry_run)?;
-                    // Now the target is gone, we can link it
+                    // Adopt the file first, the link is created afterwards
+                    if !self.plan_adopt(plan, target_path, source_path)? {
+                        return Ok(());
+                    }
                 }
                 ConflictStrategy::Override => {
-                    // Remove the conflicting file
-                    adopt::override_file(target_path, self.dry_run)?;
-                    // Now the target is gone, we can link it
+                    // Remove the conflicting file first, the link is created afterwards
+                    plan.add(planner::Action::RemoveConflict {
+                        path: target_path.to_path_buf(),
+                    });
+                }
+                ConflictStrategy::Backup => {
+                    // Move the conflicting file away first, the link is created afterwards
+                    plan.add(self.backup_action(target_path));
                 }
             }
         }
 
         // Compute relative path from target to source
         let target_parent = target_path.parent().unwrap_or(target_path);
-        let target_parent_abs = path_utils::normalize_path(target_parent)?;
+        let target_parent_abs = self.fs.normalize_path(target_parent)?;
         let relative_path = path_utils::compute_relative_path(&target_parent_abs, &source_norm)?;
 
-        // Add action to create the symlink
+        // Add action to create the symlink, hard link or copy
         plan.add(planner::Action::CreateFileLink {
             link_path: target_path.to_owned(),
             target_path: relative_path,
+            mode: self.link_mode,
         });
 
         Ok(())
@@ -238,13 +680,28 @@ impl Stower {
         target_path: &Path,
         plan: &mut planner::Plan,
     ) -> Result<()> {
+        // Never link into another stow tree or into the stow directory itself
+        if is_protected_dir(
+            self.fs.as_ref(),
+            target_path,
+            &self.stow_dir,
+            &self.target_dir,
+        ) {
+            logger::verbose(&format!(
+                "Skipping protected directory: {}",
+                target_path.display()
+            ));
+            return Ok(());
+        }
+
         // Check if this path should be deferred (only when target exists)
         // This matches GNU Stow's behavior: defer only if already stowed by another package
-        if target_path.exists() && self.patterns.should_defer(source_path) {
+        if self.fs.exists(target_path) && self.patterns.should_defer(source_path) {
             logger::verbose(&format!(
                 "Deferring: {} (already exists)",
                 source_path.display()
             ));
+            plan.add_deferred(target_path.to_path_buf());
             return Ok(());
         }
 
@@ -253,11 +710,11 @@ impl Stower {
         match decision {
             FoldDecision::Fold => {
                 // Normalize source path once to avoid repeated syscalls
-                let source_norm = path_utils::normalize_path(source_path)?;
+                let source_norm = self.fs.normalize_path(source_path)?;
 
                 // Create a directory symlink
                 let target_parent = target_path.parent().unwrap_or(target_path);
-                let target_parent_abs = path_utils::normalize_path(target_parent)?;
+                let target_parent_abs = self.fs.normalize_path(target_parent)?;
                 let relative_path =
                     path_utils::compute_relative_path(&target_parent_abs, &source_norm)?;
 
@@ -267,6 +724,11 @@ impl Stower {
                 });
             }
 
+            FoldDecision::AlreadyLinked => {
+                logger::verbose(&format!("Already linked: {}", target_path.display()));
+                plan.add_linked(target_path.to_path_buf());
+            }
+
             FoldDecision::Unfold(original_target) => {
                 // Unfold the existing symlink
                 plan.add(planner::Action::UnfoldDirLink {
@@ -275,8 +737,21 @@ impl Stower {
                 });
 
                 // After unfolding, we need to link the original target's contents
-                // and the new source's contents into the now-real directory
-                self.plan_stow_unfolded(&original_target, source_path, target_path, plan)?;
+                // and the new source's contents into the now-real directory.
+                // The link itself must not be resolved when computing relative paths.
+                let target_parent = target_path.parent().unwrap_or(target_path);
+                let target_norm = match target_path.file_name() {
+                    Some(name) => self.fs.normalize_path(target_parent)?.join(name),
+                    None => target_path.to_path_buf(),
+                };
+                self.plan_stow_unfolded(
+                    Some(&original_target),
+                    source_path,
+                    target_path,
+                    &target_norm,
+                    self.link_mode,
+                    plan,
+                )?;
             }
 
             FoldDecision::Traverse => {
@@ -284,48 +759,62 @@ impl Stower {
                 self.plan_stow_directory(source_path, target_path, plan)?;
             }
 
+            FoldDecision::Create => {
+                // The directory is created by the links placed inside it
+                logger::verbose(&format!("Not folding: {}", target_path.display()));
+                self.plan_stow_directory(source_path, target_path, plan)?;
+            }
+
             FoldDecision::Conflict => {
                 // Normalize source path once to avoid repeated syscalls
-                let source_norm = path_utils::normalize_path(source_path)?;
+                let source_norm = self.fs.normalize_path(source_path)?;
+
+                // Target exists as a file, or a file link
+                // Links of other packages are never adopted or overridden
+                let kind = self.conflict_kind(target_path, &source_norm);
+                if matches!(kind, ConflictKind::Package(_)) {
+                    self.record_conflict(plan, target_path, &source_norm, kind);
+                    return Ok(());
+                }
 
-                // Target exists as a file
-                match self.conflict_strategy {
-                    ConflictStrategy::Fail => {
-                        return Err(StowError::conflict(target_path));
+                match self.strategy_for(target_path) {
+                    ConflictStrategy::Fail | ConflictStrategy::Skip => {
+                        self.record_conflict(plan, target_path, &source_norm, kind);
+                        return Ok(());
                     }
                     ConflictStrategy::Adopt => {
                         // Adopt the conflicting file/directory
-                        if target_path.is_dir() {
-                            adopt::adopt_directory(target_path, source_path, self.dry_run)?;
-                        } else {
-                            adopt::adopt_file(target_path, source_path, self.dry_run)?;
+                        if !self.plan_adopt(plan, target_path, source_path)? {
+                            return Ok(());
                         }
-                        // Now create the link
-                        let target_parent = target_path.parent().unwrap_or(target_path);
-                        let target_parent_abs = path_utils::normalize_path(target_parent)?;
-                        let relative_path =
-                            path_utils::compute_relative_path(&target_parent_abs, &source_norm)?;
-
-                        plan.add(planner::Action::CreateDirLink {
-                            link_path: target_path.to_path_buf(),
-                            target_path: relative_path,
-                        });
                     }
                     ConflictStrategy::Override => {
                         // Remove the conflicting file/directory
-                        adopt::override_file(target_path, self.dry_run)?;
-                        // Now create the link
-                        let target_parent = target_path.parent().unwrap_or(target_path);
-                        let target_parent_abs = path_utils::normalize_path(target_parent)?;
-                        let relative_path =
-                            path_utils::compute_relative_path(&target_parent_abs, &source_norm)?;
-
-                        plan.add(planner::Action::CreateDirLink {
-                            link_path: target_path.to_path_buf(),
-                            target_path: relative_path,
+                        plan.add(planner::Action::RemoveConflict {
+                            path: target_path.to_path_buf(),
                         });
                     }
+                    ConflictStrategy::Backup => {
+                        // Move the conflicting file/directory away
+                        plan.add(self.backup_action(target_path));
+                    }
                 }
+
+                if !self.link_mode.is_symlink() {
+                    // Only files are linked, the directory is created by the links placed inside it
+                    return self.plan_stow_directory(source_path, target_path, plan);
+                }
+
+                // Now create the link
+                let target_parent = target_path.parent().unwrap_or(target_path);
+                let target_parent_abs = self.fs.normalize_path(target_parent)?;
+                let relative_path =
+                    path_utils::compute_relative_path(&target_parent_abs, &source_norm)?;
+
+                plan.add(planner::Action::CreateDirLink {
+                    link_path: target_path.to_path_buf(),
+                    target_path: relative_path,
+                });
             }
         }
 
@@ -334,37 +823,44 @@ impl Stower {
 
     /// Decide how to handle a directory
     fn decide_fold(&self, source_path: &Path, target_path: &Path) -> Result<FoldDecision> {
-        if !target_path.exists() {
+        if !self.fs.exists(target_path) {
+            if !self
+                .fold_policy
+                .allows_target(&self.target_dir, target_path)
+            {
+                return Ok(FoldDecision::Create);
+            }
+
             // Target doesn't exist, we can fold
             return Ok(FoldDecision::Fold);
         }
 
         // Check if target is a symlink
-        if fs_ops::is_symlink(target_path) {
-            let link_target = fs_ops::read_symlink(target_path)?;
-            let link_target_abs = if link_target.is_relative() {
-                target_path
-                    .parent()
-                    .unwrap_or(target_path)
-                    .join(&link_target)
-            } else {
-                link_target.clone()
-            };
-
-            let link_target_norm = path_utils::normalize_path(&link_target_abs)?;
-            let source_norm = path_utils::normalize_path(source_path)?;
+        if self.fs.is_symlink(target_path) {
+            let link_target_norm = self.fs.resolve_link(target_path)?;
+            let source_norm = self.fs.normalize_path(source_path)?;
+
+            if state::is_deployed(
+                self.fs.as_ref(),
+                self.state.as_deref(),
+                target_path,
+                &source_norm,
+            ) {
+                // Already linked to the same place, nothing to do
+                return Ok(FoldDecision::AlreadyLinked);
+            }
 
-            if path_utils::paths_equal(&link_target_norm, &source_norm) {
-                // Already linked to the same place, fold (no-op)
-                return Ok(FoldDecision::Fold);
-            } else {
-                // Linked to a different place, need to unfold
-                return Ok(FoldDecision::Unfold(link_target_abs));
+            if !self.fs.is_dir(target_path) {
+                // A file link can't be unfolded
+                return Ok(FoldDecision::Conflict);
             }
+
+            // Linked to a different place, need to unfold
+            return Ok(FoldDecision::Unfold(link_target_norm));
         }
 
         // Check if target is a directory
-        if fs_ops::is_directory(target_path)? {
+        if self.fs.is_dir(target_path) {
             // Real directory, traverse into it
             return Ok(FoldDecision::Traverse);
         }
@@ -374,30 +870,258 @@ impl Stower {
     }
 
     /// Plan stowing after unfolding
-    /// Links contents of both the original target and the new source into the target directory
+    /// Links contents of both the original target and the new source into the target directory.
+    /// The unfolded directory starts out empty, so both trees are merged here instead of being
+    /// checked against the (still folded) target on disk. Without an original target only the
+    /// new source is linked. Files of the new source are linked with `new_mode`, the original
+    /// target's files stay symlinks.
     fn plan_stow_unfolded(
         &self,
-        original_target: &Path,
+        original_target: Option<&Path>,
         new_source: &Path,
         target_dir: &Path,
+        target_dir_norm: &Path,
+        new_mode: LinkMode,
         plan: &mut planner::Plan,
     ) -> Result<()> {
-        // First, link all contents from the original target
-        if original_target.exists() && original_target.is_dir() {
-            self.plan_stow_directory(original_target, target_dir, plan)?;
+        // Entries by name: (entry in original target, entry in new source)
+        let mut entries: BTreeMap<String, (Option<PathBuf>, Option<PathBuf>)> = BTreeMap::new();
+
+        if let Some(original_target) = original_target
+            && self.fs.is_dir(original_target)
+        {
+            for path in self.fs.read_dir(original_target)? {
+                if let Some(key) = self.merge_key(&path) {
+                    entries.entry(key).or_default().0 = Some(path);
+                }
+            }
         }
 
-        // Then, link all contents from the new source
-        self.plan_stow_directory(new_source, target_dir, plan)?;
+        for path in self.fs.read_dir(new_source)? {
+            if let Some(key) = self.merge_key(&path) {
+                entries.entry(key).or_default().1 = Some(path);
+            }
+        }
+
+        for (original, new) in entries.into_values() {
+            let mode = if new.is_some() {
+                new_mode
+            } else {
+                LinkMode::Symlink
+            };
+
+            match (original, new) {
+                (Some(source_path), None) | (None, Some(source_path)) => {
+                    let Some(name) = source_path.file_name() else {
+                        continue;
+                    };
+                    let source_norm = self.fs.normalize_path(&source_path)?;
+                    let relative_path =
+                        path_utils::compute_relative_path(target_dir_norm, &source_norm)?;
+                    let link_path = target_dir.join(self.target_name(name));
+
+                    if self.fs.is_dir(&source_path)
+                        && !self.fold_policy.allows_target(&self.target_dir, &link_path)
+                    {
+                        // Link the directory's contents instead of the directory
+                        self.plan_stow_unfolded(
+                            None,
+                            &source_path,
+                            &link_path,
+                            &target_dir_norm.join(self.target_name(name)),
+                            mode,
+                            plan,
+                        )?;
+                        continue;
+                    }
+
+                    let action = if self.fs.is_dir(&source_path) {
+                        planner::Action::CreateDirLink {
+                            link_path,
+                            target_path: relative_path,
+                        }
+                    } else {
+                        planner::Action::CreateFileLink {
+                            link_path,
+                            target_path: relative_path,
+                            mode,
+                        }
+                    };
+                    // Links to the unfolded directory's contents still belong to its package
+                    plan.add_for_package(action, self.package_of(&source_norm));
+                }
+                (Some(original_path), Some(new_path)) => {
+                    let Some(name) = new_path.file_name() else {
+                        continue;
+                    };
+                    let name = self.target_name(name);
+
+                    // Both packages provide this entry: directories are merged recursively,
+                    // anything else is claimed twice
+                    if self.fs.is_dir(&original_path) && self.fs.is_dir(&new_path) {
+                        self.plan_stow_unfolded(
+                            Some(&original_path),
+                            &new_path,
+                            &target_dir.join(&name),
+                            &target_dir_norm.join(&name),
+                            new_mode,
+                            plan,
+                        )?;
+                    } else {
+                        // The original entry belongs to the package of the unfolded link
+                        let new_norm = self.fs.normalize_path(&new_path)?;
+                        let original_norm = self.fs.normalize_path(&original_path)?;
+                        let kind = match self.package_of(&original_norm) {
+                            Some(other) if self.package_of(&new_norm).as_ref() != Some(&other) => {
+                                ConflictKind::Package(other)
+                            }
+                            _ if self.fs.is_dir(&original_path) => ConflictKind::Dir,
+                            _ => ConflictKind::File,
+                        };
+                        self.record_conflict(plan, &target_dir.join(&name), &new_norm, kind);
+                    }
+                }
+                (None, None) => {}
+            }
+        }
 
         Ok(())
     }
+
+    /// Conflict strategy for a target path
+    fn strategy_for(&self, target_path: &Path) -> ConflictStrategy {
+        self.resolutions
+            .get(target_path)
+            .copied()
+            .unwrap_or(self.conflict_strategy)
+    }
+
+    /// Plan adopting a conflicting target path into the package
+    /// Target files differing from the package's version are recorded as conflicts
+    /// instead, unless both versions are kept; returns whether the path is adopted
+    fn plan_adopt(
+        &self,
+        plan: &mut planner::Plan,
+        target_path: &Path,
+        source_path: &Path,
+    ) -> Result<bool> {
+        if !self.keep_both {
+            let differing = adopt::differing_files(self.fs.as_ref(), target_path, source_path)?;
+            for (target_file, package_file) in &differing {
+                let package_norm = self.fs.normalize_path(package_file)?;
+                self.record_conflict(plan, target_file, &package_norm, ConflictKind::Differs);
+            }
+            if !differing.is_empty() {
+                return Ok(false);
+            }
+        }
+
+        let target_path = target_path.to_path_buf();
+        let package_path = source_path.to_path_buf();
+        let keep_both = self.keep_both;
+        if self.fs.is_dir(&target_path) {
+            plan.add(planner::Action::AdoptDir {
+                target_path,
+                package_path,
+                keep_both,
+            });
+        } else {
+            plan.add(planner::Action::AdoptFile {
+                target_path,
+                package_path,
+                keep_both,
+            });
+        }
+        Ok(true)
+    }
+
+    /// Action moving a conflicting target path into the backup directory
+    fn backup_action(&self, target_path: &Path) -> planner::Action {
+        planner::Action::BackupConflict {
+            path: target_path.to_path_buf(),
+            backup_path: backup::backup_path(&self.target_dir, &self.backup_dir, target_path),
+        }
+    }
+
+    /// Describe what is in the way at a conflicting target path
+    /// The source path must be normalized
+    fn conflict_kind(&self, target_path: &Path, source_norm: &Path) -> ConflictKind {
+        if self.fs.is_symlink(target_path) {
+            if let Ok(link_target_norm) = self.fs.resolve_link(target_path)
+                && let Some(other) = self.package_of(&link_target_norm)
+                && self.package_of(source_norm).as_ref() != Some(&other)
+            {
+                return ConflictKind::Package(other);
+            }
+
+            return ConflictKind::Link(self.fs.read_link(target_path).unwrap_or_default());
+        }
+
+        if self.fs.is_dir(target_path) {
+            ConflictKind::Dir
+        } else {
+            ConflictKind::File
+        }
+    }
+
+    /// Record a conflict instead of failing, so all conflicts can be reported together
+    fn record_conflict(
+        &self,
+        plan: &mut planner::Plan,
+        target_path: &Path,
+        source_norm: &Path,
+        kind: ConflictKind,
+    ) {
+        logger::verbose(&format!("Conflict: {} ({})", target_path.display(), kind));
+        plan.add_conflict(Conflict {
+            path: target_path.to_path_buf(),
+            package: self.package_of(source_norm),
+            source: Some(source_norm.to_path_buf()),
+            kind,
+        });
+    }
+
+    /// Name of the package a normalized path belongs to
+    /// Returns `None` for paths outside the stow directory
+    fn package_of(&self, path: &Path) -> Option<String> {
+        let stow_dir_norm = self.fs.normalize_path(&self.stow_dir).ok()?;
+        let relative = path_utils::strip_base(path, &stow_dir_norm)?;
+        let package = relative.components().next()?;
+        Some(package.as_os_str().to_string_lossy().into_owned())
+    }
+
+    /// Key used to match entries of two directories being merged
+    /// Returns `None` for entries that should be ignored
+    fn merge_key(&self, path: &Path) -> Option<String> {
+        if self.patterns.should_ignore(path) {
+            logger::verbose(&format!("Ignoring: {}", path.display()));
+            return None;
+        }
+
+        let name = self.target_name(path.file_name()?);
+        let name = name.to_string_lossy();
+        Some(if cfg!(windows) {
+            name.to_lowercase()
+        } else {
+            name.into_owned()
+        })
+    }
+
+    /// Name of a package entry in the target directory
+    fn target_name(&self, name: &OsStr) -> OsString {
+        if self.dotfiles {
+            path_utils::dotfile_name(name)
+        } else {
+            name.to_owned()
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::File;
+    use crate::memfs::MemoryFilesystem;
+    use std::fs::{self, File};
     use tempfile::TempDir;
 
     #[test]
@@ -459,7 +1183,7 @@ mod tests {
         let stower = Stower::new(&stow_dir, &target_dir, false, true);
         let result = stower.stow_package("mypackage");
         assert!(result.is_err());
-        assert!(matches!(result.unwrap_err(), StowError::Conflict { .. }));
+        assert!(matches!(result.unwrap_err(), StowError::Conflicts(_)));
     }
 
     #[test]
@@ -594,4 +1318,888 @@ mod tests {
         let result = stower.stow_package("mypackage");
         assert!(result.is_ok());
     }
+
+    /// Set up empty stow and target directories on an in-memory filesystem
+    fn memfs_setup() -> (Rc<MemoryFilesystem>, PathBuf, PathBuf) {
+        let fs = Rc::new(MemoryFilesystem::new());
+        let stow_dir = MemoryFilesystem::root().join("stow");
+        let target_dir = MemoryFilesystem::root().join("target");
+        fs.add_dir(&stow_dir);
+        fs.add_dir(&target_dir);
+        (fs, stow_dir, target_dir)
+    }
+
+    #[test]
+    fn test_stow_folds_new_directory() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg/app/config.txt"), "cfg");
+        fs.add_file(stow_dir.join("pkg/file.txt"), "file");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        stower.stow_package("pkg").unwrap();
+
+        // The directory is linked as a whole, the file individually
+        assert_eq!(
+            fs.read_link(&target_dir.join("app")).unwrap(),
+            PathBuf::from("../stow/pkg/app")
+        );
+        assert_eq!(
+            fs.read_link(&target_dir.join("file.txt")).unwrap(),
+            PathBuf::from("../stow/pkg/file.txt")
+        );
+        assert_eq!(
+            fs.file_content(target_dir.join("app/config.txt")).unwrap(),
+            b"cfg"
+        );
+    }
+
+    #[test]
+    fn test_stow_unfolds_directory_of_other_package() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg1/shared/a.txt"), "a");
+        fs.add_file(stow_dir.join("pkg2/shared/b.txt"), "b");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        stower.stow_package("pkg1").unwrap();
+        assert!(fs.is_symlink(&target_dir.join("shared")));
+
+        stower.stow_package("pkg2").unwrap();
+
+        let shared = target_dir.join("shared");
+        assert!(!fs.is_symlink(&shared));
+        assert_eq!(
+            fs.read_link(&shared.join("a.txt")).unwrap(),
+            PathBuf::from("../../stow/pkg1/shared/a.txt")
+        );
+        assert_eq!(
+            fs.read_link(&shared.join("b.txt")).unwrap(),
+            PathBuf::from("../../stow/pkg2/shared/b.txt")
+        );
+    }
+
+    #[test]
+    fn test_stow_dotfiles_translates_names() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg1/dot-bashrc"), "bash");
+        fs.add_file(stow_dir.join("pkg1/dot-config/a/a.toml"), "a");
+        fs.add_file(stow_dir.join("pkg2/dot-config/b/b.toml"), "b");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .with_dotfiles(true);
+        stower.stow_package("pkg1").unwrap();
+
+        assert_eq!(
+            fs.read_link(&target_dir.join(".bashrc")).unwrap(),
+            PathBuf::from("../stow/pkg1/dot-bashrc")
+        );
+        assert_eq!(
+            fs.read_link(&target_dir.join(".config")).unwrap(),
+            PathBuf::from("../stow/pkg1/dot-config")
+        );
+
+        // Stowing again finds the translated links
+        stower.stow_package("pkg1").unwrap();
+
+        // Unfolding merges the translated directory of both packages
+        stower.stow_package("pkg2").unwrap();
+        let config = target_dir.join(".config");
+        assert!(!fs.is_symlink(&config));
+        assert_eq!(
+            fs.read_link(&config.join("a")).unwrap(),
+            PathBuf::from("../../stow/pkg1/dot-config/a")
+        );
+        assert_eq!(
+            fs.read_link(&config.join("b")).unwrap(),
+            PathBuf::from("../../stow/pkg2/dot-config/b")
+        );
+        assert!(!fs.exists(&target_dir.join("dot-config")));
+    }
+
+    #[test]
+    fn test_stow_without_dotfiles_keeps_names() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg/dot-bashrc"), "bash");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        stower.stow_package("pkg").unwrap();
+
+        assert!(fs.is_symlink(&target_dir.join("dot-bashrc")));
+        assert!(!fs.exists(&target_dir.join(".bashrc")));
+    }
+
+    #[test]
+    fn test_fold_policy() {
+        let policy = FoldPolicy::new(
+            true,
+            &["AppData\\Roaming".to_string(), ".config/".to_string()],
+        );
+        assert!(!policy.allows(Path::new("AppData/Roaming")));
+        assert!(!policy.allows(Path::new(".config")));
+        assert!(policy.allows(Path::new("AppData")));
+        assert!(policy.allows(Path::new(".config/nvim")));
+
+        let policy = FoldPolicy::new(false, &[]);
+        assert!(!policy.allows(Path::new("AppData")));
+    }
+
+    #[test]
+    fn test_stow_no_folding_links_only_files() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg/app/nested/a.txt"), "a");
+        fs.add_file(stow_dir.join("pkg/file.txt"), "file");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .with_fold_policy(FoldPolicy::new(false, &[]));
+        stower.stow_package("pkg").unwrap();
+
+        assert!(fs.is_dir(&target_dir.join("app/nested")));
+        assert!(!fs.is_symlink(&target_dir.join("app")));
+        assert!(!fs.is_symlink(&target_dir.join("app/nested")));
+        assert_eq!(
+            fs.read_link(&target_dir.join("app/nested/a.txt")).unwrap(),
+            PathBuf::from("../../../stow/pkg/app/nested/a.txt")
+        );
+
+        // Stowing again is a no-op
+        stower.stow_package("pkg").unwrap();
+    }
+
+    #[test]
+    fn test_stow_hardlink_mode_links_only_files() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg/app/nested/a.txt"), "a");
+        fs.add_file(stow_dir.join("pkg/file.txt"), "file");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .with_link_mode(LinkMode::Hardlink);
+        stower.stow_package("pkg").unwrap();
+
+        // Directories are real even though they could be folded
+        assert!(fs.is_dir(&target_dir.join("app/nested")));
+        assert!(!fs.is_symlink(&target_dir.join("app")));
+        let link = target_dir.join("app/nested/a.txt");
+        assert!(!fs.is_symlink(&link));
+        assert!(fs.same_file(&link, &stow_dir.join("pkg/app/nested/a.txt")));
+
+        // Stowing again finds the hard links in place
+        let overlay = Rc::new(OverlayFilesystem::new(fs.clone()));
+        let mut plan = planner::Plan::new();
+        stower
+            .plan_packages(&["pkg".to_string()], &overlay, &mut plan)
+            .unwrap();
+        assert_eq!(plan.len(), 0);
+        assert_eq!(plan.linked().len(), 2);
+    }
+
+    #[test]
+    fn test_stow_copy_mode_unfolds_other_package_with_symlinks() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg1/app/a.txt"), "a");
+        fs.add_file(stow_dir.join("pkg2/app/b.txt"), "b");
+
+        Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .stow_package("pkg1")
+            .unwrap();
+        Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .with_link_mode(LinkMode::Copy)
+            .stow_package("pkg2")
+            .unwrap();
+
+        // pkg1's entry stays a link, pkg2's file is copied
+        assert!(fs.is_symlink(&target_dir.join("app/a.txt")));
+        let copy = target_dir.join("app/b.txt");
+        assert!(!fs.is_symlink(&copy));
+        assert!(!fs.same_file(&copy, &stow_dir.join("pkg2/app/b.txt")));
+        assert_eq!(fs.file_content(&copy).unwrap(), b"b");
+    }
+
+    #[test]
+    fn test_stow_never_folds_listed_paths() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg/.config/nvim/init.lua"), "nvim");
+        fs.add_file(stow_dir.join("pkg/.local/share/data.txt"), "data");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .with_fold_policy(FoldPolicy::new(true, &[".config".to_string()]));
+        stower.stow_package("pkg").unwrap();
+
+        // Only the listed directory is real, everything else folds
+        assert!(!fs.is_symlink(&target_dir.join(".config")));
+        assert_eq!(
+            fs.read_link(&target_dir.join(".config/nvim")).unwrap(),
+            PathBuf::from("../../stow/pkg/.config/nvim")
+        );
+        assert_eq!(
+            fs.read_link(&target_dir.join(".local")).unwrap(),
+            PathBuf::from("../stow/pkg/.local")
+        );
+    }
+
+    #[test]
+    fn test_stow_no_folding_when_unfolding() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg1/shared/sub/a.txt"), "a");
+        fs.add_file(stow_dir.join("pkg2/shared/b.txt"), "b");
+
+        Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .stow_package("pkg1")
+            .unwrap();
+        assert!(fs.is_symlink(&target_dir.join("shared")));
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .with_fold_policy(FoldPolicy::new(false, &[]));
+        let overlay = Rc::new(OverlayFilesystem::new(fs.clone()));
+        let mut plan = planner::Plan::new();
+        stower
+            .plan_packages(&["pkg2".to_string()], &overlay, &mut plan)
+            .unwrap();
+
+        // The contents of the other package are linked file by file, still owned by it
+        let links: Vec<_> = plan
+            .steps()
+            .iter()
+            .filter(|s| !matches!(s.action, planner::Action::UnfoldDirLink { .. }))
+            .map(|s| (s.action.path().to_path_buf(), s.package.as_deref()))
+            .collect();
+        assert_eq!(
+            links,
+            vec![
+                (target_dir.join("shared/b.txt"), Some("pkg2")),
+                (target_dir.join("shared/sub/a.txt"), Some("pkg1")),
+            ]
+        );
+
+        plan.execute(fs.as_ref(), false).unwrap();
+        assert!(!fs.is_symlink(&target_dir.join("shared/sub")));
+        assert_eq!(
+            fs.read_link(&target_dir.join("shared/sub/a.txt")).unwrap(),
+            PathBuf::from("../../../stow/pkg1/shared/sub/a.txt")
+        );
+    }
+
+    #[test]
+    fn test_stow_skips_stow_directory_inside_target() {
+        let fs = Rc::new(MemoryFilesystem::new());
+        let target_dir = MemoryFilesystem::root().join("home");
+        let stow_dir = target_dir.join("dotfiles");
+        fs.add_file(stow_dir.join("pkg/dotfiles/extra.txt"), "extra");
+        fs.add_file(stow_dir.join("pkg/file.txt"), "file");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        stower.stow_package("pkg").unwrap();
+
+        assert!(fs.is_symlink(&target_dir.join("file.txt")));
+        assert!(!fs.exists(&stow_dir.join("extra.txt")));
+        assert_eq!(fs.read_dir(&stow_dir).unwrap(), vec![stow_dir.join("pkg")]);
+    }
+
+    #[test]
+    fn test_stow_skips_marked_directories() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg/other/a.txt"), "a");
+        fs.add_file(stow_dir.join("pkg/tree/b.txt"), "b");
+        fs.add_file(target_dir.join("other/.nonstow"), "");
+        fs.add_file(target_dir.join("tree/.stow"), "");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        stower.stow_package("pkg").unwrap();
+
+        assert!(!fs.exists(&target_dir.join("other/a.txt")));
+        assert!(!fs.exists(&target_dir.join("tree/b.txt")));
+    }
+
+    #[test]
+    fn test_stow_skips_only_backup_and_history_directories_of_target() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        let backups = Path::new(backup::BACKUP_DIR_NAME);
+        let history = Path::new(history::HISTORY_DIR_NAME);
+        fs.add_file(stow_dir.join("pkg").join(backups).join("a.txt"), "a");
+        fs.add_file(stow_dir.join("pkg/app").join(history).join("b.txt"), "b");
+        fs.add_dir(target_dir.join(backups));
+        fs.add_dir(target_dir.join("app").join(history));
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        stower.stow_package("pkg").unwrap();
+
+        // Directories of an application that happen to share the names are stowed into
+        assert!(!fs.exists(&target_dir.join(backups).join("a.txt")));
+        assert!(fs.is_symlink(&target_dir.join("app").join(history).join("b.txt")));
+    }
+
+    #[test]
+    fn test_stow_packages_plans_shared_directory_once() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg1/shared/a.txt"), "a");
+        fs.add_file(stow_dir.join("pkg2/shared/b.txt"), "b");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        let overlay = Rc::new(OverlayFilesystem::new(fs.clone()));
+        let mut plan = planner::Plan::new();
+        stower
+            .plan_packages(
+                &["pkg1".to_string(), "pkg2".to_string()],
+                &overlay,
+                &mut plan,
+            )
+            .unwrap();
+
+        // Each link belongs to the package it points into
+        let owners: Vec<_> = plan.steps().iter().map(|s| s.package.as_deref()).collect();
+        assert_eq!(owners, vec![Some("pkg1"), Some("pkg2")]);
+
+        // The shared directory is never folded and unfolded again
+        assert!(
+            plan.actions()
+                .all(|a| matches!(a, planner::Action::CreateFileLink { .. }))
+        );
+
+        plan.execute(fs.as_ref(), false).unwrap();
+        let shared = target_dir.join("shared");
+        assert!(!fs.is_symlink(&shared));
+        assert!(fs.is_symlink(&shared.join("a.txt")));
+        assert!(fs.is_symlink(&shared.join("b.txt")));
+    }
+
+    #[test]
+    fn test_stow_packages_conflict_names_both_packages() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg1/.gitconfig"), "pkg1");
+        fs.add_file(stow_dir.join("pkg1/.bashrc"), "pkg1");
+        fs.add_file(stow_dir.join("pkg2/.gitconfig"), "pkg2");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .with_conflict_strategy(ConflictStrategy::Override);
+        let result = stower.stow_packages(&["pkg1".to_string(), "pkg2".to_string()]);
+
+        match result {
+            Err(StowError::Conflicts(conflicts)) => {
+                assert_eq!(
+                    conflicts,
+                    vec![Conflict {
+                        path: target_dir.join(".gitconfig"),
+                        package: Some("pkg2".to_string()),
+                        source: Some(stow_dir.join("pkg2/.gitconfig")),
+                        kind: ConflictKind::Package("pkg1".to_string()),
+                    }]
+                );
+            }
+            other => panic!("expected a package conflict, got {:?}", other),
+        }
+
+        // Nothing was executed
+        assert!(fs.read_dir(&target_dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_all_conflicts_are_reported() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg/a.txt"), "a");
+        fs.add_file(stow_dir.join("pkg/b.txt"), "b");
+        fs.add_file(stow_dir.join("pkg/c.txt"), "c");
+        fs.add_file(stow_dir.join("pkg/app/config.txt"), "cfg");
+        fs.add_file(target_dir.join("a.txt"), "existing");
+        fs.add_dir(target_dir.join("b.txt"));
+        fs.add_file(target_dir.join("app"), "existing");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        let result = stower.stow_package("pkg");
+
+        let Err(StowError::Conflicts(conflicts)) = result else {
+            panic!("expected conflicts, got {:?}", result);
+        };
+        let kinds: Vec<_> = conflicts.iter().map(|c| c.kind.clone()).collect();
+        assert_eq!(
+            kinds,
+            vec![ConflictKind::File, ConflictKind::File, ConflictKind::Dir]
+        );
+        assert!(
+            conflicts
+                .iter()
+                .all(|c| c.package.as_deref() == Some("pkg"))
+        );
+
+        // Nothing was linked
+        assert!(!fs.exists(&target_dir.join("c.txt")));
+    }
+
+    #[test]
+    fn test_skip_conflicts_links_everything_else() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg/a.txt"), "a");
+        fs.add_file(stow_dir.join("pkg/c.txt"), "c");
+        fs.add_file(stow_dir.join("pkg/app/config.txt"), "cfg");
+        fs.add_file(target_dir.join("a.txt"), "existing");
+        fs.add_file(target_dir.join("app"), "existing");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .with_conflict_strategy(ConflictStrategy::Skip);
+        stower.stow_package("pkg").unwrap();
+
+        assert!(fs.is_symlink(&target_dir.join("c.txt")));
+        assert_eq!(
+            fs.file_content(target_dir.join("a.txt")).unwrap(),
+            b"existing"
+        );
+        assert_eq!(
+            fs.file_content(target_dir.join("app")).unwrap(),
+            b"existing"
+        );
+    }
+
+    #[test]
+    fn test_conflict_strategy_backup_keeps_layout() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg/.config/app/a.txt"), "a");
+        fs.add_file(stow_dir.join("pkg/b.txt"), "b");
+        fs.add_file(target_dir.join(".config/app/a.txt"), "local a");
+        fs.add_dir(target_dir.join("b.txt"));
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .with_conflict_strategy(ConflictStrategy::Backup);
+        stower.stow_package("pkg").unwrap();
+
+        let backup_dir = backup::backup_dir(&target_dir, "test");
+        assert!(fs.is_symlink(&target_dir.join(".config/app/a.txt")));
+        assert!(fs.is_symlink(&target_dir.join("b.txt")));
+        assert_eq!(
+            fs.file_content(backup_dir.join(".config/app/a.txt"))
+                .unwrap(),
+            b"local a"
+        );
+        assert!(fs.is_dir(&backup_dir.join("b.txt")));
+    }
+
+    #[test]
+    fn test_resolutions_per_path() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs.add_file(stow_dir.join("pkg").join(name), "package");
+            fs.add_file(target_dir.join(name), "local");
+        }
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        let overlay = Rc::new(OverlayFilesystem::new(fs.clone() as Rc<dyn Filesystem>));
+        let packages = vec!["pkg".to_string()];
+        let conflicts = stower.find_conflicts(&packages, &overlay).unwrap();
+        assert_eq!(conflicts.len(), 3);
+        assert!(!overlay.is_symlink(&target_dir.join("a.txt")));
+
+        let stower = stower
+            .with_keep_both(true)
+            .with_resolutions(BTreeMap::from([
+                (target_dir.join("a.txt"), ConflictStrategy::Override),
+                (target_dir.join("b.txt"), ConflictStrategy::Skip),
+                (target_dir.join("c.txt"), ConflictStrategy::Adopt),
+            ]));
+        stower.stow_package("pkg").unwrap();
+
+        assert!(fs.is_symlink(&target_dir.join("a.txt")));
+        assert_eq!(fs.file_content(target_dir.join("b.txt")).unwrap(), b"local");
+        assert!(fs.is_symlink(&target_dir.join("c.txt")));
+        assert_eq!(
+            fs.file_content(stow_dir.join("pkg/c.txt.adopted")).unwrap(),
+            b"local"
+        );
+    }
+
+    #[test]
+    fn test_stow_directory_over_file_link_of_other_package() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg1/app"), "file");
+        fs.add_file(stow_dir.join("pkg2/app/config.txt"), "cfg");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        stower.stow_package("pkg1").unwrap();
+
+        let result = stower.stow_package("pkg2");
+        assert!(matches!(
+            result,
+            Err(StowError::Conflicts(c)) if matches!(c[0].kind, ConflictKind::Package(_))
+        ));
+        assert!(fs.is_symlink(&target_dir.join("app")));
+    }
+
+    #[test]
+    fn test_stow_unfold_merges_nested_directories() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg1/shared/sub/a.txt"), "a");
+        fs.add_file(stow_dir.join("pkg2/shared/sub/b.txt"), "b");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        stower.stow_package("pkg1").unwrap();
+        stower.stow_package("pkg2").unwrap();
+
+        let sub = target_dir.join("shared/sub");
+        assert!(!fs.is_symlink(&sub));
+        assert_eq!(
+            fs.read_link(&sub.join("a.txt")).unwrap(),
+            PathBuf::from("../../../stow/pkg1/shared/sub/a.txt")
+        );
+        assert_eq!(fs.file_content(sub.join("b.txt")).unwrap(), b"b");
+    }
+
+    #[test]
+    fn test_stow_is_idempotent() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg/app/config.txt"), "cfg");
+        fs.add_file(stow_dir.join("pkg/file.txt"), "file");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        stower.stow_package("pkg").unwrap();
+        // Stowing again finds everything already linked
+        assert!(stower.stow_package("pkg").is_ok());
+    }
+
+    #[test]
+    fn test_stow_traverses_existing_directory_case_insensitively() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg/app/config.txt"), "cfg");
+        fs.add_file(target_dir.join("APP/other.txt"), "other");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        stower.stow_package("pkg").unwrap();
+
+        // The existing directory is reused instead of conflicting
+        assert!(!fs.is_symlink(&target_dir.join("APP")));
+        assert!(fs.is_symlink(&target_dir.join("APP/config.txt")));
+        assert!(fs.exists(&target_dir.join("APP/other.txt")));
+    }
+
+    #[test]
+    fn test_conflict_strategy_adopt_relinks_identical_file() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg/file.txt"), "same");
+        fs.add_file(target_dir.join("file.txt"), "same");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .with_conflict_strategy(ConflictStrategy::Adopt);
+        stower.stow_package("pkg").unwrap();
+
+        assert!(fs.is_symlink(&target_dir.join("file.txt")));
+        assert_eq!(
+            fs.file_content(stow_dir.join("pkg/file.txt")).unwrap(),
+            b"same"
+        );
+        assert_eq!(fs.read_dir(&stow_dir.join("pkg")).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_conflict_strategy_adopt_refuses_differing_file() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg/file.txt"), "package");
+        fs.add_file(target_dir.join("file.txt"), "existing");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .with_conflict_strategy(ConflictStrategy::Adopt);
+        let result = stower.stow_package("pkg");
+        assert!(matches!(
+            result,
+            Err(StowError::Conflicts(c)) if c.len() == 1 && c[0].kind == ConflictKind::Differs
+        ));
+
+        // Neither copy was touched
+        assert_eq!(
+            fs.file_content(target_dir.join("file.txt")).unwrap(),
+            b"existing"
+        );
+        assert_eq!(
+            fs.file_content(stow_dir.join("pkg/file.txt")).unwrap(),
+            b"package"
+        );
+    }
+
+    #[test]
+    fn test_conflict_strategy_adopt_keep_both() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg/file.txt"), "package");
+        fs.add_file(target_dir.join("file.txt"), "existing");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .with_conflict_strategy(ConflictStrategy::Adopt)
+            .with_keep_both(true);
+        stower.stow_package("pkg").unwrap();
+
+        assert!(fs.is_symlink(&target_dir.join("file.txt")));
+        assert_eq!(
+            fs.file_content(stow_dir.join("pkg/file.txt")).unwrap(),
+            b"package"
+        );
+        assert_eq!(
+            fs.file_content(stow_dir.join("pkg/file.txt.adopted"))
+                .unwrap(),
+            b"existing"
+        );
+    }
+
+    #[test]
+    fn test_conflict_strategy_adopt_into_directory_with_differing_file() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg/app/a.txt"), "a");
+        fs.add_file(stow_dir.join("pkg/app/b.txt"), "package");
+        fs.add_file(target_dir.join("app/a.txt"), "a");
+        fs.add_file(target_dir.join("app/b.txt"), "local");
+        fs.add_file(target_dir.join("app/c.txt"), "c");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .with_conflict_strategy(ConflictStrategy::Adopt);
+        let result = stower.stow_package("pkg");
+        assert!(matches!(
+            result,
+            Err(StowError::Conflicts(c)) if c.len() == 1
+                && c[0].path == target_dir.join("app/b.txt")
+                && c[0].kind == ConflictKind::Differs
+        ));
+        assert!(!fs.is_symlink(&target_dir.join("app/a.txt")));
+
+        let stower = stower.with_keep_both(true);
+        stower.stow_package("pkg").unwrap();
+        assert!(fs.is_symlink(&target_dir.join("app/a.txt")));
+        assert!(fs.is_symlink(&target_dir.join("app/b.txt")));
+        assert_eq!(
+            fs.file_content(stow_dir.join("pkg/app/b.txt")).unwrap(),
+            b"package"
+        );
+        assert_eq!(
+            fs.file_content(stow_dir.join("pkg/app/b.txt.adopted"))
+                .unwrap(),
+            b"local"
+        );
+        assert_eq!(fs.file_content(target_dir.join("app/c.txt")).unwrap(), b"c");
+    }
+
+    /// Plan and execute adopting target paths into a package
+    fn adopt_paths(stower: &Stower, package: &str, paths: &[PathBuf]) -> Result<()> {
+        let overlay = Rc::new(OverlayFilesystem::new(Rc::clone(&stower.fs)));
+        let mut plan = planner::Plan::new();
+        stower.plan_adopt_paths(package, paths, &overlay, &mut plan)?;
+        plan.execute(stower.fs.as_ref(), false)
+    }
+
+    #[test]
+    fn test_adopt_paths_creates_package() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(target_dir.join(".bashrc"), "bash");
+        fs.add_file(target_dir.join(".config/nvim/init.lua"), "lua");
+        fs.add_file(target_dir.join(".config/other.txt"), "other");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        let paths = [target_dir.join(".bashrc"), target_dir.join(".config/nvim")];
+        adopt_paths(&stower, "shell", &paths).unwrap();
+
+        assert_eq!(
+            fs.file_content(stow_dir.join("shell/.bashrc")).unwrap(),
+            b"bash"
+        );
+        assert_eq!(
+            fs.file_content(stow_dir.join("shell/.config/nvim/init.lua"))
+                .unwrap(),
+            b"lua"
+        );
+        assert!(fs.is_symlink(&target_dir.join(".bashrc")));
+        // The adopted directory is folded, its real parent is left alone
+        assert!(fs.is_symlink(&target_dir.join(".config/nvim")));
+        assert!(!fs.is_symlink(&target_dir.join(".config")));
+        assert!(!fs.exists(&stow_dir.join("shell/.config/other.txt")));
+    }
+
+    #[test]
+    fn test_adopt_paths_extends_stowed_package() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg/dot-profile"), "profile");
+        fs.add_file(target_dir.join(".bashrc"), "bash");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .with_dotfiles(true);
+        stower.stow_package("pkg").unwrap();
+        adopt_paths(&stower, "pkg", &[target_dir.join(".bashrc")]).unwrap();
+
+        assert_eq!(
+            fs.file_content(stow_dir.join("pkg/dot-bashrc")).unwrap(),
+            b"bash"
+        );
+        assert!(fs.is_symlink(&target_dir.join(".bashrc")));
+        assert!(fs.is_symlink(&target_dir.join(".profile")));
+    }
+
+    #[test]
+    fn test_adopt_paths_rejects_invalid_paths() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg/a.txt"), "a");
+        fs.add_file(stow_dir.join("other/b.txt"), "package");
+        fs.add_file(target_dir.join("b.txt"), "local");
+        fs.add_file(MemoryFilesystem::root().join("outside.txt"), "outside");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        stower.stow_package("pkg").unwrap();
+
+        let invalid = [
+            ("pkg", target_dir.join("missing.txt")),
+            ("pkg", target_dir.join("a.txt")),
+            ("pkg", MemoryFilesystem::root().join("outside.txt")),
+            ("pkg", stow_dir.join("other/b.txt")),
+            ("pkg", target_dir.clone()),
+            ("../pkg", target_dir.join("b.txt")),
+        ];
+        for (package, path) in invalid {
+            assert!(matches!(
+                adopt_paths(&stower, package, &[path]),
+                Err(StowError::InvalidPath(_))
+            ));
+        }
+
+        // A file differing from the package's version is a conflict
+        let result = adopt_paths(&stower, "other", &[target_dir.join("b.txt")]);
+        assert!(matches!(
+            result,
+            Err(StowError::Conflicts(c)) if c[0].kind == ConflictKind::Differs
+        ));
+        assert_eq!(fs.file_content(target_dir.join("b.txt")).unwrap(), b"local");
+        assert_eq!(
+            fs.file_content(stow_dir.join("other/b.txt")).unwrap(),
+            b"package"
+        );
+    }
+
+    #[test]
+    fn test_planning_has_no_side_effects_on_conflict() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        // a.txt would be adopted, but shared/x.txt is claimed by two packages
+        fs.add_file(stow_dir.join("pkg1/shared/x.txt"), "pkg1");
+        fs.add_file(stow_dir.join("pkg2/a.txt"), "package");
+        fs.add_file(stow_dir.join("pkg2/shared/x.txt"), "pkg2");
+        fs.add_file(target_dir.join("a.txt"), "existing");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .with_conflict_strategy(ConflictStrategy::Adopt)
+            .with_keep_both(true);
+        stower.stow_package("pkg1").unwrap();
+
+        let result = stower.stow_package("pkg2");
+        assert!(matches!(
+            result,
+            Err(StowError::Conflicts(c)) if matches!(c[0].kind, ConflictKind::Package(_))
+        ));
+
+        // Nothing was adopted before the conflict was found
+        assert!(!fs.is_symlink(&target_dir.join("a.txt")));
+        assert_eq!(
+            fs.file_content(target_dir.join("a.txt")).unwrap(),
+            b"existing"
+        );
+        assert_eq!(
+            fs.file_content(stow_dir.join("pkg2/a.txt")).unwrap(),
+            b"package"
+        );
+    }
+
+    #[test]
+    fn test_conflict_strategy_override_replaces_directory() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg/app"), "package file");
+        fs.add_file(target_dir.join("app/old.txt"), "old");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .with_conflict_strategy(ConflictStrategy::Override);
+        stower.stow_package("pkg").unwrap();
+
+        assert!(fs.is_symlink(&target_dir.join("app")));
+        assert_eq!(
+            fs.file_content(target_dir.join("app")).unwrap(),
+            b"package file"
+        );
+    }
+
+    #[test]
+    fn test_status_reports_package_state() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg/a.txt"), "a");
+        fs.add_file(stow_dir.join("pkg/b.txt"), "b");
+        fs.add_file(stow_dir.join("pkg/app/config.txt"), "cfg");
+
+        let stower = Stower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        let status = stower.status("pkg").unwrap();
+        assert_eq!(status.state(), status::StowState::NotStowed);
+        assert_eq!(status.missing.len(), 3);
+
+        stower.stow_package("pkg").unwrap();
+        let status = stower.status("pkg").unwrap();
+        assert_eq!(status.state(), status::StowState::Stowed);
+        assert_eq!(status.linked.len(), 3);
+
+        // Status never changes the target
+        fs.remove_link(&target_dir.join("b.txt")).unwrap();
+        let status = stower.status("pkg").unwrap();
+        assert_eq!(status.state(), status::StowState::Partial);
+        assert_eq!(status.missing, vec![target_dir.join("b.txt")]);
+        assert!(!fs.exists(&target_dir.join("b.txt")));
+
+        fs.add_file(target_dir.join("b.txt"), "local");
+        let status = stower.status("pkg").unwrap();
+        assert_eq!(status.state(), status::StowState::Conflicting);
+        assert_eq!(status.shadowed[0].path, target_dir.join("b.txt"));
+    }
+
+    #[test]
+    fn test_status_reports_links_elsewhere_and_deferred_paths() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg1/shared.txt"), "1");
+        fs.add_file(stow_dir.join("pkg2/shared.txt"), "2");
+        fs.add_file(stow_dir.join("pkg2/deferred.txt"), "d");
+        fs.add_file(target_dir.join("deferred.txt"), "local");
+
+        let patterns = ignore::PatternSet::new(&[], &["deferred.txt".to_string()]).unwrap();
+        let stower = Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .with_patterns(patterns);
+        stower.stow_package("pkg1").unwrap();
+
+        let status = stower.status("pkg2").unwrap();
+        assert_eq!(status.state(), status::StowState::Conflicting);
+        assert_eq!(
+            status.foreign_links[0].kind,
+            ConflictKind::Package("pkg1".to_string())
+        );
+        assert_eq!(status.deferred, vec![target_dir.join("deferred.txt")]);
+    }
+
+    #[test]
+    fn test_package_files() {
+        let (fs, stow_dir, target_dir) = memfs_setup();
+        fs.add_file(stow_dir.join("pkg/dot-bashrc"), "");
+        fs.add_file(stow_dir.join("pkg/dot-config/app/config.txt"), "");
+        fs.add_file(stow_dir.join("pkg/notes.bak"), "");
+
+        let patterns = ignore::PatternSet::new(&["*.bak".to_string()], &[]).unwrap();
+        let stower = Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .with_patterns(patterns)
+            .with_dotfiles(true);
+
+        assert_eq!(
+            stower.package_files("pkg").unwrap(),
+            vec![
+                PathBuf::from(".bashrc"),
+                PathBuf::from(".config/app/config.txt")
+            ]
+        );
+        assert!(stower.package_files("missing").is_err());
+    }
 }
@@ -1,13 +1,28 @@
 use crate::error::{Result, StowError};
-use crate::{fs_ops, logger, path_utils, planner};
-use std::fs;
+use crate::filesystem::Filesystem;
+use crate::overlay::OverlayFilesystem;
+use crate::state::{self, State};
+use crate::stow::{self, FoldPolicy};
+use crate::{logger, path_utils, planner};
+use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
 /// Unstow operation manager
+#[derive(Clone)]
 pub struct Unstower {
     stow_dir: PathBuf,
     target_dir: PathBuf,
+    /// Used by the test helpers that plan and execute in one step
+    #[cfg(test)]
     dry_run: bool,
+    dotfiles: bool,
+    fold_policy: FoldPolicy,
+    /// Replace the package's links with real copies instead of removing them
+    materialize: bool,
+    /// State of the target, used to recognize hard links and copies of package files
+    state: Option<Rc<State>>,
+    fs: Rc<dyn Filesystem>,
 }
 
 impl Unstower {
@@ -16,7 +31,13 @@ impl Unstower {
         Self {
             stow_dir: context.stow_dir().to_owned(),
             target_dir: context.target_dir().to_owned(),
+            #[cfg(test)]
             dry_run: context.is_dry_run(),
+            dotfiles: context.dotfiles(),
+            fold_policy: context.fold_policy().clone(),
+            materialize: false,
+            state: None,
+            fs: Rc::clone(context.filesystem()),
         }
     }
 
@@ -32,17 +53,405 @@ impl Unstower {
             stow_dir: stow_dir.into(),
             target_dir: target_dir.into(),
             dry_run,
+            dotfiles: false,
+            fold_policy: FoldPolicy::default(),
+            materialize: false,
+            state: None,
+            fs: Rc::new(crate::filesystem::OsFilesystem),
         }
     }
 
+    /// Enable or disable `dot-` prefix translation
+    #[cfg(test)]
+    pub fn with_dotfiles(mut self, dotfiles: bool) -> Self {
+        self.dotfiles = dotfiles;
+        self
+    }
+
+    /// Set the fold policy
+    #[cfg(test)]
+    pub fn with_fold_policy(mut self, fold_policy: FoldPolicy) -> Self {
+        self.fold_policy = fold_policy;
+        self
+    }
+
+    /// Replace the package's links with real copies of their sources instead of removing them
+    pub fn with_materialize(mut self, materialize: bool) -> Self {
+        self.materialize = materialize;
+        self
+    }
+
+    /// Set the filesystem to operate on
+    #[cfg(test)]
+    pub fn with_filesystem(mut self, fs: Rc<dyn Filesystem>) -> Self {
+        self.fs = fs;
+        self
+    }
+
     /// Unstow a package
+    #[cfg(test)]
     #[must_use = "unstow operations can fail and should be checked"]
     pub fn unstow_package(&self, package_name: &str) -> Result<()> {
+        self.unstow_packages(&[package_name.to_string()])
+    }
+
+    /// Unstow several packages with a single combined plan
+    #[cfg(test)]
+    #[must_use = "unstow operations can fail and should be checked"]
+    pub fn unstow_packages(&self, package_names: &[String]) -> Result<()> {
+        let overlay = Rc::new(OverlayFilesystem::new(Rc::clone(&self.fs)));
+        let mut plan = planner::Plan::new();
+        self.plan_packages(package_names, &overlay, &mut plan)?;
+
+        logger::verbose(&format!("Plan has {} actions", plan.len()));
+
+        // Execute the combined plan
+        plan.execute(self.fs.as_ref(), self.dry_run)?;
+
+        Ok(())
+    }
+
+    /// Plan unstowing packages one after another on top of an overlay
+    /// Each package's plan is applied to the overlay and appended to `plan`
+    pub fn plan_packages(
+        &self,
+        package_names: &[String],
+        overlay: &Rc<OverlayFilesystem>,
+        plan: &mut planner::Plan,
+    ) -> Result<()> {
+        let view = Unstower {
+            fs: Rc::clone(overlay) as Rc<dyn Filesystem>,
+            state: Some(state::load_target(overlay.as_ref(), &self.target_dir)?),
+            ..self.clone()
+        };
+
+        // Directories visited while unstowing may be left with links of a single other package
+        let mut visited = Vec::new();
+
+        for package_name in package_names {
+            let mut package_plan = view.plan_package(package_name)?;
+            package_plan.set_package(package_name);
+            package_plan.simulate(overlay)?;
+            visited.extend(package_plan.actions().filter_map(|action| match action {
+                planner::Action::RemoveEmptyDir { path } => Some(path.clone()),
+                _ => None,
+            }));
+            plan.append(package_plan);
+        }
+
+        view.plan_refolds(visited, overlay, plan)
+    }
+
+    /// Plan removing broken links into the stow directory anywhere in the target
+    /// Only links into the given packages are removed, or links into any package if none
+    /// are given. The packages don't need to exist anymore.
+    pub fn plan_prune_broken(
+        &self,
+        package_names: &[String],
+        overlay: &Rc<OverlayFilesystem>,
+        plan: &mut planner::Plan,
+    ) -> Result<()> {
+        let view = Unstower {
+            fs: Rc::clone(overlay) as Rc<dyn Filesystem>,
+            ..self.clone()
+        };
+
+        let mut prune_plan = planner::Plan::new();
+        if view.is_protected_dir(&view.target_dir) {
+            logger::warn(&format!(
+                "Target directory {} is a stow directory, not pruning it",
+                view.target_dir.display()
+            ));
+        } else {
+            view.plan_prune_directory(&view.target_dir, package_names, &mut prune_plan)?;
+        }
+        prune_plan.simulate(overlay)?;
+
+        let visited = prune_plan
+            .actions()
+            .filter_map(|action| match action {
+                planner::Action::RemoveEmptyDir { path } => Some(path.clone()),
+                _ => None,
+            })
+            .collect();
+        plan.append(prune_plan);
+
+        view.plan_refolds(visited, overlay, plan)
+    }
+
+    /// Plan replacing links into the stow directory with real copies of their sources
+    /// A link is ejected on its own; a real directory has every link below it ejected.
+    /// Links inside a linked directory can only be ejected with that directory.
+    pub fn plan_eject(
+        &self,
+        paths: &[PathBuf],
+        overlay: &Rc<OverlayFilesystem>,
+        plan: &mut planner::Plan,
+    ) -> Result<()> {
+        let view = Unstower {
+            fs: Rc::clone(overlay) as Rc<dyn Filesystem>,
+            materialize: true,
+            ..self.clone()
+        };
+
+        for path in paths {
+            let invalid = |reason: &str| {
+                StowError::invalid_path(format!("Cannot eject {}: {}", path.display(), reason))
+            };
+
+            // Resolve links in the parent, but not the path itself
+            let parent_norm = match path.parent() {
+                Some(parent) if path.file_name().is_some() => view.fs.normalize_path(parent)?,
+                _ => return Err(invalid("it is not a link or directory in the target")),
+            };
+            if view.package_of(&parent_norm).is_some() {
+                return Err(invalid(
+                    "it is inside a linked directory, eject that instead",
+                ));
+            }
+
+            let mut eject_plan = planner::Plan::new();
+            if view.fs.is_symlink(path) {
+                if !view.plan_eject_link(path, &mut eject_plan)? {
+                    return Err(invalid("it is not a link into the stow directory"));
+                }
+            } else if view.fs.is_dir(path) && !view.is_protected_dir(path) {
+                view.plan_eject_directory(path, &mut eject_plan)?;
+                if eject_plan.len() == 0 {
+                    return Err(invalid(
+                        "there are no links into the stow directory below it",
+                    ));
+                }
+            } else {
+                return Err(invalid("it is not a link or directory in the target"));
+            }
+
+            eject_plan.simulate(overlay)?;
+            plan.append(eject_plan);
+        }
+
+        Ok(())
+    }
+
+    /// Recursively plan ejecting the links into the stow directory below a directory
+    /// Directory links are ejected as a whole, not followed
+    fn plan_eject_directory(&self, dir: &Path, plan: &mut planner::Plan) -> Result<()> {
+        for entry in self.fs.read_dir(dir)? {
+            if self.fs.is_symlink(&entry) {
+                self.plan_eject_link(&entry, plan)?;
+            } else if self.fs.is_dir(&entry) && !self.is_protected_dir(&entry) {
+                self.plan_eject_directory(&entry, plan)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Plan ejecting a link if it points into a package
+    /// Broken links have nothing to copy and are left alone. Returns whether the link is ejected.
+    fn plan_eject_link(&self, path: &Path, plan: &mut planner::Plan) -> Result<bool> {
+        if !self.fs.exists(path) {
+            logger::verbose(&format!("Broken link, not ejecting: {}", path.display()));
+            return Ok(false);
+        }
+
+        let link_target_norm = self.fs.resolve_link(path)?;
+        let Some(owner) = self.package_of(&link_target_norm) else {
+            return Ok(false);
+        };
+
+        plan.add_for_package(self.unlink_action(path, &link_target_norm), Some(owner));
+        Ok(true)
+    }
+
+    /// Recursively plan removing broken links below a target directory
+    /// Directory links are not followed. Returns whether anything was removed.
+    fn plan_prune_directory(
+        &self,
+        dir: &Path,
+        package_names: &[String],
+        plan: &mut planner::Plan,
+    ) -> Result<bool> {
+        let entries = match self.fs.read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if *dir != self.target_dir => {
+                logger::verbose(&format!("Cannot read {}, skipping: {}", dir.display(), e));
+                return Ok(false);
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut removed = false;
+        for entry in entries {
+            if self.fs.is_symlink(&entry) {
+                let Some(owner) = self.stale_link_owner(&entry) else {
+                    continue;
+                };
+                if !package_names.is_empty()
+                    && !package_names.iter().any(|name| same_package(name, &owner))
+                {
+                    continue;
+                }
+
+                logger::verbose(&format!("Removing broken link: {}", entry.display()));
+                plan.add_for_package(planner::Action::RemoveLink { path: entry }, Some(owner));
+                removed = true;
+            } else if self.fs.is_dir(&entry) && !self.is_protected_dir(&entry) {
+                removed |= self.plan_prune_directory(&entry, package_names, plan)?;
+            }
+        }
+
+        if removed && *dir != self.target_dir {
+            plan.add(planner::Action::RemoveEmptyDir {
+                path: dir.to_path_buf(),
+            });
+        }
+
+        Ok(removed)
+    }
+
+    /// Package a broken link points into
+    /// Returns `None` for links that still resolve and for links outside the stow directory
+    fn stale_link_owner(&self, path: &Path) -> Option<String> {
+        if !self.fs.is_symlink(path) || self.fs.exists(path) {
+            return None;
+        }
+
+        let link_target_norm = self.fs.resolve_link(path).ok()?;
+        self.package_of(&link_target_norm)
+    }
+
+    /// Plan refolding directories that only hold links into a single package directory
+    /// Deeper directories are refolded first, so their parents can be refolded as well
+    fn plan_refolds(
+        &self,
+        mut dirs: Vec<PathBuf>,
+        overlay: &OverlayFilesystem,
+        plan: &mut planner::Plan,
+    ) -> Result<()> {
+        dirs.sort_by(|a, b| {
+            b.components()
+                .count()
+                .cmp(&a.components().count())
+                .then_with(|| a.cmp(b))
+        });
+        dirs.dedup();
+
+        for dir in dirs {
+            let Some(package_dir) = self.fold_target(&dir)? else {
+                continue;
+            };
+
+            let dir_parent = dir.parent().unwrap_or(&dir);
+            let dir_parent_abs = self.fs.normalize_path(dir_parent)?;
+            let relative_path = path_utils::compute_relative_path(&dir_parent_abs, &package_dir)?;
+            let action = planner::Action::RefoldDir {
+                link_path: dir.clone(),
+                target_path: relative_path,
+            };
+
+            logger::verbose(&format!("Refolding: {}", dir.display()));
+
+            // Simulated right away so parent directories see the refolded link
+            let mut refold = planner::Plan::new();
+            refold.add_for_package(action, self.package_of(&package_dir));
+            refold.simulate(overlay)?;
+            plan.append(refold);
+        }
+
+        Ok(())
+    }
+
+    /// Find the package directory a directory can be folded into
+    /// This is the case when every entry is a link to the entry of the same name in one
+    /// package directory that mirrors the directory's position, and that package
+    /// directory has no other entries. Returns the normalized package directory.
+    fn fold_target(&self, dir: &Path) -> Result<Option<PathBuf>> {
+        if !self.fs.exists(dir) || self.fs.is_symlink(dir) || !self.fs.is_dir(dir) {
+            return Ok(None);
+        }
+
+        let entries = self.fs.read_dir(dir)?;
+        let mut package_dir: Option<PathBuf> = None;
+
+        for entry in &entries {
+            if !self.fs.is_symlink(entry) {
+                return Ok(None);
+            }
+
+            let link_target_norm = self.fs.resolve_link(entry)?;
+            let (Some(parent), Some(name)) = (link_target_norm.parent(), entry.file_name()) else {
+                return Ok(None);
+            };
+            let Some(target_name) = link_target_norm.file_name() else {
+                return Ok(None);
+            };
+
+            if !path_utils::paths_equal(self.target_name(target_name), name) {
+                return Ok(None);
+            }
+
+            match &package_dir {
+                Some(existing) if !path_utils::paths_equal(existing, parent) => return Ok(None),
+                Some(_) => {}
+                None => package_dir = Some(parent.to_path_buf()),
+            }
+        }
+
+        let Some(package_dir) = package_dir else {
+            return Ok(None);
+        };
+
+        // The package directory must be stowed at exactly this position
+        let target_dir_norm = self.fs.normalize_path(&self.target_dir)?;
+        let dir_norm = self.fs.normalize_path(dir)?;
+        let stow_dir_norm = self.fs.normalize_path(&self.stow_dir)?;
+        let (Some(dir_relative), Some(package_relative)) = (
+            path_utils::strip_base(&dir_norm, &target_dir_norm),
+            path_utils::strip_base(&package_dir, &stow_dir_norm),
+        ) else {
+            return Ok(None);
+        };
+
+        if !self.fold_policy.allows(&dir_relative) {
+            return Ok(None);
+        }
+
+        // Drop the package name and translate the rest like stowing would
+        let mut components = package_relative.components();
+        if components.next().is_none() {
+            return Ok(None);
+        }
+        let expected: PathBuf = components
+            .map(|c| self.target_name(c.as_os_str()))
+            .collect();
+        if expected.as_os_str().is_empty() || !path_utils::paths_equal(&expected, &dir_relative) {
+            return Ok(None);
+        }
+
+        // Folding must not expose package entries that were never linked here
+        if !self.fs.is_dir(&package_dir) || self.fs.read_dir(&package_dir)?.len() != entries.len() {
+            return Ok(None);
+        }
+
+        Ok(Some(package_dir))
+    }
+
+    /// Name of the package a normalized path belongs to
+    /// Returns `None` for paths outside the stow directory
+    fn package_of(&self, path: &Path) -> Option<String> {
+        let stow_dir_norm = self.fs.normalize_path(&self.stow_dir).ok()?;
+        let relative = path_utils::strip_base(path, &stow_dir_norm)?;
+        let package = relative.components().next()?;
+        Some(package.as_os_str().to_string_lossy().into_owned())
+    }
+
+    /// Plan unstowing a single package against the current filesystem
+    fn plan_package(&self, package_name: &str) -> Result<planner::Plan> {
         let package_path = self.stow_dir.join(package_name);
 
-        // Verify package exists
-        if !package_path.exists() {
-            return Err(StowError::package_not_found(package_name, &self.stow_dir));
+        // A package removed from the stow directory can still have links in the target
+        if !self.fs.exists(&package_path) {
+            return self.plan_missing_package(package_name);
         }
 
         logger::verbose(&format!("Unstowing package: {}", package_name));
@@ -50,15 +459,41 @@ impl Unstower {
         // Create a plan
         let mut plan = planner::Plan::new();
 
+        if self.is_protected_dir(&self.target_dir) {
+            logger::warn(&format!(
+                "Target directory {} is a stow directory, not unstowing from it",
+                self.target_dir.display()
+            ));
+            return Ok(plan);
+        }
+
         // Find and plan removal of all symlinks pointing to this package
         self.plan_unstow_directory(&package_path, &self.target_dir, &mut plan)?;
 
-        logger::verbose(&format!("Plan has {} actions", plan.len()));
+        Ok(plan)
+    }
 
-        // Execute the plan
-        plan.execute(self.dry_run)?;
+    /// Plan unstowing a package that no longer exists in the stow directory
+    /// All links in the target resolving into the missing package directory are removed.
+    /// Fails with `PackageNotFound` if there are none, which usually means a typo.
+    fn plan_missing_package(&self, package_name: &str) -> Result<planner::Plan> {
+        let mut plan = planner::Plan::new();
 
-        Ok(())
+        if !self.is_protected_dir(&self.target_dir) {
+            self.plan_prune_directory(&self.target_dir, &[package_name.to_string()], &mut plan)?;
+        }
+
+        if plan.len() == 0 {
+            return Err(StowError::package_not_found(package_name, &self.stow_dir));
+        }
+
+        logger::warn(&format!(
+            "Package '{}' no longer exists in {}, removing its remaining links",
+            package_name,
+            self.stow_dir.display()
+        ));
+
+        Ok(plan)
     }
 
     /// Recursively plan unstowing a directory
@@ -69,36 +504,53 @@ impl Unstower {
         plan: &mut planner::Plan,
     ) -> Result<()> {
         // If target doesn't exist, nothing to unstow
-        if !target_dir.exists() {
+        if !self.fs.exists(target_dir) {
             return Ok(());
         }
 
         // Read the package directory to know what to look for
-        let entries = fs::read_dir(package_dir)
-            .map_err(|e| StowError::io_error(package_dir.to_path_buf(), e))?;
-
-        for entry in entries {
-            let entry = entry.map_err(|e| StowError::io_error(package_dir.to_path_buf(), e))?;
-            let package_item = entry.path();
-            let name = entry.file_name();
-            let target_item = target_dir.join(&name);
+        let mut target_items = Vec::new();
+        for package_item in self.fs.read_dir(package_dir)? {
+            let Some(name) = package_item.file_name() else {
+                continue;
+            };
+            let target_item = target_dir.join(self.target_name(name));
+            target_items.push(target_item.clone());
 
             // Skip if target doesn't exist
-            if !target_item.exists() {
+            if !self.fs.exists(&target_item) {
                 continue;
             }
 
-            let metadata = entry
-                .metadata()
-                .map_err(|e| StowError::io_error(package_item.clone(), e))?;
-
-            if metadata.is_dir() {
+            if self.fs.is_dir(&package_item) {
                 self.plan_unstow_dir_item(&package_item, &target_item, plan)?;
             } else {
                 self.plan_unstow_file(&package_item, &target_item, plan)?;
             }
         }
 
+        // Links into this package whose source was removed from the package since stowing
+        let package_name = self.package_of(&self.fs.normalize_path(package_dir)?);
+        for entry in self.fs.read_dir(target_dir)? {
+            if let Some(owner) = self.stale_link_owner(&entry)
+                && package_name
+                    .as_deref()
+                    .is_some_and(|name| same_package(name, &owner))
+            {
+                logger::verbose(&format!("Removing stale link: {}", entry.display()));
+                plan.add(planner::Action::RemoveLink { path: entry });
+            } else if let Some(name) = &package_name
+                && !target_items
+                    .iter()
+                    .any(|item| path_utils::paths_equal(item, &entry))
+                && !self.fs.is_symlink(&entry)
+                && self.fs.is_dir(&entry)
+            {
+                // A directory removed from the package can still hold its recorded links
+                self.plan_remove_recorded_links(&entry, name, plan);
+            }
+        }
+
         // After removing items, check if target_dir is empty and should be pruned
         // We'll do this after all removals
         if *target_dir != self.target_dir {
@@ -111,6 +563,46 @@ impl Unstower {
         Ok(())
     }
 
+    /// Plan removing the stale links the state file records for a package below `dir`
+    /// Only the recorded paths are looked at, the directory itself is never read.
+    /// Directories left empty up to and including `dir` are removed, deepest first.
+    fn plan_remove_recorded_links(&self, dir: &Path, package_name: &str, plan: &mut planner::Plan) {
+        let Some(state) = &self.state else {
+            return;
+        };
+
+        let mut emptied = Vec::new();
+        for path in state.link_paths(package_name) {
+            if path_utils::strip_base(&path, dir).is_none_or(|rest| rest.as_os_str().is_empty())
+                || !self
+                    .stale_link_owner(&path)
+                    .is_some_and(|owner| same_package(package_name, &owner))
+            {
+                continue;
+            }
+
+            logger::verbose(&format!("Removing stale link: {}", path.display()));
+            plan.add(planner::Action::RemoveLink { path: path.clone() });
+            emptied.extend(
+                path.ancestors()
+                    .skip(1)
+                    .take_while(|parent| path_utils::strip_base(parent, dir).is_some())
+                    .map(Path::to_path_buf),
+            );
+        }
+
+        emptied.sort_by(|a, b| {
+            b.components()
+                .count()
+                .cmp(&a.components().count())
+                .then_with(|| a.cmp(b))
+        });
+        emptied.dedup();
+        for path in emptied {
+            plan.add(planner::Action::RemoveEmptyDir { path });
+        }
+    }
+
     /// Plan unstowing a file
     fn plan_unstow_file(
         &self,
@@ -118,42 +610,24 @@ impl Unstower {
         target_file: &Path,
         plan: &mut planner::Plan,
     ) -> Result<()> {
-        // Check if target is a symlink
-        if !fs_ops::is_symlink(target_file) {
-            // Not a symlink, might be a conflict or already removed
-            logger::verbose(&format!(
-                "Target is not a symlink, skipping: {}",
-                target_file.display()
-            ));
-            return Ok(());
-        }
-
-        // Read the symlink target
-        let link_target = fs_ops::read_symlink(target_file)?;
-        let link_target_abs = if link_target.is_relative() {
-            target_file
-                .parent()
-                .unwrap_or(target_file)
-                .join(&link_target)
-        } else {
-            link_target.clone()
-        };
+        let package_file_norm = self.fs.normalize_path(package_file)?;
 
-        // Normalize both paths for comparison
-        let link_target_norm = path_utils::normalize_path(&link_target_abs)?;
-        let package_file_norm = path_utils::normalize_path(package_file)?;
-
-        // Check if the symlink points to our package
-        if path_utils::paths_equal(&link_target_norm, &package_file_norm) {
-            // This symlink is from our package, remove it
-            plan.add(planner::Action::RemoveLink {
-                path: target_file.to_path_buf(),
-            });
-        } else {
+        // Only links, hard links and copies of this package file are removed
+        if !state::is_deployed(
+            self.fs.as_ref(),
+            self.state.as_deref(),
+            target_file,
+            &package_file_norm,
+        ) {
             logger::verbose(&format!(
-                "Symlink points elsewhere, skipping: {}",
+                "Target is not a link of the package, skipping: {}",
                 target_file.display()
             ));
+        } else if self.fs.is_symlink(target_file) {
+            plan.add(self.unlink_action(target_file, &package_file_norm));
+        } else {
+            // A hard link or copy of the package file is removed like a link
+            plan.add(self.uncopy_action(target_file, &package_file_norm));
         }
 
         Ok(())
@@ -166,31 +640,35 @@ impl Unstower {
         target_dir: &Path,
         plan: &mut planner::Plan,
     ) -> Result<()> {
+        // Links inside other stow trees or the stow directory are never touched
+        if self.is_protected_dir(target_dir) {
+            logger::verbose(&format!(
+                "Skipping protected directory: {}",
+                target_dir.display()
+            ));
+            return Ok(());
+        }
+
         // Check if target is a symlink
-        if fs_ops::is_symlink(target_dir) {
+        if self.fs.is_symlink(target_dir) {
             // Check if it points to our package directory
-            let link_target = fs_ops::read_symlink(target_dir)?;
-            let link_target_abs = if link_target.is_relative() {
-                target_dir.parent().unwrap_or(target_dir).join(&link_target)
-            } else {
-                link_target.clone()
-            };
-
-            let link_target_norm = path_utils::normalize_path(&link_target_abs)?;
-            let package_dir_norm = path_utils::normalize_path(package_dir)?;
+            let package_dir_norm = self.fs.normalize_path(package_dir)?;
 
-            if path_utils::paths_equal(&link_target_norm, &package_dir_norm) {
+            if state::is_deployed(
+                self.fs.as_ref(),
+                self.state.as_deref(),
+                target_dir,
+                &package_dir_norm,
+            ) {
                 // This directory symlink is from our package, remove it
-                plan.add(planner::Action::RemoveLink {
-                    path: target_dir.to_path_buf(),
-                });
+                plan.add(self.unlink_action(target_dir, &package_dir_norm));
             } else {
                 logger::verbose(&format!(
                     "Directory symlink points elsewhere, skipping: {}",
                     target_dir.display()
                 ));
             }
-        } else if target_dir.is_dir() {
+        } else if self.fs.is_dir(target_dir) {
             // It's a real directory, recurse into it
             self.plan_unstow_directory(package_dir, target_dir, plan)?;
         } else {
@@ -202,12 +680,64 @@ impl Unstower {
 
         Ok(())
     }
+
+    /// Action removing a link of the package, or replacing it with a copy of its source
+    /// The source path must be normalized
+    fn unlink_action(&self, path: &Path, source_norm: &Path) -> planner::Action {
+        if self.materialize {
+            planner::Action::MaterializeLink {
+                path: path.to_path_buf(),
+                source_path: source_norm.to_path_buf(),
+            }
+        } else {
+            planner::Action::RemoveLink {
+                path: path.to_path_buf(),
+            }
+        }
+    }
+
+    /// Action removing a hard link or copy of a package file, or replacing it with a fresh copy
+    /// The source path must be normalized
+    fn uncopy_action(&self, path: &Path, source_norm: &Path) -> planner::Action {
+        if self.materialize {
+            planner::Action::MaterializeLink {
+                path: path.to_path_buf(),
+                source_path: source_norm.to_path_buf(),
+            }
+        } else {
+            planner::Action::RemoveCopy {
+                path: path.to_path_buf(),
+                source_path: source_norm.to_path_buf(),
+            }
+        }
+    }
+
+    /// Check if a directory must never be unstowed from, see `stow::is_protected_dir`
+    fn is_protected_dir(&self, path: &Path) -> bool {
+        stow::is_protected_dir(self.fs.as_ref(), path, &self.stow_dir, &self.target_dir)
+    }
+
+    /// Name of a package entry in the target directory
+    fn target_name(&self, name: &OsStr) -> OsString {
+        if self.dotfiles {
+            path_utils::dotfile_name(name)
+        } else {
+            name.to_owned()
+        }
+    }
+}
+
+/// Compare package names, case-insensitively on Windows
+fn same_package(a: &str, b: &str) -> bool {
+    path_utils::paths_equal(Path::new(a), Path::new(b))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::File;
+    use crate::fs_ops::LinkMode;
+    use crate::memfs::MemoryFilesystem;
+    use std::fs::{self, File};
     use tempfile::TempDir;
 
     #[test]
@@ -349,4 +879,601 @@ mod tests {
         let result = unstower.unstow_package("mypackage");
         assert!(result.is_ok());
     }
+
+    /// Stow a package on an in-memory filesystem and return it
+    fn memfs_stowed(files: &[&str]) -> (Rc<MemoryFilesystem>, PathBuf, PathBuf) {
+        let fs = Rc::new(MemoryFilesystem::new());
+        let stow_dir = MemoryFilesystem::root().join("stow");
+        let target_dir = MemoryFilesystem::root().join("target");
+        fs.add_dir(&target_dir);
+        for file in files {
+            fs.add_file(stow_dir.join("pkg").join(file), *file);
+        }
+
+        crate::stow::Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .stow_package("pkg")
+            .unwrap();
+
+        (fs, stow_dir, target_dir)
+    }
+
+    #[test]
+    fn test_unstow_materialize_replaces_links_with_copies() {
+        let (fs, stow_dir, target_dir) =
+            memfs_stowed(&["a.txt", "app/config.txt", "app/sub/x.txt"]);
+        fs.add_file(stow_dir.join("other/b.txt"), "b");
+        crate::stow::Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .stow_package("other")
+            .unwrap();
+        assert!(fs.is_symlink(&target_dir.join("app")));
+
+        Unstower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .with_materialize(true)
+            .unstow_package("pkg")
+            .unwrap();
+
+        assert!(!fs.is_symlink(&target_dir.join("a.txt")));
+        assert_eq!(fs.file_content(target_dir.join("a.txt")).unwrap(), b"a.txt");
+        // The folded directory is expanded into a real tree
+        assert!(!fs.is_symlink(&target_dir.join("app")));
+        assert_eq!(
+            fs.file_content(target_dir.join("app/sub/x.txt")).unwrap(),
+            b"app/sub/x.txt"
+        );
+        // The package and other packages are left alone
+        assert!(fs.exists(&stow_dir.join("pkg/app/sub/x.txt")));
+        assert!(fs.is_symlink(&target_dir.join("b.txt")));
+    }
+
+    /// Plan and execute ejecting target paths
+    fn eject(unstower: &Unstower, paths: &[PathBuf]) -> Result<()> {
+        let overlay = Rc::new(OverlayFilesystem::new(Rc::clone(&unstower.fs)));
+        let mut plan = planner::Plan::new();
+        unstower.plan_eject(paths, &overlay, &mut plan)?;
+        plan.execute(unstower.fs.as_ref(), false)
+    }
+
+    #[test]
+    fn test_eject_links_and_directories() {
+        let (fs, stow_dir, target_dir) = memfs_stowed(&["a.txt", "app/config.txt"]);
+        fs.add_file(stow_dir.join("other/conf/b.txt"), "b");
+        fs.add_file(target_dir.join("conf/local.txt"), "local");
+        crate::stow::Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .stow_package("other")
+            .unwrap();
+        assert!(fs.is_symlink(&target_dir.join("conf/b.txt")));
+
+        let unstower =
+            Unstower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        eject(
+            &unstower,
+            &[target_dir.join("app"), target_dir.join("conf")],
+        )
+        .unwrap();
+
+        assert!(!fs.is_symlink(&target_dir.join("app")));
+        assert_eq!(
+            fs.file_content(target_dir.join("app/config.txt")).unwrap(),
+            b"app/config.txt"
+        );
+        assert!(!fs.is_symlink(&target_dir.join("conf/b.txt")));
+        assert_eq!(
+            fs.file_content(target_dir.join("conf/b.txt")).unwrap(),
+            b"b"
+        );
+        // Links not below the given paths stay
+        assert!(fs.is_symlink(&target_dir.join("a.txt")));
+    }
+
+    #[test]
+    fn test_eject_rejects_unmanaged_paths() {
+        let (fs, stow_dir, target_dir) = memfs_stowed(&["app/config.txt"]);
+        fs.add_file(target_dir.join("plain.txt"), "plain");
+        fs.add_file(target_dir.join("empty/local.txt"), "local");
+
+        let unstower =
+            Unstower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        for path in [
+            target_dir.join("app/config.txt"),
+            target_dir.join("plain.txt"),
+            target_dir.join("empty"),
+            target_dir.join("missing"),
+        ] {
+            assert!(matches!(
+                eject(&unstower, &[path]),
+                Err(StowError::InvalidPath(_))
+            ));
+        }
+        assert!(fs.is_symlink(&target_dir.join("app")));
+    }
+
+    #[test]
+    fn test_unstow_packages_sharing_a_directory() {
+        let fs = Rc::new(MemoryFilesystem::new());
+        let stow_dir = MemoryFilesystem::root().join("stow");
+        let target_dir = MemoryFilesystem::root().join("target");
+        fs.add_dir(&target_dir);
+        fs.add_file(stow_dir.join("pkg1/shared/a.txt"), "a");
+        fs.add_file(stow_dir.join("pkg2/shared/b.txt"), "b");
+        let packages = ["pkg1".to_string(), "pkg2".to_string()];
+
+        crate::stow::Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .stow_packages(&packages)
+            .unwrap();
+        assert!(fs.is_dir(&target_dir.join("shared")));
+
+        Unstower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .unstow_packages(&packages)
+            .unwrap();
+
+        assert!(fs.read_dir(&target_dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unstow_dotfiles_translates_names() {
+        let fs = Rc::new(MemoryFilesystem::new());
+        let stow_dir = MemoryFilesystem::root().join("stow");
+        let target_dir = MemoryFilesystem::root().join("target");
+        fs.add_file(stow_dir.join("pkg/dot-bashrc"), "bash");
+        fs.add_file(stow_dir.join("pkg/dot-config/app.toml"), "app");
+        fs.add_file(target_dir.join(".config/other.toml"), "other");
+
+        crate::stow::Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .with_dotfiles(true)
+            .stow_package("pkg")
+            .unwrap();
+        assert!(fs.is_symlink(&target_dir.join(".config/app.toml")));
+
+        // Without translation the links are not recognized
+        let unstower =
+            Unstower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        unstower.unstow_package("pkg").unwrap();
+        assert!(fs.is_symlink(&target_dir.join(".bashrc")));
+
+        unstower.with_dotfiles(true).unstow_package("pkg").unwrap();
+        assert!(!fs.exists(&target_dir.join(".bashrc")));
+        assert!(!fs.exists(&target_dir.join(".config/app.toml")));
+        assert!(fs.exists(&target_dir.join(".config/other.toml")));
+    }
+
+    #[test]
+    fn test_unstow_refolds_directory_of_remaining_package() {
+        let fs = Rc::new(MemoryFilesystem::new());
+        let stow_dir = MemoryFilesystem::root().join("stow");
+        let target_dir = MemoryFilesystem::root().join("target");
+        fs.add_dir(&target_dir);
+        fs.add_file(stow_dir.join("pkg1/app/nested/a.txt"), "a");
+        fs.add_file(stow_dir.join("pkg1/app/one.txt"), "one");
+        fs.add_file(stow_dir.join("pkg2/app/nested/b.txt"), "b");
+
+        let stower = crate::stow::Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone());
+        stower.stow_package("pkg1").unwrap();
+        stower.stow_package("pkg2").unwrap();
+        assert!(!fs.is_symlink(&target_dir.join("app/nested")));
+
+        let unstower =
+            Unstower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        let overlay = Rc::new(OverlayFilesystem::new(fs.clone()));
+        let mut plan = planner::Plan::new();
+        unstower
+            .plan_packages(&["pkg2".to_string()], &overlay, &mut plan)
+            .unwrap();
+
+        // The nested directory is refolded first, then its parent
+        let refolds: Vec<_> = plan
+            .steps()
+            .iter()
+            .filter(|s| matches!(s.action, planner::Action::RefoldDir { .. }))
+            .map(|s| (s.action.path().to_path_buf(), s.package.as_deref()))
+            .collect();
+        assert_eq!(
+            refolds,
+            vec![
+                (target_dir.join("app/nested"), Some("pkg1")),
+                (target_dir.join("app"), Some("pkg1")),
+            ]
+        );
+
+        plan.execute(fs.as_ref(), false).unwrap();
+        assert_eq!(
+            fs.read_link(&target_dir.join("app")).unwrap(),
+            PathBuf::from("../stow/pkg1/app")
+        );
+        assert_eq!(fs.read_dir(&target_dir).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_unstow_does_not_refold_directory_with_other_files() {
+        let fs = Rc::new(MemoryFilesystem::new());
+        let stow_dir = MemoryFilesystem::root().join("stow");
+        let target_dir = MemoryFilesystem::root().join("target");
+        fs.add_dir(&target_dir);
+        fs.add_file(stow_dir.join("pkg1/app/a.txt"), "a");
+        fs.add_file(stow_dir.join("pkg1/app/ignored.txt"), "ignored");
+        fs.add_file(stow_dir.join("pkg2/app/b.txt"), "b");
+        fs.add_file(stow_dir.join("pkg3/app/c.txt"), "c");
+        fs.add_file(target_dir.join("app/local.txt"), "local");
+
+        let stower = crate::stow::Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone());
+        stower.stow_package("pkg2").unwrap();
+        stower.stow_package("pkg3").unwrap();
+
+        // A real file keeps the directory from being refolded
+        let unstower =
+            Unstower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        unstower.unstow_package("pkg2").unwrap();
+        assert!(!fs.is_symlink(&target_dir.join("app")));
+        assert!(fs.is_symlink(&target_dir.join("app/c.txt")));
+
+        // Links of a package that provides more entries than are linked are kept as well
+        fs.remove_file(&target_dir.join("app/local.txt")).unwrap();
+        fs.create_link(
+            &target_dir.join("app/a.txt"),
+            Path::new("../../stow/pkg1/app/a.txt"),
+            false,
+        )
+        .unwrap();
+        unstower.unstow_package("pkg3").unwrap();
+        assert!(!fs.is_symlink(&target_dir.join("app")));
+        assert!(fs.is_symlink(&target_dir.join("app/a.txt")));
+    }
+
+    #[test]
+    fn test_unstow_does_not_refold_never_fold_paths() {
+        let fs = Rc::new(MemoryFilesystem::new());
+        let stow_dir = MemoryFilesystem::root().join("stow");
+        let target_dir = MemoryFilesystem::root().join("target");
+        fs.add_dir(&target_dir);
+        fs.add_file(stow_dir.join("pkg1/.config/a.toml"), "a");
+        fs.add_file(stow_dir.join("pkg2/.config/b.toml"), "b");
+        let policy = FoldPolicy::new(true, &[".config".to_string()]);
+
+        crate::stow::Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .with_fold_policy(policy.clone())
+            .stow_packages(&["pkg1".to_string(), "pkg2".to_string()])
+            .unwrap();
+
+        Unstower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .with_fold_policy(policy)
+            .unstow_package("pkg2")
+            .unwrap();
+
+        assert!(!fs.is_symlink(&target_dir.join(".config")));
+        assert!(fs.is_symlink(&target_dir.join(".config/a.toml")));
+    }
+
+    #[test]
+    fn test_unstow_skips_marked_directories() {
+        let fs = Rc::new(MemoryFilesystem::new());
+        let stow_dir = MemoryFilesystem::root().join("stow");
+        let target_dir = MemoryFilesystem::root().join("target");
+        fs.add_file(stow_dir.join("pkg/tree/a.txt"), "a");
+        fs.add_file(target_dir.join("tree/.stow"), "");
+        fs.create_link(
+            &target_dir.join("tree/a.txt"),
+            Path::new("../../stow/pkg/tree/a.txt"),
+            false,
+        )
+        .unwrap();
+
+        Unstower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .unstow_package("pkg")
+            .unwrap();
+
+        assert!(fs.is_symlink(&target_dir.join("tree/a.txt")));
+    }
+
+    #[test]
+    fn test_unstow_removes_stale_links() {
+        let (fs, stow_dir, target_dir) = memfs_stowed(&["keep.txt", "old.txt"]);
+        fs.add_file(stow_dir.join("other/other.txt"), "other");
+        crate::stow::Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .stow_package("other")
+            .unwrap();
+
+        // The package file and the other package's file are removed after stowing
+        fs.remove_file(&stow_dir.join("pkg/old.txt")).unwrap();
+        fs.remove_file(&stow_dir.join("other/other.txt")).unwrap();
+        assert!(fs.is_symlink(&target_dir.join("old.txt")));
+
+        Unstower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .unstow_package("pkg")
+            .unwrap();
+
+        assert!(!fs.is_symlink(&target_dir.join("keep.txt")));
+        assert!(!fs.is_symlink(&target_dir.join("old.txt")));
+        // Broken links of other packages are left alone
+        assert!(fs.is_symlink(&target_dir.join("other.txt")));
+    }
+
+    #[test]
+    fn test_unstow_removes_recorded_links_of_removed_package_directories() {
+        let fs = Rc::new(MemoryFilesystem::new());
+        let stow_dir = MemoryFilesystem::root().join("stow");
+        let target_dir = MemoryFilesystem::root().join("target");
+        fs.add_dir(&target_dir);
+        fs.add_file(stow_dir.join("pkg/keep.txt"), "keep");
+        fs.add_file(stow_dir.join("pkg/sub/a.txt"), "a");
+        fs.add_file(stow_dir.join("pkg/sub/deep/b.txt"), "b");
+        fs.add_file(target_dir.join("unrelated/deep/c.txt"), "local");
+
+        let stower = crate::stow::Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .with_fold_policy(FoldPolicy::new(false, &[]));
+        let overlay = Rc::new(OverlayFilesystem::new(fs.clone()));
+        let mut plan = planner::Plan::new();
+        stower
+            .plan_packages(&["pkg".to_string()], &overlay, &mut plan)
+            .unwrap();
+        plan.track_state(target_dir.join(state::STATE_FILE_NAME));
+        plan.execute(fs.as_ref(), false).unwrap();
+        assert!(fs.is_symlink(&target_dir.join("sub/deep/b.txt")));
+
+        // The whole subdirectory is removed from the package after stowing
+        fs.remove_dir_all(&stow_dir.join("pkg/sub")).unwrap();
+        let read_before = fs.dirs_read().len();
+
+        Unstower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .unstow_package("pkg")
+            .unwrap();
+
+        assert!(!fs.is_symlink(&target_dir.join("keep.txt")));
+        assert!(!fs.exists(&target_dir.join("sub")));
+        assert_eq!(
+            fs.file_content(target_dir.join("unrelated/deep/c.txt"))
+                .unwrap(),
+            b"local"
+        );
+        // Directories the package never had links in are not read
+        let unrelated = target_dir.join("unrelated");
+        assert!(
+            !fs.dirs_read()[read_before..]
+                .iter()
+                .any(|dir| dir.starts_with(&unrelated)),
+            "{:?}",
+            fs.dirs_read()
+        );
+    }
+
+    #[test]
+    fn test_prune_broken_removes_links_of_all_packages() {
+        let fs = Rc::new(MemoryFilesystem::new());
+        let stow_dir = MemoryFilesystem::root().join("stow");
+        let target_dir = MemoryFilesystem::root().join("target");
+        fs.add_file(stow_dir.join("pkg1/app/a.txt"), "a");
+        fs.add_file(stow_dir.join("pkg1/app/gone.txt"), "gone");
+        fs.add_file(stow_dir.join("pkg2/app/b.txt"), "b");
+        fs.add_file(stow_dir.join("pkg2/nested/deep/c.txt"), "c");
+        fs.add_file(target_dir.join("nested/deep/local.txt"), "local");
+        fs.create_link(
+            &target_dir.join("foreign.txt"),
+            Path::new("../elsewhere/missing.txt"),
+            false,
+        )
+        .unwrap();
+
+        crate::stow::Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .stow_packages(&["pkg1".to_string(), "pkg2".to_string()])
+            .unwrap();
+        fs.remove_file(&stow_dir.join("pkg1/app/gone.txt")).unwrap();
+        fs.remove_dir_all(&stow_dir.join("pkg2")).unwrap();
+        fs.remove_file(&target_dir.join("nested/deep/local.txt"))
+            .unwrap();
+
+        let unstower =
+            Unstower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        let overlay = Rc::new(OverlayFilesystem::new(fs.clone()));
+        let mut plan = planner::Plan::new();
+        unstower
+            .plan_prune_broken(&[], &overlay, &mut plan)
+            .unwrap();
+        plan.execute(fs.as_ref(), false).unwrap();
+
+        // app only holds pkg1's remaining link and is refolded, the emptied directories are gone
+        assert_eq!(
+            fs.read_link(&target_dir.join("app")).unwrap(),
+            PathBuf::from("../stow/pkg1/app")
+        );
+        assert!(!fs.exists(&target_dir.join("nested")));
+        // Links outside the stow directory are not touched
+        assert!(fs.is_symlink(&target_dir.join("foreign.txt")));
+    }
+
+    #[test]
+    fn test_unstow_removes_hard_links_and_recorded_copies() {
+        let fs = Rc::new(MemoryFilesystem::new());
+        let stow_dir = MemoryFilesystem::root().join("stow");
+        let target_dir = MemoryFilesystem::root().join("target");
+        fs.add_file(stow_dir.join("pkg/app/a.txt"), "a");
+        fs.add_file(stow_dir.join("pkg/app/b.txt"), "b");
+        fs.add_file(stow_dir.join("pkg/edited.txt"), "edited");
+        fs.add_dir(&target_dir);
+
+        let state_file = target_dir.join(state::STATE_FILE_NAME);
+        let plan_with_state = |mut plan: planner::Plan| {
+            plan.track_state(state_file.clone());
+            plan.execute(fs.as_ref(), false).unwrap();
+        };
+
+        let link = |name: &str, target: &str, mode| planner::Action::CreateFileLink {
+            link_path: target_dir.join(name),
+            target_path: PathBuf::from(target),
+            mode,
+        };
+        let mut plan = planner::Plan::new();
+        plan.add(link(
+            "app/a.txt",
+            "../../stow/pkg/app/a.txt",
+            LinkMode::Hardlink,
+        ));
+        plan.add(link(
+            "app/b.txt",
+            "../../stow/pkg/app/b.txt",
+            LinkMode::Copy,
+        ));
+        plan.add(link("edited.txt", "../stow/pkg/edited.txt", LinkMode::Copy));
+        plan.set_package("pkg");
+        plan_with_state(plan);
+        fs.write_file(&target_dir.join("edited.txt"), b"changed locally")
+            .unwrap();
+
+        let unstower =
+            Unstower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        let overlay = Rc::new(OverlayFilesystem::new(fs.clone()));
+        let mut plan = planner::Plan::new();
+        unstower
+            .plan_packages(&["pkg".to_string()], &overlay, &mut plan)
+            .unwrap();
+        plan_with_state(plan);
+
+        assert!(!fs.exists(&target_dir.join("app")));
+        // A copy changed in the target isn't the package's anymore
+        assert_eq!(
+            fs.file_content(target_dir.join("edited.txt")).unwrap(),
+            b"changed locally"
+        );
+        assert_eq!(
+            fs.file_content(stow_dir.join("pkg/app/a.txt")).unwrap(),
+            b"a"
+        );
+    }
+
+    #[test]
+    fn test_unstow_missing_package() {
+        let fs = Rc::new(MemoryFilesystem::new());
+        let stow_dir = MemoryFilesystem::root().join("stow");
+        let target_dir = MemoryFilesystem::root().join("target");
+        fs.add_file(stow_dir.join("pkg/app/a.txt"), "a");
+        fs.add_file(stow_dir.join("pkg/.config/tool.toml"), "tool");
+        fs.add_file(stow_dir.join("other/.config/other.toml"), "other");
+        fs.add_dir(&target_dir);
+
+        crate::stow::Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .stow_packages(&["pkg".to_string(), "other".to_string()])
+            .unwrap();
+        fs.remove_dir_all(&stow_dir.join("pkg")).unwrap();
+
+        let unstower =
+            Unstower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        unstower.unstow_package("pkg").unwrap();
+
+        // Only the other package's directory is left, refolded
+        assert_eq!(
+            fs.read_dir(&target_dir).unwrap(),
+            vec![target_dir.join(".config")]
+        );
+        assert_eq!(
+            fs.read_link(&target_dir.join(".config")).unwrap(),
+            PathBuf::from("../stow/other/.config")
+        );
+
+        // Nothing left to unstow
+        assert!(matches!(
+            unstower.unstow_package("pkg"),
+            Err(StowError::PackageNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_restow_keeps_shared_directory_unfolded() {
+        let fs = Rc::new(MemoryFilesystem::new());
+        let stow_dir = MemoryFilesystem::root().join("stow");
+        let target_dir = MemoryFilesystem::root().join("target");
+        fs.add_dir(&target_dir);
+        fs.add_file(stow_dir.join("pkg1/shared/a.txt"), "a");
+        fs.add_file(stow_dir.join("pkg2/shared/b.txt"), "b");
+
+        let stower = crate::stow::Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone());
+        stower.stow_package("pkg1").unwrap();
+        stower.stow_package("pkg2").unwrap();
+
+        // Unstow refolds into pkg1, stow unfolds again
+        let unstower =
+            Unstower::new(&stow_dir, &target_dir, false, false).with_filesystem(fs.clone());
+        let overlay = Rc::new(OverlayFilesystem::new(fs.clone()));
+        let mut plan = planner::Plan::new();
+        let packages = ["pkg2".to_string()];
+        unstower
+            .plan_packages(&packages, &overlay, &mut plan)
+            .unwrap();
+        stower
+            .plan_packages(&packages, &overlay, &mut plan)
+            .unwrap();
+        plan.verify(fs.clone()).unwrap();
+        plan.execute(fs.as_ref(), false).unwrap();
+
+        let shared = target_dir.join("shared");
+        assert!(!fs.is_symlink(&shared));
+        assert!(fs.is_symlink(&shared.join("a.txt")));
+        assert!(fs.is_symlink(&shared.join("b.txt")));
+    }
+
+    #[test]
+    fn test_unstow_removes_links() {
+        let (fs, stow_dir, target_dir) = memfs_stowed(&["file.txt", "app/config.txt"]);
+        assert!(fs.is_symlink(&target_dir.join("app")));
+
+        Unstower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .unstow_package("pkg")
+            .unwrap();
+
+        assert!(fs.read_dir(&target_dir).unwrap().is_empty());
+        // Package contents are untouched
+        assert!(fs.exists(&stow_dir.join("pkg/app/config.txt")));
+    }
+
+    #[test]
+    fn test_unstow_keeps_directories_with_other_files() {
+        let fs = Rc::new(MemoryFilesystem::new());
+        let stow_dir = MemoryFilesystem::root().join("stow");
+        let target_dir = MemoryFilesystem::root().join("target");
+        fs.add_file(stow_dir.join("pkg/.config/app.toml"), "app");
+        fs.add_file(target_dir.join(".config/other.toml"), "other");
+
+        crate::stow::Stower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .stow_package("pkg")
+            .unwrap();
+        assert!(fs.is_symlink(&target_dir.join(".config/app.toml")));
+
+        Unstower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .unstow_package("pkg")
+            .unwrap();
+
+        assert!(!fs.is_symlink(&target_dir.join(".config/app.toml")));
+        assert!(fs.exists(&target_dir.join(".config/other.toml")));
+    }
+
+    #[test]
+    fn test_unstow_ignores_links_of_other_packages() {
+        let (fs, stow_dir, target_dir) = memfs_stowed(&["file.txt"]);
+        fs.add_file(stow_dir.join("other/file.txt"), "other");
+
+        Unstower::new(&stow_dir, &target_dir, false, false)
+            .with_filesystem(fs.clone())
+            .unstow_package("other")
+            .unwrap();
+
+        assert!(fs.is_symlink(&target_dir.join("file.txt")));
+    }
 }
@@ -4,18 +4,18 @@
 
 use std::fs::{self, File};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tempfile::TempDir;
 
 // Helper to create a test package structure
-fn create_test_package(stow_dir: &PathBuf, package_name: &str) -> PathBuf {
+fn create_test_package(stow_dir: &Path, package_name: &str) -> PathBuf {
     let package_dir = stow_dir.join(package_name);
     fs::create_dir_all(&package_dir).unwrap();
     package_dir
 }
 
 // Helper to create a file with content
-fn create_file_with_content(path: &PathBuf, content: &str) {
+fn create_file_with_content(path: &Path, content: &str) {
     if let Some(parent) = path.parent() {
         fs::create_dir_all(parent).unwrap();
     }